@@ -1,30 +1,506 @@
-use crate::config::Config;
+use crate::config::{AuthScheme, CommitStyle, Config, Provider, DEFAULT_MAX_SUBJECT_LENGTH};
 use crate::errors::{Error, Result};
+use crate::git::FileStat;
+use crate::utils::{
+    allowed_types, condense_commits, estimate_tokens, gitmoji_for, parse_categorized_entry,
+    render_prompt_template, stat_summary, truncate_diff_by_tokens, with_spinner, ChangelogCategory,
+    CostEstimate, COMMITS_BUDGET,
+};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+mod retry;
+mod throttle;
+
+pub use retry::RetryPolicy;
+use retry::{is_retryable_status, Failure};
+use throttle::Throttle;
 
-const API_URL: &str = "https://api.anthropic.com/v1/messages";
 const ANTHROPIC_VERSION: &str = "2023-06-01";
 
+/// `User-Agent` of every API request, so they can be told apart in logs
+pub const USER_AGENT: &str = concat!("autocommit/", env!("CARGO_PKG_VERSION"));
+
+/// Options shaping the commit message prompts
+#[derive(Debug, Clone, Default)]
+pub struct CommitPromptOptions {
+    /// Conventional-commit scope, as in `feat(scope): ...`
+    pub scope: Option<String>,
+    /// Ask for a subject line followed by a wrapped body explaining the why
+    pub body: bool,
+    /// Earlier suggestions the user asked to regenerate, to steer away from
+    pub rejected: Vec<String>,
+    /// Freeform guidance from the user, e.g. intent the diff doesn't show
+    pub hint: Option<String>,
+    /// Allowed commit types, `CONVENTIONAL_TYPES` when empty
+    pub types: Vec<String>,
+    /// Whether to put a gitmoji before the type
+    pub style: CommitStyle,
+    /// Line counts per file, so the model sees the whole change even when
+    /// the diff is truncated
+    pub stats: Vec<FileStat>,
+    /// Language to write the description in, `Config::language` when unset
+    pub language: Option<String>,
+    /// Longest subject line, `Config::max_subject_length` when unset
+    pub max_subject_length: Option<usize>,
+    /// Subject line written by the user, kept as it is while only the body
+    /// is generated
+    pub subject: Option<String>,
+    /// Message written by the user, to be polished rather than replaced
+    pub draft: Option<String>,
+}
+
+/// Rule asking for the gitmoji matching each allowed type
+fn gitmoji_rule(types: &[String], shortcode: bool) -> String {
+    let pairs: Vec<String> = types
+        .iter()
+        .filter_map(|kind| gitmoji_for(kind, shortcode).map(|emoji| format!("{} {}", emoji, kind)))
+        .collect();
+    let mut rule = format!(
+        "- Put the gitmoji for the type before it, separated by a space: {}\n",
+        pairs.join(", ")
+    );
+    let unmapped: Vec<&str> = types
+        .iter()
+        .filter(|kind| gitmoji_for(kind, shortcode).is_none())
+        .map(String::as_str)
+        .collect();
+    if !unmapped.is_empty() {
+        rule.push_str(&format!(
+            "- Use no gitmoji for the {} types\n",
+            unmapped.join(", ")
+        ));
+    }
+    rule
+}
+
+/// Format the user's hint for inclusion in a prompt, if any
+fn hint_section(options: &CommitPromptOptions) -> String {
+    match &options.hint {
+        Some(hint) => format!("\nAdditional context from the user: {}\n", hint),
+        None => String::new(),
+    }
+}
+
+/// Format the per-file line counts for inclusion in a prompt, if any
+fn stats_section(options: &CommitPromptOptions) -> String {
+    if options.stats.is_empty() {
+        String::new()
+    } else {
+        format!("\nChange summary:\n{}\n", stat_summary(&options.stats))
+    }
+}
+
+/// Style rules shared by the commit message prompts
+fn commit_message_rules(options: &CommitPromptOptions) -> String {
+    let types = allowed_types(&options.types);
+    let mut rules = format!("- Start with a type prefix ({})\n", types.join(", "));
+    match options.style {
+        CommitStyle::Conventional => {}
+        CommitStyle::Gitmoji => rules.push_str(&gitmoji_rule(&types, false)),
+        CommitStyle::GitmojiShortcode => rules.push_str(&gitmoji_rule(&types, true)),
+    }
+    if let Some(scope) = &options.scope {
+        rules.push_str(&format!(
+            "- Use the scope \"{}\", formatted as type({}): description\n",
+            scope, scope
+        ));
+    }
+    rules.push_str("- Be written in imperative mood\n");
+    if let Some(language) = &options.language {
+        rules.push_str(&format!(
+            "- Be written in {}, except for the type prefix and scope, which stay in English\n\
+             - Count the character limits below in characters, not bytes\n",
+            language
+        ));
+    }
+    let max = options
+        .max_subject_length
+        .unwrap_or(DEFAULT_MAX_SUBJECT_LENGTH);
+    if options.body {
+        rules.push_str(&format!(
+            "- Have a subject line of max {} characters, then a blank line
+- Follow with a body paragraph explaining why the change was made, wrapped at 72 characters",
+            max
+        ));
+    } else {
+        rules.push_str(&format!("- Be a single line, max {} characters", max));
+    }
+    if !options.rejected.is_empty() {
+        rules.push_str("\n- Be worded differently from these rejected suggestions:");
+        for rejected in &options.rejected {
+            rules.push_str(&format!("\n  - {}", rejected.lines().next().unwrap_or("")));
+        }
+    }
+    rules
+}
+
+/// Build the system prompt for the body under the user's `subject`
+fn commit_body_system(options: &CommitPromptOptions) -> String {
+    let mut rules = String::from(
+        "- Explain what the change does and why it was made, in imperative mood
+- Be wrapped at 72 characters",
+    );
+    if let Some(language) = &options.language {
+        rules.push_str(&format!("\n- Be written in {}", language));
+    }
+    format!(
+        "You write the body of git commit messages for the diffs you are given. The subject line is already written and must not be changed. Write only the body, which should:
+{}
+- Not repeat or reword the subject line, or include any explanation, just the body",
+        rules
+    )
+}
+
+/// Build the system prompt for a single commit message
+fn commit_message_system(options: &CommitPromptOptions) -> String {
+    if options.subject.is_some() {
+        return commit_body_system(options);
+    }
+    format!(
+        "You write concise git commit messages for the diffs you are given. The message should:
+{}
+- Not include any explanation, just the commit message",
+        commit_message_rules(options)
+    )
+}
+
+/// Build the user message asking for a single commit message
+fn commit_message_prompt(diff: &str, options: &CommitPromptOptions) -> String {
+    let request = match &options.subject {
+        Some(subject) => format!(
+            "Write only the body of a git commit message whose subject line is \"{}\", for the following diff.",
+            subject
+        ),
+        None => match &options.draft {
+            Some(draft) => format!(
+                "Improve this draft git commit message for the following diff. Keep its meaning, \
+fixing the tense, length and type prefix so it follows the rules.
+
+Draft:
+{}
+",
+                draft
+            ),
+            None => "Generate a git commit message for the following diff.".to_string(),
+        },
+    };
+    format!(
+        "{}
+{}{}
+Diff:
+{}",
+        request,
+        hint_section(options),
+        stats_section(options),
+        diff
+    )
+}
+
+/// Instruction added to an `AUTOCOMMIT_PROMPT_FILE` prompt when the user
+/// wrote the subject line
+fn body_only_instruction(subject: &str) -> String {
+    format!(
+        "Write only the body of the commit message. Its subject line is \"{}\" and must not be repeated or reworded.",
+        subject
+    )
+}
+
+/// Whether `line` is the model's take on `subject` rather than body text:
+/// the same words in another case, or a subject with the same type prefix
+fn rewords_subject(line: &str, subject: &str) -> bool {
+    let words = |text: &str| -> String {
+        text.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    let prefix = |text: &str| -> Option<String> {
+        let (prefix, _) = text.split_once(':')?;
+        (!prefix.is_empty() && !prefix.contains(' ')).then(|| prefix.to_lowercase())
+    };
+    words(line) == words(subject) || prefix(line).is_some_and(|p| Some(p) == prefix(subject))
+}
+
+/// Put the user's `subject` above a generated `body`, dropping the subject
+/// if the model repeated or reworded it
+pub fn join_subject_body(subject: &str, body: &str) -> String {
+    let subject = subject.trim();
+    let body = body.trim();
+    let body = match body.split_once('\n') {
+        Some((first, rest)) if rewords_subject(first.trim(), subject) => rest.trim(),
+        None if rewords_subject(body, subject) => "",
+        _ => body,
+    };
+    if body.is_empty() {
+        subject.to_string()
+    } else {
+        format!("{}\n\n{}", subject, body)
+    }
+}
+
+/// Build the system prompt for `count` alternative commit messages
+fn commit_candidates_system(count: usize, options: &CommitPromptOptions) -> String {
+    format!(
+        "You write concise git commit messages for the diffs you are given. Each message should:
+{}
+- Take a different angle or wording than the others
+
+Respond with a JSON array of {count} strings, for example:
+[\"feat: add login form\", \"feat(auth): support password login\"]
+
+Only output valid JSON, no markdown code blocks.",
+        commit_message_rules(options),
+        count = count
+    )
+}
+
+/// Build the user message asking for `count` alternative commit messages
+fn commit_candidates_prompt(diff: &str, count: usize, options: &CommitPromptOptions) -> String {
+    format!(
+        "Generate {} alternative git commit messages for the following diff.
+{}{}
+Diff:
+{}",
+        count,
+        hint_section(options),
+        stats_section(options),
+        diff
+    )
+}
+
+/// System prompt for changelog entries
+const CHANGELOG_SYSTEM_PROMPT: &str =
+    "You write changelog entries for the diffs you are given. The entry should:
+- Describe the change from the point of view of end users, not developers
+- Avoid implementation details such as function, file or module names
+- Be one or two sentences, written in past tense
+- Not include a bullet, heading, or any explanation, just the entry text";
+
+/// System prompt for explaining an existing commit or PR
+const EXPLAIN_SYSTEM_PROMPT: &str =
+    "You explain code changes to developers who are reviewing or learning a codebase. Given a commit or pull request, write a plain-English explanation of:
+- What the change does, from the most important part to the least
+- Why it was likely made, based on the message and the code
+- Anything a reviewer should look at closely (risky spots, missing tests, behavior changes)
+
+Write short paragraphs or bullets in plain text. This is not a commit message: don't write a subject line or use Conventional Commits prefixes.";
+
+/// System prompt for PR titles and descriptions
+const PR_SYSTEM_PROMPT: &str = "You write GitHub Pull Request titles and descriptions.
+
+Respond in JSON format:
+{
+  \"title\": \"PR title (concise, max {max_title} chars)\",
+  \"body\": \"PR description\",
+  \"needsClarification\": false,
+  \"clarificationQuestion\": null
+}
+
+If the PR template asks for other fields (such as a risk level or a rollback plan), add each as an extra key with a string value.
+
+Only output valid JSON, no markdown code blocks.";
+
+/// Closing instruction of the new-PR prompt with `with_no_clarify`
+const NO_CLARIFY_INSTRUCTION: &str = "Do not ask for clarification; make reasonable assumptions. Always set needsClarification to false.";
+
+/// `PR_SYSTEM_PROMPT` with the title limit filled in
+fn pr_system_prompt(max_title: usize) -> String {
+    PR_SYSTEM_PROMPT.replace("{max_title}", &max_title.to_string())
+}
+
 /// Anthropic API client
 pub struct AnthropicClient {
     client: Client,
     config: Config,
+    last_usage: Mutex<Option<Usage>>,
+    /// Stream responses, echoing text to stderr as it arrives
+    stream: bool,
+    /// Print every prompt to stderr before sending it
+    debug_prompt: bool,
+    /// Shape new PRs as a squash commit, for `autopr --squash`
+    squash: bool,
+    /// Show a spinner on stdout while waiting for a response
+    spinner: bool,
+    /// Spaces requests by `AUTOCOMMIT_MIN_INTERVAL_MS`
+    throttle: Throttle,
+    /// Tell the model not to ask clarification questions, for `autopr
+    /// --no-clarify`
+    no_clarify: bool,
+    /// Shape new PRs as release notes grouped by commit type, for `autopr
+    /// --since-last-release`
+    release_notes: bool,
 }
 
 /// Message in a conversation
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Message {
     pub role: String,
     pub content: String,
 }
 
+/// A request's system prompt and messages, exactly as sent to the model
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prompt {
+    /// System prompt with the persona applied and, for commit prompts, the
+    /// rules `AUTOCOMMIT_SYSTEM_PROMPT` replaces
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+    /// Most output tokens the request allows
+    pub max_tokens: u32,
+}
+
+impl Prompt {
+    /// Estimated input tokens, see `utils::estimate_tokens`
+    pub fn input_tokens(&self) -> usize {
+        let system = self.system.as_deref().map_or(0, estimate_tokens);
+        let messages: usize = self
+            .messages
+            .iter()
+            .map(|message| estimate_tokens(&message.content))
+            .sum();
+        system + messages
+    }
+}
+
+impl fmt::Display for Prompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(system) = &self.system {
+            writeln!(f, "--- system ---\n{}", system)?;
+        }
+        for message in &self.messages {
+            writeln!(f, "--- {} ---\n{}", message.role, message.content)?;
+        }
+        Ok(())
+    }
+}
+
 /// Request to the Anthropic API
 #[derive(Serialize, Debug)]
 struct MessageRequest {
     model: String,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<RequestMetadata>,
+}
+
+/// `metadata` of a request, identifying the end user to Anthropic
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct RequestMetadata {
+    user_id: String,
+}
+
+/// Request to the Anthropic API with its static start marked for prompt
+/// caching, sent in place of `MessageRequest` when `prompt_cache` is set
+///
+/// The system prompt and the first `cached_prefix` bytes of the first
+/// message become `cache_control` breakpoints, so repeated runs only pay
+/// full price for what follows them.
+#[derive(Serialize, Debug)]
+struct CachedMessageRequest {
+    model: String,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<Vec<TextBlock>>,
+    messages: Vec<BlockMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<RequestMetadata>,
+}
+
+/// Message whose content is a list of blocks rather than a string
+#[derive(Serialize, Debug)]
+struct BlockMessage {
+    role: String,
+    content: Vec<TextBlock>,
+}
+
+/// Text content block in a request
+#[derive(Serialize, Debug)]
+struct TextBlock {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<CacheControl>,
+}
+
+impl TextBlock {
+    fn new(text: String, cached: bool) -> Self {
+        TextBlock {
+            kind: "text",
+            text,
+            cache_control: cached.then_some(CacheControl { kind: "ephemeral" }),
+        }
+    }
+}
+
+/// Prompt caching breakpoint
+#[derive(Serialize, Debug)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+impl CachedMessageRequest {
+    fn new(request: MessageRequest, cached_prefix: usize) -> Self {
+        let messages = request
+            .messages
+            .into_iter()
+            .enumerate()
+            .map(|(i, message)| {
+                let mut content = message.content;
+                let split = i == 0
+                    && cached_prefix > 0
+                    && cached_prefix <= content.len()
+                    && content.is_char_boundary(cached_prefix);
+                let content = if split {
+                    let rest = content.split_off(cached_prefix);
+                    let mut blocks = vec![TextBlock::new(content, true)];
+                    // The API rejects empty text blocks
+                    if !rest.is_empty() {
+                        blocks.push(TextBlock::new(rest, false));
+                    }
+                    blocks
+                } else {
+                    vec![TextBlock::new(content, false)]
+                };
+                BlockMessage {
+                    role: message.role,
+                    content,
+                }
+            })
+            .collect();
+
+        CachedMessageRequest {
+            model: request.model,
+            max_tokens: request.max_tokens,
+            system: request
+                .system
+                .map(|system| vec![TextBlock::new(system, true)]),
+            messages,
+            temperature: request.temperature,
+            stream: request.stream,
+            metadata: request.metadata,
+        }
+    }
 }
 
 /// Content block in the API response
@@ -33,138 +509,216 @@ struct MessageRequest {
 enum ContentBlock {
     #[serde(rename = "text")]
     Text { text: String },
+    /// Thinking, tool use and any other block without text for us
+    #[serde(other)]
+    Other,
 }
 
 /// Response from the Anthropic API
 #[derive(Deserialize, Debug)]
 struct MessageResponse {
     content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+    /// Why generation stopped: `end_turn`, `max_tokens`, `stop_sequence`...
+    stop_reason: Option<String>,
 }
 
-/// Pull request content
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PRContent {
-    pub title: String,
-    pub body: String,
-    #[serde(rename = "needsClarification")]
-    pub needs_clarification: Option<bool>,
-    #[serde(rename = "clarificationQuestion")]
-    pub clarification_question: Option<String>,
+/// Error response body, e.g.
+/// `{"type":"error","error":{"type":"rate_limit_error","message":"..."}}`
+///
+/// OpenAI-compatible APIs use the same `error` object, sometimes without a type.
+#[derive(Deserialize, Debug, PartialEq)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
 }
 
-impl AnthropicClient {
-    /// Create a new Anthropic API client
-    pub fn new(config: Config) -> Self {
-        Self {
-            client: Client::new(),
-            config,
+/// The `error` object of an error response
+#[derive(Deserialize, Debug, PartialEq)]
+struct ApiErrorDetail {
+    /// Error type such as `rate_limit_error` or `overloaded_error`
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    message: String,
+}
+
+/// Describe a failed response, from its error body when it parses
+fn api_error_message(provider: Provider, status: reqwest::StatusCode, body: &str) -> String {
+    match serde_json::from_str::<ApiErrorBody>(body) {
+        Ok(ApiErrorBody { error }) => {
+            let source = match provider {
+                Provider::Anthropic => "Anthropic",
+                Provider::OpenAi => "API",
+            };
+            match error.kind {
+                Some(kind) => format!("{} {}: {}", source, kind, error.message),
+                None => format!("{} error (status {}): {}", source, status, error.message),
+            }
         }
+        Err(_) => format!("API request failed with status {}: {}", status, body),
     }
+}
 
-    /// Send a message to the Anthropic API
-    async fn send_message(&self, messages: Vec<Message>, max_tokens: u32) -> Result<String> {
-        let request = MessageRequest {
-            model: self.config.model.clone(),
-            max_tokens,
-            messages,
-        };
+/// Response of the models endpoint, in the Anthropic and OpenAI shapes alike
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
 
-        let response = self
-            .client
-            .post(API_URL)
-            .header("x-api-key", &self.config.anthropic_api_key)
-            .header("anthropic-version", ANTHROPIC_VERSION)
-            .header("content-type", "application/json")
-            .json(&request)
-            .send()
-            .await?;
+/// One model in a `ModelList`
+#[derive(Deserialize, Debug)]
+struct ModelEntry {
+    id: String,
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| String::from("Unknown error"));
-            return Err(Error::Api(format!(
-                "API request failed with status {}: {}",
-                status, error_text
-            )));
-        }
+/// Parse the model IDs out of a models endpoint response
+fn parse_model_list(body: &str) -> Result<Vec<String>> {
+    let list: ModelList = serde_json::from_str(body)?;
+    Ok(list.data.into_iter().map(|model| model.id).collect())
+}
 
-        let message_response: MessageResponse = response.json().await?;
+/// The models endpoint next to the configured messages endpoint
+fn models_url(api_url: &str) -> String {
+    let base = api_url
+        .strip_suffix("/messages")
+        .or_else(|| api_url.strip_suffix("/chat/completions"))
+        .unwrap_or(api_url);
+    format!("{}/models", base)
+}
 
-        // Extract text from first content block
-        match message_response.content.first() {
-            Some(ContentBlock::Text { text }) => Ok(text.trim().to_string()),
-            None => Err(Error::Api("Empty response from API".to_string())),
+/// Whether `model` is one of `models`
+///
+/// The endpoint lists dated IDs only, so an alias like
+/// `claude-3-5-haiku-latest` counts when a `claude-3-5-haiku-` model is listed.
+pub fn model_is_listed(model: &str, models: &[String]) -> bool {
+    if models.iter().any(|listed| listed == model) {
+        return true;
+    }
+    match model.strip_suffix("latest") {
+        Some(family) if family.ends_with('-') => {
+            models.iter().any(|listed| listed.starts_with(family))
         }
+        _ => false,
     }
+}
 
-    /// Generate a commit message from a diff
-    pub async fn generate_commit_message(&self, diff: &str) -> Result<String> {
-        let prompt = format!(
-            "Generate a concise git commit message for the following diff. The message should:
-- Start with a type prefix (feat, fix, docs, style, refactor, test, chore)
-- Be written in imperative mood
-- Be a single line, max 72 characters
-- Not include any explanation, just the commit message
+/// Token usage reported by the API for a single request
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
 
-Diff:
-{}",
-            diff
-        );
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in / {} out", self.input_tokens, self.output_tokens)
+    }
+}
 
-        let messages = vec![Message {
-            role: "user".to_string(),
-            content: prompt,
-        }];
+/// Request to an OpenAI-compatible chat completions API
+#[derive(Serialize, Debug)]
+struct ChatCompletionRequest {
+    model: String,
+    max_tokens: u32,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    /// The chat completions counterpart of `metadata.user_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+/// Message returned in a chat completion choice
+#[derive(Deserialize, Debug)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+}
+
+/// Choice in the chat completions response
+#[derive(Deserialize, Debug)]
+struct ChatCompletionChoice {
+    message: ChatCompletionMessage,
+    /// Why generation stopped, `length` when it ran out of tokens
+    finish_reason: Option<String>,
+}
 
-        self.send_message(messages, 256).await
+/// Response from an OpenAI-compatible chat completions API
+#[derive(Deserialize, Debug)]
+struct ChatCompletionResponse {
+    choices: Vec<ChatCompletionChoice>,
+    usage: Option<ChatCompletionUsage>,
+}
+
+/// Token usage in the chat completions response
+#[derive(Deserialize, Debug, Clone, Copy)]
+struct ChatCompletionUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+impl From<ChatCompletionUsage> for Usage {
+    fn from(usage: ChatCompletionUsage) -> Self {
+        Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        }
     }
+}
 
-    /// Generate PR content from commits and diff
-    pub async fn generate_pr_content(
-        &self,
-        commits: &str,
-        diff: &str,
-        changed_files: &[String],
-        template: Option<&str>,
-        additional_context: Option<&str>,
-        existing_pr: Option<&PRContent>,
-    ) -> Result<PRContent> {
-        let prompt = if let Some(pr) = existing_pr {
-            // Update existing PR
-            let context = additional_context.unwrap_or("");
-            format!(
-                "Update the following GitHub Pull Request based on the user's feedback.
+impl From<MessageRequest> for ChatCompletionRequest {
+    /// Convert to the chat completions shape, where the system prompt is the
+    /// first message rather than a top-level field
+    fn from(request: MessageRequest) -> Self {
+        let mut messages = Vec::with_capacity(request.messages.len() + 1);
+        if let Some(system) = request.system {
+            messages.push(Message {
+                role: "system".to_string(),
+                content: system,
+            });
+        }
+        messages.extend(request.messages);
 
-Current PR:
-Title: {}
-Body:
-{}
+        ChatCompletionRequest {
+            model: request.model,
+            max_tokens: request.max_tokens,
+            messages,
+            temperature: request.temperature,
+            stream: request.stream,
+            user: request.metadata.map(|metadata| metadata.user_id),
+        }
+    }
+}
 
-User feedback: {}
+/// Body instructions for `--squash`, where the PR becomes the squash commit
+const SQUASH_PR_INSTRUCTIONS: &str = "The PR will be squash merged: the title becomes the commit subject and the body the commit message.
+- Write the title as a Conventional Commits subject (type(scope): description), in imperative mood
+- Write the body as a concise bulleted list of the notable changes, without headings or sections
+";
 
-Respond in JSON format:
-{{
-  \"title\": \"Updated PR title (concise, max 72 chars)\",
-  \"body\": \"Updated PR description\",
-  \"needsClarification\": false,
-  \"clarificationQuestion\": null
-}}
+/// Body instructions for `--since-last-release`, where the commits come
+/// grouped by type
+const RELEASE_NOTES_PR_INSTRUCTIONS: &str = "This is a release PR and the commits below are grouped by type.
+- Write the body as a changelog: one ## section per group (e.g. ## Features, ## Bug Fixes), in the order given
+- Under each section, list the notable changes as bullets, merging related commits into one
+- Leave out groups with nothing a reader of the release would care about
+";
 
-Only output valid JSON, no markdown code blocks.",
-                pr.title, pr.body, context
-            )
-        } else {
-            // Generate new PR
-            let template_instructions = if let Some(tmpl) = template {
-                format!(
-                    "Use this PR template as a guide for the body structure. IMPORTANT: Remove any sections from the template that are not relevant to the changes (e.g., if there are no breaking changes, remove the breaking changes section; if there are no migrations, remove the migration section).\n\nTemplate:\n{}\n\n",
-                    tmpl
-                )
-            } else {
-                "Structure the PR body with these sections (only include sections relevant to the changes):
+/// Start of the new-PR prompt, the instructions and template, which stay
+/// the same from one run to the next
+///
+/// A template takes precedence over the body `instructions`, which replace
+/// the default sections.
+fn pr_prompt_head(template: Option<&str>, instructions: Option<&str>) -> String {
+    let template_instructions = if let Some(tmpl) = template {
+        format!(
+            "Use this PR template as a guide for the body structure. IMPORTANT: Remove any sections from the template that are not relevant to the changes (e.g., if there are no breaking changes, remove the breaking changes section; if there are no migrations, remove the migration section).\n\nTemplate:\n{}\n\n",
+            tmpl
+        )
+    } else if let Some(instructions) = instructions {
+        instructions.to_string()
+    } else {
+        "Structure the PR body with these sections (only include sections relevant to the changes):
 ## Summary
 Brief description of changes
 
@@ -174,67 +728,2885 @@ Brief description of changes
 ## Testing
 How to test these changes
 "
-                .to_string()
-            };
+        .to_string()
+    };
+    format!(
+        "Generate a GitHub Pull Request title and description based on the following information.\n\n{}",
+        template_instructions
+    )
+}
 
-            let context_info = if let Some(ctx) = additional_context {
-                format!("\nAdditional context from user: {}\n", ctx)
-            } else {
-                String::new()
-            };
+/// Assemble the system prompt from its optional parts
+///
+/// The persona comes first so the style directive frames the instructions.
+/// Returns None when there is nothing to send.
+fn assemble_system_prompt(persona: Option<&str>, instructions: Option<&str>) -> Option<String> {
+    let parts: Vec<&str> = [persona, instructions]
+        .into_iter()
+        .flatten()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect();
 
-            // Truncate diff to 8000 characters
-            let truncated_diff = if diff.len() > 8000 {
-                &diff[..8000]
-            } else {
-                diff
-            };
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("\n\n"))
+    }
+}
 
-            format!(
-                "Generate a GitHub Pull Request title and description based on the following information.
-{}
-{}
-Changed files:
-{}
+/// Follow-up sent once when the PR response isn't valid JSON
+const JSON_CORRECTION_PROMPT: &str =
+    "Your previous response was not valid JSON. Respond with only the JSON object.";
 
-Commits:
-{}
+/// Parse the PR content JSON returned by the model
+fn parse_pr_content(response_text: &str) -> Result<PRContent> {
+    let mut content = serde_json::from_str::<PRContent>(response_text).map_err(|e| {
+        Error::Api(format!(
+            "Failed to parse API response as JSON: {}\nResponse: {}",
+            e, response_text
+        ))
+    })?;
+    // Extra keys are shown as text, so numbers, lists and the like are dropped
+    content.extra.retain(|_, value| value.is_string());
+    Ok(content)
+}
 
-Diff (truncated if too long):
-{}
+/// Parse a JSON array of commit message candidates
+///
+/// Tolerates prose or code fences around the array.
+fn parse_candidates(response_text: &str) -> Result<Vec<String>> {
+    let json = match (response_text.find('['), response_text.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &response_text[start..=end],
+        _ => response_text,
+    };
 
-Respond in JSON format:
-{{
-  \"title\": \"PR title (concise, max 72 chars)\",
-  \"body\": \"PR description following the template\",
-  \"needsClarification\": false,
-  \"clarificationQuestion\": null
-}}
+    let candidates: Vec<String> = serde_json::from_str::<Vec<String>>(json)
+        .map_err(|e| {
+            Error::Api(format!(
+                "Failed to parse commit message candidates as JSON: {}\nResponse: {}",
+                e, response_text
+            ))
+        })?
+        .into_iter()
+        .map(|candidate| candidate.trim().to_string())
+        .filter(|candidate| !candidate.is_empty())
+        .collect();
 
-If the changes are unclear or you need more context to write a good PR description, set needsClarification to true and provide a specific clarificationQuestion.
+    if candidates.is_empty() {
+        return Err(Error::Api(
+            "API returned no commit message candidates".to_string(),
+        ));
+    }
+    Ok(candidates)
+}
 
-Only output valid JSON, no markdown code blocks.",
-                context_info,
-                template_instructions,
-                changed_files.join("\n"),
-                commits,
-                truncated_diff
-            )
-        };
+impl MessageResponse {
+    /// Join the text of all text blocks, skipping the other block types
+    fn into_text(self) -> Result<String> {
+        let texts: Vec<String> = self
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                ContentBlock::Other => None,
+            })
+            .collect();
+        if texts.is_empty() {
+            return Err(Error::Api("Empty response from API".to_string()));
+        }
+        Ok(texts.concat().trim().to_string())
+    }
+}
 
-        let messages = vec![Message {
-            role: "user".to_string(),
-            content: prompt,
-        }];
+impl ChatCompletionResponse {
+    /// Extract text from the first choice
+    fn into_text(self) -> Result<String> {
+        match self.choices.into_iter().next() {
+            Some(ChatCompletionChoice {
+                message:
+                    ChatCompletionMessage {
+                        content: Some(text),
+                    },
+                ..
+            }) => Ok(text.trim().to_string()),
+            _ => Err(Error::Api("Empty response from API".to_string())),
+        }
+    }
+}
+
+/// Most tokens a response may use, and the setting that raises the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OutputLimit {
+    max_tokens: u32,
+    /// Named in the error when a response hits the limit; responses without
+    /// one may be cut short
+    setting: Option<&'static str>,
+}
+
+impl OutputLimit {
+    fn new(max_tokens: u32) -> Self {
+        OutputLimit {
+            max_tokens,
+            setting: None,
+        }
+    }
 
-        let response_text = self.send_message(messages, 1024).await?;
+    /// Fail truncated responses, pointing at `setting`
+    fn raised_by(self, setting: &'static str) -> Self {
+        OutputLimit {
+            setting: Some(setting),
+            ..self
+        }
+    }
 
-        // Parse JSON response
-        serde_json::from_str::<PRContent>(&response_text).map_err(|e| {
+    /// The error for a response that hit the limit, unless being cut short
+    /// is fine
+    fn truncation_error(&self) -> Option<Error> {
+        self.setting.map(|setting| {
             Error::Api(format!(
-                "Failed to parse API response as JSON: {}\nResponse: {}",
-                e, response_text
+                "Response truncated at max_tokens ({} tokens); increase {}",
+                self.max_tokens, setting
             ))
         })
     }
 }
+
+/// Event decoded from a streamed (server-sent events) response
+#[derive(Debug, PartialEq)]
+enum StreamEvent {
+    /// A chunk of generated text
+    Text(String),
+    /// Token counts; Anthropic sends input tokens when the stream starts and
+    /// output tokens when it ends, so either may be missing
+    Usage {
+        input_tokens: Option<u32>,
+        output_tokens: Option<u32>,
+    },
+    /// The response stopped at `max_tokens`
+    Truncated,
+    /// Error reported in the middle of the stream
+    Error(String),
+}
+
+/// Incremental parser for server-sent events
+///
+/// Chunks can end mid-line, or mid-character, so the incomplete tail is kept
+/// until the next chunk arrives.
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Feed a chunk of the response body and return the completed events
+    fn feed(&mut self, chunk: &[u8]) -> Vec<StreamEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim_end().strip_prefix("data:") {
+                events.extend(parse_stream_data(data.trim_start()));
+            }
+        }
+        events
+    }
+}
+
+/// Decode the payload of a `data:` line, in either provider's format
+///
+/// Returns no events for those carrying nothing we use, such as pings or
+/// the OpenAI `[DONE]` marker.
+fn parse_stream_data(data: &str) -> Vec<StreamEvent> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(data) else {
+        return Vec::new();
+    };
+    let tokens = |value: &serde_json::Value| value.as_u64().map(|n| n as u32);
+
+    match value["type"].as_str() {
+        Some("content_block_delta") => value["delta"]["text"]
+            .as_str()
+            .map(|text| StreamEvent::Text(text.to_string()))
+            .into_iter()
+            .collect(),
+        Some("message_start") => vec![StreamEvent::Usage {
+            input_tokens: tokens(&value["message"]["usage"]["input_tokens"]),
+            output_tokens: None,
+        }],
+        // The end of the message, with why it stopped
+        Some("message_delta") => {
+            let mut events = vec![StreamEvent::Usage {
+                input_tokens: None,
+                output_tokens: tokens(&value["usage"]["output_tokens"]),
+            }];
+            if value["delta"]["stop_reason"] == "max_tokens" {
+                events.push(StreamEvent::Truncated);
+            }
+            events
+        }
+        Some("error") => vec![StreamEvent::Error(
+            value["error"]["message"]
+                .as_str()
+                .unwrap_or("Unknown error")
+                .to_string(),
+        )],
+        Some(_) => Vec::new(),
+        // OpenAI chunks have no type
+        None => {
+            let choice = &value["choices"][0];
+            let mut events = Vec::new();
+            if let Some(text) = choice["delta"]["content"].as_str() {
+                events.push(StreamEvent::Text(text.to_string()));
+            }
+            if choice["finish_reason"] == "length" {
+                events.push(StreamEvent::Truncated);
+            }
+            if value["usage"].is_object() {
+                events.push(StreamEvent::Usage {
+                    input_tokens: tokens(&value["usage"]["prompt_tokens"]),
+                    output_tokens: tokens(&value["usage"]["completion_tokens"]),
+                });
+            }
+            events
+        }
+    }
+}
+
+/// Which part of a PR a round of feedback should change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrField {
+    Both,
+    Title,
+    Body,
+}
+
+impl PrField {
+    /// Split a `title:` or `body:` directive off feedback, case-insensitively;
+    /// feedback without one applies to both fields
+    pub fn parse(feedback: &str) -> (PrField, &str) {
+        let feedback = feedback.trim();
+        for (prefix, field) in [("title:", PrField::Title), ("body:", PrField::Body)] {
+            if let Some(head) = feedback.get(..prefix.len()) {
+                if head.eq_ignore_ascii_case(prefix) {
+                    return (field, feedback[prefix.len()..].trim_start());
+                }
+            }
+        }
+        (PrField::Both, feedback)
+    }
+
+    /// Put back the field `updated` wasn't meant to change, in case the
+    /// model rewrote it anyway
+    pub fn preserve(self, previous: &PRContent, mut updated: PRContent) -> PRContent {
+        match self {
+            PrField::Both => {}
+            PrField::Title => updated.body = previous.body.clone(),
+            PrField::Body => updated.title = previous.title.clone(),
+        }
+        updated
+    }
+}
+
+/// Pull request content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PRContent {
+    pub title: String,
+    pub body: String,
+    #[serde(rename = "needsClarification")]
+    pub needs_clarification: Option<bool>,
+    #[serde(rename = "clarificationQuestion")]
+    pub clarification_question: Option<String>,
+    /// Any other keys the model returned, as a custom template may ask for
+    ///
+    /// `parse_pr_content` keeps only the string values.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl PRContent {
+    /// Serialize as pretty-printed JSON, for `autopr --json`
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// The extra fields, sorted by key so they print the same every time
+    pub fn extra_fields(&self) -> Vec<(&str, &str)> {
+        let mut fields: Vec<(&str, &str)> = self
+            .extra
+            .iter()
+            .filter_map(|(key, value)| Some((key.as_str(), value.as_str()?)))
+            .collect();
+        fields.sort_unstable();
+        fields
+    }
+
+    /// Render as Markdown, with the title as a top-level heading and any
+    /// extra fields after the body
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n\n{}\n", self.title.trim(), self.body.trim());
+        let extra = self.extra_fields();
+        if !extra.is_empty() {
+            markdown.push('\n');
+            for (key, value) in extra {
+                markdown.push_str(&format!("**{}:** {}\n", key, value.trim()));
+            }
+        }
+        markdown
+    }
+
+    /// Write the Markdown rendering to `path`
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::User` if the file exists and `overwrite` is false, and
+    /// `Error::Io` naming the path if writing fails
+    pub async fn write_markdown(&self, path: &Path, overwrite: bool) -> Result<()> {
+        if !overwrite && tokio::fs::try_exists(path).await.unwrap_or(false) {
+            return Err(Error::User(format!(
+                "{} already exists. Pass --force to overwrite it.",
+                path.display()
+            )));
+        }
+        tokio::fs::write(path, self.to_markdown())
+            .await
+            .map_err(|e| {
+                Error::Io(std::io::Error::new(
+                    e.kind(),
+                    format!("failed to write {}: {}", path.display(), e),
+                ))
+            })
+    }
+}
+
+impl AnthropicClient {
+    /// Create a new Anthropic API client
+    ///
+    /// `AUTOCOMMIT_PROXY` replaces the proxies reqwest reads from
+    /// `HTTPS_PROXY` and friends. `Config::load` has already validated it,
+    /// but a `Config` built by hand may hold any string.
+    pub fn new(config: Config) -> Result<Self> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(USER_AGENT);
+        if config.no_proxy {
+            builder = builder.no_proxy();
+        } else if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy).map_err(|e| {
+                Error::Env(format!(
+                    "AUTOCOMMIT_PROXY is not a valid proxy URL '{}': {}",
+                    proxy, e
+                ))
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build()?;
+        let throttle = Throttle::new(Duration::from_millis(config.min_interval_ms));
+        Ok(Self {
+            client,
+            config,
+            last_usage: Mutex::new(None),
+            stream: false,
+            debug_prompt: false,
+            squash: false,
+            spinner: false,
+            throttle,
+            no_clarify: false,
+            release_notes: false,
+        })
+    }
+
+    /// Stream responses, echoing the text to stderr as it is generated
+    pub fn with_stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Print every prompt to stderr before it is sent, for `--debug-prompt`
+    pub fn with_debug_prompt(mut self, debug_prompt: bool) -> Self {
+        self.debug_prompt = debug_prompt;
+        self
+    }
+
+    /// Describe new PRs as a Conventional Commits subject and a bulleted
+    /// body, ready to become the squash commit
+    pub fn with_squash(mut self, squash: bool) -> Self {
+        self.squash = squash;
+        self
+    }
+
+    /// Describe new PRs as release notes, with one section per group of
+    /// commits from `utils::format_commit_groups`
+    pub fn with_release_notes(mut self, release_notes: bool) -> Self {
+        self.release_notes = release_notes;
+        self
+    }
+
+    /// Body structure replacing the default sections, if any
+    fn pr_body_instructions(&self) -> Option<&'static str> {
+        if self.release_notes {
+            Some(RELEASE_NOTES_PR_INSTRUCTIONS)
+        } else if self.squash {
+            Some(SQUASH_PR_INSTRUCTIONS)
+        } else {
+            None
+        }
+    }
+
+    /// Ask for best-effort PR content, never for clarification, when no one
+    /// is there to answer
+    pub fn with_no_clarify(mut self, no_clarify: bool) -> Self {
+        self.no_clarify = no_clarify;
+        self
+    }
+
+    /// Show a spinner while waiting for each response; streamed responses
+    /// never get one, their text is the feedback
+    pub fn with_spinner(mut self, spinner: bool) -> Self {
+        self.spinner = spinner;
+        self
+    }
+
+    /// Output limit for `count` commit messages
+    fn commit_limit(&self, count: u32) -> OutputLimit {
+        OutputLimit::new(self.config.max_tokens_commit * count)
+            .raised_by("AUTOCOMMIT_MAX_TOKENS_COMMIT")
+    }
+
+    /// Output limit for a PR title and description
+    fn pr_limit(&self) -> OutputLimit {
+        OutputLimit::new(self.config.max_tokens_pr).raised_by("AUTOCOMMIT_MAX_TOKENS_PR")
+    }
+
+    /// Token usage of the most recent API call, if the API reported it
+    pub fn last_usage(&self) -> Option<Usage> {
+        *self.last_usage.lock().unwrap()
+    }
+
+    /// Build the request body for the given conversation
+    ///
+    /// The persona, when set, is prepended to `system`.
+    fn build_request(
+        &self,
+        system: Option<&str>,
+        messages: Vec<Message>,
+        max_tokens: u32,
+    ) -> MessageRequest {
+        self.prompt_request(Prompt {
+            system: self.final_system(system),
+            messages,
+            max_tokens,
+        })
+    }
+
+    /// Build the request body for a prompt whose system prompt is final
+    fn prompt_request(&self, prompt: Prompt) -> MessageRequest {
+        MessageRequest {
+            model: self.config.model.clone(),
+            max_tokens: prompt.max_tokens,
+            system: prompt.system,
+            messages: prompt.messages,
+            temperature: self.config.temperature,
+            stream: self.stream,
+            metadata: self
+                .config
+                .metadata_user_id
+                .clone()
+                .map(|user_id| RequestMetadata { user_id }),
+        }
+    }
+
+    /// The system prompt sent in place of `system`, with the persona
+    fn final_system(&self, system: Option<&str>) -> Option<String> {
+        assemble_system_prompt(self.config.persona.as_deref(), system)
+    }
+
+    /// Assemble the prompt sending `user` as the only message
+    fn prompt(&self, system: Option<&str>, user: String, limit: OutputLimit) -> Prompt {
+        Prompt {
+            system: self.final_system(system),
+            messages: vec![Message {
+                role: "user".to_string(),
+                content: user,
+            }],
+            max_tokens: limit.max_tokens,
+        }
+    }
+
+    /// What sending `prompt` would cost, for `--dry-run --estimate`
+    pub fn estimate(&self, prompt: &Prompt) -> CostEstimate {
+        CostEstimate {
+            input_tokens: prompt.input_tokens(),
+            max_output_tokens: prompt.max_tokens,
+            prices: self.config.prices(),
+        }
+    }
+
+    /// Map a reqwest error, turning timeouts into a readable `Error::Api`
+    fn request_error(&self, error: reqwest::Error) -> Error {
+        if error.is_timeout() {
+            Error::Api(format!(
+                "Request timed out after {}s",
+                self.config.timeout_secs
+            ))
+        } else {
+            Error::Http(error)
+        }
+    }
+
+    /// Headers sent with every request, besides the content type
+    ///
+    /// `anthropic-version` only goes to the native Anthropic API.
+    fn request_headers(&self) -> Vec<(&'static str, String)> {
+        let key = &self.config.anthropic_api_key;
+        let mut headers = match self.config.auth_scheme {
+            AuthScheme::ApiKey => vec![("x-api-key", key.clone())],
+            AuthScheme::Bearer => vec![("authorization", format!("Bearer {}", key))],
+            AuthScheme::None => Vec::new(),
+        };
+        if self.config.provider == Provider::Anthropic {
+            headers.push(("anthropic-version", ANTHROPIC_VERSION.to_string()));
+        }
+        headers
+    }
+
+    /// Send a message to the configured API
+    ///
+    /// The request and response shapes follow `config.provider`.
+    ///
+    /// `cached_prefix` is how many bytes at the start of the first message
+    /// stay the same between runs, cached along with the system prompt when
+    /// `prompt_cache` is set.
+    ///
+    /// Responses cut short by `limit` fail when it names a setting to raise.
+    async fn send_message(
+        &self,
+        system: Option<&str>,
+        messages: Vec<Message>,
+        cached_prefix: usize,
+        limit: OutputLimit,
+    ) -> Result<String> {
+        self.send_counted(system, messages, cached_prefix, limit, &mut 0)
+            .await
+    }
+
+    /// `send_message`, counting its attempts in `attempts` so follow-up
+    /// requests share one `RetryPolicy` budget
+    async fn send_counted(
+        &self,
+        system: Option<&str>,
+        messages: Vec<Message>,
+        cached_prefix: usize,
+        limit: OutputLimit,
+        attempts: &mut u32,
+    ) -> Result<String> {
+        let request = self.build_request(system, messages, limit.max_tokens);
+        self.send_request(request, cached_prefix, limit, attempts)
+            .await
+    }
+
+    /// Send a prompt as built by `build_commit_prompt` or `build_pr_prompt`,
+    /// returning the model's raw text
+    ///
+    /// The prompt goes out as it is, so callers can change it first: the
+    /// persona is not applied again, nor is `AUTOCOMMIT_SYSTEM_PROMPT`, which
+    /// only ever replaces a commit prompt's rules, and the text is neither
+    /// parsed nor retried as JSON. A response cut short by `max_tokens` is
+    /// returned as far as it got.
+    pub async fn send_prompt(&self, prompt: Prompt) -> Result<String> {
+        let limit = OutputLimit::new(prompt.max_tokens);
+        self.send_request(self.prompt_request(prompt), 0, limit, &mut 0)
+            .await
+    }
+
+    /// Send a built request, see `send_message`
+    async fn send_request(
+        &self,
+        body: MessageRequest,
+        cached_prefix: usize,
+        limit: OutputLimit,
+        attempts: &mut u32,
+    ) -> Result<String> {
+        if self.debug_prompt {
+            let prompt = Prompt {
+                system: body.system.clone(),
+                messages: body.messages.clone(),
+                max_tokens: body.max_tokens,
+            };
+            eprintln!("{}", prompt);
+        }
+        let body = match self.config.provider {
+            Provider::Anthropic if self.config.prompt_cache => {
+                serde_json::to_value(CachedMessageRequest::new(body, cached_prefix))?
+            }
+            Provider::Anthropic => serde_json::to_value(body)?,
+            Provider::OpenAi => serde_json::to_value(ChatCompletionRequest::from(body))?,
+        };
+        self.throttle.wait().await;
+        if self.stream {
+            let response = RetryPolicy::from_config(&self.config)
+                .run(attempts, || self.send_once(&body))
+                .await?;
+            return self.read_stream(response, limit).await;
+        }
+        with_spinner(
+            "Waiting for the API...",
+            self.spinner,
+            self.send_and_read(&body, limit, attempts),
+        )
+        .await
+    }
+
+    /// Send `body`, retrying per the policy, and read the whole response
+    async fn send_and_read(
+        &self,
+        body: &serde_json::Value,
+        limit: OutputLimit,
+        attempts: &mut u32,
+    ) -> Result<String> {
+        let response = RetryPolicy::from_config(&self.config)
+            .run(attempts, || self.send_once(body))
+            .await?;
+
+        let (text, usage, truncated) = match self.config.provider {
+            Provider::Anthropic => {
+                let message_response = response
+                    .json::<MessageResponse>()
+                    .await
+                    .map_err(|e| self.request_error(e))?;
+                let usage = message_response.usage;
+                let truncated = message_response.stop_reason.as_deref() == Some("max_tokens");
+                (message_response.into_text(), usage, truncated)
+            }
+            Provider::OpenAi => {
+                let chat_response = response
+                    .json::<ChatCompletionResponse>()
+                    .await
+                    .map_err(|e| self.request_error(e))?;
+                let usage = chat_response.usage.map(Usage::from);
+                let truncated = chat_response
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.finish_reason.as_deref())
+                    == Some("length");
+                (chat_response.into_text(), usage, truncated)
+            }
+        };
+
+        tracing::debug!(?usage, truncated, "token usage");
+        *self.last_usage.lock().unwrap() = usage;
+        match limit.truncation_error() {
+            Some(error) if truncated => Err(error),
+            _ => text,
+        }
+    }
+
+    /// Post `body` once, returning the response if it succeeded
+    async fn send_once(
+        &self,
+        body: &serde_json::Value,
+    ) -> std::result::Result<reqwest::Response, Failure> {
+        let mut request = self
+            .client
+            .post(&self.config.api_url)
+            .header("content-type", "application/json");
+        for (name, value) in self.request_headers() {
+            request = request.header(name, value);
+        }
+
+        let started = Instant::now();
+        let response = request.json(body).send().await.map_err(|e| {
+            tracing::error!(error = %e, "API request failed");
+            let retryable = e.is_timeout() || e.is_connect();
+            let error = self.request_error(e);
+            if retryable {
+                Failure::Retryable(error)
+            } else {
+                Failure::Fatal(error)
+            }
+        })?;
+        tracing::debug!(
+            status = %response.status(),
+            elapsed = ?started.elapsed(),
+            model = %self.config.model,
+            "API responded"
+        );
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| String::from("Unknown error"));
+        let error = Error::Api(api_error_message(self.config.provider, status, &error_text));
+        if is_retryable_status(status) {
+            Err(Failure::Retryable(error))
+        } else {
+            Err(Failure::Fatal(error))
+        }
+    }
+
+    /// Read a streamed response, echoing text to stderr as it arrives
+    ///
+    /// Like `send_and_read`, a response cut short by `limit` fails when it
+    /// names a setting to raise.
+    async fn read_stream(
+        &self,
+        mut response: reqwest::Response,
+        limit: OutputLimit,
+    ) -> Result<String> {
+        let mut parser = SseParser::default();
+        let mut text = String::new();
+        let mut usage: Option<Usage> = None;
+        let mut truncated = false;
+
+        while let Some(chunk) = response.chunk().await.map_err(|e| self.request_error(e))? {
+            for event in parser.feed(&chunk) {
+                match event {
+                    StreamEvent::Text(delta) => {
+                        eprint!("{}", delta);
+                        std::io::stderr().flush().ok();
+                        text.push_str(&delta);
+                    }
+                    StreamEvent::Usage {
+                        input_tokens,
+                        output_tokens,
+                    } => {
+                        let usage = usage.get_or_insert_with(Usage::default);
+                        if let Some(input_tokens) = input_tokens {
+                            usage.input_tokens = input_tokens;
+                        }
+                        if let Some(output_tokens) = output_tokens {
+                            usage.output_tokens = output_tokens;
+                        }
+                    }
+                    StreamEvent::Truncated => truncated = true,
+                    StreamEvent::Error(message) => return Err(Error::Api(message)),
+                }
+            }
+        }
+        eprintln!();
+
+        tracing::debug!(?usage, truncated, "token usage");
+        *self.last_usage.lock().unwrap() = usage;
+        if let Some(error) = limit.truncation_error().filter(|_| truncated) {
+            return Err(error);
+        }
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(Error::Api("Empty response from API".to_string()));
+        }
+        Ok(text.to_string())
+    }
+
+    /// `options` with the configured language filled in
+    fn commit_options(&self, options: &CommitPromptOptions) -> CommitPromptOptions {
+        CommitPromptOptions {
+            language: options
+                .language
+                .clone()
+                .or_else(|| self.config.language.clone()),
+            max_subject_length: options
+                .max_subject_length
+                .or(Some(self.config.max_subject_length)),
+            ..options.clone()
+        }
+    }
+
+    /// Build the system instructions and user message for a commit message
+    async fn commit_prompt_parts(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+    ) -> Result<(Option<String>, String)> {
+        let options = &self.commit_options(options);
+        // `AUTOCOMMIT_SYSTEM_PROMPT` replaces the commit style rules only;
+        // the other prompts carry an output format their callers parse
+        let system_prompt = self.config.system_prompt.clone();
+        // A custom template replaces both the built-in rules and the prompt
+        Ok(match &self.config.prompt_file {
+            Some(path) => {
+                let template = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    Error::Env(format!(
+                        "Failed to read AUTOCOMMIT_PROMPT_FILE {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                let prompt = render_prompt_template(&template, diff)?;
+                // The template asks for a whole message, not just a body
+                let prompt = match &options.subject {
+                    Some(subject) => format!("{}\n\n{}", prompt, body_only_instruction(subject)),
+                    None => prompt,
+                };
+                (system_prompt, prompt)
+            }
+            None => (
+                Some(system_prompt.unwrap_or_else(|| commit_message_system(options))),
+                commit_message_prompt(diff, options),
+            ),
+        })
+    }
+
+    /// Build the prompt `generate_commit_message` sends for a diff
+    pub async fn build_commit_prompt(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+    ) -> Result<Prompt> {
+        let (system, user) = self.commit_prompt_parts(diff, options).await?;
+        Ok(self.prompt(system.as_deref(), user, self.commit_limit(1)))
+    }
+
+    /// Generate a commit message from a diff
+    pub async fn generate_commit_message(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+    ) -> Result<String> {
+        let (system, prompt) = self.commit_prompt_parts(diff, options).await?;
+
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: prompt,
+        }];
+
+        let message = self
+            .send_message(system.as_deref(), messages, 0, self.commit_limit(1))
+            .await?;
+        Ok(match &options.subject {
+            Some(subject) => join_subject_body(subject, &message),
+            None => message,
+        })
+    }
+
+    /// Ask for a commit message again after `generate_commit_message`
+    /// returned `rejected`, saying what was wrong with it
+    ///
+    /// `correction` follows the rejected answer as a message of its own, so
+    /// it is sent even when `AUTOCOMMIT_SYSTEM_PROMPT` or
+    /// `AUTOCOMMIT_PROMPT_FILE` replace the built-in rules.
+    pub async fn correct_commit_message(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+        rejected: &str,
+        correction: &str,
+    ) -> Result<String> {
+        let (system, prompt) = self.commit_prompt_parts(diff, options).await?;
+        let messages = vec![
+            Message {
+                role: "user".to_string(),
+                content: prompt,
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: rejected.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: correction.to_string(),
+            },
+        ];
+        self.send_message(system.as_deref(), messages, 0, self.commit_limit(1))
+            .await
+    }
+
+    /// Build the prompt `generate_commit_messages` sends for `count` candidates
+    pub fn build_commit_candidates_prompt(
+        &self,
+        diff: &str,
+        count: usize,
+        options: &CommitPromptOptions,
+    ) -> Prompt {
+        let options = &self.commit_options(options);
+        self.prompt(
+            Some(&commit_candidates_system(count, options)),
+            commit_candidates_prompt(diff, count, options),
+            self.commit_limit(count as u32),
+        )
+    }
+
+    /// Generate several alternative commit messages from a diff
+    ///
+    /// The model may return fewer than `count` candidates; an empty list is
+    /// an error, extra candidates are dropped.
+    pub async fn generate_commit_messages(
+        &self,
+        diff: &str,
+        count: usize,
+        options: &CommitPromptOptions,
+    ) -> Result<Vec<String>> {
+        let options = &self.commit_options(options);
+        let prompt = commit_candidates_prompt(diff, count, options);
+
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: prompt,
+        }];
+
+        let system = commit_candidates_system(count, options);
+        let response_text = self
+            .send_message(Some(&system), messages, 0, self.commit_limit(count as u32))
+            .await?;
+
+        let mut candidates = parse_candidates(&response_text)?;
+        candidates.truncate(count);
+        Ok(candidates)
+    }
+
+    /// Generate a user-facing changelog entry from a diff
+    ///
+    /// With `categorize`, the entry comes with the Keep a Changelog category
+    /// that fits it.
+    pub async fn generate_changelog_entry(
+        &self,
+        diff: &str,
+        categorize: bool,
+    ) -> Result<(Option<ChangelogCategory>, String)> {
+        let category_instruction = if categorize {
+            "\n\nStart it with the category that fits the change best, one of Added, Changed, Fixed or Removed, and a colon, e.g. \"Fixed: Fixed a crash when the config file is empty.\""
+        } else {
+            ""
+        };
+        let prompt = format!(
+            "Write a changelog entry for the following diff.{}\n\nDiff:\n{}",
+            category_instruction, diff
+        );
+
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: prompt,
+        }];
+
+        let response = self
+            .send_message(
+                Some(CHANGELOG_SYSTEM_PROMPT),
+                messages,
+                0,
+                self.commit_limit(1),
+            )
+            .await?;
+        if !categorize {
+            return Ok((None, response));
+        }
+        let (category, entry) = parse_categorized_entry(&response);
+        Ok((Some(category), entry))
+    }
+
+    /// Build the user message asking to explain `change`, a commit as `git
+    /// show` prints it or a PR's title, body and diff
+    fn explanation_prompt(&self, change: &str) -> String {
+        let (truncated, _) = truncate_diff_by_tokens(change, self.config.max_diff_tokens as usize);
+        let prompt = format!(
+            "Explain the following change (truncated if too long).\n\n{}",
+            truncated
+        );
+        match &self.config.language {
+            Some(language) => format!("{}\n\nWrite the explanation in {}.", prompt, language),
+            None => prompt,
+        }
+    }
+
+    /// Explain what an existing commit or PR does and why, in plain English
+    pub async fn generate_explanation(&self, change: &str) -> Result<String> {
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: self.explanation_prompt(change),
+        }];
+        self.send_message(Some(EXPLAIN_SYSTEM_PROMPT), messages, 0, self.pr_limit())
+            .await
+    }
+
+    /// List the IDs of the models the API offers
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        let mut request = self
+            .client
+            .get(models_url(&self.config.api_url))
+            .query(&[("limit", "1000")]);
+        for (name, value) in self.request_headers() {
+            request = request.header(name, value);
+        }
+        self.throttle.wait().await;
+        let response = request.send().await.map_err(|e| self.request_error(e))?;
+        let status = response.status();
+        let body = response.text().await.map_err(|e| self.request_error(e))?;
+        if !status.is_success() {
+            return Err(Error::Api(api_error_message(
+                self.config.provider,
+                status,
+                &body,
+            )));
+        }
+        let models = parse_model_list(&body)?;
+        tracing::debug!(count = models.len(), "listed models");
+        Ok(models)
+    }
+
+    /// Send a minimal request, checking that the API is reachable and
+    /// accepts the key
+    pub async fn ping(&self) -> Result<()> {
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: "Reply with OK.".to_string(),
+        }];
+        self.send_message(None, messages, 0, OutputLimit::new(8))
+            .await
+            .map(|_| ())
+    }
+
+    /// Build the user message asking for PR content
+    fn pr_user_prompt(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> String {
+        let prompt = self.pr_content_prompt(
+            commits,
+            diff,
+            changed_files,
+            template,
+            additional_context,
+            existing_pr,
+        );
+        match &self.config.language {
+            Some(language) => format!(
+                "{}\n\nWrite the title and description in {}, keeping the JSON keys in English.",
+                prompt, language
+            ),
+            None => prompt,
+        }
+    }
+
+    /// `pr_user_prompt` before the language instruction
+    fn pr_content_prompt(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> String {
+        if let Some(pr) = existing_pr {
+            // Update existing PR, only the field named by a directive if any
+            let (field, context) = PrField::parse(additional_context.unwrap_or(""));
+            let instruction = match field {
+                PrField::Both => "Respond with the updated title and body.",
+                PrField::Title => "Change only the title. Respond with the new title and the body exactly as it is, word for word.",
+                PrField::Body => "Change only the body. Respond with the new body and the title exactly as it is, word for word.",
+            };
+            format!(
+                "Update the following GitHub Pull Request based on the user's feedback.
+
+Current PR:
+Title: {}
+Body:
+{}
+
+User feedback: {}
+
+{}",
+                pr.title, pr.body, context, instruction
+            )
+        } else {
+            // Generate new PR
+            let context_info = if let Some(ctx) = additional_context {
+                format!("\nAdditional context from user: {}\n", ctx)
+            } else {
+                String::new()
+            };
+            let clarify = if self.no_clarify {
+                NO_CLARIFY_INSTRUCTION
+            } else {
+                "If the changes are unclear or you need more context to write a good PR description, set needsClarification to true and provide a specific clarificationQuestion."
+            };
+
+            let (truncated_diff, _) =
+                truncate_diff_by_tokens(diff, self.config.max_diff_tokens as usize);
+            let commits = condense_commits(commits, COMMITS_BUDGET);
+
+            format!(
+                "{}{}
+Changed files:
+{}
+
+Commits:
+{}
+
+Diff (truncated if too long):
+{}
+
+{}",
+                pr_prompt_head(template, self.pr_body_instructions()),
+                context_info,
+                changed_files.join("\n"),
+                commits,
+                truncated_diff,
+                clarify
+            )
+        }
+    }
+
+    /// Build the prompt `generate_pr_content` sends, before any JSON retry
+    pub fn build_pr_prompt(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> Prompt {
+        let user = self.pr_user_prompt(
+            commits,
+            diff,
+            changed_files,
+            template,
+            additional_context,
+            existing_pr,
+        );
+        self.prompt(
+            Some(&pr_system_prompt(self.config.max_subject_length)),
+            user,
+            self.pr_limit(),
+        )
+    }
+
+    /// Generate PR content from commits and diff
+    pub async fn generate_pr_content(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> Result<PRContent> {
+        let prompt = self.pr_user_prompt(
+            commits,
+            diff,
+            changed_files,
+            template,
+            additional_context,
+            existing_pr,
+        );
+        // An update starts with the current PR, so only new PRs share a start
+        let cached_prefix = match existing_pr {
+            Some(_) => 0,
+            None => pr_prompt_head(template, self.pr_body_instructions()).len(),
+        };
+        let system = pr_system_prompt(self.config.max_subject_length);
+
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: prompt,
+        }];
+
+        // The correction below is one more attempt of the same request
+        let mut attempts = 0;
+        let response_text = self
+            .send_counted(
+                Some(&system),
+                messages.clone(),
+                cached_prefix,
+                self.pr_limit(),
+                &mut attempts,
+            )
+            .await?;
+        let parsed = parse_pr_content(&response_text);
+        if parsed.is_ok() || attempts >= self.config.retry_max_attempts {
+            return parsed;
+        }
+
+        // Show the model its invalid answer and ask once more for JSON only
+        tracing::debug!("PR response was not valid JSON, retrying once");
+        let mut messages = messages;
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: response_text,
+        });
+        messages.push(Message {
+            role: "user".to_string(),
+            content: JSON_CORRECTION_PROMPT.to_string(),
+        });
+        let response_text = self
+            .send_counted(
+                Some(&system),
+                messages,
+                cached_prefix,
+                self.pr_limit(),
+                &mut attempts,
+            )
+            .await?;
+        parse_pr_content(&response_text)
+    }
+}
+
+/// A model client generating commit messages and PR content
+///
+/// Implemented by `AnthropicClient`; code generic over it can be tested
+/// against canned responses instead of the network.
+pub trait LlmClient {
+    /// Generate a single commit message for `diff`
+    fn generate_commit_message(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+    ) -> impl Future<Output = Result<String>>;
+
+    /// Generate a PR title and description, refining `existing_pr` if given
+    fn generate_pr_content(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> impl Future<Output = Result<PRContent>>;
+}
+
+impl LlmClient for AnthropicClient {
+    fn generate_commit_message(
+        &self,
+        diff: &str,
+        options: &CommitPromptOptions,
+    ) -> impl Future<Output = Result<String>> {
+        AnthropicClient::generate_commit_message(self, diff, options)
+    }
+
+    fn generate_pr_content(
+        &self,
+        commits: &str,
+        diff: &str,
+        changed_files: &[String],
+        template: Option<&str>,
+        additional_context: Option<&str>,
+        existing_pr: Option<&PRContent>,
+    ) -> impl Future<Output = Result<PRContent>> {
+        AnthropicClient::generate_pr_content(
+            self,
+            commits,
+            diff,
+            changed_files,
+            template,
+            additional_context,
+            existing_pr,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_client(vars: &[(&str, &str)]) -> AnthropicClient {
+        let config = Config::from_lookup(|name| {
+            if name == "ANTHROPIC_API_KEY" {
+                return Some("sk-ant-test".to_string());
+            }
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+        .unwrap();
+        AnthropicClient::new(config).unwrap()
+    }
+
+    fn pr_content_fixture(title: &str, body: &str) -> PRContent {
+        PRContent {
+            title: title.to_string(),
+            body: body.to_string(),
+            needs_clarification: None,
+            clarification_question: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    fn sample_messages() -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: "Hello".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let version = ("anthropic-version", ANTHROPIC_VERSION.to_string());
+
+        let client = test_client(&[]);
+        assert_eq!(
+            client.request_headers(),
+            vec![("x-api-key", "sk-ant-test".to_string()), version.clone()]
+        );
+
+        let client = test_client(&[("AUTOCOMMIT_AUTH_SCHEME", "bearer")]);
+        assert_eq!(
+            client.request_headers(),
+            vec![
+                ("authorization", "Bearer sk-ant-test".to_string()),
+                version.clone()
+            ]
+        );
+
+        let client = test_client(&[("AUTOCOMMIT_AUTH_SCHEME", "none")]);
+        assert_eq!(client.request_headers(), vec![version]);
+
+        let client = test_client(&[(
+            "AUTOCOMMIT_API_BASE",
+            "http://localhost:8080/v1/chat/completions",
+        )]);
+        assert_eq!(
+            client.request_headers(),
+            vec![("authorization", "Bearer sk-ant-test".to_string())]
+        );
+    }
+
+    /// Serve one canned Anthropic text response per request, in order
+    ///
+    /// Returns the base URL and a receiver yielding each request body.
+    async fn canned_server(
+        texts: Vec<&'static str>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        canned_server_with_status(texts.into_iter().map(|text| (200, text)).collect()).await
+    }
+
+    /// `canned_server` answering with the given statuses, where any but 200
+    /// sends the text as an API error message
+    async fn canned_server_with_status(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let bodies = responses
+            .into_iter()
+            .map(|(status, text)| match status {
+                200 => (status, json!({"content": [{"type": "text", "text": text}]})),
+                _ => (
+                    status,
+                    json!({"type": "error", "error": {"type": "api_error", "message": text}}),
+                ),
+            })
+            .collect();
+        canned_server_with_bodies(bodies).await
+    }
+
+    /// `canned_server` answering with the given statuses and JSON bodies
+    async fn canned_server_with_bodies(
+        responses: Vec<(u16, serde_json::Value)>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let (base, mut requests) = canned_server_with_headers(responses).await;
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((_, body)) = requests.recv().await {
+                if sender.send(body).is_err() {
+                    break;
+                }
+            }
+        });
+        (base, receiver)
+    }
+
+    /// `canned_server_with_bodies` sending the lowercased request line and
+    /// headers of each request along with its body
+    async fn canned_server_with_headers(
+        responses: Vec<(u16, serde_json::Value)>,
+    ) -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<(String, String)>,
+    ) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Read the headers, then the body announced by content-length
+                let body_start = loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                };
+                let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map(|value| value.trim().parse().unwrap())
+                    .unwrap_or(0);
+                while request.len() < body_start + length {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let body_text = String::from_utf8_lossy(&request[body_start..]).into_owned();
+                sender.send((headers, body_text)).unwrap();
+
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {} Canned\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://{}", addr), receiver)
+    }
+
+    #[tokio::test]
+    async fn test_send_message_retries_server_errors() {
+        let (base, mut requests) =
+            canned_server_with_status(vec![(529, "Overloaded"), (200, "feat: add login")]).await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+        let text = client
+            .send_message(None, sample_messages(), 0, OutputLimit::new(16))
+            .await
+            .unwrap();
+        assert_eq!(text, "feat: add login");
+        let (first, second) = (
+            requests.recv().await.unwrap(),
+            requests.recv().await.unwrap(),
+        );
+        assert_eq!(first, second);
+
+        // Client errors are returned at once
+        let (base, mut requests) =
+            canned_server_with_status(vec![(400, "Bad request"), (200, "unused")]).await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+        let result = client
+            .send_message(None, sample_messages(), 0, OutputLimit::new(16))
+            .await;
+        assert!(matches!(result, Err(Error::Api(message)) if message.contains("Bad request")));
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_message_fails_on_max_tokens() {
+        let truncated = json!({
+            "content": [{"type": "text", "text": "{\"title\": \"Add login\", \"body\": \"## Sum"}],
+            "stop_reason": "max_tokens"
+        });
+        let (base, _requests) =
+            canned_server_with_bodies(vec![(200, truncated.clone()), (200, truncated)]).await;
+        let client = test_client(&[
+            ("AUTOCOMMIT_API_BASE", base.as_str()),
+            ("AUTOCOMMIT_MAX_TOKENS_PR", "64"),
+        ]);
+        let result = client
+            .send_message(None, sample_messages(), 0, client.pr_limit())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Api(message)) if message
+                == "Response truncated at max_tokens (64 tokens); increase AUTOCOMMIT_MAX_TOKENS_PR"
+        ));
+
+        // Requests that expect a short answer keep it
+        let text = client
+            .send_message(None, sample_messages(), 0, OutputLimit::new(8))
+            .await
+            .unwrap();
+        assert!(text.starts_with("{\"title\""));
+    }
+
+    #[tokio::test]
+    async fn test_pr_content_retries_invalid_json() {
+        let (base, mut requests) = canned_server(vec![
+            "Here is your PR:\n{\"title\": \"Add login\",}",
+            "{\"title\": \"Add login\", \"body\": \"## Summary\\nLogin form\"}",
+        ])
+        .await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+
+        let content = client
+            .generate_pr_content("abc123 add login", "+login", &[], None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(content.title, "Add login");
+        assert_eq!(content.body, "## Summary\nLogin form");
+
+        requests.recv().await.unwrap();
+        let retry: serde_json::Value =
+            serde_json::from_str(&requests.recv().await.unwrap()).unwrap();
+        let messages = retry["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert!(messages[1]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("Here is your PR:"));
+        assert_eq!(messages[2]["content"], JSON_CORRECTION_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn test_commit_message_with_subject() {
+        let subject = "fix: handle null config";
+        let (base, mut requests) = canned_server(vec![
+            "Fix: Handle a null config\n\nTreat a missing config as the defaults.",
+            "fix: handle null config\n\nTreat a missing config as the defaults.",
+        ])
+        .await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+        let options = CommitPromptOptions {
+            subject: Some(subject.to_string()),
+            ..Default::default()
+        };
+
+        // A reworded subject is dropped like an echoed one
+        for _ in 0..2 {
+            let message = client
+                .generate_commit_message("+config.unwrap_or_default()", &options)
+                .await
+                .unwrap();
+            assert_eq!(
+                message,
+                "fix: handle null config\n\nTreat a missing config as the defaults."
+            );
+        }
+
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.recv().await.unwrap()).unwrap();
+        let system = request["system"].as_str().unwrap();
+        assert!(system.contains("Write only the body"));
+        assert!(system.contains("must not be changed"));
+        assert!(!system.contains("type prefix"));
+        let user = request["messages"][0]["content"].as_str().unwrap();
+        assert!(user.contains("Write only the body of a git commit message whose subject line is \"fix: handle null config\""));
+
+        assert_eq!(join_subject_body(subject, ""), subject);
+        assert_eq!(join_subject_body(subject, subject), subject);
+        assert_eq!(
+            join_subject_body(subject, "Fix: handle NULL config."),
+            subject
+        );
+        assert_eq!(
+            join_subject_body(subject, "Configs can be null: treat them as the defaults."),
+            "fix: handle null config\n\nConfigs can be null: treat them as the defaults."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_prompt_file_with_subject() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prompt.txt");
+        std::fs::write(&path, "Write a commit message for:\n{diff}").unwrap();
+        let client = test_client(&[("AUTOCOMMIT_PROMPT_FILE", path.to_str().unwrap())]);
+        let options = CommitPromptOptions {
+            subject: Some("fix: handle null config".to_string()),
+            ..Default::default()
+        };
+        let prompt = client.build_commit_prompt("+x", &options).await.unwrap();
+        assert_eq!(
+            prompt.messages[0].content,
+            "Write a commit message for:\n+x\n\nWrite only the body of the commit message. Its subject line is \"fix: handle null config\" and must not be repeated or reworded."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pr_content_fails_after_second_invalid_json() {
+        let (base, _requests) = canned_server(vec!["not json", "still not json"]).await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+
+        let result = client
+            .generate_pr_content("abc123 add login", "+login", &[], None, None, None)
+            .await;
+        match result {
+            Err(Error::Api(message)) => assert!(message.contains("still not json")),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pr_content_json_retry_counts_as_an_attempt() {
+        let (base, mut requests) = canned_server(vec!["not json", "{}"]).await;
+        let client = test_client(&[
+            ("AUTOCOMMIT_API_BASE", base.as_str()),
+            ("AUTOCOMMIT_RETRY_MAX_ATTEMPTS", "1"),
+        ]);
+
+        let result = client
+            .generate_pr_content("abc123 add login", "+login", &[], None, None, None)
+            .await;
+        match result {
+            Err(Error::Api(message)) => assert!(message.contains("not json")),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err(), "the correction was sent");
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // A server that accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                connections.push(socket);
+            }
+        });
+
+        let base = format!("http://{}", addr);
+        let client = test_client(&[
+            ("AUTOCOMMIT_API_BASE", base.as_str()),
+            ("AUTOCOMMIT_TIMEOUT_SECS", "1"),
+            ("AUTOCOMMIT_RETRY_MAX_ATTEMPTS", "1"),
+        ]);
+
+        let result = client
+            .send_message(None, sample_messages(), 0, OutputLimit::new(16))
+            .await;
+        match result {
+            Err(Error::Api(message)) => assert_eq!(message, "Request timed out after 1s"),
+            other => panic!("expected a timeout error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_message_request_serialization() {
+        let request = MessageRequest {
+            model: "claude-sonnet-4-20250514".to_string(),
+            max_tokens: 256,
+            system: None,
+            messages: sample_messages(),
+            temperature: None,
+            stream: false,
+            metadata: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "model": "claude-sonnet-4-20250514",
+                "max_tokens": 256,
+                "messages": [{"role": "user", "content": "Hello"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_cached_message_request_serialization() {
+        let request = |content: &str| MessageRequest {
+            model: "claude-sonnet-4-20250514".to_string(),
+            max_tokens: 256,
+            system: Some("Output only JSON.".to_string()),
+            messages: vec![Message {
+                role: "user".to_string(),
+                content: content.to_string(),
+            }],
+            temperature: None,
+            stream: false,
+            metadata: None,
+        };
+
+        let cached = CachedMessageRequest::new(request("Template:\n## What\nDiff:\n+login"), 18);
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            json!({
+                "model": "claude-sonnet-4-20250514",
+                "max_tokens": 256,
+                "system": [{
+                    "type": "text",
+                    "text": "Output only JSON.",
+                    "cache_control": {"type": "ephemeral"}
+                }],
+                "messages": [{"role": "user", "content": [
+                    {
+                        "type": "text",
+                        "text": "Template:\n## What\n",
+                        "cache_control": {"type": "ephemeral"}
+                    },
+                    {"type": "text", "text": "Diff:\n+login"}
+                ]}]
+            })
+        );
+
+        // Without a prefix, or one that doesn't fit, only the system prompt is cached
+        for prefix in [0, 100] {
+            let value =
+                serde_json::to_value(CachedMessageRequest::new(request("+login"), prefix)).unwrap();
+            assert_eq!(
+                value["messages"],
+                json!([{"role": "user", "content": [{"type": "text", "text": "+login"}]}])
+            );
+            assert_eq!(
+                value["system"][0]["cache_control"],
+                json!({"type": "ephemeral"})
+            );
+        }
+        // A prefix covering the whole message leaves no empty block behind
+        let value = serde_json::to_value(CachedMessageRequest::new(request("+login"), 6)).unwrap();
+        assert_eq!(value["messages"][0]["content"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            value["messages"][0]["content"][0]["cache_control"],
+            json!({"type": "ephemeral"})
+        );
+    }
+
+    #[test]
+    fn test_pr_prompt_head_is_prompt_prefix() {
+        let client = test_client(&[]);
+        for template in [None, Some("## What\n## Why")] {
+            let prompt = client.build_pr_prompt(
+                "feat: add login",
+                "+login",
+                &[],
+                template,
+                Some("part of the auth epic"),
+                None,
+            );
+            let user = &prompt.messages[0].content;
+            assert!(user.starts_with(&pr_prompt_head(template, None)));
+            // What changes per run comes after the cached start
+            assert!(!pr_prompt_head(template, None).contains("auth epic"));
+        }
+    }
+
+    #[test]
+    fn test_pr_field_parse() {
+        assert_eq!(
+            PrField::parse("title: make it shorter"),
+            (PrField::Title, "make it shorter")
+        );
+        assert_eq!(
+            PrField::parse("  Body:mention the migration"),
+            (PrField::Body, "mention the migration")
+        );
+        assert_eq!(
+            PrField::parse("make the title shorter"),
+            (PrField::Both, "make the title shorter")
+        );
+        assert_eq!(PrField::parse("titles: x"), (PrField::Both, "titles: x"));
+        assert_eq!(PrField::parse("é"), (PrField::Both, "é"));
+    }
+
+    #[test]
+    fn test_pr_field_preserve() {
+        let previous = pr_content_fixture("Add login", "Login form");
+        let updated = pr_content_fixture("Add OAuth login", "OAuth form");
+        let title = PrField::Title.preserve(&previous, updated.clone());
+        assert_eq!(
+            (title.title.as_str(), title.body.as_str()),
+            ("Add OAuth login", "Login form")
+        );
+        let body = PrField::Body.preserve(&previous, updated.clone());
+        assert_eq!(
+            (body.title.as_str(), body.body.as_str()),
+            ("Add login", "OAuth form")
+        );
+        assert_eq!(PrField::Both.preserve(&previous, updated.clone()), updated);
+    }
+
+    #[test]
+    fn test_build_pr_prompt_single_field_update() {
+        let client = test_client(&[]);
+        let existing = pr_content_fixture("Add login", "Login form");
+        let update = |feedback: &str| {
+            client
+                .build_pr_prompt("", "", &[], None, Some(feedback), Some(&existing))
+                .messages[0]
+                .content
+                .clone()
+        };
+
+        let user = update("title: make it shorter");
+        assert!(user.contains("User feedback: make it shorter\n"));
+        assert!(user.ends_with(
+            "Change only the title. Respond with the new title and the body exactly as it is, word for word."
+        ));
+        let user = update("body: mention the migration");
+        assert!(user.contains("Change only the body."));
+        assert!(user.contains("the title exactly as it is"));
+        assert!(update("mention the tests").ends_with("Respond with the updated title and body."));
+    }
+
+    #[test]
+    fn test_explanation_prompt() {
+        let change =
+            "feat: add login\n\ndiff --git a/src/login.rs b/src/login.rs\n+pub fn login() {}\n";
+        let prompt = test_client(&[]).explanation_prompt(change);
+        assert!(prompt.starts_with("Explain the following change"));
+        assert!(prompt.ends_with(change));
+        assert!(EXPLAIN_SYSTEM_PROMPT.contains("not a commit message"));
+
+        let prompt = test_client(&[("AUTOCOMMIT_LANGUAGE", "French")]).explanation_prompt(change);
+        assert!(prompt.contains("+pub fn login() {}"));
+        assert!(prompt.ends_with("Write the explanation in French."));
+    }
+
+    #[test]
+    fn test_build_pr_prompt_no_clarify() {
+        let prompt =
+            test_client(&[]).build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        let user = &prompt.messages[0].content;
+        assert!(user.contains("set needsClarification to true"));
+        assert!(!user.contains(NO_CLARIFY_INSTRUCTION));
+
+        let client = test_client(&[]).with_no_clarify(true);
+        let prompt = client.build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        let user = &prompt.messages[0].content;
+        assert!(user.ends_with(NO_CLARIFY_INSTRUCTION));
+        assert!(user.contains("Do not ask for clarification; make reasonable assumptions."));
+        assert!(!user.contains("set needsClarification to true"));
+    }
+
+    #[test]
+    fn test_build_pr_prompt_squash() {
+        let client = test_client(&[]).with_squash(true);
+        let prompt = client.build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        let user = &prompt.messages[0].content;
+        assert!(user.contains(SQUASH_PR_INSTRUCTIONS));
+        assert!(user.contains("Conventional Commits subject"));
+        assert!(!user.contains("## Summary"));
+
+        // A template still decides the structure
+        let prompt = client.build_pr_prompt(
+            "feat: add login",
+            "+login",
+            &[],
+            Some("## What\n## Why"),
+            None,
+            None,
+        );
+        let user = &prompt.messages[0].content;
+        assert!(user.contains("Template:\n## What\n## Why"));
+        assert!(!user.contains(SQUASH_PR_INSTRUCTIONS));
+    }
+
+    #[test]
+    fn test_build_pr_prompt_release_notes() {
+        let client = test_client(&[]).with_release_notes(true);
+        let commits = "Features:\n- add login\n\nBug Fixes:\n- handle empty input";
+        let prompt = client.build_pr_prompt(commits, "+login", &[], None, None, None);
+        let user = &prompt.messages[0].content;
+        assert!(user.contains(RELEASE_NOTES_PR_INSTRUCTIONS));
+        assert!(user.contains(&format!("Commits:\n{}\n", commits)));
+        assert!(!user.contains("## Summary"));
+    }
+
+    #[test]
+    fn test_chat_completion_request_serialization() {
+        let request = ChatCompletionRequest {
+            model: "llama3".to_string(),
+            max_tokens: 1024,
+            messages: sample_messages(),
+            temperature: None,
+            stream: false,
+            user: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "model": "llama3",
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "Hello"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_temperature_serialized_when_set() {
+        let request = MessageRequest {
+            model: "claude-sonnet-4-20250514".to_string(),
+            max_tokens: 256,
+            system: None,
+            messages: sample_messages(),
+            temperature: Some(0.5),
+            stream: false,
+            metadata: None,
+        };
+
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["temperature"], json!(0.5));
+    }
+
+    #[test]
+    fn test_metadata_user_id_serialized() {
+        let client = test_client(&[]);
+        let value = serde_json::to_value(client.build_request(None, sample_messages(), 16));
+        assert!(value.unwrap().get("metadata").is_none());
+
+        let client = test_client(&[("AUTOCOMMIT_METADATA_USER_ID", "team-a-7f3c")]);
+        let request = || client.build_request(None, sample_messages(), 16);
+        let value = serde_json::to_value(request()).unwrap();
+        assert_eq!(value["metadata"], json!({"user_id": "team-a-7f3c"}));
+        let cached = serde_json::to_value(CachedMessageRequest::new(request(), 0)).unwrap();
+        assert_eq!(cached["metadata"], json!({"user_id": "team-a-7f3c"}));
+        let chat = serde_json::to_value(ChatCompletionRequest::from(request())).unwrap();
+        assert_eq!(chat["user"], "team-a-7f3c");
+        assert!(chat.get("metadata").is_none());
+    }
+
+    #[test]
+    fn test_new_rejects_invalid_proxy() {
+        let mut config = test_client(&[]).config;
+        config.proxy = Some("http://[::1".to_string());
+        let error = AnthropicClient::new(config).err().unwrap();
+        assert!(matches!(error, Error::Env(message) if message.contains("AUTOCOMMIT_PROXY")));
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_header() {
+        let (base, mut requests) =
+            canned_server_with_headers(vec![(200, json!({"data": []}))]).await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+        assert!(client.list_models().await.unwrap().is_empty());
+        let (headers, _) = requests.recv().await.unwrap();
+        assert!(headers.starts_with("get /v1/models?limit=1000 "));
+        assert!(headers.contains(&format!("user-agent: {}\r\n", USER_AGENT)));
+        assert!(USER_AGENT.starts_with("autocommit/"));
+    }
+
+    #[test]
+    fn test_persona_in_system_prompt() {
+        let persona =
+            "You are a terse senior engineer who writes Linux-kernel-style commit messages";
+        let client = test_client(&[("AUTOCOMMIT_PERSONA", persona)]);
+        let request = client.build_request(None, sample_messages(), 256);
+        assert_eq!(request.system.as_deref(), Some(persona));
+
+        let chat = ChatCompletionRequest::from(request);
+        assert_eq!(chat.messages[0].role, "system");
+        assert_eq!(chat.messages[0].content, persona);
+        assert_eq!(chat.messages[1].role, "user");
+    }
+
+    #[test]
+    fn test_no_persona_omits_system() {
+        let client = test_client(&[]);
+        let request = client.build_request(None, sample_messages(), 256);
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("system")
+            .is_none());
+    }
+
+    #[test]
+    fn test_assemble_system_prompt() {
+        assert_eq!(assemble_system_prompt(None, None), None);
+        assert_eq!(assemble_system_prompt(Some("  "), None), None);
+        assert_eq!(
+            assemble_system_prompt(Some("Be terse."), Some("Output only JSON.")),
+            Some("Be terse.\n\nOutput only JSON.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_commit_message_prompt_scope() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(!default.contains("scope"));
+
+        let options = CommitPromptOptions {
+            scope: Some("parser".to_string()),
+            ..Default::default()
+        };
+        assert!(commit_message_system(&options)
+            .contains("Use the scope \"parser\", formatted as type(parser): description"));
+        assert!(commit_candidates_system(3, &options).contains("type(parser)"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_types() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(default.contains("type prefix (build, chore, ci, docs, feat, fix,"));
+
+        let options = CommitPromptOptions {
+            types: vec!["feat".to_string(), "fix".to_string(), "wip".to_string()],
+            ..Default::default()
+        };
+        assert!(commit_message_system(&options).contains("type prefix (feat, fix, wip)\n"));
+        assert!(commit_candidates_system(2, &options).contains("type prefix (feat, fix, wip)\n"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_gitmoji() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(!default.contains("gitmoji"));
+
+        let options = CommitPromptOptions {
+            types: vec!["feat".to_string(), "fix".to_string(), "wip".to_string()],
+            style: CommitStyle::Gitmoji,
+            ..Default::default()
+        };
+        let prompt = commit_message_system(&options);
+        assert!(prompt.contains("separated by a space: ✨ feat, 🐛 fix\n"));
+        assert!(prompt.contains("- Use no gitmoji for the wip types\n"));
+
+        let options = CommitPromptOptions {
+            style: CommitStyle::GitmojiShortcode,
+            ..options
+        };
+        assert!(commit_candidates_system(2, &options).contains(":sparkles: feat, :bug: fix\n"));
+    }
+
+    #[tokio::test]
+    async fn test_commit_message_refines_draft() {
+        let (base, mut requests) = canned_server(vec!["fix: handle a null config"]).await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+        let options = CommitPromptOptions {
+            draft: Some("Fixed the null config crash".to_string()),
+            hint: Some("reported in #42".to_string()),
+            ..Default::default()
+        };
+
+        let message = client
+            .generate_commit_message("+config.unwrap_or_default()", &options)
+            .await
+            .unwrap();
+        assert_eq!(message, "fix: handle a null config");
+
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.recv().await.unwrap()).unwrap();
+        let user = request["messages"][0]["content"].as_str().unwrap();
+        assert!(user.starts_with("Improve this draft git commit message"));
+        assert!(user.contains("Keep its meaning, fixing the tense"));
+        assert!(user.contains("Draft:\nFixed the null config crash\n"));
+        assert!(user.contains("Additional context from the user: reported in #42"));
+        assert!(user.ends_with("Diff:\n+config.unwrap_or_default()"));
+        assert!(request["system"].as_str().unwrap().contains("type prefix"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_max_subject_length() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(default.contains("- Be a single line, max 72 characters"));
+
+        let options = CommitPromptOptions {
+            max_subject_length: Some(50),
+            ..Default::default()
+        };
+        assert!(commit_message_system(&options).contains("- Be a single line, max 50 characters"));
+        let options = CommitPromptOptions {
+            body: true,
+            ..options
+        };
+        let prompt = commit_message_system(&options);
+        assert!(prompt.contains("subject line of max 50 characters"));
+        assert!(prompt.contains("wrapped at 72 characters"));
+        assert!(pr_system_prompt(50).contains("(concise, max 50 chars)"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_body() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(default.contains("Be a single line"));
+        assert!(!default.contains("body paragraph"));
+
+        let options = CommitPromptOptions {
+            body: true,
+            ..Default::default()
+        };
+        let prompt = commit_message_system(&options);
+        assert!(prompt.contains("then a blank line"));
+        assert!(prompt.contains("body paragraph"));
+        assert!(!prompt.contains("Be a single line"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_rejected() {
+        let default = commit_message_system(&CommitPromptOptions::default());
+        assert!(!default.contains("rejected"));
+
+        let options = CommitPromptOptions {
+            rejected: vec![
+                "feat: add x\n\nbody".to_string(),
+                "feat: support x".to_string(),
+            ],
+            ..Default::default()
+        };
+        let prompt = commit_message_system(&options);
+        assert!(prompt.contains("rejected suggestions:\n  - feat: add x\n  - feat: support x\n"));
+    }
+
+    #[tokio::test]
+    async fn test_correct_commit_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prompt.txt");
+        std::fs::write(&path, "Describe this diff:\n{diff}").unwrap();
+        let (base, mut requests) = canned_server(vec!["fix: handle a null config"]).await;
+        let client = test_client(&[
+            ("AUTOCOMMIT_API_BASE", base.as_str()),
+            ("AUTOCOMMIT_PROMPT_FILE", path.to_str().unwrap()),
+        ]);
+
+        let message = client
+            .correct_commit_message(
+                "+config.unwrap_or_default()",
+                &CommitPromptOptions::default(),
+                "Handle a null config",
+                "Start with a type.",
+            )
+            .await
+            .unwrap();
+        assert_eq!(message, "fix: handle a null config");
+
+        // A prompt file replaces the system rules, so the correction follows
+        // the rejected answer instead
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.recv().await.unwrap()).unwrap();
+        let messages = request["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[0]["content"],
+            "Describe this diff:\n+config.unwrap_or_default()"
+        );
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "Handle a null config");
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"], "Start with a type.");
+    }
+
+    #[tokio::test]
+    async fn test_generate_changelog_entry() {
+        let (base, mut requests) = canned_server(vec![
+            "Fixed a crash on start.",
+            "Fixed: Fixed a crash on start.",
+        ])
+        .await;
+        let client = test_client(&[("AUTOCOMMIT_API_BASE", base.as_str())]);
+
+        let plain = client
+            .generate_changelog_entry("+fix", false)
+            .await
+            .unwrap();
+        assert_eq!(plain, (None, "Fixed a crash on start.".to_string()));
+        let request = requests.recv().await.unwrap();
+        assert!(!request.contains("Start it with the category"));
+
+        let categorized = client.generate_changelog_entry("+fix", true).await.unwrap();
+        assert_eq!(
+            categorized,
+            (
+                Some(ChangelogCategory::Fixed),
+                "Fixed a crash on start.".to_string()
+            )
+        );
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("Start it with the category"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_hint() {
+        let default = commit_message_prompt("diff", &CommitPromptOptions::default());
+        assert!(!default.contains("Additional context"));
+        assert!(default.ends_with("following diff.\n\nDiff:\ndiff"));
+
+        let options = CommitPromptOptions {
+            hint: Some("this fixes issue #42".to_string()),
+            ..Default::default()
+        };
+        assert!(commit_message_prompt("diff", &options)
+            .contains("\nAdditional context from the user: this fixes issue #42\n"));
+        assert!(commit_candidates_prompt("diff", 2, &options).contains("this fixes issue #42"));
+    }
+
+    #[test]
+    fn test_commit_message_prompt_stats() {
+        let options = CommitPromptOptions {
+            stats: vec![FileStat {
+                path: "src/main.rs".to_string(),
+                added: Some(3),
+                deleted: Some(1),
+            }],
+            ..Default::default()
+        };
+        assert!(commit_message_prompt("diff", &options).ends_with(
+            "following diff.\n\nChange summary:\n1 file changed, 3 insertions(+), 1 deletion(-)\n  src/main.rs (+3 -1)\n\nDiff:\ndiff"
+        ));
+        assert!(commit_candidates_prompt("diff", 2, &options).contains("src/main.rs (+3 -1)"));
+        assert!(
+            !commit_message_prompt("diff", &CommitPromptOptions::default())
+                .contains("Change summary")
+        );
+    }
+
+    #[test]
+    fn test_commit_prompt_splits_rules_from_diff() {
+        let options = CommitPromptOptions::default();
+        let system = commit_message_system(&options);
+        let user = commit_message_prompt("+added line", &options);
+        assert!(system.contains("imperative mood"));
+        assert!(!system.contains("+added line"));
+        assert!(user.contains("+added line"));
+        assert!(!user.contains("imperative mood"));
+
+        assert!(commit_candidates_system(3, &options).contains("Only output valid JSON"));
+        assert!(!commit_candidates_prompt("diff", 3, &options).contains("Only output valid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_build_commit_prompt() {
+        let client = test_client(&[("AUTOCOMMIT_PERSONA", "Be terse.")]);
+        let options = CommitPromptOptions::default();
+        let prompt = client
+            .build_commit_prompt("+added line", &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            prompt,
+            Prompt {
+                system: Some(format!(
+                    "Be terse.\n\n{}",
+                    commit_message_system(&options)
+                )),
+                messages: vec![Message {
+                    role: "user".to_string(),
+                    content: "Generate a git commit message for the following diff.\n\nDiff:\n+added line"
+                        .to_string(),
+                }],
+                max_tokens: crate::config::DEFAULT_MAX_TOKENS_COMMIT,
+            }
+        );
+        assert_eq!(
+            prompt.to_string(),
+            format!(
+                "--- system ---\nBe terse.\n\n{}\n--- user ---\n{}\n",
+                commit_message_system(&options),
+                prompt.messages[0].content
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_prompt() {
+        let (base, mut requests) = canned_server(vec!["  feat: add a line  "]).await;
+        let client = test_client(&[
+            ("AUTOCOMMIT_API_BASE", base.as_str()),
+            ("AUTOCOMMIT_PERSONA", "Be terse."),
+        ]);
+        let mut prompt = client
+            .build_commit_prompt("+added line", &CommitPromptOptions::default())
+            .await
+            .unwrap();
+        prompt.messages[0]
+            .content
+            .push_str("\n\nMention the ticket.");
+        let system = prompt.system.clone();
+
+        // Only surrounding whitespace is trimmed from the model's text
+        assert_eq!(
+            client.send_prompt(prompt).await.unwrap(),
+            "feat: add a line"
+        );
+        let request: serde_json::Value =
+            serde_json::from_str(&requests.recv().await.unwrap()).unwrap();
+        assert_eq!(request["system"].as_str(), system.as_deref());
+        assert_eq!(
+            request["system"]
+                .as_str()
+                .unwrap()
+                .matches("Be terse.")
+                .count(),
+            1
+        );
+        assert_eq!(
+            request["messages"][0]["content"],
+            "Generate a git commit message for the following diff.\n\nDiff:\n+added line\n\nMention the ticket."
+        );
+        assert_eq!(
+            request["max_tokens"],
+            crate::config::DEFAULT_MAX_TOKENS_COMMIT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_commit_prompt_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prompt.txt");
+        std::fs::write(&path, "Describe this:\n{diff}").unwrap();
+        let client = test_client(&[("AUTOCOMMIT_PROMPT_FILE", path.to_str().unwrap())]);
+
+        let prompt = client
+            .build_commit_prompt("+added line", &CommitPromptOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(prompt.system, None);
+        assert_eq!(prompt.messages[0].content, "Describe this:\n+added line");
+    }
+
+    #[test]
+    fn test_build_commit_candidates_prompt() {
+        let options = CommitPromptOptions::default();
+        let prompt = test_client(&[]).build_commit_candidates_prompt("+added line", 3, &options);
+        assert_eq!(prompt.system, Some(commit_candidates_system(3, &options)));
+        assert_eq!(
+            prompt.messages[0].content,
+            "Generate 3 alternative git commit messages for the following diff.\n\nDiff:\n+added line"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_language_instruction() {
+        let default = test_client(&[]);
+        let prompt = default
+            .build_commit_prompt("+added line", &CommitPromptOptions::default())
+            .await
+            .unwrap();
+        assert!(!prompt.system.unwrap().contains("stay in English"));
+        let pr = default.build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        assert!(!pr.messages[0]
+            .content
+            .contains("Write the title and description in"));
+
+        let client = test_client(&[("AUTOCOMMIT_LANGUAGE", "French")]);
+        let prompt = client
+            .build_commit_prompt("+added line", &CommitPromptOptions::default())
+            .await
+            .unwrap();
+        let system = prompt.system.unwrap();
+        assert!(system.contains(
+            "- Be written in French, except for the type prefix and scope, which stay in English\n"
+        ));
+        // The limits follow the language rule they are counted for
+        let language_at = system.find("Be written in French").unwrap();
+        assert!(system.find("max 72 characters").unwrap() > language_at);
+        assert!(client
+            .build_commit_candidates_prompt("+added line", 2, &CommitPromptOptions::default())
+            .system
+            .unwrap()
+            .contains("Be written in French"));
+
+        // Options given per call win over the configured language
+        let options = CommitPromptOptions {
+            language: Some("German".to_string()),
+            ..Default::default()
+        };
+        let system = client
+            .build_commit_prompt("+added line", &options)
+            .await
+            .unwrap()
+            .system
+            .unwrap();
+        assert!(system.contains("Be written in German"));
+
+        let pr = client.build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        assert!(pr.messages[0].content.ends_with(
+            "\n\nWrite the title and description in French, keeping the JSON keys in English."
+        ));
+    }
+
+    #[test]
+    fn test_build_pr_prompt() {
+        let client = test_client(&[]);
+        let files = vec!["src/lib.rs".to_string()];
+        let prompt = client.build_pr_prompt("feat: add login", "+login", &files, None, None, None);
+        assert_eq!(prompt.system, Some(pr_system_prompt(72)));
+        assert!(prompt
+            .system
+            .as_deref()
+            .unwrap()
+            .contains("(concise, max 72 chars)"));
+        assert_eq!(prompt.messages.len(), 1);
+        let user = &prompt.messages[0].content;
+        assert!(user.starts_with("Generate a GitHub Pull Request title and description"));
+        assert!(user.contains("## Summary\nBrief description of changes"));
+        assert!(user.contains("Changed files:\nsrc/lib.rs\n\nCommits:\nfeat: add login\n"));
+        assert!(user.contains("Diff (truncated if too long):\n+login\n"));
+        assert!(!user.contains("Additional context"));
+
+        let prompt = client.build_pr_prompt(
+            "feat: add login",
+            "+login",
+            &files,
+            Some("## What\n## Why"),
+            Some("part of the auth epic"),
+            None,
+        );
+        let user = &prompt.messages[0].content;
+        assert!(user.contains("Template:\n## What\n## Why"));
+        assert!(!user.contains("## Summary"));
+        assert!(user.contains("Additional context from user: part of the auth epic"));
+
+        let existing = PRContent {
+            title: "Add login".to_string(),
+            body: "Login form".to_string(),
+            needs_clarification: None,
+            clarification_question: None,
+            extra: HashMap::new(),
+        };
+        let prompt = client.build_pr_prompt(
+            "feat: add login",
+            "+login",
+            &files,
+            None,
+            Some("mention the tests"),
+            Some(&existing),
+        );
+        assert_eq!(
+            prompt.messages[0].content,
+            "Update the following GitHub Pull Request based on the user's feedback.
+
+Current PR:
+Title: Add login
+Body:
+Login form
+
+User feedback: mention the tests
+
+Respond with the updated title and body."
+        );
+    }
+
+    #[test]
+    fn test_build_pr_prompt_truncates_like_commits() {
+        let client = test_client(&[("AUTOCOMMIT_MAX_TOKENS", "100")]);
+        // Multibyte lines put byte offsets inside characters at most cut points
+        let diff: String = (0..200)
+            .map(|i| format!("+ligne modifiée {} – déjà vue\n", i))
+            .collect();
+        let prompt = client.build_pr_prompt("feat: add login", &diff, &[], None, None, None);
+        let (expected, truncated) =
+            truncate_diff_by_tokens(&diff, client.config.max_diff_tokens as usize);
+        assert!(truncated);
+        assert!(prompt.messages[0]
+            .content
+            .contains(&format!("Diff (truncated if too long):\n{}\n", expected)));
+    }
+
+    #[test]
+    fn test_system_prompt_included_in_request() {
+        let client = test_client(&[("AUTOCOMMIT_PERSONA", "Be terse.")]);
+        let request = client.build_request(Some("Output only JSON."), sample_messages(), 256);
+        assert_eq!(
+            request.system.as_deref(),
+            Some("Be terse.\n\nOutput only JSON.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_override() {
+        let client = test_client(&[("AUTOCOMMIT_SYSTEM_PROMPT", "Write in French.")]);
+        let prompt = client
+            .build_commit_prompt("+x", &CommitPromptOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(prompt.system.as_deref(), Some("Write in French."));
+
+        // PR descriptions are parsed as JSON, so they keep their own rules
+        let prompt = client.build_pr_prompt("feat: add login", "+login", &[], None, None, None);
+        let system = prompt.system.unwrap();
+        assert!(!system.contains("Write in French."));
+        assert!(system.contains("JSON"));
+    }
+
+    #[test]
+    fn test_empty_system_prompt_omitted() {
+        let client = test_client(&[]);
+        let request = client.build_request(Some("  "), sample_messages(), 256);
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("system")
+            .is_none());
+    }
+
+    #[test]
+    fn test_stream_flag_serialized_when_set() {
+        let request =
+            test_client(&[])
+                .with_stream(true)
+                .build_request(None, sample_messages(), 256);
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["stream"],
+            json!(true)
+        );
+
+        let chat = ChatCompletionRequest::from(request);
+        assert_eq!(serde_json::to_value(&chat).unwrap()["stream"], json!(true));
+    }
+
+    #[test]
+    fn test_sse_parser_anthropic() {
+        let stream = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n",
+            "\n",
+            "event: ping\n",
+            "data: {\"type\": \"ping\"}\n",
+            "\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"feat: add \"}}\n",
+            "\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"login \u{2713}\"}}\n",
+            "\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":7}}\n",
+            "\n",
+            "event: message_stop\n",
+            "data: {\"type\":\"message_stop\"}\n",
+            "\n",
+        );
+
+        // Split into small chunks, cutting through lines and the multibyte check mark
+        let mut parser = SseParser::default();
+        let mut events = Vec::new();
+        for chunk in stream.as_bytes().chunks(7) {
+            events.extend(parser.feed(chunk));
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Usage {
+                    input_tokens: Some(25),
+                    output_tokens: None
+                },
+                StreamEvent::Text("feat: add ".to_string()),
+                StreamEvent::Text("login \u{2713}".to_string()),
+                StreamEvent::Usage {
+                    input_tokens: None,
+                    output_tokens: Some(7)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sse_parser_truncated() {
+        let mut parser = SseParser::default();
+        let events = parser.feed(
+            concat!(
+                "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\"},\"usage\":{\"output_tokens\":64}}\n\n",
+                "data: {\"choices\":[{\"delta\":{\"content\":\"## Sum\"},\"finish_reason\":\"length\"}]}\n\n",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Usage {
+                    input_tokens: None,
+                    output_tokens: Some(64)
+                },
+                StreamEvent::Truncated,
+                StreamEvent::Text("## Sum".to_string()),
+                StreamEvent::Truncated,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sse_parser_openai_and_errors() {
+        let mut parser = SseParser::default();
+        let events = parser.feed(
+            concat!(
+                "data: {\"choices\":[{\"delta\":{\"content\":\"fix: typo\"}}]}\n\n",
+                "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":3}}\n\n",
+                "data: [DONE]\n\n",
+                "event: error\n",
+                "data: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Text("fix: typo".to_string()),
+                StreamEvent::Usage {
+                    input_tokens: Some(9),
+                    output_tokens: Some(3)
+                },
+                StreamEvent::Error("Overloaded".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_api_error_body_rate_limit() {
+        let body = r#"{"type":"error","error":{"type":"rate_limit_error","message":"Number of request tokens has exceeded your per-minute rate limit"}}"#;
+        assert_eq!(
+            serde_json::from_str::<ApiErrorBody>(body).unwrap(),
+            ApiErrorBody {
+                error: ApiErrorDetail {
+                    kind: Some("rate_limit_error".to_string()),
+                    message: "Number of request tokens has exceeded your per-minute rate limit"
+                        .to_string(),
+                },
+            }
+        );
+        assert_eq!(
+            api_error_message(
+                Provider::Anthropic,
+                reqwest::StatusCode::TOO_MANY_REQUESTS,
+                body
+            ),
+            "Anthropic rate_limit_error: Number of request tokens has exceeded your per-minute rate limit"
+        );
+    }
+
+    #[test]
+    fn test_api_error_body_invalid_request() {
+        let body = r#"{"type":"error","error":{"type":"invalid_request_error","message":"max_tokens: Field required"}}"#;
+        assert_eq!(
+            api_error_message(Provider::Anthropic, reqwest::StatusCode::BAD_REQUEST, body),
+            "Anthropic invalid_request_error: max_tokens: Field required"
+        );
+
+        let body = r#"{"error":{"message":"model not found","type":null}}"#;
+        assert_eq!(
+            api_error_message(Provider::OpenAi, reqwest::StatusCode::NOT_FOUND, body),
+            "API error (status 404 Not Found): model not found"
+        );
+    }
+
+    #[test]
+    fn test_parse_model_list() {
+        let body = r#"{
+            "data": [
+                {"type": "model", "id": "claude-sonnet-4-20250514", "display_name": "Claude Sonnet 4", "created_at": "2025-05-22T00:00:00Z"},
+                {"type": "model", "id": "claude-3-5-haiku-20241022", "display_name": "Claude Haiku 3.5", "created_at": "2024-10-22T00:00:00Z"}
+            ],
+            "has_more": false,
+            "first_id": "claude-sonnet-4-20250514",
+            "last_id": "claude-3-5-haiku-20241022"
+        }"#;
+        let models = parse_model_list(body).unwrap();
+        assert_eq!(
+            models,
+            ["claude-sonnet-4-20250514", "claude-3-5-haiku-20241022"]
+        );
+        assert!(parse_model_list(r#"{"models": []}"#).is_err());
+
+        assert!(model_is_listed("claude-sonnet-4-20250514", &models));
+        assert!(model_is_listed("claude-3-5-haiku-latest", &models));
+        assert!(!model_is_listed("claude-sonet-4-20250514", &models));
+        assert!(!model_is_listed("claude-3-opus-latest", &models));
+    }
+
+    #[test]
+    fn test_models_url() {
+        assert_eq!(
+            models_url("https://api.anthropic.com/v1/messages"),
+            "https://api.anthropic.com/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:8080/v1/chat/completions"),
+            "http://localhost:8080/v1/models"
+        );
+    }
+
+    #[test]
+    fn test_api_error_unparsed_body() {
+        assert_eq!(
+            api_error_message(
+                Provider::Anthropic,
+                reqwest::StatusCode::BAD_GATEWAY,
+                "<html>Bad Gateway</html>"
+            ),
+            "API request failed with status 502 Bad Gateway: <html>Bad Gateway</html>"
+        );
+    }
+
+    #[test]
+    fn test_pr_content_json_round_trip() {
+        let content = PRContent {
+            title: "feat: add login".to_string(),
+            body: "## Summary\nAdds a \"login\" form.".to_string(),
+            needs_clarification: Some(false),
+            clarification_question: None,
+            extra: HashMap::new(),
+        };
+        let json = content.to_json().unwrap();
+        assert_eq!(serde_json::from_str::<PRContent>(&json).unwrap(), content);
+    }
+
+    #[test]
+    fn test_pr_content_extra_fields_round_trip() {
+        let content = parse_pr_content(
+            r#"{"title": "feat: add login", "body": "Adds a login form.", "needsClarification": false, "clarificationQuestion": null, "riskLevel": "low", "rollbackPlan": "Revert the commit"}"#,
+        )
+        .unwrap();
+        assert_eq!(content.title, "feat: add login");
+        assert_eq!(
+            content.extra_fields(),
+            [("riskLevel", "low"), ("rollbackPlan", "Revert the commit")]
+        );
+
+        let json = content.to_json().unwrap();
+        assert!(json.contains("\"riskLevel\": \"low\""));
+        assert_eq!(serde_json::from_str::<PRContent>(&json).unwrap(), content);
+
+        assert_eq!(
+            content.to_markdown(),
+            "# feat: add login\n\nAdds a login form.\n\n**riskLevel:** low\n**rollbackPlan:** Revert the commit\n"
+        );
+    }
+
+    #[test]
+    fn test_pr_content_non_string_extra_fields() {
+        // Not failing the parse, which would cost a JSON retry
+        let content = parse_pr_content(
+            r#"{"title": "feat: add login", "body": "Adds a login form.", "riskLevel": 3, "owners": ["ada"], "rollbackPlan": "Revert the commit"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            content.extra_fields(),
+            [("rollbackPlan", "Revert the commit")]
+        );
+        assert_eq!(content.extra.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pr.md");
+        let content = PRContent {
+            title: "feat: add login".to_string(),
+            body: "## Summary\nAdds a login form.\n".to_string(),
+            needs_clarification: None,
+            clarification_question: None,
+            extra: HashMap::new(),
+        };
+
+        content.write_markdown(&path, false).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# feat: add login\n\n## Summary\nAdds a login form.\n"
+        );
+
+        assert!(matches!(
+            content.write_markdown(&path, false).await,
+            Err(Error::User(_))
+        ));
+        content.write_markdown(&path, true).await.unwrap();
+
+        let missing_dir = dir.path().join("missing").join("pr.md");
+        match content.write_markdown(&missing_dir, true).await {
+            Err(Error::Io(e)) => assert!(e.to_string().contains("pr.md")),
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_candidates() {
+        assert_eq!(
+            parse_candidates(r#"["feat: add x", "feat(core): add x support"]"#).unwrap(),
+            vec!["feat: add x", "feat(core): add x support"]
+        );
+        assert_eq!(
+            parse_candidates("```json\n[\"fix: typo\"]\n```").unwrap(),
+            vec!["fix: typo"]
+        );
+    }
+
+    #[test]
+    fn test_parse_candidates_errors() {
+        assert!(matches!(parse_candidates("[]"), Err(Error::Api(_))));
+        assert!(matches!(parse_candidates(r#"["  "]"#), Err(Error::Api(_))));
+        assert!(matches!(
+            parse_candidates("feat: add x"),
+            Err(Error::Api(_))
+        ));
+    }
+
+    #[test]
+    fn test_message_response_text() {
+        let response: MessageResponse = serde_json::from_value(json!({
+            "content": [{"type": "text", "text": "  feat: add login\n"}]
+        }))
+        .unwrap();
+        assert_eq!(response.into_text().unwrap(), "feat: add login");
+    }
+
+    #[test]
+    fn test_message_response_multiple_blocks() {
+        let response: MessageResponse = serde_json::from_value(json!({
+            "content": [
+                {"type": "text", "text": "{\"title\": \"Add login\", "},
+                {"type": "text", "text": "\"body\": \"Adds a login form\"}"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            response.into_text().unwrap(),
+            "{\"title\": \"Add login\", \"body\": \"Adds a login form\"}"
+        );
+    }
+
+    #[test]
+    fn test_message_response_skips_other_blocks() {
+        let response: MessageResponse = serde_json::from_value(json!({
+            "content": [
+                {"type": "thinking", "thinking": "The diff adds a form", "signature": "abc"},
+                {"type": "text", "text": "feat: add login form"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(response.into_text().unwrap(), "feat: add login form");
+
+        let response: MessageResponse = serde_json::from_value(json!({
+            "content": [{"type": "redacted_thinking", "data": "xyz"}]
+        }))
+        .unwrap();
+        assert!(matches!(response.into_text(), Err(Error::Api(_))));
+    }
+
+    #[test]
+    fn test_message_response_usage() {
+        let body = r#"{
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-20250514",
+            "content": [{"type": "text", "text": "feat: add login"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 1203, "output_tokens": 48}
+        }"#;
+
+        let response: MessageResponse = serde_json::from_str(body).unwrap();
+        let usage = response.usage.unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                input_tokens: 1203,
+                output_tokens: 48
+            }
+        );
+        assert_eq!(usage.to_string(), "1203 in / 48 out");
+    }
+
+    #[test]
+    fn test_chat_completion_response_usage() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{"message": {"content": "fix: typo"}}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 3, "total_tokens": 13}
+        }))
+        .unwrap();
+        assert_eq!(
+            response.usage.map(Usage::from),
+            Some(Usage {
+                input_tokens: 10,
+                output_tokens: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_chat_completion_response_text() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "fix: typo\n"}}]
+        }))
+        .unwrap();
+        assert_eq!(response.into_text().unwrap(), "fix: typo");
+
+        let empty: ChatCompletionResponse = serde_json::from_value(json!({"choices": []})).unwrap();
+        assert!(matches!(empty.into_text(), Err(Error::Api(_))));
+    }
+}
@@ -0,0 +1,271 @@
+//! Retrying API requests with capped exponential backoff and full jitter
+//!
+//! Every request goes through one `RetryPolicy`, so commit and PR
+//! generation back off the same way when timeouts, rate limits and server
+//! errors stack up, and never wait longer than `MAX_TOTAL_WAIT` in all.
+
+use crate::config::Config;
+use crate::errors::Error;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Wait before the first retry, doubled for each one after it
+pub const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Most time spent waiting between attempts of one request, in all
+pub const MAX_TOTAL_WAIT: Duration = Duration::from_secs(30);
+
+/// Why an attempt failed, and whether trying again could help
+#[derive(Debug)]
+pub enum Failure {
+    /// Timeouts, connection errors, rate limits and server errors
+    Retryable(Error),
+    Fatal(Error),
+}
+
+/// Whether a response status is worth retrying: 429 and any 5xx, which
+/// includes Anthropic's 529 "overloaded"
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// How often and how long to retry a failed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    /// Longest single wait between attempts
+    pub max_delay: Duration,
+    /// Longest time spent waiting across all attempts
+    pub max_total_wait: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        RetryPolicy {
+            max_attempts: config.retry_max_attempts,
+            base_delay: BASE_DELAY,
+            max_delay: Duration::from_secs(config.retry_max_delay_secs),
+            max_total_wait: MAX_TOTAL_WAIT,
+        }
+    }
+
+    /// Longest wait before retry number `retry` (from 1), before jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Full jitter: a wait anywhere between zero and the backoff, picked by
+    /// `random` in `0.0..1.0`
+    pub fn delay(&self, retry: u32, random: f64) -> Duration {
+        self.backoff(retry).mul_f64(random.clamp(0.0, 1.0))
+    }
+
+    /// Run `attempt` until it succeeds, fails for good, or the policy runs
+    /// out, returning the last error then
+    ///
+    /// `attempts` counts the attempts made so far and goes up with each one,
+    /// so requests sharing it, like a follow-up to a bad answer, share
+    /// `max_attempts` too.
+    pub async fn run<T, F, Fut>(&self, attempts: &mut u32, attempt: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        self.run_with(attempts, attempt, tokio::time::sleep, random_fraction)
+            .await
+    }
+
+    /// `run` with the sleep and the source of jitter swapped out, for tests
+    async fn run_with<T, F, Fut, S, SleepFut>(
+        &self,
+        attempts: &mut u32,
+        mut attempt: F,
+        mut sleep: S,
+        mut random: impl FnMut() -> f64,
+    ) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+        S: FnMut(Duration) -> SleepFut,
+        SleepFut: Future<Output = ()>,
+    {
+        let mut waited = Duration::ZERO;
+        let mut retry = 0;
+        loop {
+            *attempts += 1;
+            let error = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(Failure::Fatal(error)) => return Err(error),
+                Err(Failure::Retryable(error)) => error,
+            };
+            retry += 1;
+            let remaining = self.max_total_wait.saturating_sub(waited);
+            if *attempts >= self.max_attempts || remaining.is_zero() {
+                return Err(error);
+            }
+            let delay = self.delay(retry, random()).min(remaining);
+            tracing::debug!(%error, retry, ?delay, "retrying API request");
+            sleep(delay).await;
+            waited += delay;
+        }
+    }
+}
+
+/// A random number in `0.0..1.0`, from the per-process random hasher keys
+/// so no extra dependency is needed for jitter
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_total_wait: Duration::from_secs(30),
+        }
+    }
+
+    /// Run `policy` against `outcomes`, one per attempt, recording each sleep
+    async fn run_outcomes(
+        policy: RetryPolicy,
+        outcomes: Vec<Result<u32, Failure>>,
+    ) -> (Result<u32, Error>, usize, Vec<Duration>) {
+        let outcomes = RefCell::new(outcomes.into_iter());
+        let attempts = RefCell::new(0);
+        let sleeps = RefCell::new(Vec::new());
+        let result = policy
+            .run_with(
+                &mut 0,
+                || {
+                    *attempts.borrow_mut() += 1;
+                    let outcome = outcomes.borrow_mut().next().expect("attempted too often");
+                    async move { outcome }
+                },
+                |delay| {
+                    sleeps.borrow_mut().push(delay);
+                    async {}
+                },
+                || 1.0,
+            )
+            .await;
+        (result, attempts.into_inner(), sleeps.into_inner())
+    }
+
+    fn retryable(message: &str) -> Result<u32, Failure> {
+        Err(Failure::Retryable(Error::Api(message.to_string())))
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max_delay() {
+        let policy = policy(10);
+        let backoffs: Vec<u128> = (1..=6)
+            .map(|retry| policy.backoff(retry).as_millis())
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let policy = policy(10);
+        assert_eq!(policy.delay(3, 0.0), Duration::ZERO);
+        assert_eq!(policy.delay(3, 0.5), Duration::from_millis(200));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_millis(400));
+        // Out of range randomness still stays within the backoff
+        assert_eq!(policy.delay(3, 7.0), Duration::from_millis(400));
+        for _ in 0..100 {
+            let random = random_fraction();
+            assert!((0.0..1.0).contains(&random));
+            assert!(policy.delay(4, random) <= policy.backoff(4));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let outcomes = vec![retryable("timed out"), retryable("429"), Ok(7)];
+        let (result, attempts, sleeps) = run_outcomes(policy(3), outcomes).await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            sleeps,
+            [Duration::from_millis(100), Duration::from_millis(200)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_returns_last_error_when_exhausted() {
+        let outcomes = vec![retryable("timed out"), retryable("429"), retryable("503")];
+        let (result, attempts, sleeps) = run_outcomes(policy(3), outcomes).await;
+        assert!(matches!(result, Err(Error::Api(msg)) if msg == "503"));
+        assert_eq!(attempts, 3);
+        assert_eq!(sleeps.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() {
+        let outcomes = vec![Err(Failure::Fatal(Error::Api("401".to_string())))];
+        let (result, attempts, sleeps) = run_outcomes(policy(3), outcomes).await;
+        assert!(matches!(result, Err(Error::Api(msg)) if msg == "401"));
+        assert_eq!(attempts, 1);
+        assert!(sleeps.is_empty());
+
+        let (result, attempts, _) = run_outcomes(policy(1), vec![retryable("503")]).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shared_attempts_count_against_max() {
+        let mut attempts = 2;
+        let result = policy(3)
+            .run_with(
+                &mut attempts,
+                || async { retryable("503") },
+                |_| async {},
+                || 1.0,
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_total_wait_is_capped() {
+        let policy = RetryPolicy {
+            max_total_wait: Duration::from_millis(250),
+            ..policy(10)
+        };
+        let outcomes = (0..10).map(|i| retryable(&i.to_string())).collect();
+        let (result, attempts, sleeps) = run_outcomes(policy, outcomes).await;
+        // 100ms, then the 200ms backoff cut to the 150ms left, then no time left
+        assert_eq!(
+            sleeps,
+            [Duration::from_millis(100), Duration::from_millis(150)]
+        );
+        assert_eq!(sleeps.iter().sum::<Duration>(), policy.max_total_wait);
+        assert_eq!(attempts, 3);
+        assert!(matches!(result, Err(Error::Api(msg)) if msg == "2"));
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_retryable_status(StatusCode::from_u16(529).unwrap()));
+        assert!(!is_retryable_status(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable_status(StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,104 @@
+//! Spacing successive API requests by a minimum interval
+//!
+//! A courtesy throttle for loops such as `autopr`'s feedback rounds or a
+//! script calling the tools back to back, set with
+//! `AUTOCOMMIT_MIN_INTERVAL_MS`. Unlike `RetryPolicy`, it waits before every
+//! request, failed or not.
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keeps requests at least `min_interval` apart
+#[derive(Debug)]
+pub struct Throttle {
+    min_interval: Duration,
+    /// When the last request was allowed to start
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    /// A throttle that never waits when `min_interval` is zero
+    pub fn new(min_interval: Duration) -> Self {
+        Throttle {
+            min_interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Wait until the next request may start
+    pub async fn wait(&self) {
+        self.wait_with(Instant::now(), tokio::time::sleep).await
+    }
+
+    /// `wait` with the clock and the sleep swapped out, for tests
+    async fn wait_with<S, SleepFut>(&self, now: Instant, sleep: S)
+    where
+        S: FnOnce(Duration) -> SleepFut,
+        SleepFut: Future<Output = ()>,
+    {
+        let delay = self.reserve(now);
+        if !delay.is_zero() {
+            tracing::debug!(?delay, "spacing out API requests");
+            sleep(delay).await;
+        }
+    }
+
+    /// How long a request asked for at `now` has to wait, recording when it
+    /// will start so the next one is spaced from then
+    fn reserve(&self, now: Instant) -> Duration {
+        if self.min_interval.is_zero() {
+            return Duration::ZERO;
+        }
+        let mut last = self.last.lock().expect("throttle lock poisoned");
+        let delay = match *last {
+            Some(previous) => (previous + self.min_interval).saturating_duration_since(now),
+            None => Duration::ZERO,
+        };
+        *last = Some(now + delay);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[tokio::test]
+    async fn test_back_to_back_calls_are_spaced() {
+        let throttle = Throttle::new(Duration::from_millis(500));
+        let start = Instant::now();
+        let sleeps = RefCell::new(Vec::new());
+        let record = |delay| {
+            sleeps.borrow_mut().push(delay);
+            async {}
+        };
+
+        throttle.wait_with(start, record).await;
+        throttle
+            .wait_with(start + Duration::from_millis(100), record)
+            .await;
+        assert_eq!(*sleeps.borrow(), [Duration::from_millis(400)]);
+
+        // A third call right away queues behind the second one
+        throttle
+            .wait_with(start + Duration::from_millis(100), record)
+            .await;
+        assert_eq!(sleeps.borrow()[1], Duration::from_millis(900));
+
+        // Once the interval has passed there's nothing to wait for
+        throttle
+            .wait_with(start + Duration::from_secs(5), record)
+            .await;
+        assert_eq!(sleeps.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_zero_interval_never_waits() {
+        let throttle = Throttle::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(throttle.reserve(now), Duration::ZERO);
+        assert_eq!(throttle.reserve(now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,251 @@
+//! Setup checks for the `doctor` subcommands
+//!
+//! Each check turns the result of one probe into a pass, warning or
+//! failure with a line of detail, so new users see every missing piece at
+//! once instead of one confusing error at a time.
+
+use crate::anthropic::AnthropicClient;
+use crate::config::{Config, Forge};
+use crate::errors::Result;
+use crate::git::GitRepo;
+use std::fmt;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Something is off, but the tool still works
+    Warn,
+    /// The tool can't work until this is fixed
+    Fail,
+}
+
+/// A named check and what it found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found, or what went wrong and how to fix it
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    /// Prints as a checklist line, e.g. `[ok]   git: git version 2.43.0`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.status {
+            CheckStatus::Pass => "[ok]  ",
+            CheckStatus::Warn => "[warn]",
+            CheckStatus::Fail => "[FAIL]",
+        };
+        write!(f, "{} {}: {}", label, self.name, self.detail)
+    }
+}
+
+/// Check that git runs, from the output of `git --version`
+fn git_check(version: Result<String>) -> Check {
+    match version {
+        Ok(version) => Check::new("git", CheckStatus::Pass, version.trim()),
+        Err(e) => Check::new("git", CheckStatus::Fail, e.to_string()),
+    }
+}
+
+/// Check that the working directory is inside a repository
+fn repo_check(inside: Result<bool>) -> Check {
+    match inside {
+        Ok(true) => Check::new("repository", CheckStatus::Pass, "inside a git work tree"),
+        Ok(false) => Check::new(
+            "repository",
+            CheckStatus::Fail,
+            "not inside a git repository; cd into one or run 'git init'",
+        ),
+        Err(e) => Check::new("repository", CheckStatus::Fail, e.to_string()),
+    }
+}
+
+/// Check that the forge CLI is installed and logged in
+///
+/// autocommit never calls the forge, so a problem there is only a warning
+/// unless `required`.
+fn forge_check(forge: Forge, auth: Result<()>, required: bool) -> Check {
+    let name = match forge {
+        Forge::GitHub => "gh",
+        Forge::GitLab => "glab",
+    };
+    match auth {
+        Ok(()) => Check::new(name, CheckStatus::Pass, "installed and logged in"),
+        Err(e) if required => Check::new(name, CheckStatus::Fail, e.to_string()),
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Warn,
+            format!("{} (only needed by autopr)", e),
+        ),
+    }
+}
+
+/// Check that the configuration loads, which needs an API key
+fn config_check(config: &Result<Config>) -> Check {
+    match config {
+        Ok(config) => match config.api_key_warning() {
+            Some(warning) => Check::new(
+                "configuration",
+                CheckStatus::Warn,
+                warning.trim_start_matches("Warning: "),
+            ),
+            None => Check::new(
+                "configuration",
+                CheckStatus::Pass,
+                format!("API key set, model {}", config.model),
+            ),
+        },
+        Err(e) => Check::new("configuration", CheckStatus::Fail, e.to_string()),
+    }
+}
+
+/// Check that the API answered the ping, `None` when it couldn't be sent
+fn api_check(ping: Option<Result<()>>) -> Check {
+    match ping {
+        Some(Ok(())) => Check::new("API", CheckStatus::Pass, "reachable, key accepted"),
+        Some(Err(e)) => Check::new("API", CheckStatus::Fail, e.to_string()),
+        None => Check::new(
+            "API",
+            CheckStatus::Fail,
+            "not checked, fix the configuration first",
+        ),
+    }
+}
+
+/// Run every check against `repo`, pinging the API with `config` if it loaded
+///
+/// `forge_required` makes a missing or logged-out forge CLI a failure, as
+/// autopr needs it.
+pub async fn run_checks(
+    repo: &GitRepo,
+    config: Result<Config>,
+    forge_required: bool,
+) -> Vec<Check> {
+    let forge = config
+        .as_ref()
+        .map(|config| config.forge)
+        .unwrap_or_default();
+    let (version, inside, auth) = tokio::join!(
+        repo.git_version(),
+        repo.is_git_repo(),
+        repo.check_gh_auth(forge)
+    );
+    let mut checks = vec![
+        git_check(version),
+        repo_check(inside),
+        forge_check(forge, auth, forge_required),
+        config_check(&config),
+    ];
+    let ping = match config {
+        Ok(config) => match AnthropicClient::new(config) {
+            Ok(client) => Some(client.ping().await),
+            Err(e) => Some(Err(e)),
+        },
+        Err(_) => None,
+    };
+    checks.push(api_check(ping));
+    checks
+}
+
+/// Count the checks that failed
+pub fn failures(checks: &[Check]) -> usize {
+    checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config> {
+        Config::from_lookup(|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn test_git_check() {
+        let check = git_check(Ok("git version 2.43.0\n".to_string()));
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert_eq!(check.to_string(), "[ok]   git: git version 2.43.0");
+
+        let check = git_check(Err(Error::User("git not found.".to_string())));
+        assert_eq!(check.to_string(), "[FAIL] git: git not found.");
+    }
+
+    #[test]
+    fn test_repo_check() {
+        assert_eq!(repo_check(Ok(true)).status, CheckStatus::Pass);
+        let outside = repo_check(Ok(false));
+        assert_eq!(outside.status, CheckStatus::Fail);
+        assert!(outside.detail.contains("git init"));
+    }
+
+    #[test]
+    fn test_forge_check() {
+        assert_eq!(
+            forge_check(Forge::GitHub, Ok(()), true).to_string(),
+            "[ok]   gh: installed and logged in"
+        );
+        let logged_out = || Err(Error::User("glab is not logged in.".to_string()));
+        let check = forge_check(Forge::GitLab, logged_out(), true);
+        assert_eq!(check.to_string(), "[FAIL] glab: glab is not logged in.");
+        let check = forge_check(Forge::GitLab, logged_out(), false);
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(check.detail.ends_with("(only needed by autopr)"));
+    }
+
+    #[test]
+    fn test_config_check() {
+        let check = config_check(&config(&[("ANTHROPIC_API_KEY", "sk-ant-test")]));
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(check.detail.starts_with("API key set, model "));
+
+        let check = config_check(&config(&[("ANTHROPIC_API_KEY", "not-a-key")]));
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(check
+            .detail
+            .starts_with("ANTHROPIC_API_KEY doesn't start with"));
+
+        let check = config_check(&config(&[]));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("ANTHROPIC_API_KEY"));
+    }
+
+    #[test]
+    fn test_api_check() {
+        assert_eq!(api_check(Some(Ok(()))).status, CheckStatus::Pass);
+        let check = api_check(Some(Err(Error::Api("authentication_error".to_string()))));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("authentication_error"));
+        assert_eq!(api_check(None).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_failures() {
+        let checks = vec![
+            git_check(Ok("git version 2.43.0".to_string())),
+            forge_check(Forge::GitHub, Err(Error::User("gh".to_string())), false),
+            api_check(None),
+        ];
+        assert_eq!(failures(&checks), 1);
+        assert_eq!(failures(&checks[..2]), 0);
+    }
+}
@@ -1,3 +1,4 @@
+use serde_json::{json, Map, Value};
 use std::process;
 use thiserror::Error;
 
@@ -31,13 +32,238 @@ pub enum Error {
     /// Environment variable errors
     #[error("Environment variable error: {0}")]
     Env(String),
+
+    /// Interrupted with Ctrl-C
+    #[error("Cancelled.")]
+    Cancelled,
+}
+
+impl Error {
+    /// Error for a failed prompt reading `what` from the terminal, where an
+    /// interrupted read (Ctrl-C at the prompt) is [`Error::Cancelled`]
+    pub fn prompt(what: &str, err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            Error::Cancelled
+        } else {
+            Error::User(format!("Failed to read {}: {}", what, err))
+        }
+    }
+
+    /// Process exit code for this error's category
+    ///
+    /// These codes are a stable contract for scripts and CI:
+    ///
+    /// | Code | Category                         |
+    /// |------|----------------------------------|
+    /// | 2    | `User` (nothing staged, etc.)    |
+    /// | 3    | `Git`                            |
+    /// | 4    | `Api`                            |
+    /// | 5    | `Http`                           |
+    /// | 6    | `Env` (missing or invalid config)|
+    /// | 7    | `Io` and `Json`                  |
+    /// | 130  | `Cancelled` (Ctrl-C, as a shell reports SIGINT) |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::User(_) => 2,
+            Error::Git { .. } => 3,
+            Error::Api(_) => 4,
+            Error::Http(_) => 5,
+            Error::Env(_) => 6,
+            Error::Io(_) | Error::Json(_) => 7,
+            Error::Cancelled => 130,
+        }
+    }
+
+    /// Name of the variant, the `kind` of `--error-format json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::User(_) => "User",
+            Error::Git { .. } => "Git",
+            Error::Api(_) => "Api",
+            Error::Io(_) => "Io",
+            Error::Json(_) => "Json",
+            Error::Http(_) => "Http",
+            Error::Env(_) => "Env",
+            Error::Cancelled => "Cancelled",
+        }
+    }
+
+    /// The error as `{"error": {"kind", "message", ...}}`, with the fields
+    /// of its variant after the message
+    pub fn to_json(&self) -> Value {
+        let mut error = Map::new();
+        error.insert("kind".to_string(), json!(self.kind()));
+        error.insert("message".to_string(), json!(self.to_string()));
+        match self {
+            Error::Git { command, stderr } => {
+                error.insert("command".to_string(), json!(command));
+                error.insert("stderr".to_string(), json!(stderr));
+            }
+            Error::Io(e) => {
+                error.insert("io_kind".to_string(), json!(format!("{:?}", e.kind())));
+            }
+            Error::Json(e) => {
+                error.insert("line".to_string(), json!(e.line()));
+                error.insert("column".to_string(), json!(e.column()));
+            }
+            Error::Http(e) => {
+                if let Some(status) = e.status() {
+                    error.insert("status".to_string(), json!(status.as_u16()));
+                }
+                if let Some(url) = e.url() {
+                    error.insert("url".to_string(), json!(url.as_str()));
+                }
+            }
+            Error::User(_) | Error::Api(_) | Error::Env(_) | Error::Cancelled => {}
+        }
+        json!({ "error": error })
+    }
+}
+
+/// How `exit_with_error` prints the error, from `--error-format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum ErrorFormat {
+    /// `Error: <message>`
+    #[default]
+    Text,
+    /// One line of `{"error": {"kind", "message", ...}}` JSON, for tools
+    /// wrapping the CLIs
+    Json,
 }
 
-/// Helper function to print error and exit with code 1
-pub fn exit_with_error(error: Error) -> ! {
-    eprintln!("Error: {}", error);
-    process::exit(1);
+/// Print the error in `format` and exit with its category's code
+pub fn exit_with_error(error: Error, format: ErrorFormat) -> ! {
+    match format {
+        ErrorFormat::Text if matches!(error, Error::Cancelled) => eprintln!("\n{}", error),
+        ErrorFormat::Text => eprintln!("Error: {}", error),
+        ErrorFormat::Json => eprintln!("{}", error.to_json()),
+    }
+    process::exit(error.exit_code());
 }
 
 /// Result type alias for autocommit operations
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let http = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+
+        let cases = [
+            (Error::User("nothing staged".to_string()), 2),
+            (
+                Error::Git {
+                    command: "git status".to_string(),
+                    stderr: String::new(),
+                },
+                3,
+            ),
+            (Error::Api("overloaded".to_string()), 4),
+            (Error::Http(http), 5),
+            (Error::Env("missing key".to_string()), 6),
+            (Error::Io(std::io::Error::other("disk full")), 7),
+            (Error::Json(json), 7),
+            (Error::Cancelled, 130),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let git = Error::Git {
+            command: "git status".to_string(),
+            stderr: "fatal: not a git repository".to_string(),
+        };
+        assert_eq!(
+            git.to_json(),
+            json!({"error": {
+                "kind": "Git",
+                "message": "Git command failed: git status\nfatal: not a git repository",
+                "command": "git status",
+                "stderr": "fatal: not a git repository",
+            }})
+        );
+
+        for (error, kind, message) in [
+            (
+                Error::User("nothing staged".to_string()),
+                "User",
+                "nothing staged",
+            ),
+            (
+                Error::Api("overloaded".to_string()),
+                "Api",
+                "Anthropic API error: overloaded",
+            ),
+            (
+                Error::Env("missing key".to_string()),
+                "Env",
+                "Environment variable error: missing key",
+            ),
+        ] {
+            assert_eq!(
+                error.to_json(),
+                json!({"error": {"kind": kind, "message": message}})
+            );
+        }
+
+        let io = Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no such file",
+        ));
+        assert_eq!(
+            io.to_json(),
+            json!({"error": {
+                "kind": "Io",
+                "message": "I/O error: no such file",
+                "io_kind": "NotFound",
+            }})
+        );
+
+        let json_error = Error::Json(serde_json::from_str::<Value>("{\n  x").unwrap_err());
+        let value = json_error.to_json();
+        assert_eq!(value["error"]["kind"], "Json");
+        assert_eq!(value["error"]["line"], 2);
+        assert_eq!(value["error"]["column"], 3);
+        assert!(value["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("JSON parsing error: "));
+
+        let http = Error::Http(reqwest::Client::new().get("not a url").build().unwrap_err());
+        let value = http.to_json();
+        assert_eq!(value["error"]["kind"], "Http");
+        assert!(value["error"].get("status").is_none());
+    }
+
+    #[test]
+    fn test_prompt_error() {
+        let interrupted = std::io::Error::from(std::io::ErrorKind::Interrupted);
+        assert!(matches!(
+            Error::prompt("input", interrupted),
+            Error::Cancelled
+        ));
+
+        let closed = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stdin closed");
+        assert_eq!(
+            Error::prompt("selection", closed).to_string(),
+            "Failed to read selection: stdin closed"
+        );
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_error_format_values() {
+        use clap::ValueEnum;
+        assert_eq!(ErrorFormat::from_str("text", false), Ok(ErrorFormat::Text));
+        assert_eq!(ErrorFormat::from_str("json", false), Ok(ErrorFormat::Json));
+        assert!(ErrorFormat::from_str("yaml", false).is_err());
+    }
+}
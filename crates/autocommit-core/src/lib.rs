@@ -10,14 +10,20 @@
 //! - `utils`: Utility functions for file filtering and diff truncation
 //! - `git`: Git and GitHub CLI subprocess operations
 //! - `anthropic`: Anthropic API client
+//! - `suggest`: Commit message and PR generation without side effects
+//! - `doctor`: Setup checks for the `doctor` subcommands
 
 pub mod anthropic;
 pub mod config;
+pub mod doctor;
 pub mod errors;
 pub mod git;
+pub mod suggest;
 pub mod utils;
 
 // Re-export commonly used types
-pub use anthropic::{AnthropicClient, PRContent};
+pub use anthropic::{AnthropicClient, LlmClient, PRContent};
 pub use config::Config;
-pub use errors::{exit_with_error, Error, Result};
+pub use errors::{exit_with_error, Error, ErrorFormat, Result};
+pub use git::GitRepo;
+pub use suggest::{suggest_commit_message, suggest_pr_content, CommitOpts, PrOpts};
@@ -0,0 +1,937 @@
+//! High-level entry points for embedding autocommit-core
+//!
+//! These read from git and call the API, but never print, stage, commit or
+//! push: the binaries keep those side effects, and any prompting, for
+//! themselves.
+
+use crate::anthropic::{
+    model_is_listed, AnthropicClient, CommitPromptOptions, LlmClient, PRContent,
+};
+use crate::config::Config;
+use crate::errors::{Error, Result};
+use crate::git::{self, DiffSource, GitRepo};
+use crate::utils;
+use std::path::PathBuf;
+
+/// Options for `suggest_commit_message`
+#[derive(Debug, Clone, Default)]
+pub struct CommitOpts {
+    /// Prompt options, used as given
+    pub prompt: CommitPromptOptions,
+    /// Describe the last commit plus the staged changes, as `--amend` would
+    pub amend: bool,
+    /// Only consider staged files with these extensions
+    pub only_ext: Vec<String>,
+    /// Summarize changes to generated files in one line each
+    pub collapse_generated: bool,
+    /// Send the diff even if it seems to contain secrets
+    pub allow_secrets: bool,
+    /// Keep lock files in the diff
+    pub include_lockfiles: bool,
+}
+
+/// Options for `suggest_pr_content`
+#[derive(Debug, Clone, Default)]
+pub struct PrOpts {
+    /// Branch to compare against, the remote's default branch when unset
+    pub base: Option<String>,
+    /// PR template to follow instead of the detected one
+    pub template: Option<PathBuf>,
+    /// Ignore PR templates
+    pub no_template: bool,
+    /// Only describe changes to paths matching these globs
+    pub paths: Vec<String>,
+    /// Keep lock files in the diff and the changed files
+    pub include_lockfiles: bool,
+    /// Files to send along as reference material
+    pub context_files: Vec<PathBuf>,
+}
+
+/// A diff ready to be sent to the model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedDiff {
+    pub diff: String,
+    /// Whether the diff was cut to fit `max_diff_tokens`
+    pub truncated: bool,
+    /// Estimated tokens before truncation
+    pub original_tokens: usize,
+}
+
+/// Refuse oversized diffs, then collapse generated files if asked to and
+/// truncate to the configured token budget
+pub fn prepare_diff(
+    raw_diff: &str,
+    config: &Config,
+    collapse_generated: bool,
+) -> Result<PreparedDiff> {
+    utils::check_diff_size(raw_diff, config.max_diff_hard_limit)?;
+    let diff = if collapse_generated {
+        utils::collapse_generated(raw_diff, &utils::GeneratedOpts::default())
+    } else {
+        raw_diff.to_string()
+    };
+    let original_tokens = utils::estimate_tokens(&diff);
+    let (diff, truncated) = utils::truncate_diff_by_tokens(&diff, config.max_diff_tokens as usize);
+    Ok(PreparedDiff {
+        diff,
+        truncated,
+        original_tokens,
+    })
+}
+
+/// Generate a commit message for the staged changes in `repo`
+///
+/// The message is wrapped and gets the branch's issue reference, as
+/// `autocommit` would commit it.
+pub async fn suggest_commit_message(
+    repo: &GitRepo,
+    config: &Config,
+    opts: CommitOpts,
+) -> Result<String> {
+    let client = AnthropicClient::new(config.clone())?;
+    suggest_commit_message_with(&client, repo, config, opts).await
+}
+
+/// `suggest_commit_message` with another `LlmClient`
+pub async fn suggest_commit_message_with(
+    client: &impl LlmClient,
+    repo: &GitRepo,
+    config: &Config,
+    opts: CommitOpts,
+) -> Result<String> {
+    // Pathspecs are relative to the repository path, so start from the top
+    let repo = &GitRepo::new(repo.repo_root().await?);
+    let pathspecs = utils::build_pathspecs(
+        &utils::extension_pathspecs(&opts.only_ext)?,
+        utils::Exclusions::new(&config.exclude).include_lock_files(opts.include_lockfiles),
+    );
+    let raw_diff = if opts.amend {
+        repo.get_head_diff(&pathspecs, config.diff_context).await?
+    } else {
+        repo.get_staged_diff(&pathspecs, config.diff_context)
+            .await?
+    };
+    let ignore = utils::load_ignore_patterns(&repo.path).await?;
+    let raw_diff = ignore.filter_diff(&raw_diff.text);
+    if raw_diff.trim().is_empty() && !opts.amend {
+        let staged = repo
+            .get_staged_files(
+                &utils::extension_pathspecs(&opts.only_ext)?,
+                utils::Exclusions::default().include_lock_files(true),
+            )
+            .await?;
+        if !staged.is_empty() {
+            return Err(nothing_staged_error(&staged));
+        }
+    }
+    if raw_diff.trim().is_empty() {
+        return Err(Error::User(
+            "No diff content found in staged changes.".to_string(),
+        ));
+    }
+
+    let conflicted = utils::conflict_marker_files(&raw_diff);
+    if !conflicted.is_empty() {
+        return Err(Error::User(format!(
+            "Unresolved merge conflict markers detected in {}",
+            conflicted.join(", ")
+        )));
+    }
+    if !opts.allow_secrets {
+        utils::check_secrets(&raw_diff)?;
+    }
+
+    let prepared = prepare_diff(&raw_diff, config, opts.collapse_generated)?;
+    let mut prompt = opts.prompt;
+    if !opts.amend {
+        prompt.stats = ignore.filter_stats(repo.get_numstat(DiffSource::Staged, &pathspecs).await?);
+    }
+    let message = client
+        .generate_commit_message(&prepared.diff, &prompt)
+        .await?;
+    let message = utils::wrap_commit_message(&message, utils::BODY_WRAP_WIDTH);
+
+    let issue_ref = match &config.issue_pattern {
+        Some(pattern) => utils::extract_issue_ref(&repo.get_current_branch().await?, pattern),
+        None => None,
+    };
+    Ok(match issue_ref {
+        Some(issue) => utils::append_issue_ref(&message, &issue),
+        None => message,
+    })
+}
+
+/// The error for a commit with nothing staged to describe
+///
+/// `excluded` are the staged files that lock-file exclusion,
+/// `AUTOCOMMIT_EXCLUDE` or `.autocommitignore` left out. When there are any,
+/// they are the reason, and the error says how to keep them.
+pub fn nothing_staged_error(excluded: &[String]) -> Error {
+    let lock_files = excluded
+        .iter()
+        .filter(|file| utils::Exclusions::default().matches(file))
+        .count();
+    let message = if excluded.is_empty() {
+        "No staged changes found. Stage your changes with 'git add' first.".to_string()
+    } else if lock_files == excluded.len() {
+        format!(
+            "Only lock files are staged ({}); pass --include-lockfiles to commit them.",
+            excluded.join(", ")
+        )
+    } else {
+        let mut message = format!(
+            "Only excluded files are staged ({}), left out by AUTOCOMMIT_EXCLUDE or {}.",
+            excluded.join(", "),
+            utils::IGNORE_FILE
+        );
+        if lock_files > 0 {
+            message.push_str(" Pass --include-lockfiles to keep lock files.");
+        }
+        message
+    };
+    Error::User(message)
+}
+
+/// The warning for a diff's `non_utf8_files`, if it has any
+///
+/// Meant for `StatusOutput`, once per diff, since the core library itself
+/// only logs it.
+pub fn non_utf8_warning(files: &[String]) -> Option<String> {
+    (!files.is_empty()).then(|| {
+        format!(
+            "Warning: {} is not valid UTF-8; its invalid bytes were replaced with \u{FFFD}",
+            files.join(", ")
+        )
+    })
+}
+
+/// The error for a branch with nothing to describe, naming the `--path`
+/// globs when they are what left nothing
+pub fn no_changes_error(paths: &[String]) -> Error {
+    if paths.is_empty() {
+        Error::User("No changes found compared to base branch.".to_string())
+    } else {
+        Error::User(format!(
+            "No changes compared to base branch match --path {}.",
+            paths.join(", ")
+        ))
+    }
+}
+
+/// Generate a PR title and description for the current branch of `repo`
+pub async fn suggest_pr_content(
+    repo: &GitRepo,
+    config: &Config,
+    opts: PrOpts,
+) -> Result<PRContent> {
+    let client = AnthropicClient::new(config.clone())?;
+    suggest_pr_content_with(&client, repo, config, opts).await
+}
+
+/// `suggest_pr_content` with another `LlmClient`
+pub async fn suggest_pr_content_with(
+    client: &impl LlmClient,
+    repo: &GitRepo,
+    config: &Config,
+    opts: PrOpts,
+) -> Result<PRContent> {
+    // Pathspecs are relative to the repository path, so start from the top
+    let repo = &GitRepo::new(repo.repo_root().await?);
+    let base = match opts.base {
+        Some(base) => base,
+        None => repo.get_default_branch(&config.remote).await?,
+    };
+    let includes = utils::path_pathspecs(&opts.paths)?;
+    let exclusions =
+        utils::Exclusions::new(&config.exclude).include_lock_files(opts.include_lockfiles);
+    let ignore = utils::load_ignore_patterns(&repo.path).await?;
+    let fallback_n = config.fallback_commits;
+    let commits = repo.get_commits(&base, &includes, fallback_n).await?.value;
+    let diff = repo
+        .get_diff(
+            &base,
+            &includes,
+            exclusions,
+            fallback_n,
+            config.diff_context,
+        )
+        .await?;
+    let diff = ignore.filter_diff(&diff.value.text);
+    let changed: Vec<_> = repo
+        .get_changed_files_with_status(&base, &includes, exclusions, fallback_n)
+        .await?
+        .value
+        .files
+        .into_iter()
+        .filter(|file| !ignore.is_ignored(&file.path))
+        .collect();
+    if changed.is_empty() {
+        return Err(no_changes_error(&opts.paths));
+    }
+    // Line counts only add context, so a failed comparison leaves them out
+    let stats = repo
+        .get_numstat(
+            DiffSource::Range(&git::base_range(&base)),
+            &utils::build_pathspecs(&includes, exclusions),
+        )
+        .await
+        .unwrap_or_default();
+    let changed_files = utils::annotate_file_stats(&changed, &stats);
+    let changed_paths: Vec<String> = changed.into_iter().map(|file| file.path).collect();
+    let template =
+        utils::load_pr_template(&repo.path, opts.template.as_deref(), opts.no_template).await?;
+    let reference =
+        utils::load_context_files(&opts.context_files, utils::MAX_CONTEXT_FILE_BYTES).await?;
+
+    let mut content = client
+        .generate_pr_content(
+            &commits,
+            &diff,
+            &changed_files,
+            template.as_deref(),
+            reference.as_deref(),
+            None,
+        )
+        .await?;
+    if utils::needs_screenshots_section(config.ui_screenshots, &changed_paths) {
+        content.body = utils::ensure_screenshots_section(&content.body);
+    }
+    Ok(content)
+}
+
+/// What a PR description is generated from
+#[derive(Debug, Clone, Copy)]
+pub struct PrInputs<'a> {
+    pub commits: &'a str,
+    pub diff: &'a str,
+    pub changed_files: &'a [String],
+    pub template: Option<&'a str>,
+    /// `--context-file` material, sent again with each answer
+    pub reference: Option<&'a str>,
+}
+
+/// How to answer the model's clarification questions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClarifyMode {
+    /// Keep the first answer without asking, for `--yes` and `--no-clarify`
+    Skip,
+    /// Keep the first answer because stdin isn't a terminal to ask on
+    NonInteractive,
+    /// Prompt the user
+    Ask,
+}
+
+/// Decide whether clarification questions can be put to the user
+pub fn clarify_mode(yes: bool, stdin_is_terminal: bool) -> ClarifyMode {
+    if yes {
+        ClarifyMode::Skip
+    } else if !stdin_is_terminal {
+        ClarifyMode::NonInteractive
+    } else {
+        ClarifyMode::Ask
+    }
+}
+
+/// Ask the user for context the diff doesn't convey, for `--ask-context`
+///
+/// `prompt` is only called when `interactive`; a blank answer is no context.
+pub fn ask_context(
+    interactive: bool,
+    prompt: impl FnOnce() -> Result<String>,
+) -> Result<Option<String>> {
+    if !interactive {
+        return Ok(None);
+    }
+    let answer = prompt()?;
+    let answer = answer.trim();
+    Ok((!answer.is_empty()).then(|| answer.to_string()))
+}
+
+/// Add `extra` context after any the user already gave
+pub fn add_context(context: Option<String>, extra: Option<String>) -> Option<String> {
+    match (context, extra) {
+        (Some(context), Some(extra)) => Some(format!("{}\n\n{}", context, extra)),
+        (context, extra) => context.or(extra),
+    }
+}
+
+/// Whether the model may ask for clarification about a PR changing
+/// `changed_files` files, given `AUTOCOMMIT_CLARIFY_MIN_FILES`
+///
+/// Small PRs are usually clear enough that a question only costs a round trip.
+pub fn clarification_allowed(changed_files: usize, min_files: usize) -> bool {
+    changed_files >= min_files
+}
+
+/// Answer `question` with `prompt` in `ClarifyMode::Ask`, and with an empty
+/// answer, keeping the current content, otherwise
+pub fn answer_clarification(
+    mode: ClarifyMode,
+    question: &str,
+    prompt: impl FnOnce(&str) -> Result<String>,
+) -> Result<String> {
+    match mode {
+        ClarifyMode::Ask => prompt(question),
+        ClarifyMode::Skip | ClarifyMode::NonInteractive => Ok(String::new()),
+    }
+}
+
+/// Answer the model's clarification questions until it stops asking
+///
+/// `ask` gets each question and returns the answer; an empty answer keeps
+/// the current content as it is.
+pub async fn clarify_pr_content(
+    client: &impl LlmClient,
+    inputs: PrInputs<'_>,
+    mut content: PRContent,
+    mut ask: impl FnMut(&str) -> Result<String>,
+) -> Result<PRContent> {
+    while content.needs_clarification.unwrap_or(false) {
+        let Some(question) = content.clarification_question.clone() else {
+            break;
+        };
+        let answer = ask(&question)?;
+        if answer.is_empty() {
+            content.needs_clarification = Some(false);
+        } else {
+            let context = utils::combine_context(Some(&answer), inputs.reference);
+            content = client
+                .generate_pr_content(
+                    inputs.commits,
+                    inputs.diff,
+                    inputs.changed_files,
+                    inputs.template,
+                    context.as_deref(),
+                    None,
+                )
+                .await?;
+        }
+    }
+    Ok(content)
+}
+
+/// Check the configured model against the models the API lists, for
+/// `--check-model`
+///
+/// When it isn't listed, `pick` chooses a replacement among them if
+/// `interactive`, and it's an error naming them otherwise.
+pub async fn check_model(
+    config: &mut Config,
+    interactive: bool,
+    pick: impl FnOnce(&str, &[String]) -> Result<String>,
+) -> Result<()> {
+    let models = AnthropicClient::new(config.clone())?.list_models().await?;
+    if let Some(model) = replacement_model(&config.model, &models, interactive, pick)? {
+        config.override_model(model);
+    }
+    Ok(())
+}
+
+/// The model to use instead of `configured`, None when it is listed
+fn replacement_model(
+    configured: &str,
+    models: &[String],
+    interactive: bool,
+    pick: impl FnOnce(&str, &[String]) -> Result<String>,
+) -> Result<Option<String>> {
+    if model_is_listed(configured, models) {
+        return Ok(None);
+    }
+    if interactive && !models.is_empty() {
+        return pick(configured, models).map(Some);
+    }
+    Err(Error::User(format!(
+        "Model '{}' is not available from the API. Set --model or AUTOCOMMIT_MODEL to one of: {}",
+        configured,
+        models.join(", ")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::git_in;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Returns canned responses and records what it was asked
+    #[derive(Default)]
+    struct MockClient {
+        /// PR contents to return, in order, then a plain one
+        pr_responses: Mutex<VecDeque<PRContent>>,
+        diffs: Mutex<Vec<String>>,
+        stats: Mutex<Vec<String>>,
+        changed_files: Mutex<Vec<String>>,
+        contexts: Mutex<Vec<Option<String>>>,
+    }
+
+    impl LlmClient for MockClient {
+        async fn generate_commit_message(
+            &self,
+            diff: &str,
+            options: &CommitPromptOptions,
+        ) -> Result<String> {
+            self.diffs.lock().unwrap().push(diff.to_string());
+            *self.stats.lock().unwrap() = options.stats.iter().map(ToString::to_string).collect();
+            Ok("feat: add login".to_string())
+        }
+
+        async fn generate_pr_content(
+            &self,
+            _commits: &str,
+            diff: &str,
+            changed_files: &[String],
+            _template: Option<&str>,
+            additional_context: Option<&str>,
+            _existing_pr: Option<&PRContent>,
+        ) -> Result<PRContent> {
+            self.diffs.lock().unwrap().push(diff.to_string());
+            *self.changed_files.lock().unwrap() = changed_files.to_vec();
+            self.contexts
+                .lock()
+                .unwrap()
+                .push(additional_context.map(str::to_string));
+            Ok(self
+                .pr_responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| pr_content("Add login", None)))
+        }
+    }
+
+    fn pr_content(title: &str, question: Option<&str>) -> PRContent {
+        PRContent {
+            title: title.to_string(),
+            body: "Adds a login function.".to_string(),
+            needs_clarification: Some(question.is_some()),
+            clarification_question: question.map(str::to_string),
+            extra: Default::default(),
+        }
+    }
+
+    fn test_config(vars: &[(&str, &str)]) -> Config {
+        Config::from_lookup(|name| {
+            if name == "ANTHROPIC_API_KEY" {
+                return Some("sk-ant-test".to_string());
+            }
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+        .unwrap()
+    }
+
+    /// A repository with one commit on `main` and a `feature/PROJ-7-login` branch
+    fn repo_with_branch() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        git_in(dir.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(dir.path().join("README.md"), "# demo\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(dir.path(), &["commit", "-q", "-m", "chore: initial commit"]);
+        git_in(
+            dir.path(),
+            &["checkout", "-q", "-b", "feature/PROJ-7-login"],
+        );
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_suggest_commit_message() {
+        let dir = repo_with_branch();
+        std::fs::write(dir.path().join("login.rs"), "pub fn login() {}\n").unwrap();
+        std::fs::write(dir.path().join("Cargo.lock"), "# lock\n").unwrap();
+        std::fs::write(dir.path().join("login.snap"), "snapshot\n").unwrap();
+        std::fs::write(dir.path().join(utils::IGNORE_FILE), "*.snap\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+
+        let repo = GitRepo::new(dir.path());
+        let config = test_config(&[("AUTOCOMMIT_ISSUE_PATTERN", "[A-Z]+-[0-9]+")]);
+        let client = MockClient::default();
+        let message = suggest_commit_message_with(&client, &repo, &config, CommitOpts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(message, "feat: add login\n\nRefs: PROJ-7");
+        let diff = client.diffs.lock().unwrap()[0].clone();
+        assert!(diff.contains("+pub fn login() {}"));
+        assert!(!diff.contains("Cargo.lock"));
+        assert!(!diff.contains("login.snap"));
+        assert_eq!(
+            *client.stats.lock().unwrap(),
+            vec![".autocommitignore (+1 -0)", "login.rs (+1 -0)"]
+        );
+        // Nothing was committed
+        assert!(!repo
+            .get_staged_files(&[], utils::Exclusions::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_suggest_commit_message_nothing_staged() {
+        let dir = repo_with_branch();
+        let repo = GitRepo::new(dir.path());
+        let result = suggest_commit_message_with(
+            &MockClient::default(),
+            &repo,
+            &test_config(&[]),
+            CommitOpts::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::User(_))));
+    }
+
+    #[tokio::test]
+    async fn test_suggest_commit_message_from_subdirectory() {
+        let dir = repo_with_branch();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("login.rs"), "pub fn login() {}\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+
+        let repo = GitRepo::new(dir.path().join("src"));
+        let client = MockClient::default();
+        suggest_commit_message_with(&client, &repo, &test_config(&[]), CommitOpts::default())
+            .await
+            .unwrap();
+        assert!(client.diffs.lock().unwrap()[0].contains("+pub fn login() {}"));
+    }
+
+    #[tokio::test]
+    async fn test_suggest_commit_message_only_lock_files() {
+        let dir = repo_with_branch();
+        std::fs::write(dir.path().join("Cargo.lock"), "# lock\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+
+        let repo = GitRepo::new(dir.path());
+        let client = MockClient::default();
+        let config = test_config(&[]);
+        let result =
+            suggest_commit_message_with(&client, &repo, &config, CommitOpts::default()).await;
+        match result {
+            Err(Error::User(message)) => {
+                assert!(message.contains("Cargo.lock"), "{}", message);
+                assert!(message.contains("--include-lockfiles"), "{}", message);
+            }
+            other => panic!("expected a user error, got {:?}", other),
+        }
+        assert!(client.diffs.lock().unwrap().is_empty());
+
+        let opts = CommitOpts {
+            include_lockfiles: true,
+            ..Default::default()
+        };
+        suggest_commit_message_with(&client, &repo, &config, opts)
+            .await
+            .unwrap();
+        assert!(client.diffs.lock().unwrap()[0].contains("+# lock"));
+    }
+
+    #[test]
+    fn test_nothing_staged_error() {
+        let message = |excluded: &[&str]| {
+            let excluded: Vec<String> = excluded.iter().map(|s| s.to_string()).collect();
+            match nothing_staged_error(&excluded) {
+                Error::User(message) => message,
+                other => panic!("expected a user error, got {:?}", other),
+            }
+        };
+        assert!(message(&[]).starts_with("No staged changes found."));
+        assert_eq!(
+            message(&["Cargo.lock", "web/package-lock.json"]),
+            "Only lock files are staged (Cargo.lock, web/package-lock.json); pass --include-lockfiles to commit them."
+        );
+        let mixed = message(&["Cargo.lock", "dist/app.js"]);
+        assert!(mixed.contains("AUTOCOMMIT_EXCLUDE or .autocommitignore"));
+        assert!(mixed.ends_with("Pass --include-lockfiles to keep lock files."));
+        assert!(!message(&["dist/app.js"]).contains("--include-lockfiles"));
+    }
+
+    #[tokio::test]
+    async fn test_suggest_pr_content() {
+        let dir = repo_with_branch();
+        std::fs::write(dir.path().join("login.rs"), "pub fn login() {}\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(dir.path(), &["commit", "-q", "-m", "feat: add login"]);
+
+        let repo = GitRepo::new(dir.path());
+        let client = MockClient::default();
+        let opts = PrOpts {
+            base: Some("main".to_string()),
+            ..Default::default()
+        };
+        let content = suggest_pr_content_with(&client, &repo, &test_config(&[]), opts)
+            .await
+            .unwrap();
+
+        assert_eq!(content.title, "Add login");
+        assert_eq!(
+            *client.changed_files.lock().unwrap(),
+            vec!["login.rs (+1 -0)"]
+        );
+        assert!(client.diffs.lock().unwrap()[0].contains("+pub fn login() {}"));
+    }
+
+    #[tokio::test]
+    async fn test_suggest_pr_content_context_files() {
+        let dir = repo_with_branch();
+        std::fs::write(dir.path().join("login.rs"), "pub fn login() {}\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(dir.path(), &["commit", "-q", "-m", "feat: add login"]);
+        let notes = tempfile::tempdir().unwrap();
+        let design = notes.path().join("design.md");
+        std::fs::write(&design, "Sessions expire after an hour").unwrap();
+
+        let repo = GitRepo::new(dir.path());
+        let client = MockClient::default();
+        let opts = PrOpts {
+            base: Some("main".to_string()),
+            context_files: vec![design],
+            ..Default::default()
+        };
+        suggest_pr_content_with(&client, &repo, &test_config(&[]), opts)
+            .await
+            .unwrap();
+        let context = client.contexts.lock().unwrap()[0].clone().unwrap();
+        assert!(context.starts_with("Additional reference material:"));
+        assert!(context.contains("Sessions expire after an hour"));
+
+        let opts = PrOpts {
+            base: Some("main".to_string()),
+            context_files: vec![notes.path().join("missing.md")],
+            ..Default::default()
+        };
+        let result = suggest_pr_content_with(&client, &repo, &test_config(&[]), opts).await;
+        assert!(matches!(result, Err(Error::User(msg)) if msg.contains("missing.md")));
+    }
+
+    #[tokio::test]
+    async fn test_suggest_pr_content_paths() {
+        let dir = repo_with_branch();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/login.rs"), "pub fn login() {}\n").unwrap();
+        std::fs::write(dir.path().join("schema.json"), "{}\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(dir.path(), &["commit", "-q", "-m", "feat: add login"]);
+
+        let repo = GitRepo::new(dir.path());
+        let client = MockClient::default();
+        let opts = |paths: &[&str]| PrOpts {
+            base: Some("main".to_string()),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        suggest_pr_content_with(&client, &repo, &test_config(&[]), opts(&["src/**"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            *client.changed_files.lock().unwrap(),
+            vec!["src/login.rs (+1 -0)"]
+        );
+        assert!(!client.diffs.lock().unwrap()[0].contains("schema.json"));
+
+        let result =
+            suggest_pr_content_with(&client, &repo, &test_config(&[]), opts(&["docs/**"])).await;
+        assert!(matches!(result, Err(Error::User(message)) if message.contains("docs/**")));
+    }
+
+    #[test]
+    fn test_prepare_diff() {
+        let diff = format!(
+            "diff --git a/a.rs b/a.rs\n--- a/a.rs\n+++ b/a.rs\n{}",
+            "+line\n".repeat(20)
+        );
+        let config = test_config(&[("AUTOCOMMIT_MAX_TOKENS", "10")]);
+        let prepared = prepare_diff(&diff, &config, false).unwrap();
+        assert!(prepared.truncated);
+        assert_eq!(prepared.original_tokens, utils::estimate_tokens(&diff));
+
+        let prepared = prepare_diff(&diff, &test_config(&[]), false).unwrap();
+        assert!(!prepared.truncated);
+        assert_eq!(prepared.diff, diff);
+
+        let config = test_config(&[("AUTOCOMMIT_MAX_DIFF_HARD_LIMIT", "16")]);
+        assert!(matches!(
+            prepare_diff(&diff, &config, false),
+            Err(Error::User(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_clarify_pr_content() {
+        let client = MockClient::default();
+        client
+            .pr_responses
+            .lock()
+            .unwrap()
+            .extend([pr_content("Add login", Some("Anything else?"))]);
+        let inputs = PrInputs {
+            commits: "feat: add login",
+            diff: "+pub fn login() {}",
+            changed_files: &["login.rs".to_string()],
+            template: None,
+            reference: None,
+        };
+        let mut questions = Vec::new();
+        let mut answers = vec!["", "fixes #42"];
+        let content = clarify_pr_content(
+            &client,
+            inputs,
+            pr_content("Add auth", Some("Which issue does this fix?")),
+            |question| {
+                questions.push(question.to_string());
+                Ok(answers.pop().unwrap().to_string())
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(questions, ["Which issue does this fix?", "Anything else?"]);
+        assert_eq!(
+            *client.contexts.lock().unwrap(),
+            vec![Some("fixes #42".to_string())]
+        );
+        assert_eq!(content.title, "Add login");
+        assert_eq!(content.needs_clarification, Some(false));
+    }
+
+    #[test]
+    fn test_clarify_mode() {
+        assert_eq!(clarify_mode(true, true), ClarifyMode::Skip);
+        assert_eq!(clarify_mode(true, false), ClarifyMode::Skip);
+        assert_eq!(clarify_mode(false, false), ClarifyMode::NonInteractive);
+        assert_eq!(clarify_mode(false, true), ClarifyMode::Ask);
+    }
+
+    #[tokio::test]
+    async fn test_ask_context() {
+        let asked = |answer: &'static str| move || Ok(answer.to_string());
+        assert_eq!(ask_context(false, || panic!("not asked")).unwrap(), None);
+        assert_eq!(ask_context(true, asked("  ")).unwrap(), None);
+        let context = ask_context(true, asked(" behind the beta flag ")).unwrap();
+        assert_eq!(context.as_deref(), Some("behind the beta flag"));
+
+        let hint = add_context(Some("fixes #42".to_string()), context);
+        let options = CommitPromptOptions {
+            hint,
+            ..Default::default()
+        };
+        let prompt = AnthropicClient::new(test_config(&[]))
+            .unwrap()
+            .build_commit_prompt("+beta", &options)
+            .await
+            .unwrap();
+        assert!(prompt.messages[0]
+            .content
+            .contains("Additional context from the user: fixes #42\n\nbehind the beta flag"));
+        assert_eq!(add_context(None, None), None);
+        assert_eq!(
+            add_context(None, Some("x".to_string())).as_deref(),
+            Some("x")
+        );
+    }
+
+    #[test]
+    fn test_clarification_allowed() {
+        let config = test_config(&[("AUTOCOMMIT_CLARIFY_MIN_FILES", "3")]);
+        let files = vec!["src/a.rs".to_string(), "src/b.rs".to_string()];
+        let prompt_for = |files: &[String]| {
+            let allowed = clarification_allowed(files.len(), config.clarify_min_files);
+            AnthropicClient::new(config.clone())
+                .unwrap()
+                .with_no_clarify(!allowed)
+                .build_pr_prompt("feat: add login", "+login", files, None, None, None)
+                .messages[0]
+                .content
+                .clone()
+        };
+        let below = prompt_for(&files);
+        assert!(below.contains("Do not ask for clarification"));
+
+        let files = [files, vec!["src/c.rs".to_string()]].concat();
+        let above = prompt_for(&files);
+        assert!(!above.contains("Do not ask for clarification"));
+        assert!(above.contains("set needsClarification to true"));
+
+        assert!(clarification_allowed(0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_clarify_pr_content_non_interactive() {
+        let client = MockClient::default();
+        let inputs = PrInputs {
+            commits: "",
+            diff: "",
+            changed_files: &[],
+            template: None,
+            reference: None,
+        };
+        for mode in [ClarifyMode::Skip, ClarifyMode::NonInteractive] {
+            let content = clarify_pr_content(
+                &client,
+                inputs,
+                pr_content("Add login", Some("Which issue?")),
+                |question| {
+                    answer_clarification(mode, question, |_| panic!("prompted in {:?}", mode))
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(content.title, "Add login");
+            assert_eq!(content.needs_clarification, Some(false));
+        }
+        assert!(client.contexts.lock().unwrap().is_empty());
+
+        let answer = answer_clarification(ClarifyMode::Ask, "Which issue?", |question| {
+            Ok(format!("answered {}", question))
+        });
+        assert_eq!(answer.unwrap(), "answered Which issue?");
+    }
+
+    #[tokio::test]
+    async fn test_clarify_pr_content_not_needed() {
+        let client = MockClient::default();
+        let inputs = PrInputs {
+            commits: "",
+            diff: "",
+            changed_files: &[],
+            template: None,
+            reference: None,
+        };
+        let content = pr_content("Add login", None);
+        let clarified = clarify_pr_content(&client, inputs, content.clone(), |_| {
+            panic!("asked a question")
+        })
+        .await
+        .unwrap();
+        assert_eq!(clarified, content);
+        assert!(client.contexts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_replacement_model() {
+        let models = vec![
+            "claude-sonnet-4-20250514".to_string(),
+            "claude-3-5-haiku-20241022".to_string(),
+        ];
+        let never = |_: &str, _: &[String]| -> Result<String> { panic!("asked to pick") };
+        assert_eq!(
+            replacement_model("claude-sonnet-4-20250514", &models, true, never).unwrap(),
+            None
+        );
+
+        let picked = replacement_model("claude-sonet-4", &models, true, |configured, models| {
+            assert_eq!(configured, "claude-sonet-4");
+            Ok(models[1].clone())
+        });
+        assert_eq!(
+            picked.unwrap().as_deref(),
+            Some("claude-3-5-haiku-20241022")
+        );
+
+        let error = replacement_model("claude-sonet-4", &models, false, never).unwrap_err();
+        assert!(matches!(&error, Error::User(message)
+            if message.contains("'claude-sonet-4'") && message.contains("claude-3-5-haiku-20241022")));
+    }
+}
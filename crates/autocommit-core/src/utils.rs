@@ -1,5 +1,81 @@
-/// Maximum diff size before truncation (8000 characters)
-pub const MAX_DIFF_SIZE: usize = 8000;
+use crate::anthropic::PRContent;
+use crate::config::{ModelPrices, ScreenshotsMode, Trailer};
+use crate::errors::{Error, Result};
+use crate::git::{ChangedFile, FileStat, FileStatus};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Bytes per token assumed by `estimate_tokens`
+pub const BYTES_PER_TOKEN: usize = 4;
+
+/// Estimate how many tokens the model will count in `text`
+///
+/// A rough approximation of about four bytes per token rather than a real
+/// tokenizer: close for English and code, and on the high side for
+/// non-ASCII text, so budgets based on it err towards sending less.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(BYTES_PER_TOKEN)
+}
+
+/// Cost in USD of `tokens` at `price` USD per million tokens
+pub fn estimate_cost(tokens: usize, price: f64) -> f64 {
+    tokens as f64 * price / 1_000_000.0
+}
+
+/// Format a cost in USD to a hundredth of a cent, as `$0.0096`
+pub fn format_cost(usd: f64) -> String {
+    format!("${:.4}", usd)
+}
+
+/// What a request would cost, printed by `--dry-run --estimate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    pub input_tokens: usize,
+    /// The request's `max_tokens`, the most output it can be billed for
+    pub max_output_tokens: u32,
+    /// `None` when the model's prices aren't known
+    pub prices: Option<ModelPrices>,
+}
+
+impl CostEstimate {
+    /// Cost of the input alone, in USD
+    pub fn input_cost(&self) -> Option<f64> {
+        self.prices
+            .map(|prices| estimate_cost(self.input_tokens, prices.input))
+    }
+
+    /// Cost if the response uses all of `max_output_tokens`, in USD
+    pub fn max_cost(&self) -> Option<f64> {
+        self.prices.map(|prices| {
+            estimate_cost(self.input_tokens, prices.input)
+                + estimate_cost(self.max_output_tokens as usize, prices.output)
+        })
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "~{} input tokens", self.input_tokens)?;
+        match (self.input_cost(), self.max_cost()) {
+            (Some(input), Some(max)) => write!(
+                f,
+                ", est. {} (up to {} with {} output tokens)",
+                format_cost(input),
+                format_cost(max),
+                self.max_output_tokens
+            ),
+            _ => write!(
+                f,
+                ", no price known for this model (set AUTOCOMMIT_PRICE_INPUT and AUTOCOMMIT_PRICE_OUTPUT)"
+            ),
+        }
+    }
+}
 
 /// Lock files to exclude from diffs and file lists
 pub const EXCLUDED_LOCK_FILES: &[&str] = &[
@@ -21,30 +97,331 @@ pub const EXCLUDED_LOCK_FILES: &[&str] = &[
     "uv.lock",
 ];
 
-/// Generate git pathspec exclusions for lock files
+/// Generate git pathspec exclusions for lock files and extra patterns
+///
+/// Lock files become `:!filename`. Each extra pattern (from
+/// `AUTOCOMMIT_EXCLUDE`) becomes a glob exclusion; patterns without a `/`
+/// match file names at any depth, like the built-in list.
+pub fn get_lock_file_exclusions(extra: &[String]) -> Vec<String> {
+    Exclusions::new(extra).pathspecs()
+}
+
+/// Files left out of diffs and file lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exclusions<'a> {
+    /// File names or globs from `AUTOCOMMIT_EXCLUDE`
+    pub globs: &'a [String],
+    /// Also leave out `EXCLUDED_LOCK_FILES`
+    pub lock_files: bool,
+}
+
+impl<'a> Exclusions<'a> {
+    /// Lock files plus `globs`
+    pub fn new(globs: &'a [String]) -> Self {
+        Exclusions {
+            globs,
+            lock_files: true,
+        }
+    }
+
+    /// Keep lock files when `include` is set, as `--include-lockfiles` does
+    pub fn include_lock_files(self, include: bool) -> Self {
+        Exclusions {
+            lock_files: !include,
+            ..self
+        }
+    }
+
+    /// Git pathspecs excluding these files
+    ///
+    /// Lock files become `:!filename`. Each glob becomes a glob exclusion;
+    /// globs without a `/` match file names at any depth, like lock files.
+    pub fn pathspecs(&self) -> Vec<String> {
+        let lock_files = EXCLUDED_LOCK_FILES
+            .iter()
+            .filter(|_| self.lock_files)
+            .map(|file| format!(":!{}", file));
+        let globs = self.globs.iter().map(|pattern| {
+            if pattern.contains('/') {
+                format!(":(exclude,glob){}", pattern)
+            } else {
+                format!(":(exclude,glob)**/{}", pattern)
+            }
+        });
+        lock_files.chain(globs).collect()
+    }
+
+    /// Check whether `path` is left out
+    pub fn matches(&self, path: &str) -> bool {
+        let basename = path.rsplit('/').next().unwrap_or(path);
+        (self.lock_files && EXCLUDED_LOCK_FILES.contains(&basename))
+            || self
+                .globs
+                .iter()
+                .any(|pattern| matches_exclude(path, pattern))
+    }
+}
+
+impl Default for Exclusions<'_> {
+    /// Only the lock files
+    fn default() -> Self {
+        Exclusions::new(&[])
+    }
+}
+
+/// Build git pathspecs from include patterns plus `exclusions`
+///
+/// With no includes, the whole tree (`.`) is included. This is the single
+/// place diff, file-list and commit commands get their pathspecs from.
+pub fn build_pathspecs(includes: &[String], exclusions: Exclusions<'_>) -> Vec<String> {
+    let mut pathspecs = if includes.is_empty() {
+        vec![".".to_string()]
+    } else {
+        includes.to_vec()
+    };
+    pathspecs.extend(exclusions.pathspecs());
+    pathspecs
+}
+
+/// Convert file extensions into glob pathspecs matching them at any depth
 ///
-/// Returns a vector of strings in the format `:!filename` that can be
-/// passed to git commands to exclude lock files from diffs.
-pub fn get_lock_file_exclusions() -> Vec<String> {
-    EXCLUDED_LOCK_FILES
+/// Accepts `rs`, `.rs` or `*.rs`. Dotfiles are matched by their name, so
+/// `gitignore` matches `.gitignore` as well as `foo.gitignore`.
+pub fn extension_pathspecs(extensions: &[String]) -> Result<Vec<String>> {
+    extensions
         .iter()
-        .map(|file| format!(":!{}", file))
+        .map(|ext| {
+            let ext = ext.trim().trim_start_matches('*').trim_start_matches('.');
+            if ext.is_empty() || ext.contains(['/', '*', '?', '[']) {
+                return Err(Error::User(format!(
+                    "Invalid file extension '{}'. Use plain extensions like 'rs,toml'.",
+                    ext
+                )));
+            }
+            Ok(format!(":(glob)**/*.{}", ext))
+        })
         .collect()
 }
 
-/// Filter out lock files from a list of file paths
+/// Convert `--path` globs into pathspecs matching them from the repo root
 ///
-/// Removes any files whose basename matches one of the excluded lock files.
-pub fn filter_lock_files(files: Vec<String>) -> Vec<String> {
-    files
-        .into_iter()
-        .filter(|file| {
-            let basename = file.rsplit('/').next().unwrap_or(file);
-            !EXCLUDED_LOCK_FILES.contains(&basename)
+/// `src/**` matches everything under `src`, and `*.rs` only files at the root.
+pub fn path_pathspecs(globs: &[String]) -> Result<Vec<String>> {
+    globs
+        .iter()
+        .map(|glob| {
+            let glob = glob.trim().trim_start_matches("./");
+            if glob.is_empty() {
+                return Err(Error::User(
+                    "Empty --path. Use a glob like 'src/**'.".to_string(),
+                ));
+            }
+            Ok(format!(":(glob){}", glob))
         })
         .collect()
 }
 
+/// Check whether a path matches an exclusion glob
+///
+/// Patterns without a `/` are matched against the file name, others against
+/// the whole path, where `*` stays within a directory and `**` spans several.
+fn matches_exclude(path: &str, pattern: &str) -> bool {
+    let Ok(glob) = glob::Pattern::new(pattern) else {
+        return false;
+    };
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    if pattern.contains('/') {
+        glob.matches_with(path, options)
+    } else {
+        let basename = path.rsplit('/').next().unwrap_or(path);
+        glob.matches_with(basename, options)
+    }
+}
+
+/// Filter out lock files and extra excluded patterns from a list of file paths
+///
+/// Removes any files whose basename matches one of the excluded lock files,
+/// or that match one of the `extra` globs.
+pub fn filter_lock_files(files: Vec<String>, extra: &[String]) -> Vec<String> {
+    filter_excluded_files(files, Exclusions::new(extra)).0
+}
+
+/// Remove the files `exclusions` leaves out, also returning how many were
+/// removed
+pub fn filter_excluded_files(
+    files: Vec<String>,
+    exclusions: Exclusions<'_>,
+) -> (Vec<String>, usize) {
+    let total = files.len();
+    let kept: Vec<String> = files
+        .into_iter()
+        .filter(|file| !exclusions.matches(file))
+        .collect();
+    let removed = total - kept.len();
+    (kept, removed)
+}
+
+/// Check whether a path is a lock file or matches one of the `extra` globs
+pub fn is_excluded_file(path: &str, extra: &[String]) -> bool {
+    Exclusions::new(extra).matches(path)
+}
+
+/// Repository-local file listing more paths to leave out, in gitignore syntax
+pub const IGNORE_FILE: &str = ".autocommitignore";
+
+/// One pattern line of an ignore file
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    pattern: glob::Pattern,
+    /// `!pattern`, re-including what earlier patterns ignored
+    negated: bool,
+    /// `pattern/`, only matching directories
+    dir_only: bool,
+    /// Contains a `/` other than a trailing one, so matches from the root
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        if self.anchored {
+            self.pattern.matches_with(path, options)
+        } else {
+            let basename = path.rsplit('/').next().unwrap_or(path);
+            self.pattern.matches_with(basename, options)
+        }
+    }
+}
+
+/// Paths to leave out of diffs and file lists, as read from `IGNORE_FILE`
+///
+/// Follows gitignore rules: the last matching pattern wins, `!` re-includes
+/// a path, a trailing `/` only matches directories, and files inside an
+/// ignored directory can't be re-included.
+#[derive(Debug, Clone, Default)]
+pub struct IgnorePatterns {
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnorePatterns {
+    /// Parse the content of an ignore file
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, pattern),
+            };
+            let anchored = pattern.contains('/');
+            let pattern = pattern.trim_start_matches('/');
+            let pattern = glob::Pattern::new(pattern).map_err(|e| {
+                Error::User(format!(
+                    "Invalid pattern '{}' in {}: {}",
+                    line, IGNORE_FILE, e
+                ))
+            })?;
+            rules.push(IgnoreRule {
+                pattern,
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Whether there are no patterns at all
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the last pattern matching `path` ignores it
+    fn decide(&self, path: &str, is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| !rule.negated)
+    }
+
+    /// Check whether a file path, relative to the repository root, is ignored
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let in_ignored_dir = path
+            .match_indices('/')
+            .any(|(i, _)| self.decide(&path[..i], true) == Some(true));
+        in_ignored_dir || self.decide(path, false) == Some(true)
+    }
+
+    /// Remove ignored paths from a list of files
+    pub fn filter_files(&self, files: Vec<String>) -> Vec<String> {
+        files
+            .into_iter()
+            .filter(|file| !self.is_ignored(file))
+            .collect()
+    }
+
+    /// Remove the line counts of ignored files
+    pub fn filter_stats(&self, stats: Vec<FileStat>) -> Vec<FileStat> {
+        stats
+            .into_iter()
+            .filter(|stat| !self.is_ignored(&stat.path))
+            .collect()
+    }
+
+    /// Remove the sections of ignored files from a diff
+    pub fn filter_diff(&self, diff: &str) -> String {
+        if self.is_empty() {
+            return diff.to_string();
+        }
+        split_diff_files(diff)
+            .into_iter()
+            .filter(|file| !diff_file_path(file).is_some_and(|path| self.is_ignored(path)))
+            .collect()
+    }
+}
+
+/// Load `IGNORE_FILE` from the repository root
+///
+/// A missing file means no extra exclusions.
+pub async fn load_ignore_patterns(repo_root: &Path) -> Result<IgnorePatterns> {
+    match tokio::fs::read_to_string(repo_root.join(IGNORE_FILE)).await {
+        Ok(content) => IgnorePatterns::parse(&content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(IgnorePatterns::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find the largest char boundary at or below `index`
+///
+/// Slicing at the result never splits a multibyte UTF-8 character.
+pub fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    let mut boundary = index;
+    while !s.is_char_boundary(boundary) {
+        boundary -= 1;
+    }
+    boundary
+}
+
 /// Truncate a diff if it exceeds the maximum size
 ///
 /// Returns a tuple of (truncated_diff, was_truncated)
@@ -52,89 +429,4186 @@ pub fn truncate_diff(diff: &str, max_size: usize) -> (String, bool) {
     if diff.len() <= max_size {
         (diff.to_string(), false)
     } else {
+        let cut = floor_char_boundary(diff, max_size);
         let truncated = format!(
             "{}\n\n... (diff truncated, {} characters omitted)",
-            &diff[..max_size],
-            diff.len() - max_size
+            &diff[..cut],
+            diff.len() - cut
         );
         (truncated, true)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Split a diff into per-file chunks on `diff --git` boundaries
+///
+/// Anything before the first file header is kept as its own chunk.
+fn split_diff_files(diff: &str) -> Vec<&str> {
+    let mut starts: Vec<usize> = diff
+        .match_indices("\ndiff --git ")
+        .map(|(i, _)| i + 1)
+        .collect();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    starts.push(diff.len());
+    starts
+        .windows(2)
+        .map(|range| &diff[range[0]..range[1]])
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
 
-    #[test]
-    fn test_get_lock_file_exclusions() {
-        let exclusions = get_lock_file_exclusions();
-        assert!(!exclusions.is_empty());
-        assert!(exclusions.contains(&":!package-lock.json".to_string()));
-        assert!(exclusions.contains(&":!Cargo.lock".to_string()));
-        assert_eq!(exclusions.len(), EXCLUDED_LOCK_FILES.len());
+/// Refuse diffs larger than `hard_limit` bytes instead of truncating them
+///
+/// Diffs that large are usually a vendored directory or a build output
+/// staged by accident, and would be described from a small fraction of it.
+pub fn check_diff_size(diff: &str, hard_limit: Option<usize>) -> Result<()> {
+    match hard_limit {
+        Some(limit) if diff.len() > limit => Err(Error::User(format!(
+            "Diff too large ({}kb); split your commit or raise AUTOCOMMIT_MAX_DIFF_HARD_LIMIT",
+            diff.len().div_ceil(1024)
+        ))),
+        _ => Ok(()),
     }
+}
 
-    #[test]
-    fn test_filter_lock_files_removes_lock_files() {
-        let files = vec![
-            "src/main.rs".to_string(),
-            "package-lock.json".to_string(),
-            "Cargo.lock".to_string(),
-            "src/lib.rs".to_string(),
-            "yarn.lock".to_string(),
-        ];
+/// The first `max_lines` lines of `diff` for `--show-diff`, ending with how
+/// many lines were left out of the preview, if any
+pub fn diff_preview(diff: &str, max_lines: usize) -> String {
+    let total = diff.lines().count();
+    let mut preview: Vec<String> = diff.lines().take(max_lines).map(str::to_string).collect();
+    if total > max_lines {
+        preview.push(format!("... {} more lines not shown", total - max_lines));
+    }
+    preview.join("\n")
+}
 
-        let filtered = filter_lock_files(files);
-        assert_eq!(filtered, vec!["src/main.rs", "src/lib.rs"]);
+/// Truncate a diff to about `max_tokens` estimated tokens, sharing the
+/// budget across files like `truncate_diff_balanced`
+///
+/// Returns a tuple of (truncated_diff, was_truncated)
+pub fn truncate_diff_by_tokens(diff: &str, max_tokens: usize) -> (String, bool) {
+    truncate_diff_balanced(diff, max_tokens.saturating_mul(BYTES_PER_TOKEN))
+}
+
+/// Collapse binary file stanzas into a one-line note
+///
+/// Git prints `Binary files a/x and b/x differ` (or a `GIT binary patch`
+/// blob) for binary files, which tells the model nothing useful. The file
+/// header is kept so the change itself stays visible.
+pub fn strip_binary_diffs(diff: &str) -> String {
+    split_diff_files(diff)
+        .into_iter()
+        .map(|file| {
+            let marker = file.lines().position(|line| {
+                (line.starts_with("Binary files ") && line.ends_with(" differ"))
+                    || line == "GIT binary patch"
+            });
+            match marker {
+                Some(index) => {
+                    let mut collapsed: String = file
+                        .lines()
+                        .take(index)
+                        .map(|line| format!("{}\n", line))
+                        .collect();
+                    collapsed.push_str("(binary file changed)\n");
+                    collapsed
+                }
+                None => file.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Globs of files that are usually generated rather than written by hand
+pub const GENERATED_FILE_PATTERNS: &[&str] = &[
+    "*.min.js",
+    "*.min.css",
+    "*.map",
+    "*.snap",
+    "**/__snapshots__/**",
+    "dist/**",
+];
+
+/// Diff lines longer than this many characters suggest minified output
+pub const GENERATED_MAX_LINE_LENGTH: usize = 500;
+
+/// How `collapse_generated` recognizes generated files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedOpts {
+    /// File names or globs treated as generated, matched like `AUTOCOMMIT_EXCLUDE`
+    pub patterns: Vec<String>,
+    /// A changed line longer than this marks the file as generated
+    pub max_line_length: usize,
+}
+
+impl Default for GeneratedOpts {
+    fn default() -> Self {
+        GeneratedOpts {
+            patterns: GENERATED_FILE_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            max_line_length: GENERATED_MAX_LINE_LENGTH,
+        }
     }
+}
 
-    #[test]
-    fn test_filter_lock_files_with_paths() {
-        let files = vec![
-            "src/main.rs".to_string(),
-            "frontend/package-lock.json".to_string(),
-            "backend/Cargo.lock".to_string(),
-            "src/lib.rs".to_string(),
-        ];
+/// Path of the file a `diff --git a/x b/x` chunk changes
+fn diff_file_path(file: &str) -> Option<&str> {
+    let header = file.lines().next()?.strip_prefix("diff --git ")?;
+    header.rfind(" b/").map(|i| &header[i + 3..])
+}
 
-        let filtered = filter_lock_files(files);
-        assert_eq!(filtered, vec!["src/main.rs", "src/lib.rs"]);
+/// Collapse the diffs of generated files into a one-line summary
+///
+/// A file counts as generated when its path matches one of `opts.patterns`
+/// or one of its changed lines is longer than `opts.max_line_length`, as in
+/// minified bundles. Like binary files, its header is kept.
+pub fn collapse_generated(diff: &str, opts: &GeneratedOpts) -> String {
+    split_diff_files(diff)
+        .into_iter()
+        .map(|file| {
+            let Some(path) = diff_file_path(file) else {
+                return file.to_string();
+            };
+            let Some(hunks) = file.find("\n@@").map(|i| i + 1) else {
+                return file.to_string();
+            };
+            let (header, body) = file.split_at(hunks);
+            let changed = || {
+                body.lines().filter(|line| {
+                    (line.starts_with('+') || line.starts_with('-'))
+                        && !line.starts_with("+++")
+                        && !line.starts_with("---")
+                })
+            };
+
+            let generated = opts
+                .patterns
+                .iter()
+                .any(|pattern| matches_exclude(path, pattern))
+                || changed().any(|line| line.chars().count() > opts.max_line_length);
+            if !generated {
+                return file.to_string();
+            }
+
+            let added = changed().filter(|line| line.starts_with('+')).count();
+            let removed = changed().filter(|line| line.starts_with('-')).count();
+            format!(
+                "{}(generated file changed, {} lines added, {} removed)\n",
+                header, added, removed
+            )
+        })
+        .collect()
+}
+
+/// Split one file's diff into its header, everything before the first `@@`
+/// hunk, and its hunks
+fn split_file_header(file: &str) -> (&str, &str) {
+    let header_end = match file.find("\n@@") {
+        Some(i) => i + 1,
+        None => file.find('\n').map_or(file.len(), |i| i + 1),
+    };
+    file.split_at(header_end)
+}
+
+/// Length of the marker for omitting all of `body`, the longest it can be
+fn omission_marker_len(body: &str) -> usize {
+    format!("... ({} characters omitted)\n", body.len()).len()
+}
+
+/// Fewest bytes `truncate_file_diff` can cut a file's diff to: the whole
+/// diff when that small, else its header and omission marker
+fn file_diff_floor(file: &str) -> usize {
+    let (header, body) = split_file_header(file);
+    file.len().min(header.len() + omission_marker_len(body))
+}
+
+/// List the `diff --git` line of each file that fits in `budget` bytes,
+/// then how many files were left out
+fn list_diff_files(files: &[&str], budget: usize) -> String {
+    let more = |count: usize| format!("... ({} more files omitted)\n", count);
+    let mut listed = String::new();
+    for (n, file) in files.iter().enumerate() {
+        let line = file.lines().next().unwrap_or("");
+        let after = files.len() - n - 1;
+        let marker_len = if after > 0 { more(after).len() } else { 0 };
+        if listed.len() + line.len() + 1 + marker_len > budget {
+            let marker = more(after + 1);
+            if listed.len() + marker.len() <= budget {
+                listed.push_str(&marker);
+            }
+            break;
+        }
+        listed.push_str(line);
+        listed.push('\n');
     }
+    listed
+}
 
-    #[test]
-    fn test_filter_lock_files_preserves_non_lock_files() {
-        let files = vec![
-            "src/main.rs".to_string(),
-            "README.md".to_string(),
-            "config.json".to_string(),
-        ];
+/// Truncate one file's diff to `budget` bytes, keeping its header
+///
+/// The header (everything before the first `@@` hunk) is always kept, and
+/// the hunks are cut at a line boundary followed by an omission marker.
+fn truncate_file_diff(file: &str, budget: usize) -> String {
+    if file.len() <= budget {
+        return file.to_string();
+    }
 
-        let filtered = filter_lock_files(files.clone());
-        assert_eq!(filtered, files);
+    let (header, body) = split_file_header(file);
+    // Size the marker for the largest possible omission so the result fits
+    let marker_len = omission_marker_len(body);
+    let available = budget.saturating_sub(header.len() + marker_len);
+    let kept = body.as_bytes()[..available.min(body.len())]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+
+    format!(
+        "{}{}... ({} characters omitted)\n",
+        header,
+        &body[..kept],
+        body.len() - kept
+    )
+}
+
+/// Truncate a diff to `total_budget` bytes, sharing the budget across files
+///
+/// Every file keeps its header, then files smaller than their fair share
+/// of the rest are kept whole and the remainder is split evenly between the
+/// larger ones, so one huge file can't crowd out the others. When the
+/// budget can't even hold every header, only the `diff --git` lines that
+/// fit are listed.
+///
+/// Returns a tuple of (truncated_diff, was_truncated)
+pub fn truncate_diff_balanced(diff: &str, total_budget: usize) -> (String, bool) {
+    if diff.len() <= total_budget {
+        return (diff.to_string(), false);
     }
 
-    #[test]
-    fn test_truncate_diff_no_truncation() {
-        let diff = "This is a short diff";
-        let (truncated, was_truncated) = truncate_diff(diff, 100);
-        assert_eq!(truncated, diff);
-        assert!(!was_truncated);
+    let files = split_diff_files(diff);
+    let mut budgets: Vec<usize> = files.iter().map(|file| file_diff_floor(file)).collect();
+    let floors: usize = budgets.iter().sum();
+    if floors > total_budget {
+        return (list_diff_files(&files, total_budget), true);
     }
 
-    #[test]
-    fn test_truncate_diff_with_truncation() {
-        let diff = "a".repeat(1000);
-        let (truncated, was_truncated) = truncate_diff(&diff, 100);
-        assert!(was_truncated);
-        assert!(truncated.starts_with(&"a".repeat(100)));
-        assert!(truncated.contains("900 characters omitted"));
+    let extra: Vec<usize> = files
+        .iter()
+        .zip(&budgets)
+        .map(|(file, floor)| file.len() - floor)
+        .collect();
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by_key(|&i| extra[i]);
+
+    let mut remaining = total_budget - floors;
+    for (n, &i) in order.iter().enumerate() {
+        let share = remaining / (files.len() - n);
+        let granted = extra[i].min(share);
+        budgets[i] += granted;
+        remaining -= granted;
     }
 
-    #[test]
-    fn test_truncate_diff_exact_size() {
-        let diff = "a".repeat(100);
-        let (truncated, was_truncated) = truncate_diff(&diff, 100);
-        assert_eq!(truncated, diff);
-        assert!(!was_truncated);
+    let truncated = files
+        .iter()
+        .zip(budgets)
+        .map(|(file, budget)| truncate_file_diff(file, budget))
+        .collect();
+    (truncated, true)
+}
+
+/// Bytes of commit log a PR prompt includes, about 2000 tokens
+pub const COMMITS_BUDGET: usize = 8 * 1024;
+
+/// Fit a `get_commits` log into `budget` bytes, keeping every subject
+///
+/// Subjects are the unindented lines and bodies the indented ones below
+/// them; blank lines are dropped once the log needs condensing. Like files
+/// in `truncate_diff_balanced`, short bodies are kept whole and long ones
+/// share the rest of the budget, cut at a line boundary and marked with
+/// `[...]`. Only the bodies are trimmed, so a log of subjects longer than
+/// `budget` is still returned whole.
+pub fn condense_commits(commits: &str, budget: usize) -> String {
+    if commits.len() <= budget {
+        return commits.to_string();
+    }
+
+    let mut entries: Vec<(&str, Vec<&str>)> = Vec::new();
+    for line in commits.lines().filter(|line| !line.trim().is_empty()) {
+        match entries.last_mut() {
+            Some((_, body)) if line.starts_with(char::is_whitespace) => body.push(line),
+            _ => entries.push((line, Vec::new())),
+        }
+    }
+    let body_len = |body: &[&str]| body.iter().map(|line| line.len() + 1).sum::<usize>();
+
+    let subjects: usize = entries.iter().map(|(subject, _)| subject.len() + 1).sum();
+    let mut remaining = budget.saturating_sub(subjects);
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| body_len(&entries[i].1));
+    let mut budgets = vec![0; entries.len()];
+    for (n, &i) in order.iter().enumerate() {
+        let share = remaining / (entries.len() - n);
+        budgets[i] = body_len(&entries[i].1).min(share);
+        remaining -= budgets[i];
+    }
+
+    let mut condensed = Vec::new();
+    for ((subject, body), budget) in entries.iter().zip(budgets) {
+        condensed.push(*subject);
+        if body_len(body) <= budget {
+            condensed.extend(body);
+            continue;
+        }
+        let mut used = 0;
+        for line in body {
+            used += line.len() + 1;
+            if used > budget {
+                break;
+            }
+            condensed.push(line);
+        }
+        condensed.push("    [...]");
+    }
+    condensed.join("\n")
+}
+
+/// Check whether an added diff line is a conflict start or end marker
+///
+/// Markers must sit at column zero. A lone `=======` separator isn't enough,
+/// since Markdown and reStructuredText use it to underline headings.
+fn is_conflict_marker(line: &str) -> bool {
+    let Some(content) = line.strip_prefix('+') else {
+        return false;
+    };
+    ["<<<<<<<", ">>>>>>>"].iter().any(|marker| {
+        content
+            .strip_prefix(marker)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+    })
+}
+
+/// List the files whose added lines contain merge conflict markers
+pub fn conflict_marker_files(diff: &str) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    let mut current_file = "";
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            current_file = path.strip_prefix("b/").unwrap_or(path);
+        } else if is_conflict_marker(line) && !files.iter().any(|file| file == current_file) {
+            files.push(current_file.to_string());
+        }
+    }
+    files
+}
+
+/// List the files whose lines in raw diff output aren't valid UTF-8
+///
+/// Decoding the diff replaces their invalid bytes with U+FFFD, so these are
+/// the files the model sees mangled, usually ones in a legacy encoding.
+pub fn non_utf8_files(diff: &[u8]) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    let mut current_file = String::new();
+    for line in diff.split(|&byte| byte == b'\n') {
+        if let Some(header) = line.strip_prefix(b"diff --git ") {
+            let header = String::from_utf8_lossy(header);
+            current_file = header
+                .rfind(" b/")
+                .map_or(&*header, |i| &header[i + 3..])
+                .to_string();
+        } else if std::str::from_utf8(line).is_err() && !files.contains(&current_file) {
+            files.push(current_file.clone());
+        }
+    }
+    files
+}
+
+/// Check if the diff adds merge conflict markers
+pub fn has_conflict_markers(diff: &str) -> bool {
+    !conflict_marker_files(diff).is_empty()
+}
+
+/// Check if the diff only changes whitespace, as a reindent does
+///
+/// Changed lines are compared file by file, trimmed and in order, leaving
+/// out blank ones, so moving code around, even to another file, or touching
+/// a binary file counts as content. A diff without changed lines isn't
+/// whitespace-only.
+pub fn is_whitespace_only(diff: &str) -> bool {
+    let mut changed = false;
+    for file in split_diff_files(diff) {
+        match file_whitespace_only(file) {
+            Some(false) => return false,
+            Some(true) => changed = true,
+            None => {}
+        }
+    }
+    changed
+}
+
+/// Whether one file's section of a diff only changes whitespace, None when
+/// it has no changed lines
+fn file_whitespace_only(file: &str) -> Option<bool> {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let mut in_hunk = false;
+    let mut changed = false;
+    for line in file.lines() {
+        if line.starts_with("@@") {
+            in_hunk = true;
+        } else if line.starts_with("Binary files ") {
+            return Some(false);
+        } else if in_hunk {
+            let (lines, content) = if let Some(content) = line.strip_prefix('+') {
+                (&mut added, content.trim())
+            } else if let Some(content) = line.strip_prefix('-') {
+                (&mut removed, content.trim())
+            } else {
+                continue;
+            };
+            changed = true;
+            if !content.is_empty() {
+                lines.push(content);
+            }
+        }
+    }
+    changed.then_some(removed == added)
+}
+
+/// Suggested message for a whitespace-only diff, naming a single file
+pub fn whitespace_message(diff: &str) -> String {
+    let files: Vec<&str> = split_diff_files(diff)
+        .into_iter()
+        .filter_map(diff_file_path)
+        .collect();
+    match files.as_slice() {
+        [file] => format!(
+            "style: fix whitespace in {}",
+            file.rsplit('/').next().unwrap_or(file)
+        ),
+        _ => format!("style: fix whitespace in {} files", files.len()),
+    }
+}
+
+/// Patterns for credentials that shouldn't leave the machine
+const SECRET_PATTERNS: &[(&str, &str)] = &[
+    ("AWS access key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+    ("private key", r"-----BEGIN ([A-Z0-9]+ )*PRIVATE KEY-----"),
+    ("Anthropic API key", r"\bsk-ant-[A-Za-z0-9_-]{20,}"),
+    (
+        "API key assignment",
+        r#"\bANTHROPIC_API_KEY\s*[=:]\s*['"]?([^\s'"]{8,})"#,
+    ),
+];
+
+/// Shortest token considered for the entropy check
+const HIGH_ENTROPY_MIN_LENGTH: usize = 32;
+
+/// Shannon entropy, in bits per character, above which a token looks random
+///
+/// Hex digests top out at 4 bits, so commit and content hashes stay below it.
+const HIGH_ENTROPY_THRESHOLD: f64 = 4.5;
+
+/// A likely secret on a line added by a diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretHit {
+    pub file: String,
+    /// Line number in the new version of the file
+    pub line: usize,
+    /// What matched, such as "AWS access key"
+    pub kind: &'static str,
+}
+
+impl fmt::Display for SecretHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} ({})", self.file, self.line, self.kind)
+    }
+}
+
+/// Check whether an assigned value is a placeholder like `your-api-key-here`
+fn is_placeholder(value: &str) -> bool {
+    let value = value.to_lowercase();
+    value.starts_with(['$', '<'])
+        || ["your", "example", "xxxx", "changeme", "placeholder"]
+            .iter()
+            .any(|word| value.contains(word))
+}
+
+/// Shannon entropy of `token` in bits per character
+fn shannon_entropy(token: &str) -> f64 {
+    let mut counts = std::collections::HashMap::new();
+    for c in token.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let len = token.chars().count() as f64;
+    counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Check whether `line` holds a long random-looking token, such as a
+/// generated API token
+fn has_high_entropy_token(line: &str) -> bool {
+    line.split(|c: char| !(c.is_ascii_alphanumeric() || "+/=_-".contains(c)))
+        .filter(|token| token.len() >= HIGH_ENTROPY_MIN_LENGTH)
+        .any(|token| {
+            token.chars().any(|c| c.is_ascii_digit())
+                && token.chars().any(|c| c.is_ascii_alphabetic())
+                && shannon_entropy(token) >= HIGH_ENTROPY_THRESHOLD
+        })
+}
+
+/// Find likely secrets on the lines `diff` adds
+///
+/// Matches AWS access keys, private key headers, Anthropic API keys and
+/// `ANTHROPIC_API_KEY=` assignments, then long high-entropy tokens. Each
+/// line is reported once, under the first kind that matches. Lock files and
+/// checksums such as `sha512-...` integrity hashes or go.sum `h1:` hashes
+/// are random by design, so they aren't counted as high-entropy tokens.
+pub fn scan_secrets(diff: &str) -> Vec<SecretHit> {
+    let patterns: Vec<(&str, Regex)> = SECRET_PATTERNS
+        .iter()
+        .map(|(kind, pattern)| (*kind, Regex::new(pattern).expect("valid regex")))
+        .collect();
+    let hunk_header = Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)").expect("valid regex");
+    let checksum =
+        Regex::new(r"(?:\bsha(?:256|384|512)-|\bh1:)[A-Za-z0-9+/=_-]+").expect("valid regex");
+
+    let mut hits = Vec::new();
+    let mut current_file = "";
+    let mut next_line = 0;
+    let mut in_hunk = false;
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") {
+            in_hunk = false;
+        } else if let Some(captures) = hunk_header.captures(line) {
+            next_line = captures[1].parse().unwrap_or(0);
+            in_hunk = true;
+        } else if !in_hunk {
+            if let Some(path) = line.strip_prefix("+++ ") {
+                current_file = path.strip_prefix("b/").unwrap_or(path);
+            }
+        } else if let Some(added) = line.strip_prefix('+') {
+            let kind = patterns
+                .iter()
+                .find(|(kind, pattern)| match pattern.captures(added) {
+                    Some(captures) if *kind == "API key assignment" => {
+                        !is_placeholder(&captures[1])
+                    }
+                    Some(_) => true,
+                    None => false,
+                })
+                .map(|(kind, _)| *kind)
+                .or_else(|| {
+                    let basename = current_file.rsplit('/').next().unwrap_or(current_file);
+                    let lock_file = EXCLUDED_LOCK_FILES.contains(&basename);
+                    let checked = checksum.replace_all(added, "");
+                    (!lock_file && has_high_entropy_token(&checked)).then_some("high-entropy token")
+                });
+            if let Some(kind) = kind {
+                hits.push(SecretHit {
+                    file: current_file.to_string(),
+                    line: next_line,
+                    kind,
+                });
+            }
+            next_line += 1;
+        } else if !line.starts_with('-') && !line.starts_with('\\') {
+            next_line += 1;
+        }
+    }
+    hits
+}
+
+/// Refuse to send a diff that adds likely secrets
+///
+/// # Errors
+///
+/// Returns `Error::User` listing where each secret was found
+pub fn check_secrets(diff: &str) -> Result<()> {
+    let hits = scan_secrets(diff);
+    if hits.is_empty() {
+        return Ok(());
+    }
+    let locations: Vec<String> = hits.iter().map(ToString::to_string).collect();
+    Err(Error::User(format!(
+        "The changes look like they contain secrets, which would be sent to the API:\n  {}\nRemove them, or pass --allow-secrets if they are safe to share.",
+        locations.join("\n  ")
+    )))
+}
+
+/// Build files whose changes read as `build:` on their own
+const BUILD_FILES: &[&str] = &[
+    "Cargo.toml",
+    "build.rs",
+    "package.json",
+    "tsconfig.json",
+    "go.mod",
+    "pyproject.toml",
+    "setup.py",
+    "Makefile",
+    "Dockerfile",
+];
+
+/// The commit type a change to `path` alone would get
+fn heuristic_type(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let stem = name.split('.').next().unwrap_or(name);
+    let in_dir =
+        |dir: &str| path.starts_with(&format!("{}/", dir)) || path.contains(&format!("/{}/", dir));
+
+    if path.starts_with(".github/workflows/")
+        || path.starts_with(".circleci/")
+        || name == ".gitlab-ci.yml"
+    {
+        "ci"
+    } else if in_dir("tests")
+        || in_dir("test")
+        || stem.starts_with("test_")
+        || stem.ends_with("_test")
+        || name.contains(".test.")
+        || name.contains(".spec.")
+    {
+        "test"
+    } else if in_dir("docs")
+        || [".md", ".rst", ".adoc"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+        || ["LICENSE", "CHANGELOG"].contains(&stem)
+    {
+        "docs"
+    } else if BUILD_FILES.contains(&name) {
+        "build"
+    } else {
+        "chore"
+    }
+}
+
+/// Build a basic Conventional Commit message from the changed files alone,
+/// for `--offline`
+///
+/// The type is `docs`, `test`, `ci` or `build` when every file is of that
+/// kind and `chore` otherwise. The verb follows the statuses (`add`,
+/// `remove`, `rename`, else `update`), and a single file is named.
+pub fn heuristic_message(files: &[ChangedFile]) -> String {
+    let Some(first) = files.first() else {
+        return "chore: update files".to_string();
+    };
+    let kind = heuristic_type(&first.path);
+    let kind = if files.iter().all(|file| heuristic_type(&file.path) == kind) {
+        kind
+    } else {
+        "chore"
+    };
+
+    let all = |status: FileStatus| files.iter().all(|file| file.status == status);
+    let verb = if all(FileStatus::Added) {
+        "add"
+    } else if all(FileStatus::Deleted) {
+        "remove"
+    } else if all(FileStatus::Renamed) {
+        "rename"
+    } else {
+        "update"
+    };
+
+    let name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+    let object = match files {
+        [file] => match (&file.old_path, verb) {
+            (Some(old_path), "rename") => format!("{} to {}", name(old_path), name(&file.path)),
+            _ => name(&file.path),
+        },
+        _ => format!("{} files", files.len()),
+    };
+    format!("{}: {} {}", kind, verb, object)
+}
+
+/// Most files `stat_summary` lists one by one
+pub const MAX_STAT_FILES: usize = 50;
+
+/// Summarize per-file line counts like `git diff --stat`, a total line
+/// followed by one line per file
+///
+/// The summary covers files that diff truncation cuts short or drops.
+pub fn stat_summary(stats: &[FileStat]) -> String {
+    let added: u32 = stats.iter().filter_map(|stat| stat.added).sum();
+    let deleted: u32 = stats.iter().filter_map(|stat| stat.deleted).sum();
+    let plural = |count: usize, noun: &str| {
+        format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+    };
+    let mut summary = format!(
+        "{} changed, {}(+), {}(-)",
+        plural(stats.len(), "file"),
+        plural(added as usize, "insertion"),
+        plural(deleted as usize, "deletion")
+    );
+    for stat in stats.iter().take(MAX_STAT_FILES) {
+        summary.push_str(&format!("\n  {}", stat));
+    }
+    if stats.len() > MAX_STAT_FILES {
+        summary.push_str(&format!(
+            "\n  ... and {} more",
+            stats.len() - MAX_STAT_FILES
+        ));
+    }
+    summary
+}
+
+/// List changed files with their line counts from `stats`, as
+/// `path (+added -deleted)` where known
+pub fn annotate_file_stats(files: &[ChangedFile], stats: &[FileStat]) -> Vec<String> {
+    files
+        .iter()
+        .map(
+            |file| match stats.iter().find(|stat| stat.path == file.path) {
+                Some(FileStat {
+                    added: Some(added),
+                    deleted: Some(deleted),
+                    ..
+                }) => format!("{} (+{} -{})", file, added, deleted),
+                Some(_) => format!("{} (binary)", file),
+                None => file.to_string(),
+            },
+        )
+        .collect()
+}
+
+/// Validate a conventional-commit scope given on the command line
+///
+/// Scopes such as `parser` or `api-client` end up inside `type(scope):`, so
+/// whitespace and parentheses are rejected.
+pub fn validate_scope(scope: &str) -> Result<()> {
+    if scope.is_empty() || scope.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+        return Err(Error::User(format!(
+            "Invalid scope '{}'. Scopes can't be empty or contain whitespace or parentheses.",
+            scope
+        )));
+    }
+    Ok(())
+}
+
+/// Monorepo directories holding one package per subdirectory, whose scope
+/// is the package name rather than the directory's own
+pub const PACKAGE_CONTAINER_DIRS: &[&str] = &[
+    "apps", "crates", "libs", "modules", "packages", "plugins", "services",
+];
+
+/// Infer a commit scope from the directory all `files` share
+///
+/// The scope is the first directory segment, or the package under a
+/// `PACKAGE_CONTAINER_DIRS` entry, so `services/api/src/main.rs` gives
+/// `api`. Files spanning several top-level directories or packages, or
+/// sitting at the root, have no common scope.
+pub fn common_path_scope(files: &[String]) -> Option<String> {
+    let mut dirs = files.iter().map(|file| {
+        let mut segments: Vec<&str> = file.split('/').collect();
+        segments.pop();
+        segments
+    });
+    let mut common = dirs.next()?;
+    for segments in dirs {
+        let shared = common
+            .iter()
+            .zip(&segments)
+            .take_while(|(a, b)| a == b)
+            .count();
+        common.truncate(shared);
+    }
+
+    let segment = match common.as_slice() {
+        [container, package, ..] if PACKAGE_CONTAINER_DIRS.contains(container) => *package,
+        [container] if PACKAGE_CONTAINER_DIRS.contains(container) => return None,
+        [top, ..] => *top,
+        [] => return None,
+    };
+    // Hidden directories such as .github make a scope without the dot
+    let scope = segment.trim_start_matches('.');
+    validate_scope(scope).ok()?;
+    Some(scope.to_string())
+}
+
+/// Group for the files at the root of the repository in
+/// `group_files_by_directory`
+pub const ROOT_GROUP: &str = ".";
+
+/// Group files by top-level directory, for `autocommit --split`
+///
+/// Like `common_path_scope`, a package under a `PACKAGE_CONTAINER_DIRS`
+/// entry is a group of its own, so `services/api/src/main.rs` lands in
+/// `services/api`. Files at the root go in `ROOT_GROUP`.
+pub fn group_files_by_directory(files: &[String]) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let segments: Vec<&str> = file.split('/').collect();
+        let group = match segments.as_slice() {
+            [container, package, _, ..] if PACKAGE_CONTAINER_DIRS.contains(container) => {
+                format!("{}/{}", container, package)
+            }
+            [top, _, ..] => top.to_string(),
+            _ => ROOT_GROUP.to_string(),
+        };
+        groups.entry(group).or_default().push(file.clone());
+    }
+    groups
+}
+
+/// Staged files `autocommit --split` commits together
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitGroup {
+    /// Files whose diff describes the commit
+    pub files: Vec<String>,
+    /// Lock files and other excluded files, committed but not described
+    pub excluded: Vec<String>,
+}
+
+impl SplitGroup {
+    /// Every path the group's commit takes
+    pub fn paths(&self) -> Vec<String> {
+        self.files.iter().chain(&self.excluded).cloned().collect()
+    }
+}
+
+/// Group `files` with `group_files_by_directory`, adding each of `excluded`
+/// to its directory's group so that every staged file gets committed
+///
+/// An excluded file whose group has nothing to describe goes in the first
+/// group instead. With no `files` at all there are no groups.
+pub fn group_split_files(files: &[String], excluded: &[String]) -> BTreeMap<String, SplitGroup> {
+    let mut groups: BTreeMap<String, SplitGroup> = group_files_by_directory(files)
+        .into_iter()
+        .map(|(group, files)| {
+            let group_files = SplitGroup {
+                files,
+                ..Default::default()
+            };
+            (group, group_files)
+        })
+        .collect();
+    let Some(first) = groups.keys().next().cloned() else {
+        return groups;
+    };
+    for (group, excluded) in group_files_by_directory(excluded) {
+        let target = if groups.contains_key(&group) {
+            group
+        } else {
+            first.clone()
+        };
+        groups.get_mut(&target).unwrap().excluded.extend(excluded);
+    }
+    groups
+}
+
+/// Default commit types, used when `AUTOCOMMIT_TYPES` is unset
+pub const CONVENTIONAL_TYPES: &[&str] = &[
+    "build", "chore", "ci", "docs", "feat", "fix", "perf", "refactor", "revert", "style", "test",
+];
+
+/// The commit types `types` allows, `CONVENTIONAL_TYPES` when it is empty
+pub fn allowed_types(types: &[String]) -> Vec<String> {
+    if types.is_empty() {
+        CONVENTIONAL_TYPES.iter().map(|t| t.to_string()).collect()
+    } else {
+        types.to_vec()
+    }
+}
+
+/// Gitmoji for each of `CONVENTIONAL_TYPES`, as (type, emoji, shortcode)
+pub const GITMOJI: &[(&str, &str, &str)] = &[
+    ("build", "📦", ":package:"),
+    ("chore", "🔧", ":wrench:"),
+    ("ci", "👷", ":construction_worker:"),
+    ("docs", "📝", ":memo:"),
+    ("feat", "✨", ":sparkles:"),
+    ("fix", "🐛", ":bug:"),
+    ("perf", "⚡", ":zap:"),
+    ("refactor", "♻️", ":recycle:"),
+    ("revert", "⏪", ":rewind:"),
+    ("style", "🎨", ":art:"),
+    ("test", "✅", ":white_check_mark:"),
+];
+
+/// Look up the gitmoji for a commit type, as a shortcode or the emoji itself
+pub fn gitmoji_for(kind: &str, shortcode: bool) -> Option<&'static str> {
+    GITMOJI
+        .iter()
+        .find(|(name, _, _)| *name == kind)
+        .map(|(_, emoji, code)| if shortcode { *code } else { *emoji })
+}
+
+/// Remove a leading gitmoji or `:shortcode:` from a commit message
+///
+/// Leaves `:sparkles: feat: add login` as `feat: add login`.
+pub fn strip_gitmoji(message: &str) -> &str {
+    let Some((first, rest)) = message.split_once(' ') else {
+        return message;
+    };
+    let is_shortcode = first.len() > 2
+        && first.starts_with(':')
+        && first.ends_with(':')
+        && first[1..first.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-');
+    let is_emoji = GITMOJI.iter().any(|(_, emoji, _)| *emoji == first);
+    if is_shortcode || is_emoji {
+        rest.trim_start_matches(' ')
+    } else {
+        message
+    }
+}
+
+/// Check that the subject line follows Conventional Commits
+///
+/// Accepts `type: subject`, `type(scope): subject` and the breaking-change
+/// `!` marker, with `type` one of `types`, `CONVENTIONAL_TYPES` when empty.
+pub fn validate_conventional(message: &str, types: &[String]) -> Result<()> {
+    let types = allowed_types(types);
+    let subject = message.lines().next().unwrap_or("").trim();
+    let pattern = Regex::new(r"^([^\s():!]+)(\([^()\s]+\))?!?: \S").expect("valid regex");
+    let valid = pattern
+        .captures(subject)
+        .is_some_and(|captures| types.iter().any(|t| *t == captures[1]));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::User(format!(
+            "'{}' is not a Conventional Commit. Expected 'type(scope): subject' with type one of {}.",
+            subject,
+            types.join(", ")
+        )))
+    }
+}
+
+/// Group for commit subjects that don't follow Conventional Commits
+pub const OTHER_COMMITS: &str = "Other";
+
+/// Changelog section for each conventional type, in the order they're shown
+const COMMIT_GROUP_HEADINGS: &[(&str, &str)] = &[
+    ("feat", "Features"),
+    ("fix", "Bug Fixes"),
+    ("perf", "Performance"),
+    ("refactor", "Refactoring"),
+    ("docs", "Documentation"),
+    ("test", "Tests"),
+    ("build", "Build"),
+    ("ci", "CI"),
+    ("style", "Style"),
+    ("chore", "Chores"),
+    ("revert", "Reverts"),
+];
+
+/// Group commit subjects, one per line, by their conventional type
+///
+/// Each subject is kept without its type, so `feat(api): add x` is filed
+/// under `feat` as `api: add x`, and `feat!: drop y` as `BREAKING: drop y`.
+/// Only `types` count as types, `CONVENTIONAL_TYPES` when empty; other
+/// subjects go under `OTHER_COMMITS`, unchanged.
+pub fn group_commits_by_type(commits: &str, types: &[String]) -> BTreeMap<String, Vec<String>> {
+    let pattern = Regex::new(r"^([a-z]+)(?:\(([^()]+)\))?(!?): (.+)$").expect("valid regex");
+    let types = allowed_types(types);
+    let is_type = |kind: &str| types.iter().any(|t| t == kind);
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for subject in commits.lines().map(str::trim).filter(|s| !s.is_empty()) {
+        let (kind, entry) = match pattern.captures(subject) {
+            Some(captures) if is_type(&captures[1]) => {
+                let mut entry = String::new();
+                if !captures[3].is_empty() {
+                    entry.push_str("BREAKING: ");
+                }
+                if let Some(scope) = captures.get(2) {
+                    entry.push_str(&format!("{}: ", scope.as_str()));
+                }
+                entry.push_str(captures[4].trim());
+                (captures[1].to_string(), entry)
+            }
+            _ => (OTHER_COMMITS.to_string(), subject.to_string()),
+        };
+        groups.entry(kind).or_default().push(entry);
+    }
+    groups
+}
+
+/// Lay out `group_commits_by_type` groups as headed lists for the prompt
+///
+/// Known types come in `COMMIT_GROUP_HEADINGS` order, then any other type
+/// under its own name, then `OTHER_COMMITS`.
+pub fn format_commit_groups(groups: &BTreeMap<String, Vec<String>>) -> String {
+    let rank = |kind: &str| {
+        COMMIT_GROUP_HEADINGS
+            .iter()
+            .position(|(known, _)| *known == kind)
+            .unwrap_or(if kind == OTHER_COMMITS {
+                usize::MAX
+            } else {
+                COMMIT_GROUP_HEADINGS.len()
+            })
+    };
+    let mut kinds: Vec<&String> = groups.keys().collect();
+    kinds.sort_by_key(|kind| rank(kind));
+    kinds
+        .into_iter()
+        .map(|kind| {
+            let heading = COMMIT_GROUP_HEADINGS
+                .iter()
+                .find(|(known, _)| known == kind)
+                .map_or(kind.as_str(), |(_, heading)| heading);
+            let entries: Vec<String> = groups[kind]
+                .iter()
+                .map(|entry| format!("- {}", entry))
+                .collect();
+            format!("{}:\n{}", heading, entries.join("\n"))
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Pick the Changesets bump type for a conventional commit message
+///
+/// Breaking changes (`type!:` or a `BREAKING CHANGE` footer) are a major
+/// bump, `feat` is a minor bump and everything else is a patch.
+pub fn changeset_bump(commit_message: &str) -> &'static str {
+    let subject = commit_message.lines().next().unwrap_or("");
+    let prefix = subject.split(':').next().unwrap_or("");
+
+    if prefix.ends_with('!') || commit_message.contains("BREAKING CHANGE") {
+        "major"
+    } else if prefix.split('(').next() == Some("feat") {
+        "minor"
+    } else {
+        "patch"
+    }
+}
+
+/// Derive a changeset file name from the commit subject
+///
+/// The conventional type prefix is dropped and the description is turned
+/// into a lowercase, dash-separated slug, e.g. `add-login-form.md`.
+pub fn changeset_file_name(commit_message: &str) -> String {
+    let subject = commit_message.lines().next().unwrap_or("");
+    let description = subject
+        .split_once(": ")
+        .map(|(_, rest)| rest)
+        .unwrap_or(subject);
+
+    let mut slug = String::new();
+    for c in description.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= 50 {
+            break;
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "autocommit-change.md".to_string()
+    } else {
+        format!("{}.md", slug)
+    }
+}
+
+/// Read the package name from a `package.json` manifest
+pub fn package_name(package_json: &str) -> Option<String> {
+    let manifest: serde_json::Value = serde_json::from_str(package_json).ok()?;
+    manifest
+        .get("name")
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+}
+
+/// Format a changeset fragment in the Changesets markdown format
+///
+/// Without a package name the frontmatter is left empty, which Changesets
+/// treats as a change that doesn't release any package.
+pub fn format_changeset(package: Option<&str>, bump: &str, entry: &str) -> String {
+    match package {
+        Some(name) => format!("---\n\"{}\": {}\n---\n\n{}\n", name, bump, entry.trim()),
+        None => format!("---\n---\n\n{}\n", entry.trim()),
+    }
+}
+
+/// Check if a markdown line is an "Unreleased" changelog heading
+fn is_unreleased_heading(line: &str) -> bool {
+    let Some(title) = line.strip_prefix("## ") else {
+        return false;
+    };
+    let title = title.trim().trim_start_matches('[').trim_end_matches(']');
+    title.eq_ignore_ascii_case("unreleased")
+}
+
+/// Subsections of a Keep a Changelog release, in the order the format lists
+/// them
+const KEEP_A_CHANGELOG_SECTIONS: &[&str] = &[
+    "Added",
+    "Changed",
+    "Deprecated",
+    "Removed",
+    "Fixed",
+    "Security",
+];
+
+/// Keep a Changelog subsection a generated entry goes under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangelogCategory {
+    Added,
+    Changed,
+    Fixed,
+    Removed,
+}
+
+impl ChangelogCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangelogCategory::Added => "Added",
+            ChangelogCategory::Changed => "Changed",
+            ChangelogCategory::Fixed => "Fixed",
+            ChangelogCategory::Removed => "Removed",
+        }
+    }
+
+    /// Parse a category name, case-insensitively
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.trim();
+        [
+            ChangelogCategory::Added,
+            ChangelogCategory::Changed,
+            ChangelogCategory::Fixed,
+            ChangelogCategory::Removed,
+        ]
+        .into_iter()
+        .find(|category| category.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for ChangelogCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Split a `Category: entry` response into its category and entry text
+///
+/// Responses without a known category are filed under Changed.
+pub fn parse_categorized_entry(response: &str) -> (ChangelogCategory, String) {
+    let response = response.trim();
+    if let Some((name, entry)) = response.split_once(':') {
+        if let Some(category) = ChangelogCategory::parse(name) {
+            return (category, entry.trim().to_string());
+        }
+    }
+    (ChangelogCategory::Changed, response.to_string())
+}
+
+/// Position of a `### <subsection>` heading in the Keep a Changelog order
+fn subsection_rank(line: &str) -> Option<usize> {
+    let title = line.strip_prefix("### ")?.trim();
+    KEEP_A_CHANGELOG_SECTIONS
+        .iter()
+        .position(|section| section.eq_ignore_ascii_case(title))
+}
+
+/// Check if a markdown line is a bullet reading `entry`, ignoring case
+fn is_bullet_for(line: &str, entry: &str) -> bool {
+    let line = line.trim();
+    line.strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .is_some_and(|text| text.trim().eq_ignore_ascii_case(entry))
+}
+
+/// Add an entry to the "Unreleased" section of a changelog
+///
+/// With a category, the entry becomes the first bullet of its `### Category`
+/// subsection, a missing one added in the order Keep a Changelog lists them.
+/// Without, it becomes the first bullet of the section. A missing
+/// "Unreleased" section is added above the first release (or at the end, or
+/// in a new changelog when the content is empty). Returns None when the
+/// section already has the entry.
+pub fn insert_changelog_entry(
+    changelog: &str,
+    category: Option<ChangelogCategory>,
+    entry: &str,
+) -> Option<String> {
+    let entry = entry.trim();
+    let bullet = format!("- {}", entry);
+    let subsection = category.map(|category| format!("### {}", category));
+    let lines: Vec<&str> = changelog.lines().collect();
+    // Lines a new "Unreleased" section starts with
+    let mut section = vec!["## [Unreleased]", ""];
+    if let Some(subsection) = &subsection {
+        section.extend([subsection.as_str(), ""]);
+    }
+    section.push(&bullet);
+
+    let mut output: Vec<&str> = Vec::new();
+    if let Some(heading) = lines.iter().position(|line| is_unreleased_heading(line)) {
+        let end = lines[heading + 1..]
+            .iter()
+            .position(|line| line.starts_with("## "))
+            .map_or(lines.len(), |i| heading + 1 + i);
+        if lines[heading + 1..end]
+            .iter()
+            .any(|line| is_bullet_for(line, entry))
+        {
+            return None;
+        }
+
+        let rank = subsection.as_deref().and_then(subsection_rank);
+        // The line the bullet goes under, if it is already there
+        let existing = match &subsection {
+            Some(_) => (heading + 1..end).find(|&i| subsection_rank(lines[i]) == rank),
+            None => Some(heading),
+        };
+        if let Some(existing) = existing {
+            let mut rest = existing + 1;
+            while rest < lines.len() && lines[rest].trim().is_empty() {
+                rest += 1;
+            }
+            output.extend(&lines[..=existing]);
+            output.push("");
+            output.push(&bullet);
+            if rest < lines.len() {
+                if !lines[rest].starts_with("- ") && !lines[rest].starts_with("* ") {
+                    output.push("");
+                }
+                output.extend(&lines[rest..]);
+            }
+        } else if let Some(subsection) = &subsection {
+            // Before the first later subsection, or at the end of the section
+            let at = (heading + 1..end)
+                .find(|&i| subsection_rank(lines[i]) > rank)
+                .unwrap_or(end);
+            let mut before = at;
+            while before > heading + 1 && lines[before - 1].trim().is_empty() {
+                before -= 1;
+            }
+            output.extend(&lines[..before]);
+            output.extend(["", subsection, "", &bullet]);
+            if at < lines.len() {
+                output.push("");
+                output.extend(&lines[at..]);
+            }
+        }
+    } else if let Some(first_release) = lines.iter().position(|line| line.starts_with("## ")) {
+        output.extend(&lines[..first_release]);
+        output.extend(&section);
+        output.push("");
+        output.extend(&lines[first_release..]);
+    } else if changelog.trim().is_empty() {
+        output.extend(["# Changelog", ""]);
+        output.extend(&section);
+    } else {
+        let end = lines
+            .iter()
+            .rposition(|line| !line.trim().is_empty())
+            .map_or(0, |i| i + 1);
+        output.extend(&lines[..end]);
+        output.push("");
+        output.extend(&section);
+    }
+
+    Some(output.join("\n") + "\n")
+}
+
+/// Candidates tried, in order, when `core.commentChar` is `auto`
+const AUTO_COMMENT_CHARS: &str = "#;@!$%^&|:";
+
+/// Resolve the comment prefix for a commit message
+///
+/// `setting` is the value of `core.commentChar`. With `auto`, this picks the
+/// first candidate that doesn't start any line of `message`, like git does.
+pub fn comment_prefix(setting: &str, message: &str) -> String {
+    if setting != "auto" {
+        return setting.to_string();
+    }
+
+    AUTO_COMMENT_CHARS
+        .chars()
+        .find(|&c| !message.lines().any(|line| line.starts_with(c)))
+        .unwrap_or('#')
+        .to_string()
+}
+
+/// Remove comment lines from an edited commit message
+///
+/// Lines starting with `prefix` are dropped and surrounding blank lines are
+/// trimmed, leaving an empty string when nothing but comments remain.
+pub fn strip_comment_lines(message: &str, prefix: &str) -> String {
+    message
+        .lines()
+        .filter(|line| !line.starts_with(prefix))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Clean up a commit message handed to autocommit on stdin
+///
+/// Comment lines starting with `#` are dropped, as git does for edited
+/// messages. An input with nothing else in it is a user error.
+pub fn draft_message(input: &str) -> Result<String> {
+    let message = strip_comment_lines(input, "#");
+    if message.is_empty() {
+        return Err(Error::User("No commit message on stdin.".to_string()));
+    }
+    Ok(message)
+}
+
+/// What to do with a generated commit message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitAction {
+    Commit,
+    Edit,
+    Regenerate,
+    Cancel,
+}
+
+/// Parse the answer to the "Commit with this message?" prompt
+///
+/// An empty answer accepts the message. Returns None for anything else the
+/// prompt doesn't offer, so the caller can ask again.
+pub fn parse_commit_action(response: &str) -> Option<CommitAction> {
+    match response.trim().to_lowercase().as_str() {
+        "" | "y" | "yes" => Some(CommitAction::Commit),
+        "e" | "edit" => Some(CommitAction::Edit),
+        "r" | "regenerate" => Some(CommitAction::Regenerate),
+        "n" | "no" => Some(CommitAction::Cancel),
+        _ => None,
+    }
+}
+
+/// Parse the answer to the "Amend the last commit with this message?" prompt
+///
+/// Rewriting a commit is harder to undo, so unlike `parse_commit_action` an
+/// empty answer cancels.
+pub fn parse_amend_action(response: &str) -> Option<CommitAction> {
+    match response.trim() {
+        "" => Some(CommitAction::Cancel),
+        response => parse_commit_action(response),
+    }
+}
+
+/// Pick the editor used to edit commit messages
+///
+/// Follows the usual precedence of `$VISUAL` then `$EDITOR`, falling back to
+/// `notepad` on Windows and `vi` elsewhere.
+pub fn resolve_editor(visual: Option<String>, editor: Option<String>) -> String {
+    [visual, editor]
+        .into_iter()
+        .flatten()
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        })
+}
+
+/// Build the file content handed to the editor
+///
+/// The message is followed by instructions commented out with `prefix`, so
+/// `strip_comment_lines` removes them again.
+pub fn edit_template(message: &str, prefix: &str) -> String {
+    format!(
+        "{}\n\n{p} Edit the commit message above. Lines starting with '{p}' will be ignored,\n{p} and an empty message aborts the commit.\n",
+        message.trim(),
+        p = prefix
+    )
+}
+
+/// Column `git log` expects commit message bodies to be wrapped at
+pub const BODY_WRAP_WIDTH: usize = 72;
+
+/// Split a list item into its marker (`- `, `* `, `+ `, `1. `, `1) `) and text
+fn split_list_marker(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    let marker_len =
+        if trimmed.starts_with("- ") || trimmed.starts_with("* ") || trimmed.starts_with("+ ") {
+            2
+        } else {
+            let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+            let rest = &trimmed[digits..];
+            if digits == 0 || !(rest.starts_with(". ") || rest.starts_with(") ")) {
+                return None;
+            }
+            digits + 2
+        };
+    Some(line.split_at(indent + marker_len))
+}
+
+/// Fill `words` into lines of at most `width` columns
+///
+/// The first line starts with `first_prefix`, the following ones with
+/// `indent`. Words longer than the width get a line of their own.
+fn fill_words(words: &[&str], first_prefix: &str, indent: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = first_prefix.to_string();
+    let mut line_has_words = false;
+    for word in words {
+        if line_has_words && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::replace(&mut line, indent.to_string()));
+            line_has_words = false;
+        }
+        if line_has_words {
+            line.push(' ');
+        }
+        line.push_str(word);
+        line_has_words = true;
+    }
+    if line_has_words {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Rewrap the paragraphs of a commit message body to `width` columns
+///
+/// Blank lines are kept, list items are wrapped with a hanging indent, and
+/// fenced or indented code blocks are left exactly as they are. Trailers
+/// such as `Signed-off-by:` and Markdown headings stay one per line, so git
+/// still finds the trailers.
+pub fn wrap_body(text: &str, width: usize) -> String {
+    let trailer_line =
+        Regex::new(r"^(?:[A-Za-z][A-Za-z0-9-]*|BREAKING CHANGE): \S").expect("valid regex");
+    let heading = Regex::new(r"^#{1,6} ").expect("valid regex");
+    let mut lines: Vec<String> = Vec::new();
+    // Paragraph or list item being collected: its words and prefixes
+    let mut words: Vec<&str> = Vec::new();
+    let mut first_prefix = String::new();
+    let mut indent = String::new();
+    let mut in_fence = false;
+
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        if in_fence || is_fence {
+            lines.extend(fill_words(&words, &first_prefix, &indent, width));
+            words.clear();
+            lines.push(line.to_string());
+            if is_fence {
+                in_fence = !in_fence;
+            }
+            continue;
+        }
+
+        if line.trim().is_empty()
+            || line.starts_with("    ")
+            || line.starts_with('\t')
+            || trailer_line.is_match(line)
+            || heading.is_match(line)
+        {
+            lines.extend(fill_words(&words, &first_prefix, &indent, width));
+            words.clear();
+            lines.push(line.trim_end().to_string());
+        } else if let Some((marker, rest)) = split_list_marker(line) {
+            lines.extend(fill_words(&words, &first_prefix, &indent, width));
+            words = rest.split_whitespace().collect();
+            first_prefix = marker.to_string();
+            indent = " ".repeat(marker.chars().count());
+        } else {
+            if words.is_empty() {
+                let leading = &line[..line.len() - line.trim_start().len()];
+                first_prefix = leading.to_string();
+                indent = leading.to_string();
+            }
+            words.extend(line.split_whitespace());
+        }
+    }
+    lines.extend(fill_words(&words, &first_prefix, &indent, width));
+
+    let mut wrapped = lines.join("\n");
+    if text.ends_with('\n') {
+        wrapped.push('\n');
+    }
+    wrapped
+}
+
+/// Rewrap the body of a commit message, leaving the subject line untouched
+pub fn wrap_commit_message(message: &str, width: usize) -> String {
+    match message.split_once('\n') {
+        Some((subject, body)) => format!("{}\n{}", subject, wrap_body(body, width)),
+        None => message.to_string(),
+    }
+}
+
+/// Shorten a subject line to at most `max` characters
+///
+/// Cuts at the last word boundary that leaves room for a trailing `...`,
+/// and only mid-word when the first word alone is too long.
+pub fn enforce_subject_length(subject: &str, max: usize) -> String {
+    const ELLIPSIS: &str = "...";
+    if subject.chars().count() <= max {
+        return subject.to_string();
+    }
+    if max <= ELLIPSIS.len() {
+        return subject.chars().take(max).collect();
+    }
+    let cut = subject
+        .char_indices()
+        .nth(max - ELLIPSIS.len())
+        .map_or(subject.len(), |(index, _)| index);
+    let mut head = &subject[..cut];
+    if !subject[cut..].starts_with(char::is_whitespace) {
+        if let Some(space) = head.rfind(char::is_whitespace).filter(|&i| i > 0) {
+            head = &head[..space];
+        }
+    }
+    let head = head.trim_end_matches(|c: char| c.is_whitespace() || ",;:.-".contains(c));
+    format!("{}{}", head, ELLIPSIS)
+}
+
+/// Extract an issue reference from a branch name
+///
+/// Uses the first capture group of `pattern` when it has one, otherwise the
+/// whole match. Bare numbers get a `#` prefix, e.g. `123-fix-bug` -> `#123`.
+pub fn extract_issue_ref(branch: &str, pattern: &Regex) -> Option<String> {
+    let captures = pattern.captures(branch)?;
+    let issue = captures.get(1).or_else(|| captures.get(0))?.as_str();
+    if issue.is_empty() {
+        None
+    } else if issue.chars().all(|c| c.is_ascii_digit()) {
+        Some(format!("#{}", issue))
+    } else {
+        Some(issue.to_string())
+    }
+}
+
+/// Append a `Refs: <issue>` trailer unless the message already mentions it
+pub fn append_issue_ref(message: &str, issue: &str) -> String {
+    if message.contains(issue) {
+        message.to_string()
+    } else {
+        format!("{}\n\nRefs: {}", message.trim_end(), issue)
+    }
+}
+
+/// Format `Name <email>` co-authors as `Co-authored-by:` trailers, one per line
+///
+/// # Errors
+///
+/// Returns `Error::User` for an entry without a name or a valid email
+pub fn format_coauthors(authors: &[String]) -> Result<String> {
+    let trailers = authors
+        .iter()
+        .map(|author| {
+            let identity = normalize_identity(author).ok_or_else(|| {
+                Error::User(format!(
+                    "Invalid co-author '{}'. Expected \"Name <email>\".",
+                    author
+                ))
+            })?;
+            Ok(format!("Co-authored-by: {}", identity))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(trailers.join("\n"))
+}
+
+/// Check a `--author` value, returning it as `Name <email>`
+///
+/// # Errors
+///
+/// Returns `Error::User` without a name or a valid email
+pub fn validate_author(author: &str) -> Result<String> {
+    normalize_identity(author).ok_or_else(|| {
+        Error::User(format!(
+            "Invalid author '{}'. Expected \"Name <email>\".",
+            author
+        ))
+    })
+}
+
+/// Tidy a `Name <email>` identity, or None if it isn't one
+fn normalize_identity(identity: &str) -> Option<String> {
+    let pattern =
+        Regex::new(r"^([^<>]*[^<>\s])\s*<([^<>\s@]+@[^<>\s@]+\.[^<>\s@]+)>$").expect("valid regex");
+    let captures = pattern.captures(identity.trim())?;
+    Some(format!("{} <{}>", captures[1].trim(), &captures[2]))
+}
+
+/// The trailer block for a commit: the co-author trailers, then one
+/// provenance trailer per entry in `trailers` naming `model`
+pub fn commit_trailers(coauthors: &str, trailers: &[Trailer], model: &str) -> String {
+    std::iter::once(coauthors.to_string())
+        .filter(|coauthors| !coauthors.is_empty())
+        .chain(trailers.iter().map(|trailer| trailer.render(model)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Append `trailers` to `message`, joining the trailers already closing it
+/// (such as `Refs:`) so git reads them as one block
+pub fn append_trailers(message: &str, trailers: &str) -> String {
+    let message = message.trim_end();
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    let trailer_line = Regex::new(r"^[A-Za-z][A-Za-z0-9-]*: \S").expect("valid regex");
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or("");
+    let ends_with_trailers = message.contains("\n\n")
+        && last_paragraph
+            .lines()
+            .all(|line| trailer_line.is_match(line));
+    let separator = if ends_with_trailers { "\n" } else { "\n\n" };
+    format!("{}{}{}", message, separator, trailers)
+}
+
+/// Placeholder replaced with the diff in a custom prompt template
+pub const DIFF_PLACEHOLDER: &str = "{diff}";
+
+/// Render a custom prompt template by substituting the diff
+///
+/// Fails when the template has no `{diff}` placeholder, since the model
+/// would otherwise never see the changes.
+pub fn render_prompt_template(template: &str, diff: &str) -> Result<String> {
+    if !template.contains(DIFF_PLACEHOLDER) {
+        return Err(Error::Env(format!(
+            "The prompt template must contain a {} placeholder where the diff goes",
+            DIFF_PLACEHOLDER
+        )));
+    }
+    Ok(template.replace(DIFF_PLACEHOLDER, diff))
+}
+
+/// File extensions that indicate UI work
+const UI_FILE_EXTENSIONS: &[&str] = &[
+    "tsx", "jsx", "vue", "svelte", "astro", "html", "css", "scss", "sass", "less",
+];
+
+/// Placeholder section added to PR bodies for UI changes
+const SCREENSHOTS_SECTION: &str = "## Screenshots\n\n<!-- add screenshots here -->";
+
+/// Check if a file looks like UI code based on its extension
+pub fn is_ui_file(path: &str) -> bool {
+    path.rsplit_once('.')
+        .map(|(_, ext)| UI_FILE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Decide whether a PR needs a screenshots section
+pub fn needs_screenshots_section(mode: ScreenshotsMode, changed_files: &[String]) -> bool {
+    match mode {
+        ScreenshotsMode::Always => true,
+        ScreenshotsMode::Never => false,
+        ScreenshotsMode::Auto => changed_files.iter().any(|file| is_ui_file(file)),
+    }
+}
+
+/// Append a screenshots placeholder section unless the body already has one
+pub fn ensure_screenshots_section(body: &str) -> String {
+    let has_section = body.lines().any(|line| {
+        line.trim_start_matches('#')
+            .trim()
+            .eq_ignore_ascii_case("screenshots")
+            && line.starts_with('#')
+    });
+
+    if has_section {
+        body.to_string()
+    } else if body.trim().is_empty() {
+        SCREENSHOTS_SECTION.to_string()
+    } else {
+        format!("{}\n\n{}", body.trim_end(), SCREENSHOTS_SECTION)
+    }
+}
+
+/// Hash a diff into a short, stable hex string (64-bit FNV-1a)
+///
+/// Only used to notice that a diff changed, not for security.
+pub fn diff_hash(diff: &str) -> String {
+    let hash = diff.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// How long cached PR content stays valid, in seconds
+pub const PR_CACHE_TTL_SECS: u64 = 60 * 60;
+
+/// PR content generated for a branch, saved so a failed run can be resumed
+/// without calling the API again
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrCache {
+    pub branch: String,
+    /// Commit `HEAD` pointed at when the content was generated
+    pub head: String,
+    pub diff_hash: String,
+    /// Unix timestamp of when the content was generated
+    pub created_at: u64,
+    pub content: PRContent,
+}
+
+impl PrCache {
+    /// Whether the cache was generated for this exact branch state, recently
+    pub fn matches(&self, branch: &str, head: &str, diff_hash: &str, now: u64) -> bool {
+        self.branch == branch
+            && self.head == head
+            && self.diff_hash == diff_hash
+            && now.saturating_sub(self.created_at) <= PR_CACHE_TTL_SECS
+    }
+}
+
+/// Load the PR cache at `path`
+///
+/// A missing or unreadable cache is a cache miss, not an error.
+pub async fn load_pr_cache(path: &Path) -> Option<PrCache> {
+    let content = tokio::fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Save the PR cache to `path`, replacing any previous one
+pub async fn store_pr_cache(path: &Path, cache: &PrCache) -> Result<()> {
+    tokio::fs::write(path, serde_json::to_string_pretty(cache)?).await?;
+    Ok(())
+}
+
+/// Where PR templates are looked for, relative to the repository root
+pub const PR_TEMPLATE_PATHS: &[&str] = &[
+    ".github/PULL_REQUEST_TEMPLATE.md",
+    ".github/pull_request_template.md",
+    "PULL_REQUEST_TEMPLATE.md",
+    "pull_request_template.md",
+];
+
+/// Load the PR template to follow, if any
+///
+/// An explicit `template` path is read relative to `root` and must exist.
+/// Otherwise the first of `PR_TEMPLATE_PATHS` found is used, unless
+/// `disabled` skips discovery entirely.
+pub async fn load_pr_template(
+    root: &Path,
+    template: Option<&Path>,
+    disabled: bool,
+) -> Result<Option<String>> {
+    if let Some(template) = template {
+        let path = root.join(template);
+        return match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) => Err(Error::User(format!(
+                "Cannot read PR template {}: {}",
+                template.display(),
+                e
+            ))),
+        };
+    }
+    if disabled {
+        return Ok(None);
+    }
+
+    for path in PR_TEMPLATE_PATHS {
+        if let Ok(content) = tokio::fs::read_to_string(root.join(path)).await {
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the `<!-- ... -->` comments from a PR template, which are notes
+/// to the author the model would otherwise copy into the description
+///
+/// Runs of blank lines left behind are collapsed to one.
+pub fn strip_template_comments(template: &str) -> String {
+    let comment = Regex::new(r"(?s)<!--.*?-->").expect("valid regex");
+    let stripped = comment.replace_all(template, "");
+    let mut lines: Vec<&str> = Vec::new();
+    for line in stripped.lines().map(str::trim_end) {
+        let repeated_blank = line.is_empty() && lines.last().is_none_or(|last| last.is_empty());
+        if !repeated_blank {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim_end().to_string()
+}
+
+/// Fill the `{{branch}}` and `{{ticket}}` placeholders of a PR template
+///
+/// `ticket` comes from the branch name (see `extract_issue_ref`); without
+/// one, `{{ticket}}` is left for the author to fill in.
+pub fn render_template_vars(template: &str, branch: &str, ticket: Option<&str>) -> String {
+    let placeholder = Regex::new(r"\{\{\s*(branch|ticket)\s*\}\}").expect("valid regex");
+    placeholder
+        .replace_all(template, |captures: &regex::Captures| {
+            match (&captures[1], ticket) {
+                ("branch", _) => branch.to_string(),
+                (_, Some(ticket)) => ticket.to_string(),
+                (_, None) => captures[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Await `fut` and measure how long it took
+///
+/// `label` names the step in the debug log.
+pub async fn timed<F: Future>(label: &str, fut: F) -> (F::Output, Duration) {
+    let started = Instant::now();
+    let output = fut.await;
+    let elapsed = started.elapsed();
+    tracing::debug!(step = label, ?elapsed, "finished");
+    (output, elapsed)
+}
+
+/// Wait for Ctrl-C, forever if it can't be listened for
+///
+/// Listening replaces the default of killing the process, so Ctrl-C only
+/// stops the work passed to `until_interrupted` with it.
+pub async fn ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Run `work` until `interrupt` completes, dropping it then, which cancels
+/// any request in flight, and returning `Error::Cancelled`
+///
+/// `work` is polled first, so when it nests another `until_interrupted`
+/// listening for the same signal, the inner one sees it and cleans up.
+pub async fn until_interrupted<T>(
+    work: impl Future<Output = Result<T>>,
+    interrupt: impl Future<Output = ()>,
+) -> Result<T> {
+    tokio::select! {
+        biased;
+        result = work => result,
+        () = interrupt => Err(Error::Cancelled),
+    }
+}
+
+/// Where progress and other informational messages go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusOutput {
+    /// `--quiet`: only results and errors are printed
+    quiet: bool,
+    /// Stdout carries `--json` output, so messages move to stderr
+    json: bool,
+}
+
+impl StatusOutput {
+    pub fn new(quiet: bool, json: bool) -> Self {
+        StatusOutput { quiet, json }
+    }
+
+    pub fn is_quiet(self) -> bool {
+        self.quiet
+    }
+
+    /// Print an informational message, unless quiet
+    pub fn info(self, message: fmt::Arguments<'_>) {
+        self.info_to(message, &mut std::io::stdout(), &mut std::io::stderr());
+    }
+
+    /// Print a result, such as the commit made or the PR URL, even when quiet
+    pub fn result(self, message: fmt::Arguments<'_>) {
+        self.result_to(message, &mut std::io::stdout(), &mut std::io::stderr());
+    }
+
+    fn info_to(
+        self,
+        message: fmt::Arguments<'_>,
+        stdout: &mut dyn std::io::Write,
+        stderr: &mut dyn std::io::Write,
+    ) {
+        if !self.is_quiet() {
+            self.result_to(message, stdout, stderr);
+        }
+    }
+
+    fn result_to(
+        self,
+        message: fmt::Arguments<'_>,
+        stdout: &mut dyn std::io::Write,
+        stderr: &mut dyn std::io::Write,
+    ) {
+        let _ = if self.json {
+            writeln!(stderr, "{}", message)
+        } else {
+            writeln!(stdout, "{}", message)
+        };
+    }
+}
+
+/// Frames of the spinner shown while waiting for the API
+const SPINNER_FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// How often the spinner advances, and how long a request may take before
+/// it appears
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether to show a spinner: only on an interactive stdout, and never when
+/// `suppressed` by `--quiet` or by stdout carrying `--json` output
+pub fn spinner_enabled(stdout_is_terminal: bool, suppressed: bool) -> bool {
+    stdout_is_terminal && !suppressed
+}
+
+/// Await `fut`, showing a spinner with `label` on stdout until it completes
+/// when `enabled`
+pub async fn with_spinner<F: Future>(label: &str, enabled: bool, fut: F) -> F::Output {
+    if enabled {
+        spin(label, fut, std::io::stdout()).await
+    } else {
+        fut.await
+    }
+}
+
+/// Draw spinner frames to `out` while `fut` is pending, then clear the line
+///
+/// Nothing is drawn for a future that completes within the first interval.
+async fn spin<F: Future, W: std::io::Write>(label: &str, fut: F, mut out: W) -> F::Output {
+    tokio::pin!(fut);
+    let start = tokio::time::Instant::now() + SPINNER_INTERVAL;
+    let mut ticks = tokio::time::interval_at(start, SPINNER_INTERVAL);
+    let mut frames = 0;
+    let output = loop {
+        tokio::select! {
+            biased;
+            output = &mut fut => break output,
+            _ = ticks.tick() => {
+                let frame = SPINNER_FRAMES[frames % SPINNER_FRAMES.len()];
+                let _ = write!(out, "\r{} {}", frame, label);
+                let _ = out.flush();
+                frames += 1;
+            }
+        }
+    };
+    if frames > 0 {
+        // Erase the spinner line so the next output starts clean
+        let _ = write!(out, "\r\x1b[2K");
+        let _ = out.flush();
+    }
+    output
+}
+
+/// How long each step of a run took, shown with `--verbose`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    pub fn record(&mut self, label: &'static str, elapsed: Duration) {
+        self.0.push((label, elapsed));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Format a duration for people, as milliseconds under a second
+pub fn format_duration(elapsed: Duration) -> String {
+    if elapsed < Duration::from_secs(1) {
+        format!("{}ms", elapsed.as_millis())
+    } else {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    }
+}
+
+impl fmt::Display for Timings {
+    /// Lists the steps in order, e.g. "git diff: 120ms, api: 3.4s"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps: Vec<String> = self
+            .0
+            .iter()
+            .map(|(label, elapsed)| format!("{}: {}", label, format_duration(*elapsed)))
+            .collect();
+        write!(f, "{}", steps.join(", "))
+    }
+}
+
+/// Most bytes of `--context-file` material sent with a PR prompt, shared
+/// by all the files
+pub const MAX_CONTEXT_FILE_BYTES: usize = 16_000;
+
+/// Lay out reference files as prompt context, keeping the total within
+/// `budget` bytes
+///
+/// Files shorter than an even share are kept whole and leave the rest to
+/// the longer ones, which are cut on a char boundary.
+pub fn format_context_files(files: &[(String, String)], budget: usize) -> String {
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by_key(|&i| files[i].1.len());
+    let mut limits = vec![0; files.len()];
+    let mut remaining = budget;
+    for (done, &i) in order.iter().enumerate() {
+        let share = remaining / (files.len() - done);
+        limits[i] = files[i].1.len().min(share);
+        remaining -= limits[i];
+    }
+
+    let mut out = String::from("Additional reference material:\n");
+    for ((name, content), limit) in files.iter().zip(limits) {
+        let cut = floor_char_boundary(content, limit);
+        out.push_str(&format!("\n--- {} ---\n{}", name, &content[..cut]));
+        if cut < content.len() {
+            out.push_str(&format!(
+                "\n... (file truncated, {} characters omitted)",
+                content.len() - cut
+            ));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Read the `--context-file` paths into prompt context, `None` when there
+/// are none
+///
+/// Every file must exist, so a typo fails instead of being left out.
+pub async fn load_context_files(paths: &[PathBuf], budget: usize) -> Result<Option<String>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+            Error::User(format!(
+                "Cannot read context file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        files.push((path.display().to_string(), content));
+    }
+    Ok(Some(format_context_files(&files, budget)))
+}
+
+/// Put a clarification answer ahead of the reference material, so both
+/// reach the model
+pub fn combine_context(answer: Option<&str>, reference: Option<&str>) -> Option<String> {
+    match (answer, reference) {
+        (Some(answer), Some(reference)) => Some(format!("{}\n\n{}", answer, reference)),
+        (answer, reference) => answer.or(reference).map(str::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_prefix() {
+        assert_eq!(comment_prefix("#", "feat: add x"), "#");
+        assert_eq!(comment_prefix(";", "feat: add x"), ";");
+        assert_eq!(comment_prefix("auto", "feat: add x"), "#");
+        assert_eq!(comment_prefix("auto", "feat: add x\n\n#123 fixed"), ";");
+        assert_eq!(comment_prefix("auto", "#1\n;2\n@3"), "!");
+    }
+
+    #[test]
+    fn test_strip_comment_lines_default_char() {
+        let edited = "feat: add x\n\nBody line\n# Please enter the commit message\n#\n";
+        assert_eq!(strip_comment_lines(edited, "#"), "feat: add x\n\nBody line");
+    }
+
+    #[test]
+    fn test_strip_comment_lines_custom_char() {
+        let edited = "fix: handle #42\n\n#hashtags stay\n; Lines starting with ';' are ignored\n";
+        assert_eq!(
+            strip_comment_lines(edited, ";"),
+            "fix: handle #42\n\n#hashtags stay"
+        );
+    }
+
+    #[test]
+    fn test_strip_comment_lines_only_comments() {
+        assert_eq!(strip_comment_lines("; nothing here\n;\n", ";"), "");
+    }
+
+    #[test]
+    fn test_draft_message() {
+        let piped =
+            "\nfix: handle null config\n\n# from the release script\nTreat it as the defaults.\n";
+        assert_eq!(
+            draft_message(piped).unwrap(),
+            "fix: handle null config\n\nTreat it as the defaults."
+        );
+        assert!(matches!(
+            draft_message("# only a comment\n\n"),
+            Err(Error::User(_))
+        ));
+        assert!(matches!(draft_message(""), Err(Error::User(_))));
+    }
+
+    #[test]
+    fn test_parse_commit_action() {
+        for response in ["", "y", "Y", " yes "] {
+            assert_eq!(parse_commit_action(response), Some(CommitAction::Commit));
+        }
+        assert_eq!(parse_commit_action("e"), Some(CommitAction::Edit));
+        assert_eq!(parse_commit_action("r"), Some(CommitAction::Regenerate));
+        assert_eq!(
+            parse_commit_action("Regenerate"),
+            Some(CommitAction::Regenerate)
+        );
+        assert_eq!(parse_commit_action("n"), Some(CommitAction::Cancel));
+        assert_eq!(parse_commit_action("NO"), Some(CommitAction::Cancel));
+        assert_eq!(parse_commit_action("maybe"), None);
+    }
+
+    #[test]
+    fn test_parse_amend_action() {
+        for response in ["", "  ", "n"] {
+            assert_eq!(parse_amend_action(response), Some(CommitAction::Cancel));
+        }
+        assert_eq!(parse_amend_action("y"), Some(CommitAction::Commit));
+        assert_eq!(parse_amend_action("e"), Some(CommitAction::Edit));
+        assert_eq!(parse_amend_action("maybe"), None);
+    }
+
+    #[test]
+    fn test_resolve_editor() {
+        assert_eq!(
+            resolve_editor(Some("code --wait".to_string()), Some("nano".to_string())),
+            "code --wait"
+        );
+        assert_eq!(resolve_editor(None, Some("nano".to_string())), "nano");
+        assert_eq!(
+            resolve_editor(Some(" ".to_string()), Some("nano".to_string())),
+            "nano"
+        );
+
+        let fallback = resolve_editor(None, None);
+        if cfg!(windows) {
+            assert_eq!(fallback, "notepad");
+        } else {
+            assert_eq!(fallback, "vi");
+        }
+    }
+
+    #[test]
+    fn test_edit_template_round_trip() {
+        let message = "feat: add login\n\nAdds a login form.";
+        for prefix in ["#", ";"] {
+            let template = edit_template(message, prefix);
+            assert!(template.starts_with(message));
+            assert_eq!(strip_comment_lines(&template, prefix), message);
+        }
+    }
+
+    #[test]
+    fn test_get_lock_file_exclusions() {
+        let exclusions = get_lock_file_exclusions(&[]);
+        assert!(!exclusions.is_empty());
+        assert!(exclusions.contains(&":!package-lock.json".to_string()));
+        assert!(exclusions.contains(&":!Cargo.lock".to_string()));
+        assert_eq!(exclusions.len(), EXCLUDED_LOCK_FILES.len());
+    }
+
+    #[test]
+    fn test_get_lock_file_exclusions_extra() {
+        let extra = vec!["*.generated.ts".to_string(), "dist/**".to_string()];
+        let exclusions = get_lock_file_exclusions(&extra);
+        assert_eq!(exclusions.len(), EXCLUDED_LOCK_FILES.len() + 2);
+        assert!(exclusions.contains(&":!Cargo.lock".to_string()));
+        assert!(exclusions.contains(&":(exclude,glob)**/*.generated.ts".to_string()));
+        assert!(exclusions.contains(&":(exclude,glob)dist/**".to_string()));
+    }
+
+    #[test]
+    fn test_build_pathspecs() {
+        let pathspecs = build_pathspecs(&[], Exclusions::default());
+        assert_eq!(pathspecs[0], ".");
+        assert!(pathspecs.contains(&":!Cargo.lock".to_string()));
+        assert_eq!(pathspecs.len(), EXCLUDED_LOCK_FILES.len() + 1);
+
+        let pathspecs = build_pathspecs(&[], Exclusions::default().include_lock_files(true));
+        assert_eq!(pathspecs, vec!["."]);
+
+        let pathspecs = build_pathspecs(&[":(glob)**/*.rs".to_string()], Exclusions::default());
+        assert_eq!(pathspecs[0], ":(glob)**/*.rs");
+        assert!(!pathspecs.contains(&".".to_string()));
+    }
+
+    /// A diff adding `lines` to `name` after one line of context.
+    /// Secrets in these tests are assembled at runtime so this file doesn't
+    /// trip the scan itself.
+    fn added_lines_diff(name: &str, lines: &[String]) -> String {
+        let mut diff = format!(
+            "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n@@ -1,2 +1,{1} @@\n context\n-removed\n",
+            name,
+            lines.len() + 1
+        );
+        for line in lines {
+            diff.push_str(&format!("+{}\n", line));
+        }
+        diff
+    }
+
+    #[test]
+    fn test_scan_secrets_patterns() {
+        let cases = [
+            (
+                format!("aws_key = \"AKIA{}\"", "IOSFODNN7EXAMPLE"),
+                "AWS access key",
+            ),
+            (
+                format!("-----BEGIN {} KEY-----", "RSA PRIVATE"),
+                "private key",
+            ),
+            (format!("-----BEGIN {} KEY-----", "PRIVATE"), "private key"),
+            (
+                format!("KEY = \"sk-ant-{}\"", "api03-abcdefghijklmnopqrstuv"),
+                "Anthropic API key",
+            ),
+            (
+                format!("ANTHROPIC_API_KEY={}", "hunter2hunter2"),
+                "API key assignment",
+            ),
+            (
+                format!(
+                    "token: {}{}",
+                    "q8Zr2LxV/9mNcT4bWk+1", "sH7eYj0PdGuA3fRi6oKl"
+                ),
+                "high-entropy token",
+            ),
+        ];
+        for (line, kind) in cases {
+            let diff = added_lines_diff("src/config.rs", &["fn ok() {}".to_string(), line.clone()]);
+            assert_eq!(
+                scan_secrets(&diff),
+                vec![SecretHit {
+                    file: "src/config.rs".to_string(),
+                    line: 3,
+                    kind,
+                }],
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_secrets_clean_diff() {
+        let lines = [
+            "ANTHROPIC_API_KEY=your-api-key-here".to_string(),
+            "ANTHROPIC_API_KEY=$ANTHROPIC_API_KEY".to_string(),
+            // A commit hash is long but only hex
+            "fixes 3f786850e387550fdab836ed7e6dc881de23001b6c2a1e0f".to_string(),
+            "let total = compute_the_sum_of_all_values_in_the_list(values);".to_string(),
+        ];
+        assert!(scan_secrets(&added_lines_diff("README.md", &lines)).is_empty());
+
+        // Checksums are random by design, in lock files and out
+        let lines = [
+            "  integrity sha512-v2kDEe57lecTulaDIuNTPy3Ry4gLGJ6Z1O3vE1krgXZNrsQ+LFTGHVxVjcXPs17LhbZVGedAJv8XZ1tvj5FvSg==".to_string(),
+            "golang.org/x/text v0.3.7 h1:olpwvP2KacW1ZWvsR7uQhoyTYvKAupfQrRGBFM352Gk=".to_string(),
+            r#"<script src="app.js" integrity="sha384-oqVuAfXRKap7fdgcCY5uykM6+R9GqQ8K/uxy9rx7HNQlGYl1kPzQho1wx4JwY8wC"></script>"#.to_string(),
+        ];
+        assert!(scan_secrets(&added_lines_diff("yarn.lock", &lines[..1])).is_empty());
+        assert!(scan_secrets(&added_lines_diff("go.sum", &lines[1..2])).is_empty());
+        assert!(scan_secrets(&added_lines_diff("index.html", &lines)).is_empty());
+
+        // Removed and context lines are already in the repository
+        let diff = format!(
+            "diff --git a/a.pem b/a.pem\n--- a/a.pem\n+++ b/a.pem\n@@ -1 +1 @@\n------BEGIN {} KEY-----\n",
+            "PRIVATE"
+        );
+        assert!(scan_secrets(&diff).is_empty());
+        assert!(check_secrets(&diff).is_ok());
+    }
+
+    #[test]
+    fn test_check_secrets_lists_locations() {
+        let line = format!("-----BEGIN {} KEY-----", "OPENSSH PRIVATE");
+        let diff = added_lines_diff("deploy/id_ed25519", &[line]);
+        match check_secrets(&diff) {
+            Err(Error::User(message)) => {
+                assert!(message.contains("deploy/id_ed25519:2 (private key)"));
+                assert!(message.contains("--allow-secrets"));
+            }
+            other => panic!("expected a user error, got {:?}", other),
+        }
+    }
+
+    fn changed(status: FileStatus, path: &str) -> ChangedFile {
+        ChangedFile {
+            status,
+            path: path.to_string(),
+            old_path: None,
+        }
+    }
+
+    #[test]
+    fn test_heuristic_message() {
+        use FileStatus::*;
+
+        let cases: Vec<(Vec<ChangedFile>, &str)> = vec![
+            (
+                vec![changed(Modified, "README.md")],
+                "docs: update README.md",
+            ),
+            (
+                vec![
+                    changed(Added, "docs/setup.md"),
+                    changed(Added, "docs/img/flow.svg"),
+                ],
+                "docs: add 2 files",
+            ),
+            (
+                vec![
+                    changed(Modified, "tests/cli.rs"),
+                    changed(Added, "src/parser_test.go"),
+                ],
+                "test: update 2 files",
+            ),
+            (
+                vec![changed(Added, "web/button.spec.ts")],
+                "test: add button.spec.ts",
+            ),
+            (
+                vec![changed(Modified, ".github/workflows/ci.yml")],
+                "ci: update ci.yml",
+            ),
+            (
+                vec![changed(Modified, "Cargo.toml")],
+                "build: update Cargo.toml",
+            ),
+            (vec![changed(Deleted, "src/old.rs")], "chore: remove old.rs"),
+            (
+                vec![
+                    changed(Modified, "src/lib.rs"),
+                    changed(Modified, "README.md"),
+                    changed(Added, "src/new.rs"),
+                ],
+                "chore: update 3 files",
+            ),
+            (Vec::new(), "chore: update files"),
+        ];
+        for (files, expected) in cases {
+            let message = heuristic_message(&files);
+            assert_eq!(message, expected);
+            assert!(validate_conventional(&message, &default_types()).is_ok());
+        }
+
+        let renamed = ChangedFile {
+            old_path: Some("src/utils.rs".to_string()),
+            ..changed(Renamed, "src/helpers.rs")
+        };
+        assert_eq!(
+            heuristic_message(&[renamed]),
+            "chore: rename utils.rs to helpers.rs"
+        );
+    }
+
+    fn stat(path: &str, added: Option<u32>, deleted: Option<u32>) -> FileStat {
+        FileStat {
+            path: path.to_string(),
+            added,
+            deleted,
+        }
+    }
+
+    #[test]
+    fn test_stat_summary() {
+        let stats = vec![
+            stat("src/main.rs", Some(40), Some(5)),
+            stat("logo.png", None, None),
+            stat("README.md", Some(2), Some(0)),
+        ];
+        assert_eq!(
+            stat_summary(&stats),
+            "3 files changed, 42 insertions(+), 5 deletions(-)
+  src/main.rs (+40 -5)
+  logo.png (binary)
+  README.md (+2 -0)"
+        );
+        assert_eq!(
+            stat_summary(&stats[..1]),
+            "1 file changed, 40 insertions(+), 5 deletions(-)\n  src/main.rs (+40 -5)"
+        );
+
+        let many: Vec<FileStat> = (0..MAX_STAT_FILES + 2)
+            .map(|i| stat(&format!("f{}.rs", i), Some(1), Some(1)))
+            .collect();
+        let summary = stat_summary(&many);
+        assert!(summary.starts_with("52 files changed, 52 insertions(+), 52 deletions(-)"));
+        assert!(summary.ends_with("  f49.rs (+1 -1)\n  ... and 2 more"));
+    }
+
+    #[test]
+    fn test_annotate_file_stats() {
+        let renamed = ChangedFile {
+            old_path: Some("src/old.rs".to_string()),
+            ..changed(FileStatus::Renamed, "src/new.rs")
+        };
+        let files = vec![
+            changed(FileStatus::Modified, "src/main.rs"),
+            renamed,
+            changed(FileStatus::Added, "logo.png"),
+            changed(FileStatus::Modified, "unknown.rs"),
+        ];
+        let stats = vec![
+            stat("src/main.rs", Some(3), Some(1)),
+            stat("src/new.rs", Some(0), Some(2)),
+            stat("logo.png", None, None),
+        ];
+        assert_eq!(
+            annotate_file_stats(&files, &stats),
+            vec![
+                "src/main.rs (+3 -1)",
+                "src/old.rs -> src/new.rs (+0 -2)",
+                "logo.png (binary)",
+                "unknown.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_coauthors() {
+        let authors = vec!["Ada Lovelace <ada@example.com>".to_string()];
+        assert_eq!(
+            format_coauthors(&authors).unwrap(),
+            "Co-authored-by: Ada Lovelace <ada@example.com>"
+        );
+
+        let authors = vec![
+            " Ada Lovelace<ada@example.com> ".to_string(),
+            "grace <grace.hopper@navy.example.org>".to_string(),
+        ];
+        assert_eq!(
+            format_coauthors(&authors).unwrap(),
+            "Co-authored-by: Ada Lovelace <ada@example.com>\nCo-authored-by: grace <grace.hopper@navy.example.org>"
+        );
+        assert_eq!(format_coauthors(&[]).unwrap(), "");
+    }
+
+    #[test]
+    fn test_format_coauthors_malformed() {
+        for author in [
+            "ada@example.com",
+            "<ada@example.com>",
+            "Ada <ada>",
+            "Ada <ada@example>",
+            "Ada <ada@example.com",
+            "Ada <a b@example.com>",
+            "Ada <ada@example.com> extra",
+        ] {
+            assert!(
+                matches!(format_coauthors(&[author.to_string()]), Err(Error::User(_))),
+                "{}",
+                author
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_author() {
+        assert_eq!(
+            validate_author(" Ada Lovelace<ada@example.com> ").unwrap(),
+            "Ada Lovelace <ada@example.com>"
+        );
+        for author in [
+            "ada@example.com",
+            "Ada <ada>",
+            "Ada <ada@example.com> extra",
+        ] {
+            match validate_author(author) {
+                Err(Error::User(message)) => assert!(message.contains("Invalid author")),
+                other => panic!("expected a user error for {}, got {:?}", author, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_trailers() {
+        let trailers = "Co-authored-by: Ada <ada@example.com>";
+        assert_eq!(
+            append_trailers("feat: add login\n", trailers),
+            "feat: add login\n\nCo-authored-by: Ada <ada@example.com>"
+        );
+        assert_eq!(
+            append_trailers("feat: add login\n\nAdds a form.\n\nRefs: PROJ-7", trailers),
+            "feat: add login\n\nAdds a form.\n\nRefs: PROJ-7\nCo-authored-by: Ada <ada@example.com>"
+        );
+        // A paragraph mixing prose and `Key: value` lines isn't a trailer block
+        assert_eq!(
+            append_trailers("fix: parser\n\nHandles tabs.\nSee: #12", trailers),
+            "fix: parser\n\nHandles tabs.\nSee: #12\n\nCo-authored-by: Ada <ada@example.com>"
+        );
+        assert_eq!(append_trailers("fix: parser", ""), "fix: parser");
+    }
+
+    #[test]
+    fn test_commit_trailers() {
+        let model = "claude-sonnet-4";
+        assert_eq!(commit_trailers("", &[], model), "");
+        assert_eq!(
+            commit_trailers("", &[Trailer::GeneratedBy], model),
+            "Generated-by: autocommit (claude-sonnet-4)"
+        );
+
+        // Provenance trailers come after the co-authors, in the configured order
+        let coauthors = "Co-authored-by: Ada <ada@example.com>";
+        let trailers = commit_trailers(
+            coauthors,
+            &[Trailer::AssistedBy, Trailer::GeneratedBy],
+            model,
+        );
+        assert_eq!(
+            trailers,
+            "Co-authored-by: Ada <ada@example.com>\n\
+             Assisted-by: claude-sonnet-4\n\
+             Generated-by: autocommit (claude-sonnet-4)"
+        );
+        assert_eq!(
+            append_trailers("feat: add login", &trailers),
+            format!("feat: add login\n\n{}", trailers)
+        );
+    }
+
+    #[test]
+    fn test_path_pathspecs() {
+        let globs = vec!["src/**".to_string(), " ./docs/*.md ".to_string()];
+        assert_eq!(
+            path_pathspecs(&globs).unwrap(),
+            vec![":(glob)src/**", ":(glob)docs/*.md"]
+        );
+        assert!(matches!(
+            path_pathspecs(&[" ".to_string()]),
+            Err(Error::User(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_pathspecs_include_and_exclude() {
+        let includes = path_pathspecs(&["src/**".to_string()]).unwrap();
+        let globs = vec!["src/generated/**".to_string()];
+        let pathspecs = build_pathspecs(&includes, Exclusions::new(&globs));
+        assert_eq!(pathspecs[0], ":(glob)src/**");
+        assert!(!pathspecs.contains(&".".to_string()));
+        assert!(pathspecs.contains(&":!Cargo.lock".to_string()));
+        assert_eq!(pathspecs.last().unwrap(), ":(exclude,glob)src/generated/**");
+        assert_eq!(pathspecs.len(), EXCLUDED_LOCK_FILES.len() + 2);
+    }
+
+    #[test]
+    fn test_extension_pathspecs() {
+        let extensions = vec!["rs".to_string(), ".toml".to_string(), "*.md".to_string()];
+        assert_eq!(
+            extension_pathspecs(&extensions).unwrap(),
+            vec![":(glob)**/*.rs", ":(glob)**/*.toml", ":(glob)**/*.md"]
+        );
+    }
+
+    #[test]
+    fn test_extension_pathspecs_dotfiles_and_multipart() {
+        let extensions = vec![".gitignore".to_string(), "tar.gz".to_string()];
+        assert_eq!(
+            extension_pathspecs(&extensions).unwrap(),
+            vec![":(glob)**/*.gitignore", ":(glob)**/*.tar.gz"]
+        );
+    }
+
+    #[test]
+    fn test_extension_pathspecs_rejects_invalid() {
+        for ext in ["", ".", "src/rs", "r?"] {
+            let result = extension_pathspecs(&[ext.to_string()]);
+            assert!(matches!(result, Err(Error::User(_))), "accepted '{}'", ext);
+        }
+    }
+
+    #[test]
+    fn test_conflict_markers_detected() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,7 @@
+ fn main() {
++<<<<<<< HEAD
++    one();
++=======
++    two();
++>>>>>>> feature
+ }
+diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -1 +1,2 @@
++more
+";
+        assert!(has_conflict_markers(diff));
+        assert_eq!(conflict_marker_files(diff), vec!["src/lib.rs"]);
+    }
+
+    #[test]
+    fn test_conflict_markers_ignores_lookalikes() {
+        let diff = "diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -1,2 +1,6 @@
++Title
++=======
++    =======
++  <<<<<<< indented
++<<<<<<<<< too long
+-<<<<<<< HEAD
+ >>>>>>> context lines are not added
+";
+        assert!(!has_conflict_markers(diff));
+        assert!(conflict_marker_files(diff).is_empty());
+    }
+
+    #[test]
+    fn test_non_utf8_files_valid_replacement_char() {
+        let diff = "diff --git a/ok.txt b/ok.txt\n+bad byte: \u{FFFD}\n";
+        assert!(non_utf8_files(diff.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn test_non_utf8_files() {
+        let mut bytes = b"diff --git a/ok.txt b/ok.txt\n+caf\xc3\xa9\n".to_vec();
+        bytes.extend_from_slice(b"diff --git a/latin1.txt b/latin1.txt\n+caf\xe9\n+na\xefve\n");
+        assert_eq!(non_utf8_files(&bytes), ["latin1.txt"]);
+        assert!(non_utf8_files(b"diff --git a/ok.txt b/ok.txt\n+fine\n").is_empty());
+    }
+
+    #[test]
+    fn test_validate_scope() {
+        assert!(validate_scope("parser").is_ok());
+        assert!(validate_scope("api-client").is_ok());
+        for scope in ["", "my scope", "core)", "(core", "tab\tscope"] {
+            assert!(
+                matches!(validate_scope(scope), Err(Error::User(_))),
+                "accepted '{}'",
+                scope
+            );
+        }
+    }
+
+    #[test]
+    fn test_group_files_by_directory() {
+        let files: Vec<String> = [
+            "src/main.rs",
+            "README.md",
+            "docs/usage.md",
+            "src/git/diff.rs",
+            "services/api/src/main.rs",
+            "services/web/app.tsx",
+            "services/README.md",
+            "Cargo.toml",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let groups = group_files_by_directory(&files);
+        let names: Vec<&str> = groups.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            [
+                ".",
+                "docs",
+                "services",
+                "services/api",
+                "services/web",
+                "src"
+            ]
+        );
+        assert_eq!(groups[ROOT_GROUP], ["README.md", "Cargo.toml"]);
+        assert_eq!(groups["src"], ["src/main.rs", "src/git/diff.rs"]);
+        assert_eq!(groups["services"], ["services/README.md"]);
+        assert_eq!(groups["services/api"], ["services/api/src/main.rs"]);
+        assert!(group_files_by_directory(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_group_split_files() {
+        let strings =
+            |paths: &[&str]| -> Vec<String> { paths.iter().map(|s| s.to_string()).collect() };
+        let files = strings(&["src/main.rs", "web/app.tsx"]);
+        let excluded = strings(&["web/package-lock.json", "Cargo.lock", "src/generated.rs"]);
+        let groups = group_split_files(&files, &excluded);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["src"].files, ["src/main.rs"]);
+        // Cargo.lock's own group has nothing to describe, so it joins the first
+        assert_eq!(groups["src"].excluded, ["Cargo.lock", "src/generated.rs"]);
+        assert_eq!(
+            groups["web"].paths(),
+            ["web/app.tsx", "web/package-lock.json"]
+        );
+        assert!(group_split_files(&[], &excluded).is_empty());
+    }
+
+    #[test]
+    fn test_common_path_scope() {
+        let scope = |files: &[&str]| {
+            let files: Vec<String> = files.iter().map(|s| s.to_string()).collect();
+            common_path_scope(&files)
+        };
+        // Single directory
+        assert_eq!(
+            scope(&["docs/setup.md", "docs/usage.md"]),
+            Some("docs".to_string())
+        );
+        assert_eq!(scope(&["parser/lexer.rs"]), Some("parser".to_string()));
+        // Nested under a package directory
+        assert_eq!(
+            scope(&["services/api/src/main.rs", "services/api/tests/routes.rs"]),
+            Some("api".to_string())
+        );
+        assert_eq!(
+            scope(&["crates/autocommit-core/src/git.rs"]),
+            Some("autocommit-core".to_string())
+        );
+        assert_eq!(
+            scope(&["web/src/components/Button.tsx", "web/src/app.tsx"]),
+            Some("web".to_string())
+        );
+        assert_eq!(
+            scope(&[".github/workflows/ci.yml"]),
+            Some("github".to_string())
+        );
+        // Several top-level directories or packages
+        assert_eq!(scope(&["services/api/main.go", "web/index.ts"]), None);
+        assert_eq!(
+            scope(&["services/api/main.go", "services/web/index.ts"]),
+            None
+        );
+        assert_eq!(scope(&["services/README.md"]), None);
+        // Files at the root
+        assert_eq!(scope(&["README.md"]), None);
+        assert_eq!(scope(&["src/lib.rs", "Cargo.toml"]), None);
+        assert_eq!(scope(&[]), None);
+        // Directory names that can't be a scope
+        assert_eq!(scope(&["my docs/a.md"]), None);
+    }
+
+    fn default_types() -> Vec<String> {
+        CONVENTIONAL_TYPES.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_validate_conventional() {
+        for message in [
+            "feat: add login",
+            "fix(parser): handle empty input",
+            "refactor!: drop the v1 API",
+            "chore(deps)!: bump reqwest\n\nBREAKING CHANGE: needs Rust 1.70",
+        ] {
+            assert!(
+                validate_conventional(message, &default_types()).is_ok(),
+                "rejected '{}'",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_conventional_default_types() {
+        assert!(validate_conventional("feat: add login", &[]).is_ok());
+        let error = validate_conventional("wip: add login", &[]).unwrap_err();
+        assert!(error.to_string().contains("one of build, chore, ci"));
+    }
+
+    #[test]
+    fn test_validate_conventional_invalid() {
+        for message in [
+            "Updated the parser",
+            "",
+            "feature: add login",
+            "Feat: add login",
+            "feat:add login",
+            "feat: ",
+            "feat(): add login",
+            "feat(my scope): add login",
+            "\nfeat: add login",
+        ] {
+            assert!(
+                matches!(
+                    validate_conventional(message, &default_types()),
+                    Err(Error::User(_))
+                ),
+                "accepted '{}'",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_gitmoji_covers_conventional_types() {
+        for kind in CONVENTIONAL_TYPES {
+            assert!(gitmoji_for(kind, false).is_some(), "no emoji for {}", kind);
+            let code = gitmoji_for(kind, true).unwrap();
+            assert!(code.starts_with(':') && code.ends_with(':'));
+        }
+        assert_eq!(GITMOJI.len(), CONVENTIONAL_TYPES.len());
+        assert_eq!(gitmoji_for("feat", false), Some("✨"));
+        assert_eq!(gitmoji_for("fix", true), Some(":bug:"));
+        assert_eq!(gitmoji_for("wip", false), None);
+    }
+
+    #[test]
+    fn test_strip_gitmoji() {
+        assert_eq!(strip_gitmoji("✨ feat: add login"), "feat: add login");
+        assert_eq!(
+            strip_gitmoji(":sparkles: feat: add login"),
+            "feat: add login"
+        );
+        assert_eq!(
+            strip_gitmoji("♻️ refactor(parser): split"),
+            "refactor(parser): split"
+        );
+        assert_eq!(strip_gitmoji("feat: add login"), "feat: add login");
+        assert_eq!(strip_gitmoji("feat: :sparkles: x"), "feat: :sparkles: x");
+        assert_eq!(strip_gitmoji("::"), "::");
+    }
+
+    #[test]
+    fn test_validate_conventional_custom_types() {
+        let types = vec!["feat".to_string(), "wip".to_string()];
+        assert!(validate_conventional("wip: sketch the parser", &types).is_ok());
+        assert!(validate_conventional("feat(parser): add x", &types).is_ok());
+        assert!(matches!(
+            validate_conventional("fix: handle empty input", &types),
+            Err(Error::User(_))
+        ));
+    }
+
+    #[test]
+    fn test_group_commits_by_type() {
+        let commits = "feat(api): add the users endpoint\n\
+                       fix: handle empty input\n\
+                       Merge branch 'main' into release\n\
+                       feat!: drop the v1 routes\n\
+                       wip: sketch the parser\n\
+                       note: remember the docs\n\
+                       \n\
+                       Bump version\n";
+        let groups = group_commits_by_type(commits, &[]);
+        assert_eq!(
+            groups["feat"],
+            [
+                "api: add the users endpoint",
+                "BREAKING: drop the v1 routes"
+            ]
+        );
+        assert_eq!(groups["fix"], ["handle empty input"]);
+        assert_eq!(
+            groups[OTHER_COMMITS],
+            [
+                "Merge branch 'main' into release",
+                "wip: sketch the parser",
+                "note: remember the docs",
+                "Bump version"
+            ]
+        );
+        assert_eq!(groups.len(), 3);
+
+        assert_eq!(
+            format_commit_groups(&groups),
+            "Features:\n- api: add the users endpoint\n- BREAKING: drop the v1 routes\n\n\
+             Bug Fixes:\n- handle empty input\n\n\
+             Other:\n- Merge branch 'main' into release\n- wip: sketch the parser\n\
+             - note: remember the docs\n- Bump version"
+        );
+        assert!(group_commits_by_type("", &[]).is_empty());
+
+        // Configured types replace the defaults, and get their own heading
+        let types: Vec<String> = ["feat", "wip"].iter().map(|t| t.to_string()).collect();
+        let groups = group_commits_by_type(commits, &types);
+        assert_eq!(groups["wip"], ["sketch the parser"]);
+        assert!(!groups.contains_key("fix"));
+        assert!(format_commit_groups(&groups).contains("\n\nwip:\n- sketch the parser\n\n"));
+    }
+
+    #[test]
+    fn test_ignore_patterns_negation() {
+        let ignore = IgnorePatterns::parse("# noise\n*.snap\n!keep.snap\n\n").unwrap();
+        assert!(ignore.is_ignored("a.snap"));
+        assert!(ignore.is_ignored("src/__tests__/b.snap"));
+        assert!(!ignore.is_ignored("keep.snap"));
+        assert!(!ignore.is_ignored("src/keep.snap"));
+        assert!(!ignore.is_ignored("src/main.rs"));
+
+        // Later patterns win
+        let ignore = IgnorePatterns::parse("!keep.snap\n*.snap").unwrap();
+        assert!(ignore.is_ignored("keep.snap"));
+    }
+
+    #[test]
+    fn test_ignore_patterns_directories() {
+        let ignore =
+            IgnorePatterns::parse("testdata/\n/build\ndocs/**/*.png\nlogs/\n!logs/keep.txt")
+                .unwrap();
+        assert!(ignore.is_ignored("testdata/input.json"));
+        assert!(ignore.is_ignored("crates/core/testdata/input.json"));
+        assert!(!ignore.is_ignored("testdata"));
+        assert!(ignore.is_ignored("build/out.js"));
+        assert!(ignore.is_ignored("build"));
+        assert!(!ignore.is_ignored("src/build/out.js"));
+        assert!(ignore.is_ignored("docs/img/logo.png"));
+        assert!(!ignore.is_ignored("img/logo.png"));
+        // A file in an ignored directory can't be re-included
+        assert!(ignore.is_ignored("logs/keep.txt"));
+    }
+
+    #[test]
+    fn test_ignore_patterns_invalid() {
+        assert!(matches!(
+            IgnorePatterns::parse("src/[a"),
+            Err(Error::User(_))
+        ));
+    }
+
+    #[test]
+    fn test_ignore_patterns_filter_diff() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+fn a() {}\ndiff --git a/snap/x.snap b/snap/x.snap\n+snapshot\n";
+        let ignore = IgnorePatterns::parse("*.snap").unwrap();
+        assert_eq!(
+            ignore.filter_diff(diff),
+            "diff --git a/src/lib.rs b/src/lib.rs\n+fn a() {}\n"
+        );
+        assert_eq!(
+            ignore.filter_files(vec!["src/lib.rs".to_string(), "snap/x.snap".to_string()]),
+            vec!["src/lib.rs"]
+        );
+        assert_eq!(IgnorePatterns::default().filter_diff(diff), diff);
+    }
+
+    #[tokio::test]
+    async fn test_load_ignore_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_ignore_patterns(dir.path()).await.unwrap().is_empty());
+
+        std::fs::write(dir.path().join(IGNORE_FILE), "*.snap\n").unwrap();
+        let ignore = load_ignore_patterns(dir.path()).await.unwrap();
+        assert!(ignore.is_ignored("a.snap"));
+    }
+
+    #[test]
+    fn test_filter_lock_files_removes_lock_files() {
+        let files = vec![
+            "src/main.rs".to_string(),
+            "package-lock.json".to_string(),
+            "Cargo.lock".to_string(),
+            "src/lib.rs".to_string(),
+            "yarn.lock".to_string(),
+        ];
+
+        let filtered = filter_lock_files(files, &[]);
+        assert_eq!(filtered, vec!["src/main.rs", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn test_filter_excluded_files() {
+        let files = vec![
+            "src/main.rs".to_string(),
+            "Cargo.lock".to_string(),
+            "web/yarn.lock".to_string(),
+            "schema.graphql".to_string(),
+        ];
+        let (kept, removed) = filter_excluded_files(files.clone(), Exclusions::default());
+        assert_eq!(kept, vec!["src/main.rs", "schema.graphql"]);
+        assert_eq!(removed, 2);
+
+        let globs = vec!["*.graphql".to_string()];
+        let (kept, removed) = filter_excluded_files(files.clone(), Exclusions::new(&globs));
+        assert_eq!(kept, vec!["src/main.rs"]);
+        assert_eq!(removed, 3);
+
+        // --include-lockfiles keeps lock files but not the configured globs
+        let exclusions = Exclusions::new(&globs).include_lock_files(true);
+        let (kept, removed) = filter_excluded_files(files, exclusions);
+        assert_eq!(kept, vec!["src/main.rs", "Cargo.lock", "web/yarn.lock"]);
+        assert_eq!(removed, 1);
+
+        let (_, removed) =
+            filter_excluded_files(vec!["README.md".to_string()], Exclusions::default());
+        assert_eq!(removed, 0);
+    }
+
+    #[test]
+    fn test_filter_lock_files_with_paths() {
+        let files = vec![
+            "src/main.rs".to_string(),
+            "frontend/package-lock.json".to_string(),
+            "backend/Cargo.lock".to_string(),
+            "src/lib.rs".to_string(),
+        ];
+
+        let filtered = filter_lock_files(files, &[]);
+        assert_eq!(filtered, vec!["src/main.rs", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn test_filter_lock_files_preserves_non_lock_files() {
+        let files = vec![
+            "src/main.rs".to_string(),
+            "README.md".to_string(),
+            "config.json".to_string(),
+        ];
+
+        let filtered = filter_lock_files(files.clone(), &[]);
+        assert_eq!(filtered, files);
+    }
+
+    #[test]
+    fn test_filter_lock_files_extra_exclusions() {
+        let files = vec![
+            "src/main.rs".to_string(),
+            "schema.graphql".to_string(),
+            "api/schema.graphql".to_string(),
+            "web/src/api.generated.ts".to_string(),
+            "web/src/api.ts".to_string(),
+            "Cargo.lock".to_string(),
+        ];
+        let extra = vec!["schema.graphql".to_string(), "*.generated.ts".to_string()];
+
+        let filtered = filter_lock_files(files, &extra);
+        assert_eq!(filtered, vec!["src/main.rs", "web/src/api.ts"]);
+    }
+
+    #[test]
+    fn test_filter_lock_files_directory_globs() {
+        let files = vec![
+            "dist/app.js".to_string(),
+            "dist/assets/logo.svg".to_string(),
+            "packages/ui/dist/index.js".to_string(),
+            "src/dist.rs".to_string(),
+            "vendor/lib.lock".to_string(),
+        ];
+        let extra = vec!["dist/**".to_string(), "*.lock".to_string()];
+
+        let filtered = filter_lock_files(files, &extra);
+        assert_eq!(filtered, vec!["packages/ui/dist/index.js", "src/dist.rs"]);
+
+        let extra = vec!["dist/*".to_string()];
+        let filtered = filter_lock_files(
+            vec![
+                "dist/app.js".to_string(),
+                "dist/assets/logo.svg".to_string(),
+            ],
+            &extra,
+        );
+        assert_eq!(filtered, vec!["dist/assets/logo.svg"]);
+    }
+
+    #[test]
+    fn test_diff_preview_line_cap() {
+        let diff = "diff --git a/a.rs b/a.rs\n+one\n+two\n+three\n";
+        assert_eq!(diff_preview(diff, 10), diff.trim_end());
+        assert_eq!(diff_preview(diff, 4), diff.trim_end());
+        assert_eq!(
+            diff_preview(diff, 2),
+            "diff --git a/a.rs b/a.rs\n+one\n... 2 more lines not shown"
+        );
+        assert_eq!(diff_preview(diff, 0), "... 4 more lines not shown");
+        assert_eq!(diff_preview("", 5), "");
+    }
+
+    #[test]
+    fn test_truncate_diff_no_truncation() {
+        let diff = "This is a short diff";
+        let (truncated, was_truncated) = truncate_diff(diff, 100);
+        assert_eq!(truncated, diff);
+        assert!(!was_truncated);
+    }
+
+    #[test]
+    fn test_truncate_diff_with_truncation() {
+        let diff = "a".repeat(1000);
+        let (truncated, was_truncated) = truncate_diff(&diff, 100);
+        assert!(was_truncated);
+        assert!(truncated.starts_with(&"a".repeat(100)));
+        assert!(truncated.contains("900 characters omitted"));
+    }
+
+    #[test]
+    fn test_floor_char_boundary() {
+        let s = "aé€";
+        assert_eq!(floor_char_boundary(s, 0), 0);
+        assert_eq!(floor_char_boundary(s, 2), 1);
+        assert_eq!(floor_char_boundary(s, 3), 3);
+        assert_eq!(floor_char_boundary(s, 5), 3);
+        assert_eq!(floor_char_boundary(s, 100), s.len());
+    }
+
+    #[test]
+    fn test_truncate_diff_multibyte_boundary() {
+        // "é" is two bytes and straddles the cutoff at byte 10
+        let diff = format!("{}é{}", "a".repeat(9), "b".repeat(20));
+        let (truncated, was_truncated) = truncate_diff(&diff, 10);
+        assert!(was_truncated);
+        assert!(truncated.starts_with(&"a".repeat(9)));
+        assert!(!truncated.contains('é'));
+        assert!(truncated.contains("22 characters omitted"));
+
+        // Emoji are four bytes
+        let diff = format!("+let s = \"{}\";\n", "🎉".repeat(10));
+        for max_size in 0..diff.len() {
+            truncate_diff(&diff, max_size);
+        }
+    }
+
+    #[test]
+    fn test_truncate_diff_exact_size() {
+        let diff = "a".repeat(100);
+        let (truncated, was_truncated) = truncate_diff(&diff, 100);
+        assert_eq!(truncated, diff);
+        assert!(!was_truncated);
+    }
+
+    fn file_diff(name: &str, lines: usize) -> String {
+        let mut diff = format!(
+            "diff --git a/{name} b/{name}\nindex 1111111..2222222 100644\n--- a/{name}\n+++ b/{name}\n@@ -1,{lines} +1,{lines} @@\n",
+            name = name,
+            lines = lines
+        );
+        for i in 0..lines {
+            diff.push_str(&format!("+line {} of {}\n", i, name));
+        }
+        diff
+    }
+
+    #[test]
+    fn test_split_diff_files() {
+        let diff = format!("{}{}", file_diff("a.rs", 2), file_diff("b.rs", 3));
+        let files = split_diff_files(&diff);
+        assert_eq!(files.len(), 2);
+        assert!(files[0].starts_with("diff --git a/a.rs"));
+        assert!(files[1].starts_with("diff --git a/b.rs"));
+        assert_eq!(files.concat(), diff);
+    }
+
+    #[test]
+    fn test_strip_binary_diffs() {
+        let text = file_diff("src/main.rs", 2);
+        let binary = "diff --git a/logo.png b/logo.png
+index 1111111..2222222 100644
+Binary files a/logo.png and b/logo.png differ
+";
+        let patch = "diff --git a/icon.ico b/icon.ico
+new file mode 100644
+index 0000000..3333333
+GIT binary patch
+literal 1024
+zcmZ?wbhEHbRA5kG;K;y0tT~~zfbpUf
+";
+        let diff = format!("{}{}{}", binary, text, patch);
+
+        let stripped = strip_binary_diffs(&diff);
+        assert_eq!(
+            stripped,
+            format!(
+                "diff --git a/logo.png b/logo.png
+index 1111111..2222222 100644
+(binary file changed)
+{}diff --git a/icon.ico b/icon.ico
+new file mode 100644
+index 0000000..3333333
+(binary file changed)
+",
+                text
+            )
+        );
+    }
+
+    #[test]
+    fn test_strip_binary_diffs_text_only() {
+        let diff = format!("{}{}", file_diff("a.rs", 2), file_diff("b.rs", 3));
+        assert_eq!(strip_binary_diffs(&diff), diff);
+        assert_eq!(strip_binary_diffs(""), "");
+    }
+
+    #[test]
+    fn test_condense_commits() {
+        let short = "feat: add login\n    Adds the form.\n\nfix: typo\n";
+        assert_eq!(condense_commits(short, 1000), short);
+
+        let long_body: String = (0..50)
+            .map(|i| format!("    Detail line {} about the parser rewrite\n", i))
+            .collect();
+        let commits = format!(
+            "refactor(parser): rewrite the lexer\n{}\nfix: handle empty input\n    One line.\n\ndocs: update README\n",
+            long_body
+        );
+        let condensed = condense_commits(&commits, 300);
+        assert!(condensed.len() <= 300, "{}", condensed);
+        for subject in [
+            "refactor(parser): rewrite the lexer",
+            "fix: handle empty input",
+            "docs: update README",
+        ] {
+            assert!(condensed.lines().any(|line| line == subject), "{}", subject);
+        }
+        // The short body survives, the long one is cut and marked
+        assert!(condensed.contains("fix: handle empty input\n    One line."));
+        assert!(condensed.contains("    Detail line 0 about the parser rewrite"));
+        assert!(!condensed.contains("Detail line 49"));
+        assert!(condensed.contains("    [...]"));
+
+        // Subjects stay even when they alone exceed the budget
+        let condensed = condense_commits(&commits, 10);
+        assert_eq!(
+            condensed,
+            "refactor(parser): rewrite the lexer\n    [...]\n\
+             fix: handle empty input\n    [...]\n\
+             docs: update README"
+        );
+    }
+
+    #[test]
+    fn test_truncate_diff_balanced_no_truncation() {
+        let diff = file_diff("a.rs", 3);
+        let (truncated, was_truncated) = truncate_diff_balanced(&diff, diff.len());
+        assert_eq!(truncated, diff);
+        assert!(!was_truncated);
+    }
+
+    #[test]
+    fn test_truncate_diff_balanced_keeps_every_file() {
+        let names = ["huge.rs", "small.rs", "medium.rs", "tiny.rs"];
+        let diff = format!(
+            "{}{}{}{}",
+            file_diff(names[0], 2000),
+            file_diff(names[1], 5),
+            file_diff(names[2], 40),
+            file_diff(names[3], 1)
+        );
+        let budget = 2000;
+
+        let (truncated, was_truncated) = truncate_diff_balanced(&diff, budget);
+        assert!(was_truncated);
+        assert!(
+            truncated.len() <= budget,
+            "{} > {}",
+            truncated.len(),
+            budget
+        );
+        for name in names {
+            assert!(truncated.contains(&format!("diff --git a/{name} b/{name}\n")));
+            assert!(truncated.contains(&format!("+++ b/{}\n", name)));
+        }
+
+        // Small files fit their share and are kept whole
+        assert!(truncated.contains(&file_diff("small.rs", 5)));
+        assert!(truncated.contains(&file_diff("tiny.rs", 1)));
+        // The huge file still gets some of its hunk before being cut
+        assert!(truncated.contains("+line 0 of huge.rs\n"));
+        assert!(truncated.contains("characters omitted)\n"));
+    }
+
+    #[test]
+    fn test_truncate_diff_balanced_many_files() {
+        let diff: String = (0..100)
+            .map(|i| file_diff(&format!("m{}.rs", i), 30))
+            .collect();
+
+        // Too many files for their full headers: only the file names stay
+        let budget = 4000;
+        let (truncated, was_truncated) = truncate_diff_balanced(&diff, budget);
+        assert!(was_truncated);
+        assert!(
+            truncated.len() <= budget,
+            "{} > {}",
+            truncated.len(),
+            budget
+        );
+        for i in 0..100 {
+            let name = format!("m{}.rs", i);
+            assert!(truncated.contains(&format!("diff --git a/{name} b/{name}\n")));
+        }
+
+        // Too many even for the names: as many as fit, then a count
+        let budget = 1000;
+        let (truncated, _) = truncate_diff_balanced(&diff, budget);
+        assert!(
+            truncated.len() <= budget,
+            "{} > {}",
+            truncated.len(),
+            budget
+        );
+        assert!(truncated.starts_with("diff --git a/m0.rs b/m0.rs\n"));
+        assert!(truncated.ends_with("more files omitted)\n"));
+
+        // Headers fit, so every file keeps its header and some of its lines
+        let budget = 20_000;
+        let (truncated, _) = truncate_diff_balanced(&diff, budget);
+        assert!(
+            truncated.len() <= budget,
+            "{} > {}",
+            truncated.len(),
+            budget
+        );
+        assert!(truncated.contains("+++ b/m99.rs\n@@"));
+        assert!(truncated.contains("+line 0 of m99.rs\n"));
+    }
+
+    #[test]
+    fn test_truncate_file_diff_cuts_at_line_boundary() {
+        let file = file_diff("a.rs", 100);
+        let truncated = truncate_file_diff(&file, 300);
+        assert!(truncated.len() <= 300);
+        let kept = truncated.split("... (").next().unwrap();
+        assert!(kept.ends_with('\n'));
+        assert!(file.starts_with(kept));
+    }
+
+    #[test]
+    fn test_changeset_bump() {
+        assert_eq!(changeset_bump("feat: add login form"), "minor");
+        assert_eq!(changeset_bump("feat(auth): add login form"), "minor");
+        assert_eq!(changeset_bump("fix: handle empty input"), "patch");
+        assert_eq!(changeset_bump("feat!: drop legacy API"), "major");
+        assert_eq!(
+            changeset_bump("refactor: rework config\n\nBREAKING CHANGE: renamed keys"),
+            "major"
+        );
+    }
+
+    #[test]
+    fn test_changeset_file_name() {
+        assert_eq!(
+            changeset_file_name("feat(auth): Add login form"),
+            "add-login-form.md"
+        );
+        assert_eq!(
+            changeset_file_name("fix: handle `null` values!"),
+            "handle-null-values.md"
+        );
+        assert_eq!(changeset_file_name("!!!"), "autocommit-change.md");
+    }
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(
+            package_name(r#"{"name": "my-app", "version": "1.0.0"}"#),
+            Some("my-app".to_string())
+        );
+        assert_eq!(package_name(r#"{"private": true}"#), None);
+        assert_eq!(package_name("not json"), None);
+    }
+
+    #[test]
+    fn test_format_changeset() {
+        assert_eq!(
+            format_changeset(Some("my-app"), "minor", "Added a login form.\n"),
+            "---\n\"my-app\": minor\n---\n\nAdded a login form.\n"
+        );
+        assert_eq!(
+            format_changeset(None, "patch", "Fixed a crash."),
+            "---\n---\n\nFixed a crash.\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_uncategorized_entry_existing_section() {
+        let changelog =
+            "# Changelog\n\n## [Unreleased]\n\n- Older entry\n\n## 1.0.0\n\n- Initial release\n";
+        let updated = insert_changelog_entry(changelog, None, "New entry").unwrap();
+        assert_eq!(
+            updated,
+            "# Changelog\n\n## [Unreleased]\n\n- New entry\n- Older entry\n\n## 1.0.0\n\n- Initial release\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_uncategorized_entry_missing_section() {
+        let changelog = "# Changelog\n\n## 1.0.0\n\n- Initial release\n";
+        let updated = insert_changelog_entry(changelog, None, "New entry").unwrap();
+        assert_eq!(
+            updated,
+            "# Changelog\n\n## [Unreleased]\n\n- New entry\n\n## 1.0.0\n\n- Initial release\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_uncategorized_entry_empty_changelog() {
+        assert_eq!(
+            insert_changelog_entry("", None, "New entry").unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n- New entry\n"
+        );
+        assert_eq!(
+            insert_changelog_entry("## Unreleased\n\n- New entry\n", None, "new entry"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_insert_changelog_entry_existing_section() {
+        let changelog = "# Changelog\n\n## [Unreleased]\n\n### Fixed\n\n- Older fix\n\n## [1.0.0] - 2024-01-01\n\n### Added\n\n- Initial release\n";
+        assert_eq!(
+            insert_changelog_entry(changelog, Some(ChangelogCategory::Fixed), "New fix").unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n### Fixed\n\n- New fix\n- Older fix\n\n## [1.0.0] - 2024-01-01\n\n### Added\n\n- Initial release\n"
+        );
+        // A new subsection goes in Keep a Changelog order, not under the release
+        assert_eq!(
+            insert_changelog_entry(changelog, Some(ChangelogCategory::Added), "New feature").unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n### Added\n\n- New feature\n\n### Fixed\n\n- Older fix\n\n## [1.0.0] - 2024-01-01\n\n### Added\n\n- Initial release\n"
+        );
+        assert_eq!(
+            insert_changelog_entry(changelog, Some(ChangelogCategory::Removed), "Old flag").unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n### Removed\n\n- Old flag\n\n### Fixed\n\n- Older fix\n\n## [1.0.0] - 2024-01-01\n\n### Added\n\n- Initial release\n"
+        );
+        assert_eq!(
+            insert_changelog_entry(
+                "## [Unreleased]\n\n### Added\n\n- Login\n",
+                Some(ChangelogCategory::Fixed),
+                "Crash"
+            )
+            .unwrap(),
+            "## [Unreleased]\n\n### Added\n\n- Login\n\n### Fixed\n\n- Crash\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_changelog_entry_missing_section() {
+        assert_eq!(
+            insert_changelog_entry(
+                "# Changelog\n\n## [1.0.0]\n\n- Initial release\n",
+                Some(ChangelogCategory::Added),
+                "Login form"
+            )
+            .unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n### Added\n\n- Login form\n\n## [1.0.0]\n\n- Initial release\n"
+        );
+        assert_eq!(
+            insert_changelog_entry("", Some(ChangelogCategory::Fixed), "Crash").unwrap(),
+            "# Changelog\n\n## [Unreleased]\n\n### Fixed\n\n- Crash\n"
+        );
+        assert_eq!(
+            insert_changelog_entry(
+                "# Changelog\n\nNotes.\n\n",
+                Some(ChangelogCategory::Fixed),
+                "Crash"
+            )
+            .unwrap(),
+            "# Changelog\n\nNotes.\n\n## [Unreleased]\n\n### Fixed\n\n- Crash\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_changelog_entry_skips_duplicates() {
+        let changelog = "## [Unreleased]\n\n### Fixed\n\n- Fixed a crash on start\n\n## [1.0.0]\n\n- Login form\n";
+        assert_eq!(
+            insert_changelog_entry(
+                changelog,
+                Some(ChangelogCategory::Fixed),
+                "fixed a crash on start"
+            ),
+            None
+        );
+        assert_eq!(
+            insert_changelog_entry(
+                changelog,
+                Some(ChangelogCategory::Changed),
+                "Fixed a crash on start"
+            ),
+            None
+        );
+        // Entries of past releases don't count
+        assert!(
+            insert_changelog_entry(changelog, Some(ChangelogCategory::Added), "Login form")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_parse_categorized_entry() {
+        assert_eq!(
+            parse_categorized_entry("Fixed: Fixed a crash on start.\n"),
+            (
+                ChangelogCategory::Fixed,
+                "Fixed a crash on start.".to_string()
+            )
+        );
+        assert_eq!(
+            parse_categorized_entry("added: Login form."),
+            (ChangelogCategory::Added, "Login form.".to_string())
+        );
+        assert_eq!(
+            parse_categorized_entry("Note: the login form moved."),
+            (
+                ChangelogCategory::Changed,
+                "Note: the login form moved.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_extract_issue_ref() {
+        let pattern = Regex::new(r"[A-Z][A-Z0-9]+-[0-9]+|(?:^|/)([0-9]+)-").unwrap();
+        let cases = [
+            ("feature/PROJ-123-foo", Some("PROJ-123")),
+            ("PROJ-7", Some("PROJ-7")),
+            ("bugfix/AB2-99_login", Some("AB2-99")),
+            ("123-fix-bug", Some("#123")),
+            ("fix/456-typo", Some("#456")),
+            ("main", None),
+            ("release-2024", None),
+        ];
+        for (branch, expected) in cases {
+            assert_eq!(
+                extract_issue_ref(branch, &pattern).as_deref(),
+                expected,
+                "branch '{}'",
+                branch
+            );
+        }
+    }
+
+    #[test]
+    fn test_append_issue_ref() {
+        assert_eq!(
+            append_issue_ref("feat: add login\n", "PROJ-123"),
+            "feat: add login\n\nRefs: PROJ-123"
+        );
+        assert_eq!(
+            append_issue_ref("fix: crash\n\nExplain.", "#42"),
+            "fix: crash\n\nExplain.\n\nRefs: #42"
+        );
+        assert_eq!(
+            append_issue_ref("feat(PROJ-123): add login", "PROJ-123"),
+            "feat(PROJ-123): add login"
+        );
+    }
+
+    #[test]
+    fn test_wrap_body_paragraphs() {
+        let body = "This change makes the parser tolerate empty input, which used to panic when a file was truncated.\n\nShort paragraph.";
+        assert_eq!(
+            wrap_body(body, 40),
+            "This change makes the parser tolerate\nempty input, which used to panic when a\nfile was truncated.\n\nShort paragraph."
+        );
+        // Lines that were wrapped too short are joined back up
+        assert_eq!(wrap_body("one\ntwo\nthree\n", 72), "one two three\n");
+    }
+
+    #[test]
+    fn test_wrap_body_keeps_bullets() {
+        let body = "- Retry the request when the API is overloaded instead of failing\n- Log retries\n1. Numbered items work too";
+        assert_eq!(
+            wrap_body(body, 30),
+            "- Retry the request when the\n  API is overloaded instead of\n  failing\n- Log retries\n1. Numbered items work too"
+        );
+    }
+
+    #[test]
+    fn test_wrap_body_leaves_code_untouched() {
+        let body = "Example:\n\n```\nlet a_very_long_line_of_code = compute(first_argument, second_argument);\n```\n\n    indented code that is also quite long and must not be rewrapped at all\n";
+        assert_eq!(wrap_body(body, 30), body);
+    }
+
+    #[test]
+    fn test_wrap_body_keeps_trailers_and_headings() {
+        assert_eq!(
+            wrap_commit_message(
+                "fix: x\n\nBody.\n\nSigned-off-by: A <a@x.io>\nCo-authored-by: B <b@x.io>\n",
+                72
+            ),
+            "fix: x\n\nBody.\n\nSigned-off-by: A <a@x.io>\nCo-authored-by: B <b@x.io>\n"
+        );
+        let body = "## Changes\nThe parser\nno longer panics.\nBREAKING CHANGE: empty input is an error\nRefs: #12\n";
+        assert_eq!(
+            wrap_body(body, 72),
+            "## Changes\nThe parser no longer panics.\nBREAKING CHANGE: empty input is an error\nRefs: #12\n"
+        );
+    }
+
+    #[test]
+    fn test_wrap_commit_message_keeps_subject() {
+        let subject = "feat(parser): tolerate empty input in every entry point of the parser";
+        let message = format!(
+            "{}\n\nEmpty files used to panic the parser before this change.",
+            subject
+        );
+        assert_eq!(
+            wrap_commit_message(&message, 30),
+            format!(
+                "{}\n\nEmpty files used to panic the\nparser before this change.",
+                subject
+            )
+        );
+        assert_eq!(wrap_commit_message(subject, 30), subject);
+    }
+
+    #[test]
+    fn test_enforce_subject_length_under_limit() {
+        let subject = "fix(git): handle detached HEAD";
+        assert_eq!(enforce_subject_length(subject, 72), subject);
+    }
+
+    #[test]
+    fn test_enforce_subject_length_exact_limit() {
+        let subject = "fix(git): handle detached HEAD";
+        assert_eq!(enforce_subject_length(subject, subject.len()), subject);
+    }
+
+    #[test]
+    fn test_enforce_subject_length_over_limit() {
+        let subject = "feat(parser): tolerate empty input in every entry point of the parser";
+        let shortened = enforce_subject_length(subject, 40);
+        assert_eq!(shortened, "feat(parser): tolerate empty input in...");
+        assert!(shortened.chars().count() <= 40);
+        // Trailing punctuation goes before the ellipsis
+        assert_eq!(
+            enforce_subject_length("fix: close files, sockets and pipes", 22),
+            "fix: close files..."
+        );
+        // A single long word is cut mid-word, on a char boundary
+        assert_eq!(enforce_subject_length("ééééééééé", 6), "ééé...");
+        assert_eq!(enforce_subject_length("feat: add", 2), "fe");
+    }
+
+    #[test]
+    fn test_render_prompt_template() {
+        assert_eq!(
+            render_prompt_template("Use gitmoji.\n\n{diff}\n", "+added").unwrap(),
+            "Use gitmoji.\n\n+added\n"
+        );
+    }
+
+    #[test]
+    fn test_render_prompt_template_missing_placeholder() {
+        assert!(matches!(
+            render_prompt_template("Use gitmoji.", "+added"),
+            Err(Error::Env(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_ui_file() {
+        assert!(is_ui_file("src/components/Button.tsx"));
+        assert!(is_ui_file("app/App.vue"));
+        assert!(is_ui_file("styles/main.CSS"));
+        assert!(!is_ui_file("src/main.rs"));
+        assert!(!is_ui_file("Makefile"));
+    }
+
+    #[test]
+    fn test_needs_screenshots_section() {
+        let ui = vec!["src/main.rs".to_string(), "web/Button.tsx".to_string()];
+        let backend = vec!["src/main.rs".to_string()];
+
+        assert!(needs_screenshots_section(ScreenshotsMode::Auto, &ui));
+        assert!(!needs_screenshots_section(ScreenshotsMode::Auto, &backend));
+        assert!(needs_screenshots_section(ScreenshotsMode::Always, &backend));
+        assert!(!needs_screenshots_section(ScreenshotsMode::Never, &ui));
+    }
+
+    #[test]
+    fn test_ensure_screenshots_section_appends() {
+        let body = "## Summary\nNew button\n";
+        assert_eq!(
+            ensure_screenshots_section(body),
+            "## Summary\nNew button\n\n## Screenshots\n\n<!-- add screenshots here -->"
+        );
+    }
+
+    #[test]
+    fn test_ensure_screenshots_section_keeps_existing() {
+        let body = "## Summary\nNew button\n\n### Screenshots\n![before](x.png)";
+        assert_eq!(ensure_screenshots_section(body), body);
+    }
+
+    #[test]
+    fn test_diff_hash_stable() {
+        // Known FNV-1a values, so the hash doesn't drift between releases
+        assert_eq!(diff_hash(""), "cbf29ce484222325");
+        assert_eq!(diff_hash("a"), "af63dc4c8601ec8c");
+        assert_eq!(diff_hash("+fn main() {}"), diff_hash("+fn main() {}"));
+        assert_ne!(diff_hash("+fn main() {}"), diff_hash("+fn main() {} "));
+    }
+
+    fn sample_cache() -> PrCache {
+        PrCache {
+            branch: "feature/login".to_string(),
+            head: "abc123".to_string(),
+            diff_hash: diff_hash("+login"),
+            created_at: 1_000,
+            content: PRContent {
+                title: "feat: add login".to_string(),
+                body: "## Summary".to_string(),
+                needs_clarification: None,
+                clarification_question: None,
+                extra: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_pr_cache_matches() {
+        let cache = sample_cache();
+        let hash = diff_hash("+login");
+        assert!(cache.matches("feature/login", "abc123", &hash, 1_000));
+        assert!(cache.matches("feature/login", "abc123", &hash, 1_000 + PR_CACHE_TTL_SECS));
+
+        assert!(!cache.matches("feature/other", "abc123", &hash, 1_000));
+        assert!(!cache.matches("feature/login", "def456", &hash, 1_000));
+        assert!(!cache.matches("feature/login", "abc123", &diff_hash("+logout"), 1_000));
+        assert!(!cache.matches("feature/login", "abc123", &hash, 1_001 + PR_CACHE_TTL_SECS));
+    }
+
+    #[tokio::test]
+    async fn test_pr_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("autopr-cache.json");
+        assert_eq!(load_pr_cache(&path).await, None);
+
+        let cache = sample_cache();
+        store_pr_cache(&path, &cache).await.unwrap();
+        assert_eq!(load_pr_cache(&path).await, Some(cache));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert_eq!(load_pr_cache(&path).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_load_pr_template_discovery() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            load_pr_template(dir.path(), None, false).await.unwrap(),
+            None
+        );
+
+        std::fs::write(dir.path().join("pull_request_template.md"), "root").unwrap();
+        std::fs::create_dir(dir.path().join(".github")).unwrap();
+        std::fs::write(
+            dir.path().join(".github/PULL_REQUEST_TEMPLATE.md"),
+            "## Summary",
+        )
+        .unwrap();
+        assert_eq!(
+            load_pr_template(dir.path(), None, false).await.unwrap(),
+            Some("## Summary".to_string())
+        );
+        assert_eq!(
+            load_pr_template(dir.path(), None, true).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_pr_template_explicit_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join(".github/PULL_REQUEST_TEMPLATE")).unwrap();
+        std::fs::write(
+            dir.path().join(".github/PULL_REQUEST_TEMPLATE.md"),
+            "default",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join(".github/PULL_REQUEST_TEMPLATE/bugfix.md"),
+            "## Bug",
+        )
+        .unwrap();
+
+        let bugfix = Path::new(".github/PULL_REQUEST_TEMPLATE/bugfix.md");
+        assert_eq!(
+            load_pr_template(dir.path(), Some(bugfix), false)
+                .await
+                .unwrap(),
+            Some("## Bug".to_string())
+        );
+
+        let missing = Path::new(".github/PULL_REQUEST_TEMPLATE/feature.md");
+        let err = load_pr_template(dir.path(), Some(missing), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::User(msg) if msg.contains("feature.md")));
+    }
+
+    #[test]
+    fn test_collapse_generated() {
+        let minified = format!(
+            "diff --git a/static/app.js b/static/app.js\n\
+             index 1111111..2222222 100644\n\
+             --- a/static/app.js\n\
+             +++ b/static/app.js\n\
+             @@ -1 +1 @@\n\
+             -var a=1;\n\
+             +{}\n",
+            "x".repeat(GENERATED_MAX_LINE_LENGTH + 1)
+        );
+        let normal = "diff --git a/src/lib.rs b/src/lib.rs\n\
+             index 3333333..4444444 100644\n\
+             --- a/src/lib.rs\n\
+             +++ b/src/lib.rs\n\
+             @@ -1 +1,2 @@\n \
+             pub fn a() {}\n\
+             +pub fn b() {}\n";
+        let diff = format!("{}{}", minified, normal);
+
+        let collapsed = collapse_generated(&diff, &GeneratedOpts::default());
+        assert_eq!(
+            collapsed,
+            format!(
+                "diff --git a/static/app.js b/static/app.js\n\
+                 index 1111111..2222222 100644\n\
+                 --- a/static/app.js\n\
+                 +++ b/static/app.js\n\
+                 (generated file changed, 1 lines added, 1 removed)\n{}",
+                normal
+            )
+        );
+    }
+
+    #[test]
+    fn test_collapse_generated_patterns() {
+        let diff = "diff --git a/dist/app.js.map b/dist/app.js.map\n\
+             --- a/dist/app.js.map\n\
+             +++ b/dist/app.js.map\n\
+             @@ -1 +1 @@\n\
+             -{\"version\":3}\n\
+             +{\"version\":3,\"file\":\"app.js\"}\n";
+        let collapsed = collapse_generated(diff, &GeneratedOpts::default());
+        assert!(collapsed.ends_with("(generated file changed, 1 lines added, 1 removed)\n"));
+
+        let opts = GeneratedOpts {
+            patterns: Vec::new(),
+            ..GeneratedOpts::default()
+        };
+        assert_eq!(collapse_generated(diff, &opts), diff);
+    }
+
+    #[test]
+    fn test_cost_estimate() {
+        assert_eq!(format_cost(estimate_cost(3200, 3.0)), "$0.0096");
+        assert_eq!(format_cost(estimate_cost(0, 3.0)), "$0.0000");
+        assert_eq!(format_cost(estimate_cost(1_000_000, 0.25)), "$0.2500");
+
+        let estimate = CostEstimate {
+            input_tokens: 3200,
+            max_output_tokens: 256,
+            prices: Some(ModelPrices {
+                input: 3.0,
+                output: 15.0,
+            }),
+        };
+        assert_eq!(estimate.input_cost(), Some(0.0096));
+        assert_eq!(format_cost(estimate.max_cost().unwrap()), "$0.0134");
+        assert_eq!(
+            estimate.to_string(),
+            "~3200 input tokens, est. $0.0096 (up to $0.0134 with 256 output tokens)"
+        );
+
+        let unknown = CostEstimate {
+            prices: None,
+            ..estimate
+        };
+        assert_eq!(unknown.max_cost(), None);
+        assert!(unknown
+            .to_string()
+            .starts_with("~3200 input tokens, no price known"));
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        // Multibyte characters count by their UTF-8 length
+        assert_eq!(estimate_tokens("é"), 1);
+        assert_eq!(estimate_tokens("ééé"), 2);
+    }
+
+    #[test]
+    fn test_truncate_diff_by_tokens() {
+        let file = |name: &str, lines: usize| {
+            let mut diff = format!(
+                "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n@@ -0,0 +1,{1} @@\n",
+                name, lines
+            );
+            for i in 0..lines {
+                diff.push_str(&format!("+line {} of {}\n", i, name));
+            }
+            diff
+        };
+        let diff = format!("{}{}", file("big.rs", 400), file("small.rs", 3));
+        assert!(estimate_tokens(&diff) > 500);
+
+        let (truncated, was_truncated) = truncate_diff_by_tokens(&diff, 500);
+        assert!(was_truncated);
+        assert!(estimate_tokens(&truncated) <= 500);
+        assert!(truncated.contains("+line 2 of small.rs"));
+
+        let (untouched, was_truncated) = truncate_diff_by_tokens(&diff, estimate_tokens(&diff));
+        assert!(!was_truncated);
+        assert_eq!(untouched, diff);
+    }
+
+    #[test]
+    fn test_check_diff_size() {
+        let diff = "+".repeat(2048);
+        assert!(check_diff_size(&diff, None).is_ok());
+        assert!(check_diff_size(&diff, Some(2048)).is_ok());
+
+        let err = check_diff_size(&diff, Some(2047)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::User(msg) if msg == "Diff too large (2kb); split your commit or raise AUTOCOMMIT_MAX_DIFF_HARD_LIMIT"
+        ));
+        let err = check_diff_size(&format!("{}+", diff), Some(2048)).unwrap_err();
+        assert!(matches!(err, Error::User(msg) if msg.starts_with("Diff too large (3kb)")));
+    }
+
+    #[test]
+    fn test_format_context_files_within_budget() {
+        let files = vec![
+            ("notes.md".to_string(), "short".to_string()),
+            ("spec.md".to_string(), "a".repeat(100)),
+        ];
+        let out = format_context_files(&files, 1000);
+        assert!(out.starts_with("Additional reference material:\n"));
+        assert!(out.contains("--- notes.md ---\nshort\n"));
+        assert!(out.contains(&format!("--- spec.md ---\n{}\n", "a".repeat(100))));
+        assert!(!out.contains("truncated"));
+    }
+
+    #[test]
+    fn test_format_context_files_truncates_long_files() {
+        let files = vec![
+            ("notes.md".to_string(), "short".to_string()),
+            ("spec.md".to_string(), "é".repeat(100)),
+        ];
+        // The short file is kept whole and the long one gets the rest
+        let out = format_context_files(&files, 45);
+        assert!(out.contains("--- notes.md ---\nshort\n"));
+        assert!(out.contains(&format!("--- spec.md ---\n{}\n", "é".repeat(20))));
+        assert!(out.contains("(file truncated, 160 characters omitted)"));
+    }
+
+    #[tokio::test]
+    async fn test_load_context_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("design.md");
+        std::fs::write(&path, "Use a queue").unwrap();
+
+        assert_eq!(load_context_files(&[], 100).await.unwrap(), None);
+        let out = load_context_files(std::slice::from_ref(&path), 100)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(out.contains(&format!("--- {} ---\nUse a queue", path.display())));
+
+        let missing = dir.path().join("missing.md");
+        let err = load_context_files(&[path, missing], 100).await.unwrap_err();
+        assert!(matches!(&err, Error::User(msg) if msg.contains("Cannot read context file")));
+        assert!(err.to_string().contains("missing.md"));
+    }
+
+    #[test]
+    fn test_combine_context() {
+        assert_eq!(combine_context(None, None), None);
+        assert_eq!(combine_context(Some("a"), None), Some("a".to_string()));
+        assert_eq!(combine_context(None, Some("r")), Some("r".to_string()));
+        assert_eq!(
+            combine_context(Some("a"), Some("r")),
+            Some("a\n\nr".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pr_template_preparation() {
+        let template = "<!-- Thanks for contributing! -->\n## Summary\n<!-- describe your changes -->\n\n## Ticket\n{{ticket}} on `{{ branch }}`\n\n<!--\n  Checklist:\n  - tests\n-->\n- [ ] Tests pass <!-- run cargo test -->\n";
+        let stripped = strip_template_comments(template);
+        assert_eq!(
+            stripped,
+            "## Summary\n\n## Ticket\n{{ticket}} on `{{ branch }}`\n\n- [ ] Tests pass"
+        );
+        assert_eq!(strip_template_comments("<!-- unclosed"), "<!-- unclosed");
+
+        assert_eq!(
+            render_template_vars(&stripped, "feat/PROJ-12-login", Some("PROJ-12")),
+            "## Summary\n\n## Ticket\nPROJ-12 on `feat/PROJ-12-login`\n\n- [ ] Tests pass"
+        );
+        assert_eq!(
+            render_template_vars("{{ticket}} {{branch}} {{other}}", "fix", None),
+            "{{ticket}} fix {{other}}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_until_interrupted() {
+        let result = until_interrupted(std::future::pending::<Result<()>>(), async {}).await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+
+        let result = until_interrupted(async { Ok(42) }, async {}).await;
+        assert_eq!(result.unwrap(), 42);
+
+        let result = until_interrupted(async { Ok(1) }, std::future::pending()).await;
+        assert_eq!(result.unwrap(), 1);
+
+        // The inner call reports the interrupt, so its caller can clean up
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let interrupt = || {
+            let mut rx = rx.clone();
+            async move {
+                rx.wait_for(|interrupted| *interrupted).await.unwrap();
+            }
+        };
+        let cleaned_up = std::cell::Cell::new(false);
+        let nested = until_interrupted(
+            async {
+                let inner =
+                    until_interrupted(std::future::pending::<Result<()>>(), interrupt()).await;
+                cleaned_up.set(inner.is_err());
+                inner
+            },
+            interrupt(),
+        );
+        tx.send(true).unwrap();
+        assert!(matches!(nested.await, Err(Error::Cancelled)));
+        assert!(cleaned_up.get());
+    }
+
+    #[tokio::test]
+    async fn test_timed_preserves_result() {
+        let (value, _) = timed("answer", async { 42 }).await;
+        assert_eq!(value, 42);
+
+        let (result, elapsed) = timed("sleep", async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Err::<(), _>(Error::User("failed".to_string()))
+        })
+        .await;
+        assert!(matches!(result, Err(Error::User(msg)) if msg == "failed"));
+        assert!(elapsed >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_status_output_quiet_suppresses_info() {
+        let print = |status: StatusOutput| {
+            let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+            status.info_to(format_args!("Generating..."), &mut stdout, &mut stderr);
+            status.result_to(format_args!("https://pr/1"), &mut stdout, &mut stderr);
+            (
+                String::from_utf8(stdout).unwrap(),
+                String::from_utf8(stderr).unwrap(),
+            )
+        };
+        assert_eq!(
+            print(StatusOutput::new(false, false)),
+            ("Generating...\nhttps://pr/1\n".to_string(), String::new())
+        );
+        assert_eq!(
+            print(StatusOutput::new(false, true)),
+            (String::new(), "Generating...\nhttps://pr/1\n".to_string())
+        );
+        // Quiet keeps only the result, off stdout when that carries JSON
+        assert_eq!(
+            print(StatusOutput::new(true, false)),
+            ("https://pr/1\n".to_string(), String::new())
+        );
+        assert_eq!(
+            print(StatusOutput::new(true, true)),
+            (String::new(), "https://pr/1\n".to_string())
+        );
+        assert!(StatusOutput::new(true, false).is_quiet());
+    }
+
+    #[test]
+    fn test_spinner_enabled() {
+        assert!(spinner_enabled(true, false));
+        assert!(!spinner_enabled(false, false));
+        assert!(!spinner_enabled(true, true));
+        assert!(!spinner_enabled(false, true));
+    }
+
+    #[tokio::test]
+    async fn test_spinner_not_started_when_disabled() {
+        // A disabled spinner only awaits the future, it never touches stdout
+        assert_eq!(with_spinner("Waiting...", false, async { 7 }).await, 7);
+
+        let mut out = Vec::new();
+        assert_eq!(spin("Waiting...", async { 7 }, &mut out).await, 7);
+        assert!(out.is_empty(), "a fast request draws nothing");
+    }
+
+    #[tokio::test]
+    async fn test_spinner_draws_and_clears() {
+        let mut out = Vec::new();
+        let slow = async {
+            tokio::time::sleep(SPINNER_INTERVAL * 3).await;
+            "done"
+        };
+        assert_eq!(spin("Waiting for the API...", slow, &mut out).await, "done");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\r⠋ Waiting for the API..."));
+        assert!(out.ends_with("\r\x1b[2K"));
+    }
+
+    #[test]
+    fn test_timings_display() {
+        let mut timings = Timings::default();
+        assert!(timings.is_empty());
+        assert_eq!(timings.to_string(), "");
+
+        timings.record("git diff", Duration::from_millis(120));
+        timings.record("api", Duration::from_millis(3_420));
+        assert!(!timings.is_empty());
+        assert_eq!(timings.to_string(), "git diff: 120ms, api: 3.4s");
+    }
+
+    #[test]
+    fn test_is_whitespace_only() {
+        let reindent = "diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,4 @@
+ fn main() {
+-  run();
+-  exit(0);   
++    run();
++
++    exit(0);
+ }
+";
+        assert!(is_whitespace_only(reindent));
+
+        let content = "diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,3 @@
+ fn main() {
+-  run();
++    run(true);
+ }
+";
+        assert!(!is_whitespace_only(content));
+
+        // Lines swapped around are a content change even with the same text
+        let moved = "diff --git a/a.sql b/a.sql
+@@ -1,2 +1,2 @@
+--- drop first
+-select 1;
++select 1;
++-- drop first
+";
+        assert!(!is_whitespace_only(moved));
+
+        // So is moving a line to another file
+        let moved = "diff --git a/a.rs b/a.rs
+@@ -1 +0,0 @@
+-fn foo() {}
+diff --git a/b.rs b/b.rs
+@@ -0,0 +1 @@
++fn foo() {}
+";
+        assert!(!is_whitespace_only(moved));
+
+        assert!(!is_whitespace_only(""));
+        assert!(!is_whitespace_only(
+            "diff --git a/logo.png b/logo.png\nBinary files a/logo.png and b/logo.png differ\n"
+        ));
+    }
+
+    #[test]
+    fn test_whitespace_message() {
+        let file = |path: &str| format!("diff --git a/{0} b/{0}\n@@ -1 +1 @@\n-a \n+a\n", path);
+        assert_eq!(
+            whitespace_message(&file("src/lib.rs")),
+            "style: fix whitespace in lib.rs"
+        );
+        assert_eq!(
+            whitespace_message(&format!("{}{}", file("a.rs"), file("b.rs"))),
+            "style: fix whitespace in 2 files"
+        );
     }
 }
@@ -1,7 +1,24 @@
 use autocommit_core::{
-    anthropic::AnthropicClient, exit_with_error, git, utils, Config, Error, Result,
+    anthropic::{AnthropicClient, CommitPromptOptions},
+    config::{CommitStyle, Trailer, MAX_DIFF_CONTEXT},
+    doctor, exit_with_error,
+    git::{self, CommitOptions, DiffSource},
+    suggest,
+    utils::{self, StatusOutput},
+    Config, Error, ErrorFormat, GitRepo, Result,
 };
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
+use dialoguer::{Input, MultiSelect, Select};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+
+/// Directory holding Changesets fragments
+const CHANGESET_DIR: &str = ".changeset";
+
+/// Changelog updated when the repository doesn't use Changesets
+const CHANGELOG_FILE: &str = "CHANGELOG.md";
 
 /// Generate commit messages from staged changes using AI
 #[derive(Parser)]
@@ -11,68 +28,1377 @@ struct Cli {
     /// Dry run mode - generate message but don't commit
     #[arg(long)]
     dry_run: bool,
+
+    /// Skip the confirmation prompt and commit immediately
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Open the message in your editor before committing, even with --yes
+    #[arg(short, long)]
+    edit: bool,
+
+    /// Stage all modified and deleted tracked files first (git add -u)
+    #[arg(short, long)]
+    all: bool,
+
+    /// Also stage untracked files (git add -A), implies --all
+    #[arg(long)]
+    untracked: bool,
+
+    /// Regenerate the message of the last commit and amend it, including any staged changes
+    #[arg(long, conflicts_with = "only_ext")]
+    amend: bool,
+
+    /// Amend the last commit instead when its subject marks it as work in
+    /// progress (wip, tmp, fixup!..., see AUTOCOMMIT_WIP_PATTERN)
+    #[arg(long, conflicts_with_all = ["amend", "only_ext", "offline", "range"])]
+    replace_wip: bool,
+
+    /// GPG/SSH-sign the commit (git commit -S)
+    #[arg(short = 'S', long)]
+    sign: bool,
+
+    /// Record someone else as the commit author, as "Name <email>"
+    #[arg(long, value_name = "NAME <EMAIL>", conflicts_with = "range")]
+    author: Option<String>,
+
+    /// Make you the author of the amended commit again (with --amend)
+    #[arg(long, requires = "amend", conflicts_with = "author")]
+    reset_author: bool,
+
+    /// Skip the pre-commit and commit-msg hooks for this commit
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Also generate a changelog entry (a .changeset fragment, or CHANGELOG.md)
+    #[arg(long)]
+    changeset: bool,
+
+    /// Also add a categorized entry to the Unreleased section of CHANGELOG.md
+    #[arg(long, conflicts_with = "changeset")]
+    changelog: bool,
+
+    /// Print token usage after each API call; -vv also logs git commands and API calls
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print only the commit git made (the message with --dry-run) and errors, for scripts
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Fail instead of warning when the message isn't a Conventional Commit
+    #[arg(long)]
+    strict: bool,
+
+    /// Start the subject with the gitmoji for its type, e.g. "✨ feat: ..."
+    #[arg(long)]
+    gitmoji: bool,
+
+    /// Like --gitmoji, but with shortcodes, e.g. ":sparkles: feat: ..."
+    #[arg(long, conflicts_with = "gitmoji")]
+    gitmoji_shortcode: bool,
+
+    /// Print each prompt to stderr before sending it (with --dry-run, print it and stop)
+    #[arg(long)]
+    debug_prompt: bool,
+
+    /// With --dry-run, estimate the prompt's tokens and cost instead of calling the API
+    #[arg(long, requires = "dry_run", conflicts_with = "offline")]
+    estimate: bool,
+
+    /// Print the commit message as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// Summarize changes to generated files (minified bundles, source maps, snapshots) in one line
+    #[arg(long)]
+    collapse_generated: bool,
+
+    /// Only consider and commit staged files with these extensions (e.g. rs,toml)
+    #[arg(long, value_name = "EXTS", value_delimiter = ',')]
+    only_ext: Vec<String>,
+
+    /// Skip the API and write a basic message from the staged file list
+    #[arg(long, conflicts_with_all = ["amend", "range", "candidates", "changeset", "changelog", "debug_prompt", "stream"])]
+    offline: bool,
+
+    /// Credit a pair with a Co-authored-by trailer, as "Name <email>" (repeatable)
+    #[arg(
+        long = "co-author",
+        value_name = "NAME <EMAIL>",
+        conflicts_with = "range"
+    )]
+    co_authors: Vec<String>,
+
+    /// Append a provenance trailer naming the model, e.g. "Generated-by:
+    /// autocommit (<model>)" (repeatable, adds to AUTOCOMMIT_TRAILERS)
+    #[arg(
+        long = "trailer",
+        value_name = "KIND",
+        value_parser = ["generated-by", "assisted-by"],
+        conflicts_with = "range"
+    )]
+    trailers: Vec<String>,
+
+    /// Keep lock files in the diff and the staged files, for dependency bumps
+    #[arg(long)]
+    include_lockfiles: bool,
+
+    /// Send the diff even if it seems to contain API keys, private keys or tokens
+    #[arg(long)]
+    allow_secrets: bool,
+
+    /// Print the diff about to be sent to the API first, up to LINES lines
+    /// (200 by default), so it can be checked and aborted with Ctrl-C
+    #[arg(long, value_name = "LINES", num_args = 0..=1, require_equals = true, default_missing_value = "200", conflicts_with = "offline")]
+    show_diff: Option<usize>,
+
+    /// Model to use (overrides AUTOCOMMIT_MODEL, which overrides the built-in default)
+    #[arg(long, value_name = "MODEL")]
+    model: Option<String>,
+
+    /// Check the model against the API's model list first, offering the
+    /// available ones to pick from when it isn't there
+    #[arg(long, conflicts_with = "offline")]
+    check_model: bool,
+
+    /// Language to write in, e.g. French (overrides AUTOCOMMIT_LANGUAGE, default English)
+    #[arg(long, value_name = "LANG")]
+    language: Option<String>,
+
+    /// Longest subject line, in characters; longer ones are shortened
+    /// (overrides AUTOCOMMIT_MAX_SUBJECT_LENGTH, default 72)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    max_subject_length: Option<u16>,
+
+    /// Lines of context around each change in the diff sent to the model
+    /// (overrides AUTOCOMMIT_DIFF_CONTEXT, default git's 3)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(..=i64::from(MAX_DIFF_CONTEXT)))]
+    diff_context: Option<u32>,
+
+    /// Read settings from this TOML file instead of the autocommit.toml found
+    /// in this directory or a parent (environment variables override it)
+    #[arg(long = "config", value_name = "PATH")]
+    config_file: Option<PathBuf>,
+
+    /// Use the [profile.NAME] settings of the config file, e.g. a faster or
+    /// more thorough preset (overrides AUTOCOMMIT_PROFILE)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Conventional-commit scope for the subject, as in feat(SCOPE): ...
+    #[arg(long, value_name = "SCOPE")]
+    scope: Option<String>,
+
+    /// Use the top-level directory or monorepo package all staged files share
+    /// as the scope, e.g. api for services/api/...
+    #[arg(long, conflicts_with_all = ["scope", "range", "amend"])]
+    infer_scope: bool,
+
+    /// Commit the staged files one top-level directory (or monorepo package)
+    /// at a time, each with its own message
+    #[arg(long, conflicts_with_all = ["amend", "replace_wip", "range", "only_ext", "offline", "candidates", "changeset", "changelog", "edit"])]
+    split: bool,
+
+    /// Extra context for the model, e.g. "this fixes issue #42"
+    #[arg(long, value_name = "TEXT")]
+    hint: Option<String>,
+
+    /// Generate a subject line plus a body paragraph explaining the change
+    #[arg(long)]
+    body: bool,
+
+    /// Ask for context the diff doesn't show before generating, added to --hint
+    #[arg(long, conflicts_with = "offline")]
+    ask_context: bool,
+
+    /// Use TEXT as the subject line, verbatim, and only generate the body
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["body", "offline", "candidates", "changeset", "changelog", "split", "scope"])]
+    subject: Option<String>,
+
+    /// Read the commit message from stdin and commit it as it is, without asking
+    #[arg(long, conflicts_with_all = ["offline", "candidates", "changeset", "changelog", "split", "subject", "range", "ask_context"])]
+    message_from_stdin: bool,
+
+    /// Have the model polish the message from stdin against the diff
+    #[arg(long, requires = "message_from_stdin")]
+    refine: bool,
+
+    /// Generate N alternative messages and pick one from a menu
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=10))]
+    candidates: u8,
+
+    /// Describe an existing commit range (e.g. HEAD~3..HEAD) instead of staged changes, without committing
+    #[arg(long, value_name = "RANGE", conflicts_with_all = ["amend", "all", "untracked", "changeset", "changelog", "edit"])]
+    range: Option<String>,
+
+    /// Print errors as plain text, or as one JSON object on stderr for tools
+    #[arg(long, global = true, value_name = "FORMAT", value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-async fn run() -> Result<()> {
-    // Load .env file if it exists
-    dotenvy::dotenv().ok();
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the resolved configuration and where each value came from
+    Config {
+        /// Print JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check git, the forge CLI, the configuration and the API, printing a checklist
+    Doctor,
+    /// Explain in plain English what an existing commit does and why
+    Explain {
+        /// Commit to explain, e.g. HEAD~1 or a hash
+        commit: String,
+    },
+    /// Print a shell completion script, e.g. `autocommit completions zsh > _autocommit`
+    #[command(hide = true)]
+    Completions {
+        /// bash, zsh, fish, powershell or elvish
+        shell: clap_complete::Shell,
+    },
+}
 
-    // Parse CLI arguments
-    let cli = Cli::parse();
+/// Write the completion script for `shell` to `out`
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    clap_complete::generate(shell, &mut Cli::command(), "autocommit", out);
+}
 
-    // Load configuration
-    let config = Config::from_env()?;
+/// Print the diff for `--show-diff`, capped at `max_lines` lines
+fn show_diff(diff: &str, max_lines: Option<usize>) {
+    if let Some(max_lines) = max_lines {
+        eprintln!(
+            "\nDiff to send:\n{}\n",
+            utils::diff_preview(diff, max_lines)
+        );
+    }
+}
+
+/// Print the setup checklist for the `doctor` subcommand, failing if any
+/// check failed
+async fn run_doctor(cli: &Cli) -> Result<()> {
+    let config =
+        Config::load(cli.config_file.as_deref(), cli.profile.as_deref()).map(|mut config| {
+            if let Some(model) = cli.model.clone() {
+                config.override_model(model);
+            }
+            config
+        });
+    let checks = doctor::run_checks(&GitRepo::cwd(), config, false).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+    match doctor::failures(&checks) {
+        0 => Ok(()),
+        1 => Err(Error::User("1 check failed.".to_string())),
+        failed => Err(Error::User(format!("{} checks failed.", failed))),
+    }
+}
+
+/// Let the user pick one of `models` in place of the unlisted `configured`,
+/// for `--check-model`
+fn pick_model(configured: &str, models: &[String]) -> Result<String> {
+    let selection = Select::new()
+        .with_prompt(format!("Model '{}' is not available. Pick one", configured))
+        .items(models)
+        .default(0)
+        .interact()
+        .map_err(|dialoguer::Error::IO(e)| Error::prompt("selection", e))?;
+    Ok(models[selection].clone())
+}
+
+/// Read the answer to `--ask-context` from the terminal
+fn read_context() -> Result<String> {
+    Input::new()
+        .with_prompt("Any context to include? (optional)")
+        .allow_empty(true)
+        .interact_text()
+        .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))
+}
+
+/// Print the resolved configuration for the `config` subcommand
+fn print_config(config: &Config, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&config.to_json())?);
+    } else {
+        print!("{}", config.to_toml());
+    }
+    Ok(())
+}
+
+/// Report a message that isn't a Conventional Commit, failing under `--strict`
+fn check_conventional(message: &str, types: &[String], strict: bool) -> Result<()> {
+    match utils::validate_conventional(utils::strip_gitmoji(message), types) {
+        Err(e) if strict => Err(e),
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+impl Cli {
+    /// Where progress lines go for this run
+    fn status(&self) -> StatusOutput {
+        StatusOutput::new(self.quiet, false)
+    }
+
+    /// Whether the run stops once the prompt is built, without calling the API
+    fn prompt_only(&self) -> bool {
+        self.dry_run && (self.debug_prompt || self.estimate)
+    }
+}
+
+/// Print a progress line, unless `--quiet`
+macro_rules! status {
+    ($status:expr, $($arg:tt)*) => {
+        $status.info(format_args!($($arg)*))
+    };
+}
+
+/// Generate a commit message, asking once more if it isn't a Conventional Commit
+async fn generate_conventional(
+    client: &AnthropicClient,
+    diff: &str,
+    options: &CommitPromptOptions,
+    cli: &Cli,
+) -> Result<String> {
+    let message = client.generate_commit_message(diff, options).await?;
+    report_usage(client, cli.verbose > 0, cli.status());
+    // The user's own subject isn't the model's to fix
+    if options.subject.is_some() {
+        return Ok(message);
+    }
+    let Err(error) = utils::validate_conventional(utils::strip_gitmoji(&message), &options.types)
+    else {
+        return Ok(message);
+    };
+
+    status!(
+        cli.status(),
+        "\nThe message is missing a Conventional Commits type, retrying..."
+    );
+    let correction = format!("{} Respond with only the corrected commit message.", error);
+    let message = client
+        .correct_commit_message(diff, options, &message, &correction)
+        .await?;
+    report_usage(client, cli.verbose > 0, cli.status());
+    check_conventional(&message, &options.types, cli.strict)?;
+    Ok(message)
+}
+
+/// Generate and print a message for an existing commit range, for `--range`
+async fn describe_range(
+    range: &str,
+    pathspecs: &[String],
+    ignore: &utils::IgnorePatterns,
+    config: Config,
+    options: &CommitPromptOptions,
+    cli: &Cli,
+) -> Result<()> {
+    let raw_diff = ignore.filter_diff(&git::get_range_diff(range, pathspecs).await?);
+    if raw_diff.trim().is_empty() {
+        return Err(Error::User(format!("No changes in range {}.", range)));
+    }
+    if !cli.allow_secrets {
+        utils::check_secrets(&raw_diff)?;
+    }
+    let diff = suggest::prepare_diff(&raw_diff, &config, cli.collapse_generated)?.diff;
+    show_diff(&diff, cli.show_diff);
+    let options = &CommitPromptOptions {
+        stats: ignore.filter_stats(git::get_numstat(DiffSource::Range(range), pathspecs).await?),
+        ..options.clone()
+    };
+
+    let max_subject_length = config.max_subject_length;
+    let client = AnthropicClient::new(config)?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.quiet,
+        ));
+    if cli.prompt_only() {
+        return print_prompt_only(&client, &diff, options, cli).await;
+    }
+    let message = generate_conventional(&client, &diff, options, cli).await?;
+    let message = enforce_subject(&message, max_subject_length);
+    let message = utils::wrap_commit_message(&message, utils::BODY_WRAP_WIDTH);
+
+    status!(cli.status(), "\nGenerated commit message for {}:", range);
+    cli.status().result(format_args!("{}", message));
+    status!(
+        cli.status(),
+        "\n[dry-run] --range only describes existing commits, nothing was committed."
+    );
+    Ok(())
+}
+
+/// What `commit_split` commits and how
+struct SplitCommit<'a> {
+    files: &'a [String],
+    /// Committed with their directory's group, but left out of its diff
+    excluded: &'a [String],
+    ignore: &'a utils::IgnorePatterns,
+    prompt_options: &'a CommitPromptOptions,
+    commit_options: &'a CommitOptions,
+}
+
+/// Commit the staged files one group at a time, for `--split`
+///
+/// Each group gets a message from its own diff and, unless `--yes`, is
+/// committed only once confirmed; declined groups stay staged.
+async fn commit_split(
+    split: SplitCommit<'_>,
+    config: Config,
+    finish_message: &impl Fn(String) -> String,
+    cli: &Cli,
+) -> Result<()> {
+    let groups = utils::group_split_files(split.files, split.excluded);
+    let client = AnthropicClient::new(config.clone())?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.quiet,
+        ));
+    let interactive = !cli.yes && !cli.dry_run;
+
+    for (index, (group, group_files)) in groups.iter().enumerate() {
+        let files = &group_files.files;
+        status!(
+            cli.status(),
+            "\n[{}/{}] {}:\n  {}",
+            index + 1,
+            groups.len(),
+            group,
+            group_files.paths().join("\n  ")
+        );
+        let raw_diff = git::get_staged_diff(files, config.diff_context).await?;
+        let raw_diff = split.ignore.filter_diff(&raw_diff.text);
+        let diff = suggest::prepare_diff(&raw_diff, &config, cli.collapse_generated)?.diff;
+        show_diff(&diff, cli.show_diff);
+        let options = CommitPromptOptions {
+            stats: split
+                .ignore
+                .filter_stats(git::get_numstat(DiffSource::Staged, files).await?),
+            ..split.prompt_options.clone()
+        };
+        if cli.prompt_only() {
+            print_prompt_only(&client, &diff, &options, cli).await?;
+            continue;
+        }
+        let message = generate_conventional(&client, &diff, &options, cli).await?;
+        let message = finish_message(message);
+        print_preview(&message, cli.status());
+
+        if cli.dry_run {
+            status!(
+                cli.status(),
+                "[dry-run] Would commit {} with the above message.",
+                group
+            );
+            continue;
+        }
+        if interactive {
+            let response: String = Input::new()
+                .with_prompt(format!("Commit {} with this message? (Y/n)", group))
+                .allow_empty(true)
+                .interact_text()
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))?;
+            if !matches!(response.trim().to_lowercase().as_str(), "" | "y" | "yes") {
+                status!(cli.status(), "Skipped {}, its files stay staged.", group);
+                continue;
+            }
+        }
+        let output =
+            git::git_commit_paths(&message, &group_files.paths(), split.commit_options).await?;
+        cli.status().result(format_args!("{}", output));
+    }
+    Ok(())
+}
+
+/// Explain an existing commit for the `explain` subcommand, without
+/// changing anything
+async fn explain_commit(
+    commit: &str,
+    pathspecs: &[String],
+    ignore: &utils::IgnorePatterns,
+    config: Config,
+    cli: &Cli,
+) -> Result<()> {
+    if cli.offline {
+        return Err(Error::User(
+            "autocommit explain needs the API. Drop --offline.".to_string(),
+        ));
+    }
+    let shown = ignore.filter_diff(&git::show_commit(commit, pathspecs).await?);
+    if !cli.allow_secrets {
+        utils::check_secrets(&shown)?;
+    }
+
+    let client = AnthropicClient::new(config)?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.quiet,
+        ));
+    status!(cli.status(), "Explaining {}...\n", commit);
+    let explanation = client.generate_explanation(&shown).await?;
+    report_usage(&client, cli.verbose > 0, cli.status());
+    cli.status().result(format_args!("{}", explanation.trim()));
+    Ok(())
+}
+
+/// Shorten the subject line of `message` to `max` characters, saying so
+/// when it was too long
+fn enforce_subject(message: &str, max: usize) -> String {
+    let (subject, rest) = match message.split_once('\n') {
+        Some((subject, rest)) => (subject, Some(rest)),
+        None => (message, None),
+    };
+    let shortened = utils::enforce_subject_length(subject, max);
+    if shortened == subject {
+        return message.to_string();
+    }
+    eprintln!(
+        "Note: shortened the subject line to {} characters (--max-subject-length)",
+        max
+    );
+    match rest {
+        Some(rest) => format!("{}\n{}", shortened, rest),
+        None => shortened,
+    }
+}
+
+/// Print the prompt a run would send, without sending it, for
+/// `--debug-prompt --dry-run`
+async fn print_prompt_only(
+    client: &AnthropicClient,
+    diff: &str,
+    options: &CommitPromptOptions,
+    cli: &Cli,
+) -> Result<()> {
+    let prompt = if cli.candidates > 1 {
+        client.build_commit_candidates_prompt(diff, usize::from(cli.candidates), options)
+    } else {
+        client.build_commit_prompt(diff, options).await?
+    };
+    if cli.debug_prompt {
+        eprint!("{}", prompt);
+        status!(
+            cli.status(),
+            "\n[dry-run] Printed the prompt, nothing was sent."
+        );
+    }
+    if cli.estimate {
+        cli.status()
+            .result(format_args!("{}", client.estimate(&prompt)));
+    }
+    Ok(())
+}
+
+/// Print the generated message in a box, like autopr's PR preview
+fn print_preview(message: &str, status: StatusOutput) {
+    status!(status, "\n{}", "=".repeat(60));
+    status!(status, "Generated commit message");
+    status!(status, "{}", "=".repeat(60));
+    status!(status, "\n{}", message);
+    status!(status, "\n{}\n", "=".repeat(60));
+}
 
-    // Get staged files
-    let staged_files = git::get_staged_files().await?;
-    if staged_files.is_empty() {
+/// Log diagnostics to stderr, filtered by `RUST_LOG` or else by `-vv`/`-vvv`
+fn init_logging(verbose: u8) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(match verbose {
+            0 | 1 => "off",
+            2 => "autocommit_core=debug",
+            _ => "autocommit_core=trace",
+        })
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Print the token usage of the last API call in verbose mode
+fn report_usage(client: &AnthropicClient, verbose: bool, status: StatusOutput) {
+    if verbose {
+        if let Some(usage) = client.last_usage() {
+            status!(status, "Tokens: {}", usage);
+        }
+    }
+}
+
+/// Let the user edit the commit message in their editor
+///
+/// Works like `git commit`: the message is written to a file in the git
+/// directory, comment lines are stripped afterwards using `core.commentChar`,
+/// and an empty result aborts the commit.
+async fn edit_message(message: &str) -> Result<String> {
+    let setting = git::get_comment_char().await?;
+    let prefix = utils::comment_prefix(&setting, message);
+    let path = format!("{}/AUTOCOMMIT_EDITMSG", git::get_git_dir().await?);
+    fs::write(&path, utils::edit_template(message, &prefix)).await?;
+
+    let editor = utils::resolve_editor(std::env::var("VISUAL").ok(), std::env::var("EDITOR").ok());
+
+    // Run through the shell so editors configured with arguments work
+    let status = if cfg!(windows) {
+        Command::new("cmd")
+            .arg("/C")
+            .arg(format!("{} \"{}\"", editor, path))
+            .status()
+            .await
+    } else {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&path)
+            .status()
+            .await
+    }
+    .map_err(|e| Error::User(format!("Failed to launch editor '{}': {}", editor, e)))?;
+
+    if !status.success() {
+        return Err(Error::User(format!(
+            "Editor '{}' exited with {}; aborting commit.",
+            editor, status
+        )));
+    }
+
+    let edited = fs::read_to_string(&path).await?;
+    let _ = fs::remove_file(&path).await;
+
+    let message = utils::strip_comment_lines(&edited, &prefix);
+    if message.is_empty() {
         return Err(Error::User(
-            "No staged changes found. Stage your changes with 'git add' first.".to_string(),
+            "Aborting commit due to empty commit message.".to_string(),
         ));
     }
+    Ok(message)
+}
+
+/// Build the changelog fragment to write alongside the commit
+///
+/// Returns the path and full content to write. Repositories with a
+/// `.changeset` directory get a new Changesets file, otherwise the entry is
+/// added under the "Unreleased" heading of CHANGELOG.md, or None when it is
+/// already there.
+async fn build_changelog_fragment(
+    commit_message: &str,
+    entry: &str,
+) -> Result<Option<(String, String)>> {
+    let commit_message = utils::strip_gitmoji(commit_message);
+    let uses_changesets = fs::metadata(CHANGESET_DIR)
+        .await
+        .map(|metadata| metadata.is_dir())
+        .unwrap_or(false);
+
+    if uses_changesets {
+        let package = fs::read_to_string("package.json")
+            .await
+            .ok()
+            .and_then(|manifest| utils::package_name(&manifest));
+        let bump = utils::changeset_bump(commit_message);
+        let content = utils::format_changeset(package.as_deref(), bump, entry);
+
+        let file_name = utils::changeset_file_name(commit_message);
+        let stem = file_name.trim_end_matches(".md");
+        let mut path = format!("{}/{}", CHANGESET_DIR, file_name);
+        let mut suffix = 2;
+        while fs::try_exists(&path).await? {
+            path = format!("{}/{}-{}.md", CHANGESET_DIR, stem, suffix);
+            suffix += 1;
+        }
+
+        Ok(Some((path, content)))
+    } else {
+        let existing = read_changelog().await?;
+        Ok(utils::insert_changelog_entry(&existing, None, entry)
+            .map(|content| (CHANGELOG_FILE.to_string(), content)))
+    }
+}
+
+/// Read CHANGELOG.md, empty when there is none yet
+async fn read_changelog() -> Result<String> {
+    match fs::read_to_string(CHANGELOG_FILE).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Get the staged files to commit, and the staged files left out as lock
+/// files, `AUTOCOMMIT_EXCLUDE` globs or `.autocommitignore` paths
+///
+/// The left-out files tell an empty index apart from one holding only
+/// excluded files. Both lists are limited to `ext_pathspecs`.
+async fn get_staged_files(
+    pathspecs: &[String],
+    ext_pathspecs: &[String],
+    exclusions: utils::Exclusions<'_>,
+    ignore: &utils::IgnorePatterns,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let (staged, all) = tokio::join!(
+        git::get_staged_files(pathspecs, exclusions),
+        git::get_staged_files(
+            ext_pathspecs,
+            utils::Exclusions::default().include_lock_files(true)
+        )
+    );
+    let staged = ignore.filter_files(staged?);
+    let excluded = all?
+        .into_iter()
+        .filter(|file| !staged.contains(file))
+        .collect();
+    Ok((staged, excluded))
+}
+
+/// Let the user pick unstaged and untracked files to stage
+///
+/// Returns whether anything was staged.
+async fn pick_files_to_stage() -> Result<bool> {
+    let files = git::get_unstaged_files().await?;
+    if files.is_empty() {
+        return Ok(false);
+    }
+
+    let items: Vec<String> = files
+        .iter()
+        .map(|(status, path)| format!("{:<10} {}", status.to_string(), path))
+        .collect();
+    let selection = MultiSelect::new()
+        .with_prompt("Nothing is staged. Pick files to stage (space to toggle, enter to confirm)")
+        .items(&items)
+        .interact()
+        .map_err(|dialoguer::Error::IO(e)| Error::prompt("selection", e))?;
+    if selection.is_empty() {
+        return Ok(false);
+    }
+
+    let paths: Vec<String> = selection.into_iter().map(|i| files[i].1.clone()).collect();
+    git::stage_files(&paths).await?;
+    println!();
+    Ok(true)
+}
+
+async fn run(mut cli: Cli) -> Result<()> {
+    init_logging(cli.verbose);
+
+    if let Some(Commands::Completions { shell }) = cli.command {
+        write_completions(shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    // Checks the configuration itself, so it runs before loading it
+    if let Some(Commands::Doctor) = cli.command {
+        return run_doctor(&cli).await;
+    }
+
+    // A message from stdin needs the API only to be refined
+    let offline = cli.offline || (cli.message_from_stdin && !cli.refine);
+
+    // Load configuration
+    let mut config = if offline {
+        Config::load_offline(cli.config_file.as_deref(), cli.profile.as_deref())?
+    } else {
+        Config::load(cli.config_file.as_deref(), cli.profile.as_deref())?
+    };
+    if let Some(warning) = config.api_key_warning().filter(|_| !offline) {
+        eprintln!("{}", warning);
+    }
+    if let Some(model) = cli.model.clone() {
+        config.override_model(model);
+    }
+    if let Some(language) = cli
+        .language
+        .clone()
+        .filter(|language| !language.trim().is_empty())
+    {
+        config.override_language(language.trim().to_string());
+    }
+    if let Some(max) = cli.max_subject_length {
+        config.override_max_subject_length(usize::from(max));
+    }
+    if let Some(lines) = cli.diff_context {
+        config.override_diff_context(lines);
+    }
+    let trailers: Vec<_> = cli
+        .trailers
+        .iter()
+        .filter_map(|name| Trailer::parse(name))
+        .collect();
+    config.add_trailers(&trailers);
+
+    if let Some(Commands::Config { json }) = cli.command {
+        print_config(&config, json)?;
+        return Ok(());
+    }
+    if cli.check_model {
+        // --yes never waits on a prompt, so an unlisted model is an error
+        let interactive = !cli.yes && std::io::stdin().is_terminal();
+        suggest::check_model(&mut config, interactive, pick_model).await?;
+    }
+
+    // Run from the top of the work tree, so pathspecs and repository files
+    // such as .autocommitignore mean the same from any subdirectory
+    std::env::set_current_dir(git::repo_root().await?)?;
+
+    if let Some(scope) = &cli.scope {
+        utils::validate_scope(scope)?;
+    }
+    if cli
+        .subject
+        .as_deref()
+        .is_some_and(|subject| subject.trim().is_empty())
+    {
+        return Err(Error::User("--subject must not be empty.".to_string()));
+    }
+    let draft = if cli.message_from_stdin {
+        Some(utils::draft_message(&std::io::read_to_string(
+            std::io::stdin(),
+        )?)?)
+    } else {
+        None
+    };
+    let coauthors = utils::format_coauthors(&cli.co_authors)?;
+    let author = cli
+        .author
+        .as_deref()
+        .map(utils::validate_author)
+        .transpose()?;
+    let mut prompt_options = CommitPromptOptions {
+        scope: cli.scope.clone(),
+        body: cli.body,
+        hint: cli.hint.clone().filter(|hint| !hint.trim().is_empty()),
+        subject: cli.subject.clone(),
+        draft: draft.clone().filter(|_| cli.refine),
+        types: config.commit_types.clone(),
+        style: if cli.gitmoji {
+            CommitStyle::Gitmoji
+        } else if cli.gitmoji_shortcode {
+            CommitStyle::GitmojiShortcode
+        } else {
+            config.commit_style
+        },
+        ..Default::default()
+    };
+
+    // Restrict to the requested file types, leaving out lock files unless asked not to
+    let exclude = config.exclude.clone();
+    let exclusions = utils::Exclusions::new(&exclude).include_lock_files(cli.include_lockfiles);
+    let ext_pathspecs = utils::extension_pathspecs(&cli.only_ext)?;
+    let pathspecs = utils::build_pathspecs(&ext_pathspecs, exclusions);
+    let ignore = utils::load_ignore_patterns(Path::new(".")).await?;
+
+    // Explaining a commit only reads it
+    if let Some(Commands::Explain { commit }) = &cli.command {
+        return explain_commit(commit, &pathspecs, &ignore, config, &cli).await;
+    }
+
+    // A range is already committed, so only describe it
+    if let Some(range) = &cli.range {
+        return describe_range(range, &pathspecs, &ignore, config, &prompt_options, &cli).await;
+    }
+
+    // Stage everything first when asked to
+    if cli.all || cli.untracked {
+        let mut auto_staged = git::get_unstaged_paths(&[]).await?;
+        if cli.untracked {
+            auto_staged.extend(git::get_untracked_files().await?);
+        }
+        git::stage_all(cli.untracked).await?;
+        if !auto_staged.is_empty() {
+            status!(
+                cli.status(),
+                "Auto-staged:\n  {}\n",
+                auto_staged.join("\n  ")
+            );
+        }
+    }
 
-    println!("Staged files:\n  {}\n", staged_files.join("\n  "));
+    if git::is_detached_head().await? {
+        eprintln!(
+            "Warning: {} Otherwise the commit won't be on any branch.\n",
+            git::DETACHED_HEAD_HINT
+        );
+    }
 
-    // Get the staged diff
-    let raw_diff = git::get_staged_diff().await?;
+    // A WIP commit gets replaced by the finished one rather than built on
+    if cli.replace_wip {
+        if let Some(subject) = git::last_commit_subject().await? {
+            if config.wip_pattern.is_match(subject.trim()) {
+                status!(cli.status(), "Replacing the WIP commit: {}\n", subject);
+                cli.amend = true;
+            }
+        }
+    }
+
+    // Get staged files, offering to stage some when there are none
+    let (mut staged_files, mut excluded) =
+        get_staged_files(&pathspecs, &ext_pathspecs, exclusions, &ignore).await?;
+    if staged_files.is_empty()
+        && excluded.is_empty()
+        && !cli.amend
+        && !cli.yes
+        && std::io::stdin().is_terminal()
+        && pick_files_to_stage().await?
+    {
+        (staged_files, excluded) =
+            get_staged_files(&pathspecs, &ext_pathspecs, exclusions, &ignore).await?;
+    }
+
+    if cli.amend {
+        if !git::has_commits().await? {
+            return Err(Error::User(
+                "Nothing to amend: the repository has no commits yet.".to_string(),
+            ));
+        }
+        if staged_files.is_empty() {
+            status!(cli.status(), "Amending the last commit\n");
+        } else {
+            status!(
+                cli.status(),
+                "Amending the last commit with staged files:\n  {}\n",
+                staged_files.join("\n  ")
+            );
+        }
+    } else {
+        if staged_files.is_empty() {
+            if !cli.only_ext.is_empty() {
+                return Err(Error::User(format!(
+                    "No staged files match --only-ext {}.",
+                    cli.only_ext.join(",")
+                )));
+            }
+            return Err(suggest::nothing_staged_error(&excluded));
+        }
+
+        status!(
+            cli.status(),
+            "Staged files:\n  {}\n",
+            staged_files.join("\n  ")
+        );
+
+        if cli.infer_scope {
+            match utils::common_path_scope(&staged_files) {
+                Some(scope) => {
+                    status!(cli.status(), "Inferred scope: {}\n", scope);
+                    prompt_options.scope = Some(scope);
+                }
+                None => status!(
+                    cli.status(),
+                    "Note: the staged files don't share a directory, committing without a scope\n"
+                ),
+            }
+        }
+
+        // Make sure the commit wouldn't be empty once exclusions are applied
+        if !git::has_staged_changes(&pathspecs).await? {
+            return Err(Error::User(
+                "Nothing to commit: the staged changes only touch excluded files.".to_string(),
+            ));
+        }
+
+        // Committing a subset records the working tree content of those files,
+        // so unstaged edits to them would sneak into the commit. Unstaged
+        // edits to files left out of the commit don't matter.
+        if !cli.only_ext.is_empty() || cli.split {
+            let mut unstaged = git::get_unstaged_paths(&pathspecs).await?;
+            unstaged.retain(|path| staged_files.contains(path));
+            if !unstaged.is_empty() {
+                return Err(Error::User(format!(
+                    "These files have unstaged changes, stage or stash them first:\n  {}",
+                    unstaged.join("\n  ")
+                )));
+            }
+        }
+    }
+
+    // Get the diff the commit will record
+    let raw_diff = if cli.amend {
+        git::get_head_diff(&pathspecs, config.diff_context).await?
+    } else {
+        git::get_staged_diff(&pathspecs, config.diff_context).await?
+    };
+    let non_utf8 = ignore.filter_files(raw_diff.non_utf8_files);
+    let raw_diff = ignore.filter_diff(&raw_diff.text);
     if raw_diff.trim().is_empty() {
         return Err(Error::User(
             "No diff content found in staged changes.".to_string(),
         ));
     }
 
-    // Truncate large diffs
-    let (diff, was_truncated) = utils::truncate_diff(&raw_diff, utils::MAX_DIFF_SIZE);
-    if was_truncated {
-        println!(
-            "\nNote: Diff was truncated ({} chars -> {} chars)",
-            raw_diff.len(),
-            utils::MAX_DIFF_SIZE
+    // Don't describe, let alone commit, a half-resolved merge
+    let conflicted = utils::conflict_marker_files(&raw_diff);
+    if !conflicted.is_empty() {
+        return Err(Error::User(format!(
+            "Unresolved merge conflict markers detected in {}",
+            conflicted.join(", ")
+        )));
+    }
+    if let Some(warning) = suggest::non_utf8_warning(&non_utf8) {
+        status!(cli.status(), "{}", warning);
+    }
+
+    // A reindent gets a low-value message from the API, so offer a canned one
+    let whitespace_message = if !offline && utils::is_whitespace_only(&raw_diff) {
+        let message = utils::whitespace_message(&raw_diff);
+        status!(
+            cli.status(),
+            "Warning: the staged changes only touch whitespace, suggesting: {}",
+            message
         );
+        let ask = !cli.yes && !cli.dry_run && std::io::stdin().is_terminal();
+        let accepted = ask && {
+            let response: String = Input::new()
+                .with_prompt("Use this message instead of generating one? (Y/n)")
+                .allow_empty(true)
+                .interact_text()
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))?;
+            matches!(response.trim().to_lowercase().as_str(), "" | "y" | "yes")
+        };
+        accepted.then_some(message)
+    } else {
+        None
+    };
+
+    // Everything below goes to the API, so keep credentials out of it
+    if !cli.allow_secrets && !offline {
+        utils::check_secrets(&raw_diff)?;
+    }
+
+    // Refuse huge diffs, truncate large ones after shrinking generated files if asked to
+    let diff = if offline || cli.split {
+        String::new()
+    } else {
+        let prepared = suggest::prepare_diff(&raw_diff, &config, cli.collapse_generated)?;
+        if prepared.truncated {
+            status!(
+                cli.status(),
+                "\nNote: Diff was truncated (~{} tokens -> ~{} tokens, see AUTOCOMMIT_MAX_TOKENS)",
+                prepared.original_tokens,
+                config.max_diff_tokens
+            );
+        }
+        show_diff(&prepared.diff, cli.show_diff);
+        prepared.diff
+    };
+    // Line counts cover files the truncated diff leaves out
+    if !cli.amend && !offline && !cli.split {
+        prompt_options.stats =
+            ignore.filter_stats(git::get_numstat(DiffSource::Staged, &pathspecs).await?);
+    }
+
+    // Reference the issue named in the branch, if configured
+    let issue_ref = match &config.issue_pattern {
+        Some(pattern) => {
+            let branch = git::get_current_branch().await?;
+            utils::extract_issue_ref(&branch, pattern)
+        }
+        None => None,
+    };
+    // --offline describes which files changed without asking the API, as
+    // does an accepted whitespace suggestion. A message from stdin is taken
+    // as it is
+    let offline_message = if let Some(draft) = draft.filter(|_| !cli.refine) {
+        Some(draft)
+    } else if cli.offline {
+        let changes: Vec<_> = git::get_staged_changes(&pathspecs, exclusions)
+            .await?
+            .into_iter()
+            .filter(|file| !ignore.is_ignored(&file.path))
+            .collect();
+        status!(
+            cli.status(),
+            "Offline: the message only reflects which files changed, edit it as needed"
+        );
+        Some(utils::heuristic_message(&changes))
+    } else {
+        whitespace_message
+    };
+
+    // Provenance trailers only describe messages the model wrote
+    let provenance: &[_] = match offline_message {
+        Some(_) => &[],
+        None => &config.trailers,
+    };
+    let trailers = utils::commit_trailers(&coauthors, provenance, &config.model);
+    let max_subject_length = config.max_subject_length;
+    // A subject the user wrote, with --subject or an unrefined message from
+    // stdin, is theirs to keep at whatever length
+    let own_subject = cli.subject.is_some() || (cli.message_from_stdin && !cli.refine);
+    let finish_message = |message: String| {
+        let message = if own_subject {
+            message
+        } else {
+            enforce_subject(&message, max_subject_length)
+        };
+        let message = utils::wrap_commit_message(&message, utils::BODY_WRAP_WIDTH);
+        let message = match &issue_ref {
+            Some(issue) => utils::append_issue_ref(&message, issue),
+            None => message,
+        };
+        utils::append_trailers(&message, &trailers)
+    };
+    let options = CommitOptions {
+        sign: cli.sign,
+        no_verify: cli.no_verify,
+        author,
+        reset_author: cli.reset_author,
+    };
+
+    // Context the diff doesn't show, given up front rather than the whole
+    // message rewritten afterwards
+    if cli.ask_context {
+        let interactive = !cli.yes && std::io::stdin().is_terminal();
+        let context = suggest::ask_context(interactive, read_context)?;
+        prompt_options.hint = suggest::add_context(prompt_options.hint.take(), context);
+    }
+
+    if cli.split {
+        let split = SplitCommit {
+            files: &staged_files,
+            excluded: &excluded,
+            ignore: &ignore,
+            prompt_options: &prompt_options,
+            commit_options: &options,
+        };
+        return commit_split(split, config, &finish_message, &cli).await;
     }
 
     // Generate commit message
-    let client = AnthropicClient::new(config);
-    let commit_message = client.generate_commit_message(&diff).await?;
+    let client = AnthropicClient::new(config)?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.quiet,
+        ));
+    if cli.prompt_only() {
+        return print_prompt_only(&client, &diff, &prompt_options, &cli).await;
+    }
+    let mut commit_message = if let Some(message) = offline_message {
+        message
+    } else if cli.candidates > 1 {
+        let count = usize::from(cli.candidates);
+        let candidates = client
+            .generate_commit_messages(&diff, count, &prompt_options)
+            .await?;
+        report_usage(&client, cli.verbose > 0, cli.status());
+
+        if candidates.len() < count {
+            status!(
+                cli.status(),
+                "\nNote: Only {} of {} requested candidates were generated",
+                candidates.len(),
+                count
+            );
+        }
+
+        let message = if cli.yes || cli.dry_run || candidates.len() == 1 {
+            status!(cli.status(), "\nCandidates:\n  {}", candidates.join("\n  "));
+            candidates[0].clone()
+        } else {
+            let selection = Select::new()
+                .with_prompt("Pick a commit message")
+                .items(&candidates)
+                .default(0)
+                .interact()
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("selection", e))?;
+            candidates[selection].clone()
+        };
+        check_conventional(&message, &prompt_options.types, cli.strict)?;
+        message
+    } else {
+        generate_conventional(&client, &diff, &prompt_options, &cli).await?
+    };
+    commit_message = finish_message(commit_message);
+
+    // --quiet still shows the message when asking about it. Stdin already
+    // gave the message, so there is nothing left to answer with
+    let interactive = !cli.yes && !cli.dry_run && !cli.message_from_stdin;
+    let preview = if interactive {
+        StatusOutput::new(false, false)
+    } else {
+        cli.status()
+    };
+    print_preview(&commit_message, preview);
+
+    // Let the user accept, edit, regenerate or drop the message
+    let mut edited = false;
+    if interactive {
+        let question = if cli.amend {
+            "Amend the last commit with this message? (y/N/e=edit/r=regenerate)"
+        } else {
+            "Commit with this message? (Y/n/e=edit/r=regenerate)"
+        };
+        loop {
+            let response: String = Input::new()
+                .with_prompt(question)
+                .allow_empty(true)
+                .interact_text()
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))?;
+
+            // --amend and --replace-wip rewrite a commit, so Enter doesn't
+            let action = if cli.amend {
+                utils::parse_amend_action(&response)
+            } else {
+                utils::parse_commit_action(&response)
+            };
+            match action {
+                Some(utils::CommitAction::Commit) => break,
+                Some(utils::CommitAction::Edit) => {
+                    commit_message = edit_message(&commit_message).await?;
+                    edited = true;
+                    break;
+                }
+                Some(utils::CommitAction::Regenerate) if cli.offline => {
+                    println!("Nothing to regenerate offline, edit the message instead.");
+                }
+                Some(utils::CommitAction::Regenerate) => {
+                    status!(cli.status(), "\nRegenerating commit message...");
+                    // Steer away from earlier suggestions so an identical diff still varies
+                    prompt_options.rejected.push(commit_message);
+                    commit_message = finish_message(
+                        generate_conventional(&client, &diff, &prompt_options, &cli).await?,
+                    );
+                    print_preview(&commit_message, preview);
+                }
+                Some(utils::CommitAction::Cancel) => {
+                    println!("Commit cancelled.");
+                    return Ok(());
+                }
+                None => println!("Please answer y, n, e or r."),
+            }
+        }
+    }
+
+    // --edit opens the editor even with --yes
+    if cli.edit && !edited && !cli.dry_run {
+        commit_message = edit_message(&commit_message).await?;
+    }
 
-    println!("\nGenerated commit message:\n{}\n", commit_message);
+    // Generate the changelog fragment from the final message
+    let changelog = if cli.changeset {
+        let (_, entry) = client.generate_changelog_entry(&diff, false).await?;
+        report_usage(&client, cli.verbose > 0, cli.status());
+        let fragment = build_changelog_fragment(&commit_message, &entry).await?;
+        match &fragment {
+            Some((path, _)) => status!(cli.status(), "Changelog entry ({}):\n{}\n", path, entry),
+            None => status!(
+                cli.status(),
+                "{} already has this entry, leaving it as is",
+                CHANGELOG_FILE
+            ),
+        }
+        fragment
+    } else if cli.changelog {
+        let (category, entry) = client.generate_changelog_entry(&diff, true).await?;
+        report_usage(&client, cli.verbose > 0, cli.status());
+        let existing = read_changelog().await?;
+        match utils::insert_changelog_entry(&existing, category, &entry) {
+            Some(content) => {
+                let category = category.map_or(String::new(), |c| format!(", {}", c));
+                status!(
+                    cli.status(),
+                    "Changelog entry ({}{}):\n{}\n",
+                    CHANGELOG_FILE,
+                    category,
+                    entry
+                );
+                Some((CHANGELOG_FILE.to_string(), content))
+            }
+            None => {
+                status!(
+                    cli.status(),
+                    "{} already has this entry, leaving it as is",
+                    CHANGELOG_FILE
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    // Exit if dry-run
+    // Exit if dry-run, where the message is the result
     if cli.dry_run {
-        println!("[dry-run] Would commit with the above message.");
+        if cli.status().is_quiet() {
+            cli.status().result(format_args!("{}", commit_message));
+        }
+        if cli.amend {
+            status!(
+                cli.status(),
+                "[dry-run] Would amend the last commit with the above message."
+            );
+        } else {
+            status!(
+                cli.status(),
+                "[dry-run] Would commit with the above message."
+            );
+        }
         return Ok(());
     }
 
+    // Write and stage the changelog fragment so it lands in the same commit
+    let mut commit_paths = staged_files;
+    if let Some((path, content)) = changelog {
+        fs::write(&path, content).await?;
+        git::stage_files(std::slice::from_ref(&path)).await?;
+        commit_paths.push(path);
+    }
+
     // Commit with the generated message
-    let output = git::git_commit(&commit_message).await?;
-    println!("{}", output);
+    let output = if cli.amend {
+        git::git_commit_amend(&commit_message, &options).await?
+    } else if cli.only_ext.is_empty() {
+        git::git_commit(&commit_message, &options).await?
+    } else {
+        git::git_commit_paths(&commit_message, &commit_paths, &options).await?
+    };
+    cli.status().result(format_args!("{}", output));
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run().await {
-        exit_with_error(e);
+    // Load .env file if it exists
+    for name in autocommit_core::config::load_dotenv() {
+        eprintln!(
+            "Warning: ignoring {} from .env, set it in the environment instead",
+            name
+        );
+    }
+
+    // Parse CLI arguments
+    let cli = Cli::parse();
+    let error_format = cli.error_format;
+    if let Err(e) = utils::until_interrupted(run(cli), utils::ctrl_c()).await {
+        exit_with_error(e, error_format);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bash_completions() {
+        let mut out = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("autocommit"));
+        assert!(script.contains("--dry-run"));
     }
 }
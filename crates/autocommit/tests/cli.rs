@@ -0,0 +1,48 @@
+use assert_cmd::Command;
+use std::path::Path;
+
+/// Run git in `dir` with a fixed identity, failing the test if it fails
+fn git_in(dir: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "Test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "Test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_message_from_stdin_commits_as_given() {
+    let dir = tempfile::tempdir().unwrap();
+    git_in(dir.path(), &["init", "-q"]);
+    std::fs::write(dir.path().join("a.txt"), "hello\n").unwrap();
+    git_in(dir.path(), &["add", "a.txt"]);
+
+    // Longer than the default --max-subject-length, which only applies to
+    // generated subjects
+    let subject = "feat: accept a commit message on stdin and commit it exactly as it was written";
+    Command::cargo_bin("autocommit")
+        .unwrap()
+        .arg("--message-from-stdin")
+        .current_dir(dir.path())
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap())
+        .env("HOME", dir.path())
+        .env("GIT_AUTHOR_NAME", "Test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "Test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .write_stdin(format!("# a comment\n{}\n\nThe body.\n", subject))
+        .assert()
+        .success();
+
+    assert_eq!(
+        git_in(dir.path(), &["log", "-1", "--format=%B"]).trim(),
+        format!("{}\n\nThe body.", subject)
+    );
+}
@@ -1,7 +1,19 @@
-use autocommit_core::{anthropic::AnthropicClient, exit_with_error, git, Config, Error, Result};
-use clap::Parser;
-use dialoguer::Input;
-use tokio::fs;
+use autocommit_core::{
+    anthropic::{AnthropicClient, PrField, Prompt},
+    config::MAX_DIFF_CONTEXT,
+    doctor, exit_with_error, git,
+    git::PushAction,
+    suggest,
+    suggest::ClarifyMode,
+    utils::{self, StatusOutput},
+    Config, Error, ErrorFormat, GitRepo, PRContent, Result,
+};
+use clap::{CommandFactory, Parser, Subcommand};
+use dialoguer::{Input, Select};
+use std::cell::Cell;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Generate PR title and description from branch changes
 #[derive(Parser)]
@@ -12,220 +24,1133 @@ struct Cli {
     #[arg(short, long)]
     yes: bool,
 
-    /// Dry run mode - generate PR content but don't create it
+    /// Never ask clarification questions, have the model make assumptions (implied by --yes)
+    #[arg(long)]
+    no_clarify: bool,
+
+    /// Dry run mode - generate PR content without pushing or creating the PR
     #[arg(long)]
     dry_run: bool,
+
+    /// Print token usage and step timings; -vv also logs git commands and API calls
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print the PR title and body as JSON on stdout, with progress on stderr
+    #[arg(long)]
+    json: bool,
+
+    /// Print only the PR URL (or the --json output) and errors, for scripts
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also save the PR title and body as Markdown to PATH (works with --dry-run)
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Overwrite the --output file if it already exists
+    #[arg(long, requires = "output")]
+    force: bool,
+
+    /// Regenerate the PR description even if a recent one was cached for this diff
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Print each prompt to stderr before sending it (with --dry-run, print it and stop)
+    #[arg(long)]
+    debug_prompt: bool,
+
+    /// With --dry-run, estimate the prompt's tokens and cost instead of calling the API
+    #[arg(long, requires = "dry_run")]
+    estimate: bool,
+
+    /// Print the PR content as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// Follow the PR template at PATH instead of the discovered one
+    #[arg(long, value_name = "PATH", conflicts_with = "no_template")]
+    template: Option<PathBuf>,
+
+    /// Ignore any PR template and use the default sections
+    #[arg(long)]
+    no_template: bool,
+
+    /// Ask for context the changes don't show before generating, added to any --context-file
+    #[arg(long)]
+    ask_context: bool,
+
+    /// Send the PR template as it is, without removing its <!-- comments -->
+    /// or filling in {{branch}} and {{ticket}}
+    #[arg(long, conflicts_with = "no_template")]
+    raw_template: bool,
+
+    /// Write a Conventional Commits title and a bulleted body for squash merges (a PR template still wins)
+    #[arg(long)]
+    squash: bool,
+
+    /// Send the file at PATH as reference material for the description (repeatable)
+    #[arg(long = "context-file", value_name = "PATH")]
+    context_files: Vec<PathBuf>,
+
+    /// Model to use (overrides AUTOCOMMIT_MODEL, which overrides the built-in default)
+    #[arg(long, value_name = "MODEL")]
+    model: Option<String>,
+
+    /// Check the model against the API's model list first, offering the
+    /// available ones to pick from when it isn't there
+    #[arg(long)]
+    check_model: bool,
+
+    /// Language to write in, e.g. French (overrides AUTOCOMMIT_LANGUAGE, default English)
+    #[arg(long, value_name = "LANG")]
+    language: Option<String>,
+
+    /// Longest PR title, in characters; longer ones are shortened
+    /// (overrides AUTOCOMMIT_MAX_SUBJECT_LENGTH, default 72)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    max_subject_length: Option<u16>,
+
+    /// Lines of context around each change in the diff sent to the model
+    /// (overrides AUTOCOMMIT_DIFF_CONTEXT, default git's 3)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(..=i64::from(MAX_DIFF_CONTEXT)))]
+    diff_context: Option<u32>,
+
+    /// Read settings from this TOML file instead of the autocommit.toml found
+    /// in this directory or a parent (environment variables override it)
+    #[arg(long = "config", value_name = "PATH")]
+    config_file: Option<PathBuf>,
+
+    /// Use the [profile.NAME] settings of the config file, e.g. a faster or
+    /// more thorough preset (overrides AUTOCOMMIT_PROFILE)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Base branch to open the PR against (defaults to the remote's default branch)
+    #[arg(long, value_name = "BRANCH")]
+    base: Option<String>,
+
+    /// Compare against the remote's copy of the base branch (e.g. origin/main)
+    /// instead of the local one, which may be behind
+    #[arg(long, conflicts_with_all = ["since", "since_tag", "since_last_release"])]
+    base_remote: bool,
+
+    /// With --base-remote, fetch the remote's base branch first
+    #[arg(long, requires = "base_remote")]
+    fetch: bool,
+
+    /// Open the PR as a draft
+    #[arg(long)]
+    draft: bool,
+
+    /// Keep lock files in the diff and the changed files, for dependency bumps
+    #[arg(long)]
+    include_lockfiles: bool,
+
+    /// How many changed files to list before summarizing the rest
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    show_files: usize,
+
+    /// Print the diff about to be sent to the API first, up to LINES lines
+    /// (200 by default), so it can be checked and aborted with Ctrl-C
+    #[arg(long, value_name = "LINES", num_args = 0..=1, require_equals = true, default_missing_value = "200")]
+    show_diff: Option<usize>,
+
+    /// Describe the changes since the last tag instead of the base branch, for release PRs
+    #[arg(long, conflicts_with = "since")]
+    since_tag: bool,
+
+    /// Describe the changes since REF instead of the base branch
+    #[arg(long, value_name = "REF")]
+    since: Option<String>,
+
+    /// Like --since-tag, with the body written as release notes grouped by commit type
+    #[arg(long, conflicts_with_all = ["since", "since_tag", "squash"])]
+    since_last_release: bool,
+
+    /// Only describe changes to paths matching GLOB (repeatable); the PR still
+    /// contains every change
+    #[arg(long = "path", value_name = "GLOB")]
+    paths: Vec<String>,
+
+    /// Don't push the branch first, open the PR from what the remote already has
+    #[arg(long)]
+    no_push: bool,
+
+    /// Only push the branch and print the page for opening its PR
+    #[arg(long, conflicts_with_all = ["no_push", "update", "draft", "reviewers", "assignees", "labels"])]
+    push_only: bool,
+
+    /// Regenerate the title and body of this branch's existing PR and update it
+    #[arg(long, conflicts_with_all = ["draft", "reviewers", "assignees", "labels"])]
+    update: bool,
+
+    /// Request a review from USER (repeatable)
+    #[arg(long = "reviewer", value_name = "USER")]
+    reviewers: Vec<String>,
+
+    /// Assign the PR to USER (repeatable)
+    #[arg(long = "assignee", value_name = "USER")]
+    assignees: Vec<String>,
+
+    /// Add LABEL to the PR (repeatable)
+    #[arg(long = "label", value_name = "LABEL")]
+    labels: Vec<String>,
+
+    /// Print errors as plain text, or as one JSON object on stderr for tools
+    #[arg(long, global = true, value_name = "FORMAT", value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-/// Get the PR template from the repository
-async fn get_pr_template() -> Result<Option<String>> {
-    let template_paths = [
-        ".github/PULL_REQUEST_TEMPLATE.md",
-        ".github/pull_request_template.md",
-        "PULL_REQUEST_TEMPLATE.md",
-        "pull_request_template.md",
-    ];
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the resolved configuration and where each value came from
+    Config {
+        /// Print JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check git, the forge CLI, the configuration and the API, printing a checklist
+    Doctor,
+    /// Explain in plain English what an existing PR does and why
+    Explain {
+        /// PR (or MR) number, e.g. 12 or #12
+        number: String,
+    },
+    /// Print a shell completion script, e.g. `autopr completions zsh > _autopr`
+    #[command(hide = true)]
+    Completions {
+        /// bash, zsh, fish, powershell or elvish
+        shell: clap_complete::Shell,
+    },
+}
 
-    for path in &template_paths {
-        match fs::read_to_string(path).await {
-            Ok(content) => return Ok(Some(content)),
-            Err(_) => continue,
-        }
+/// Write the completion script for `shell` to `out`
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    clap_complete::generate(shell, &mut Cli::command(), "autopr", out);
+}
+
+/// Print the setup checklist for the `doctor` subcommand, failing if any
+/// check failed
+async fn run_doctor(cli: &Cli) -> Result<()> {
+    let config =
+        Config::load(cli.config_file.as_deref(), cli.profile.as_deref()).map(|mut config| {
+            if let Some(model) = cli.model.clone() {
+                config.override_model(model);
+            }
+            config
+        });
+    let checks = doctor::run_checks(&GitRepo::cwd(), config, true).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+    match doctor::failures(&checks) {
+        0 => Ok(()),
+        1 => Err(Error::User("1 check failed.".to_string())),
+        failed => Err(Error::User(format!("{} checks failed.", failed))),
     }
+}
 
-    Ok(None)
+/// Let the user pick one of `models` in place of the unlisted `configured`,
+/// for `--check-model`
+fn pick_model(configured: &str, models: &[String]) -> Result<String> {
+    let selection = Select::new()
+        .with_prompt(format!("Model '{}' is not available. Pick one", configured))
+        .items(models)
+        .default(0)
+        .interact()
+        .map_err(|dialoguer::Error::IO(e)| Error::prompt("selection", e))?;
+    Ok(models[selection].clone())
 }
 
-async fn run() -> Result<()> {
-    // Load .env file if it exists
-    dotenvy::dotenv().ok();
+/// Read the answer to `--ask-context` from the terminal
+fn read_context() -> Result<String> {
+    Input::new()
+        .with_prompt("Any context to include? (optional)")
+        .allow_empty(true)
+        .interact_text()
+        .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))
+}
 
-    // Parse CLI arguments
-    let cli = Cli::parse();
+/// Print the resolved configuration for the `config` subcommand
+fn print_config(config: &Config, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&config.to_json())?);
+    } else {
+        print!("{}", config.to_toml());
+    }
+    Ok(())
+}
 
-    // Load configuration
-    let config = Config::from_env()?;
+impl Cli {
+    /// Where progress lines go for this run
+    fn status(&self) -> StatusOutput {
+        StatusOutput::new(self.quiet, self.json)
+    }
+}
 
-    // Get current and base branches
-    let current_branch = git::get_current_branch().await?;
-    if current_branch.is_empty() {
-        return Err(Error::User(
-            "Not on a branch. Please checkout a branch first.".to_string(),
-        ));
+/// Print a progress line, on stderr in `--json` mode so stdout stays
+/// parseable, and not at all with `--quiet`
+macro_rules! status {
+    ($status:expr, $($arg:tt)*) => {
+        $status.info(format_args!($($arg)*))
+    };
+}
+
+/// Log diagnostics to stderr, filtered by `RUST_LOG` or else by `-vv`/`-vvv`
+fn init_logging(verbose: u8) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(match verbose {
+            0 | 1 => "off",
+            2 => "autocommit_core=debug",
+            _ => "autocommit_core=trace",
+        })
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Print the token usage of the last API call in verbose mode
+fn report_usage(client: &AnthropicClient, verbose: bool, status: StatusOutput) {
+    if verbose {
+        if let Some(usage) = client.last_usage() {
+            status!(status, "Tokens: {}", usage);
+        }
     }
+}
 
-    let base_branch = git::get_default_branch().await?;
-    println!("Current branch: {}", current_branch);
-    println!("Base branch: {}", base_branch);
+/// Cache file for generated PR content, inside the git directory
+const PR_CACHE_FILE: &str = "autopr-cache.json";
 
-    if current_branch == base_branch {
-        return Err(Error::User(format!(
-            "You are on the base branch ({}). Create a feature branch first.",
-            base_branch
-        )));
+/// Seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Key cached PR content by everything that shaped it: the template,
+/// language and flags all end up in the prompt, next to the diff
+fn pr_cache_key(model: &str, prompt: &Prompt) -> String {
+    utils::diff_hash(&format!("{}\n{}", model, prompt))
+}
+
+/// Save the PR cache, warning instead of failing since it is only an optimization
+async fn store_cache(path: &Path, cache: &utils::PrCache) {
+    if let Err(e) = utils::store_pr_cache(path, cache).await {
+        eprintln!("Warning: failed to cache the PR description: {}", e);
     }
+}
 
-    // Check if PR already exists
-    if let Some(existing_pr_url) = git::get_existing_pr().await? {
-        println!("A PR already exists for this branch: {}", existing_pr_url);
-        return Ok(());
+/// Shorten the PR title to `max` characters, saying so when it was too long
+fn enforce_title(pr_content: &mut PRContent, max: usize, status: StatusOutput) {
+    let shortened = utils::enforce_subject_length(&pr_content.title, max);
+    if shortened != pr_content.title {
+        status!(
+            status,
+            "Note: shortened the PR title to {} characters (--max-subject-length)",
+            max
+        );
+        pr_content.title = shortened;
+    }
+}
+
+/// Show the PR title and body for review
+fn print_preview(heading: &str, pr_content: &PRContent, status: StatusOutput) {
+    status!(status, "\n{}", "=".repeat(60));
+    status!(status, "{}", heading);
+    status!(status, "{}", "=".repeat(60));
+    status!(status, "\nTitle: {}", pr_content.title);
+    status!(status, "\nBody:\n{}", pr_content.body);
+    for (key, value) in pr_content.extra_fields() {
+        status!(status, "\n{}: {}", key, value);
     }
+    status!(status, "\n{}", "=".repeat(60));
+}
 
-    // Push branch if needed (skip in dry-run mode)
-    if !cli.dry_run {
-        let remote_exists = git::remote_branch_exists().await?;
-        let has_unpushed = git::check_unpushed_commits().await?;
+/// Explain an existing PR for the `explain` subcommand, without changing
+/// anything
+async fn explain_pr(number: &str, config: Config, cli: &Cli) -> Result<()> {
+    let number = git::parse_pr_number_arg(number)?;
+    let forge = config.forge;
+    let reference = number.to_string();
+    let (diff, content) = tokio::join!(
+        git::get_pr_diff(forge, number),
+        git::get_pr_content(forge, &reference)
+    );
+    let ignore = utils::load_ignore_patterns(Path::new(".")).await?;
+    let diff = ignore.filter_diff(&diff?);
+    let change = match content? {
+        Some(pr) => format!(
+            "Title: {}\n\nDescription:\n{}\n\nDiff:\n{}",
+            pr.title.trim(),
+            pr.body.trim(),
+            diff
+        ),
+        None => format!("Diff:\n{}", diff),
+    };
 
-        if !remote_exists || has_unpushed {
-            git::push_branch().await?;
+    let client = AnthropicClient::new(config)?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.json || cli.quiet,
+        ));
+    status!(cli.status(), "Explaining PR #{}...\n", number);
+    let explanation = client.generate_explanation(&change).await?;
+    report_usage(&client, cli.verbose > 0, cli.status());
+    cli.status().result(format_args!("{}", explanation.trim()));
+    Ok(())
+}
+
+/// Push the branch if the remote is missing it or behind, reporting instead
+/// on a dry run or with --no-push
+///
+/// `progress` follows the push, for the hint shown if the run stops midway.
+async fn push_if_needed(
+    cli: &Cli,
+    remote: &str,
+    branch: &str,
+    progress: &Cell<git::PushProgress>,
+) -> Result<()> {
+    match git::plan_push(remote, branch, cli.dry_run).await? {
+        PushAction::Skip => Ok(()),
+        _ if cli.no_push => {
+            status!(
+                cli.status(),
+                "Not pushing branch {} (--no-push), the PR uses what {} already has",
+                branch,
+                remote
+            );
+            Ok(())
+        }
+        PushAction::WouldPush => {
+            status!(
+                cli.status(),
+                "[dry-run] Would push branch {} if needed",
+                branch
+            );
+            Ok(())
+        }
+        PushAction::Push => {
+            status!(cli.status(), "Pushing branch {} to {}...", branch, remote);
+            progress.set(git::PushProgress::Pushing);
+            git::push_branch(remote, branch).await?;
+            progress.set(git::PushProgress::Pushed);
+            Ok(())
         }
     }
+}
 
+/// What the PR describes and where it goes, settled before pushing
+struct PrTarget {
+    current_branch: String,
+    base_branch: String,
+    /// Ref the commits and diff are taken from, the base branch unless a
+    /// tag or `--since` says otherwise
+    describe_from: String,
+    /// The branch's open PR, which only `--update` gets this far with
+    existing_pr_url: Option<String>,
+}
+
+/// Describe the branch and open or update its PR, the part of the run
+/// after pushing
+async fn open_pr(cli: &Cli, config: Config, target: PrTarget) -> Result<()> {
+    let PrTarget {
+        current_branch,
+        base_branch,
+        describe_from,
+        existing_pr_url,
+    } = target;
+    let forge = config.forge;
     // Gather PR information in parallel
-    println!("\nGathering commit information...");
-    let (commits, diff, changed_files, template) = tokio::join!(
-        git::get_commits(&base_branch),
-        git::get_diff(&base_branch),
-        git::get_changed_files(&base_branch),
-        get_pr_template()
+    status!(cli.status(), "\nGathering commit information...");
+    let includes = utils::path_pathspecs(&cli.paths)?;
+    let exclusions =
+        utils::Exclusions::new(&config.exclude).include_lock_files(cli.include_lockfiles);
+    let range = git::base_range(&describe_from);
+    let pathspecs = utils::build_pathspecs(&includes, exclusions);
+    let gather = git::GatherOpts {
+        includes: &includes,
+        exclusions,
+        fallback_n: config.fallback_commits,
+        diff_context: config.diff_context,
+    };
+    let (inputs, stats, template, reference) = tokio::join!(
+        git::gather_pr_inputs(&describe_from, &gather),
+        utils::timed(
+            "numstat",
+            git::get_numstat(git::DiffSource::Range(&range), &pathspecs)
+        ),
+        utils::timed(
+            "template",
+            utils::load_pr_template(Path::new("."), cli.template.as_deref(), cli.no_template)
+        ),
+        utils::timed(
+            "context files",
+            utils::load_context_files(&cli.context_files, utils::MAX_CONTEXT_FILE_BYTES)
+        )
     );
+    let inputs = inputs?;
+    if inputs.used_fallback {
+        eprintln!(
+            "Warning: couldn't diff against {}, using last {} commits instead.",
+            describe_from, config.fallback_commits
+        );
+    }
+    // Where the time went, reported with --verbose
+    let mut timings = inputs.timings.clone();
+    timings.record("numstat", stats.1);
+    timings.record("template", template.1);
+    if !cli.context_files.is_empty() {
+        timings.record("context files", reference.1);
+    }
+    let (stats, template, reference) = (stats.0, template.0, reference.0);
 
-    let commits = commits?;
-    let diff = diff?;
-    let changed_files = changed_files?;
-    let template = template?;
+    let ignore = utils::load_ignore_patterns(Path::new(".")).await?;
+    let (commits, diff) = (inputs.commits, ignore.filter_diff(&inputs.diff));
+    let non_utf8 = ignore.filter_files(inputs.non_utf8_files);
+    if let Some(warning) = suggest::non_utf8_warning(&non_utf8) {
+        status!(cli.status(), "{}", warning);
+    }
+    // Release notes are written from the commits grouped by type
+    let commits = if cli.since_last_release {
+        let subjects = git::get_commit_subjects(&describe_from, &includes).await?;
+        utils::format_commit_groups(&utils::group_commits_by_type(
+            &subjects,
+            &config.commit_types,
+        ))
+    } else {
+        commits
+    };
+    // Count what .autocommitignore drops along with lock and excluded files
+    let git::ChangedFiles { files, excluded } = inputs.changed_files;
+    let found = files.len();
+    let changed: Vec<_> = files
+        .into_iter()
+        .filter(|file| !ignore.is_ignored(&file.path))
+        .collect();
+    let excluded = excluded + found - changed.len();
+    // Renames read as "old -> new", so the model sees where files moved
+    let changed_files: Vec<String> = changed.iter().map(ToString::to_string).collect();
+    // Line counts only add context, so a failed comparison leaves them out
+    let prompt_files = utils::annotate_file_stats(&changed, &stats.unwrap_or_default());
+    let (template, reference) = (template?, reference?);
+    let template = template.map(|template| {
+        if cli.raw_template {
+            return template;
+        }
+        let ticket = config
+            .issue_pattern
+            .as_ref()
+            .and_then(|pattern| utils::extract_issue_ref(&current_branch, pattern));
+        utils::render_template_vars(
+            &utils::strip_template_comments(&template),
+            &current_branch,
+            ticket.as_deref(),
+        )
+    });
 
     if changed_files.is_empty() {
-        return Err(Error::User(
-            "No changes found compared to base branch.".to_string(),
-        ));
+        return Err(suggest::no_changes_error(&cli.paths));
     }
 
-    println!("\nChanged files ({}):", changed_files.len());
-    for file in changed_files.iter().take(10) {
-        println!("  {}", file);
+    status!(cli.status(), "\nChanged files ({}):", changed_files.len());
+    for file in changed_files.iter().take(cli.show_files) {
+        status!(cli.status(), "  {}", file);
+    }
+    if changed_files.len() > cli.show_files {
+        status!(
+            cli.status(),
+            "  ... and {} more",
+            changed_files.len() - cli.show_files
+        );
     }
-    if changed_files.len() > 10 {
-        println!("  ... and {} more", changed_files.len() - 10);
+    if excluded > 0 {
+        let noun = if excluded == 1 { "file" } else { "files" };
+        status!(
+            cli.status(),
+            "  ({} lock or excluded {} left out)",
+            excluded,
+            noun
+        );
+    }
+
+    if let Some(max_lines) = cli.show_diff {
+        let (sent, _) = utils::truncate_diff_by_tokens(&diff, config.max_diff_tokens as usize);
+        eprintln!("\nDiff to send:\n{}", utils::diff_preview(&sent, max_lines));
     }
 
+    // Context given up front can spare a clarification round trip
+    let reference = if cli.ask_context {
+        let interactive = !cli.yes && std::io::stdin().is_terminal();
+        let context = suggest::ask_context(interactive, read_context)?;
+        suggest::add_context(reference, context)
+    } else {
+        reference
+    };
+
+    let changed_paths: Vec<String> = changed.into_iter().map(|file| file.path).collect();
+    let add_screenshots = utils::needs_screenshots_section(config.ui_screenshots, &changed_paths);
+
+    // The previous description is context for the update, kept out of the cache
+    let existing_pr = match &existing_pr_url {
+        Some(url) => git::get_pr_content(forge, url).await?,
+        None => None,
+    };
+
     // Generate PR content
-    println!("\nGenerating PR description...");
-    let client = AnthropicClient::new(config);
-    let mut pr_content = client
-        .generate_pr_content(
-            &commits,
-            &diff,
-            &changed_files,
-            template.as_deref(),
-            None,
-            None,
-        )
-        .await?;
+    let max_title = config.max_subject_length;
+    let model = config.model.clone();
+    // Small PRs are described as best as possible rather than asked about
+    let no_clarify = cli.yes
+        || cli.no_clarify
+        || config.no_clarify
+        || !suggest::clarification_allowed(changed_files.len(), config.clarify_min_files);
+    let client = AnthropicClient::new(config)?
+        .with_stream(cli.stream)
+        .with_debug_prompt(cli.debug_prompt)
+        .with_spinner(utils::spinner_enabled(
+            std::io::stdout().is_terminal(),
+            cli.json || cli.quiet,
+        ))
+        .with_squash(cli.squash)
+        .with_release_notes(cli.since_last_release)
+        .with_no_clarify(no_clarify);
+    let prompt = client.build_pr_prompt(
+        &commits,
+        &diff,
+        &prompt_files,
+        template.as_deref(),
+        reference.as_deref(),
+        existing_pr.as_ref(),
+    );
+    if cli.dry_run && (cli.debug_prompt || cli.estimate) {
+        if cli.debug_prompt {
+            eprint!("{}", prompt);
+            status!(
+                cli.status(),
+                "\n[dry-run] Printed the prompt, nothing was sent."
+            );
+        }
+        if cli.estimate {
+            cli.status()
+                .result(format_args!("{}", client.estimate(&prompt)));
+        }
+        return Ok(());
+    }
+
+    // Reuse content from a previous run on the same commits and diff
+    let (git_dir, head) = tokio::join!(git::get_git_dir(), git::get_head_commit());
+    let cache_path = Path::new(&git_dir?).join(PR_CACHE_FILE);
+    let head = head?;
+    let diff_hash = pr_cache_key(&model, &prompt);
+    let now = unix_now();
+    let cached = if cli.no_cache || existing_pr.is_some() {
+        None
+    } else {
+        utils::load_pr_cache(&cache_path)
+            .await
+            .filter(|cache| cache.matches(&current_branch, &head, &diff_hash, now))
+    };
+    let mut pr_content = match cached {
+        Some(cache) => {
+            status!(
+                cli.status(),
+                "\nReusing the PR description generated {} minutes ago (--no-cache to regenerate)",
+                now.saturating_sub(cache.created_at) / 60
+            );
+            cache.content
+        }
+        None => {
+            status!(cli.status(), "\nGenerating PR description...");
+            let (pr_content, elapsed) = utils::timed(
+                "api",
+                client.generate_pr_content(
+                    &commits,
+                    &diff,
+                    &prompt_files,
+                    template.as_deref(),
+                    reference.as_deref(),
+                    existing_pr.as_ref(),
+                ),
+            )
+            .await;
+            let pr_content = pr_content?;
+            timings.record("api", elapsed);
+            report_usage(&client, cli.verbose > 0, cli.status());
+            pr_content
+        }
+    };
+    if cli.verbose > 0 {
+        status!(cli.status(), "Timings: {}", timings);
+    }
 
     // Handle clarification if needed
-    while pr_content.needs_clarification.unwrap_or(false) {
-        if let Some(question) = &pr_content.clarification_question {
-            println!("\nClarification needed:");
-            let answer: String = Input::new()
+    let inputs = suggest::PrInputs {
+        commits: &commits,
+        diff: &diff,
+        changed_files: &prompt_files,
+        template: template.as_deref(),
+        reference: reference.as_deref(),
+    };
+    let stdin_is_terminal = std::io::stdin().is_terminal();
+    let mode = suggest::clarify_mode(no_clarify, stdin_is_terminal);
+    let mut answered = false;
+    pr_content = suggest::clarify_pr_content(&client, inputs, pr_content, |question| {
+        if mode == ClarifyMode::NonInteractive {
+            status!(
+                cli.status(),
+                "\nNote: stdin is not a terminal, skipping the clarification question: {}",
+                question
+            );
+        }
+        let answer = suggest::answer_clarification(mode, question, |question| {
+            status!(cli.status(), "\nClarification needed:");
+            Input::new()
                 .with_prompt(question)
                 .allow_empty(true)
                 .interact_text()
-                .map_err(|e| Error::User(format!("Failed to read input: {}", e)))?;
-
-            if answer.is_empty() {
-                println!("Proceeding without additional context...");
-                pr_content.needs_clarification = Some(false);
-            } else {
-                pr_content = client
-                    .generate_pr_content(
-                        &commits,
-                        &diff,
-                        &changed_files,
-                        template.as_deref(),
-                        Some(&answer),
-                        None,
-                    )
-                    .await?;
-            }
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))
+        })?;
+        if answer.is_empty() {
+            status!(cli.status(), "Proceeding without additional context...");
         } else {
-            break;
+            answered = true;
         }
+        Ok(answer)
+    })
+    .await?;
+    if answered {
+        report_usage(&client, cli.verbose > 0, cli.status());
     }
+    enforce_title(&mut pr_content, max_title, cli.status());
 
-    // Show preview
-    println!("\n{}", "=".repeat(60));
-    println!("PR PREVIEW");
-    println!("{}", "=".repeat(60));
-    println!("\nTitle: {}", pr_content.title);
-    println!("\nBody:\n{}", pr_content.body);
-    println!("\n{}", "=".repeat(60));
+    if add_screenshots {
+        pr_content.body = utils::ensure_screenshots_section(&pr_content.body);
+    }
+
+    // Show preview; with --json or --quiet only when there is a prompt to answer
+    let interactive = !cli.yes && !cli.dry_run;
+    if !(cli.json || cli.quiet) || interactive {
+        print_preview("PR PREVIEW", &pr_content, cli.status());
+    }
+
+    let cache = |content: &PRContent| utils::PrCache {
+        branch: current_branch.clone(),
+        head: head.clone(),
+        diff_hash: diff_hash.clone(),
+        created_at: unix_now(),
+        content: content.clone(),
+    };
 
     // Exit if dry-run
     if cli.dry_run {
-        println!("\n[dry-run] Would create PR with the above content.");
+        store_cache(&cache_path, &cache(&pr_content)).await;
+        if let Some(path) = &cli.output {
+            pr_content.write_markdown(path, cli.force).await?;
+            status!(cli.status(), "\nSaved PR content to {}", path.display());
+        }
+        if cli.json {
+            println!("{}", pr_content.to_json()?);
+        } else if let Some(url) = &existing_pr_url {
+            status!(
+                cli.status(),
+                "\n[dry-run] Would update {} with the above content.",
+                url
+            );
+        } else if cli.draft {
+            status!(
+                cli.status(),
+                "\n[dry-run] Would create a draft PR with the above content."
+            );
+        } else {
+            status!(
+                cli.status(),
+                "\n[dry-run] Would create PR with the above content."
+            );
+        }
         return Ok(());
     }
 
-    // Interactive adjustment loop unless --yes flag is passed
-    if !cli.yes {
+    // Interactive adjustment loop unless --yes flag is passed or there is no one to ask
+    let (question, cancelled) = match existing_pr_url {
+        Some(_) => (
+            "Update the existing PR with this content? (Y/n/comment)",
+            "PR update cancelled.",
+        ),
+        None => (
+            "Is this PR ready to create? (Y/n/comment)",
+            "PR creation cancelled.",
+        ),
+    };
+    if !cli.yes && !stdin_is_terminal && existing_pr_url.is_some() {
+        return Err(Error::User(
+            "stdin is not a terminal, pass --yes to update the existing PR without review."
+                .to_string(),
+        ));
+    } else if !cli.yes && !stdin_is_terminal {
+        status!(
+            cli.status(),
+            "\nNote: stdin is not a terminal, creating the PR without review (pass --yes to silence this)"
+        );
+    } else if !cli.yes {
         loop {
             let response: String = Input::new()
-                .with_prompt("Is this PR ready to create? (Y/n/comment)")
+                .with_prompt(question)
                 .allow_empty(true)
                 .interact_text()
-                .map_err(|e| Error::User(format!("Failed to read input: {}", e)))?;
+                .map_err(|dialoguer::Error::IO(e)| Error::prompt("input", e))?;
 
             let response_lower = response.trim().to_lowercase();
 
             if response_lower == "y" || response_lower == "yes" || response.is_empty() {
                 break;
             } else if response_lower == "n" || response_lower == "no" {
-                println!("PR creation cancelled.");
+                status!(cli.status(), "{}", cancelled);
                 return Ok(());
             } else {
-                // User provided feedback - update existing PR
-                println!("\nAdjusting PR based on your feedback...");
+                // User provided feedback - update existing PR, keeping the
+                // other field as it was after a "title:" or "body:" directive
+                status!(cli.status(), "\nAdjusting PR based on your feedback...");
 
-                pr_content = client
+                let updated = client
                     .generate_pr_content(
                         &commits,
                         &diff,
-                        &changed_files,
+                        &prompt_files,
                         template.as_deref(),
                         Some(&response),
                         Some(&pr_content),
                     )
                     .await?;
+                let (field, _) = PrField::parse(&response);
+                pr_content = field.preserve(&pr_content, updated);
+                enforce_title(&mut pr_content, max_title, cli.status());
+                report_usage(&client, cli.verbose > 0, cli.status());
+
+                if add_screenshots {
+                    pr_content.body = utils::ensure_screenshots_section(&pr_content.body);
+                }
 
                 // Show updated preview
-                println!("\n{}", "=".repeat(60));
-                println!("UPDATED PR PREVIEW");
-                println!("{}", "=".repeat(60));
-                println!("\nTitle: {}", pr_content.title);
-                println!("\nBody:\n{}", pr_content.body);
-                println!("\n{}", "=".repeat(60));
+                print_preview("UPDATED PR PREVIEW", &pr_content, cli.status());
             }
         }
     }
 
-    // Create PR
-    println!("\nCreating PR...");
-    let pr_url = git::create_pr(
+    if cli.json {
+        println!("{}", pr_content.to_json()?);
+    }
+
+    if let Some(path) = &cli.output {
+        pr_content.write_markdown(path, cli.force).await?;
+        status!(cli.status(), "\nSaved PR content to {}", path.display());
+    }
+
+    if let Some(url) = existing_pr_url {
+        status!(cli.status(), "\nUpdating PR...");
+        git::update_pr(forge, &url, &pr_content.title, &pr_content.body).await?;
+        cli.status().result(format_args!("{}", url));
+        return Ok(());
+    }
+
+    // Create PR, keeping the content around in case creation fails
+    store_cache(&cache_path, &cache(&pr_content)).await;
+    status!(
+        cli.status(),
+        "\nCreating {}PR...",
+        if cli.draft { "draft " } else { "" }
+    );
+    let status = cli.status();
+    let pr_options = git::CreatePrOptions {
+        reviewers: cli.reviewers.clone(),
+        assignees: cli.assignees.clone(),
+        labels: cli.labels.clone(),
+        draft: cli.draft,
+    };
+    let pr = git::create_pr(
+        forge,
         &pr_content.title,
         &pr_content.body,
         &base_branch,
         &current_branch,
+        &pr_options,
     )
     .await?;
-    println!("{}", pr_url);
+    match pr.number {
+        Some(number) if !status.is_quiet() => {
+            status!(status, "Created PR #{}: {}", number, pr.url)
+        }
+        _ => status.result(format_args!("{}", pr.url)),
+    }
 
     Ok(())
 }
 
+async fn run(mut cli: Cli) -> Result<()> {
+    init_logging(cli.verbose);
+
+    if let Some(Commands::Completions { shell }) = cli.command {
+        write_completions(shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    // Checks the configuration itself, so it runs before loading it
+    if let Some(Commands::Doctor) = cli.command {
+        return run_doctor(&cli).await;
+    }
+
+    // Load configuration
+    // --push-only never calls the API
+    let mut config = if cli.push_only {
+        Config::load_offline(cli.config_file.as_deref(), cli.profile.as_deref())?
+    } else {
+        Config::load(cli.config_file.as_deref(), cli.profile.as_deref())?
+    };
+    if let Some(warning) = config.api_key_warning().filter(|_| !cli.push_only) {
+        eprintln!("{}", warning);
+    }
+    if let Some(model) = cli.model.clone() {
+        config.override_model(model);
+    }
+    if let Some(language) = cli
+        .language
+        .clone()
+        .filter(|language| !language.trim().is_empty())
+    {
+        config.override_language(language.trim().to_string());
+    }
+    if let Some(max) = cli.max_subject_length {
+        config.override_max_subject_length(usize::from(max));
+    }
+    if let Some(lines) = cli.diff_context {
+        config.override_diff_context(lines);
+    }
+
+    if let Some(Commands::Config { json }) = cli.command {
+        print_config(&config, json)?;
+        return Ok(());
+    }
+    if cli.check_model {
+        // --yes never waits on a prompt, so an unlisted model is an error
+        let interactive = !cli.yes && std::io::stdin().is_terminal();
+        suggest::check_model(&mut config, interactive, pick_model).await?;
+    }
+
+    // Run from the top of the work tree, so pathspecs and repository files
+    // such as the PR template mean the same from any subdirectory. The
+    // --template, --context-file and --output paths stay relative to where
+    // autopr was started.
+    let cwd = std::env::current_dir()?;
+    cli.template = cli.template.take().map(|template| cwd.join(template));
+    cli.output = cli.output.take().map(|output| cwd.join(output));
+    for path in &mut cli.context_files {
+        *path = cwd.join(&*path);
+    }
+    std::env::set_current_dir(git::repo_root().await?)?;
+    let forge = config.forge;
+
+    // Explaining a PR only reads it
+    if let Some(Commands::Explain { number }) = &cli.command {
+        return explain_pr(number, config, &cli).await;
+    }
+    // Get the remote and the current branch, which can't change during the run
+    let (remote, current_branch) = tokio::join!(
+        git::resolve_remote(&config.remote),
+        git::require_current_branch()
+    );
+    let (remote, current_branch) = (remote?, current_branch?);
+
+    let base_branch = match cli.base.clone() {
+        Some(base) => {
+            git::check_branch_name(&base)?;
+            if !git::branch_exists(&base).await? {
+                return Err(Error::User(format!(
+                    "Base branch '{}' does not exist. Check the name, or fetch it first.",
+                    base
+                )));
+            }
+            base
+        }
+        None => git::get_default_branch(&remote).await?,
+    };
+    status!(cli.status(), "Current branch: {}", current_branch);
+    status!(cli.status(), "Base branch: {}", base_branch);
+
+    if current_branch == base_branch {
+        return Err(Error::User(format!(
+            "You are on the base branch ({}). Create a feature branch first.",
+            base_branch
+        )));
+    }
+
+    // Release PRs describe everything since a tag rather than the base branch
+    let describe_from = if cli.since_tag || cli.since_last_release {
+        let flag = if cli.since_tag {
+            "--since-tag"
+        } else {
+            "--since-last-release"
+        };
+        let tag = git::last_tag().await?.ok_or_else(|| {
+            Error::User(format!(
+                "{} found no tag reachable from HEAD. Tag a release first, or use --since <REF>.",
+                flag
+            ))
+        })?;
+        status!(cli.status(), "Describing changes since tag: {}", tag);
+        tag
+    } else if let Some(since) = cli.since.clone() {
+        if !git::branch_exists(&since).await? {
+            return Err(Error::User(format!(
+                "'{}' given to --since does not resolve to a commit.",
+                since
+            )));
+        }
+        status!(cli.status(), "Describing changes since: {}", since);
+        since
+    } else if cli.base_remote {
+        if cli.fetch {
+            status!(cli.status(), "Fetching {} from {}...", base_branch, remote);
+            git::fetch_branch(&remote, &base_branch).await?;
+        }
+        let tracking = git::remote_tracking_ref(&remote, &base_branch);
+        if !git::branch_exists(&tracking).await? {
+            return Err(Error::User(format!(
+                "{} does not exist locally. Pass --fetch to fetch it from the remote.",
+                tracking
+            )));
+        }
+        status!(cli.status(), "Describing changes since: {}", tracking);
+        tracking
+    } else {
+        base_branch.clone()
+    };
+
+    // Push and point at the page for opening the PR by hand
+    if cli.push_only {
+        let progress = Cell::new(git::PushProgress::NotPushed);
+        push_if_needed(&cli, &remote, &current_branch, &progress).await?;
+        let remote_url = git::get_remote_url(&remote).await?;
+        match git::remote_web_url(&remote_url) {
+            Some(web_url) => {
+                let url = git::compare_url(forge, &web_url, &base_branch, &current_branch);
+                if cli.status().is_quiet() {
+                    cli.status().result(format_args!("{}", url));
+                } else {
+                    cli.status()
+                        .result(format_args!("Branch {} is ready: {}", current_branch, url));
+                }
+            }
+            None => status!(
+                cli.status(),
+                "Branch {} is ready (no web URL for remote {})",
+                current_branch,
+                remote_url
+            ),
+        }
+        return Ok(());
+    }
+
+    // Fail before pushing if the PR can't be opened, and check if a PR
+    // already exists, which only --update goes on with. A dry run skips
+    // both rather than risk a login prompt.
+    let existing_pr_url = git::check_pr_target(forge, cli.dry_run).await?;
+    if let Some(url) = &existing_pr_url {
+        if !cli.update {
+            status!(
+                cli.status(),
+                "A PR already exists for this branch: {} (pass --update to regenerate it)",
+                url
+            );
+            return Ok(());
+        }
+        status!(cli.status(), "Updating the existing PR: {}", url);
+    }
+
+    // Tell the user the branch may be out there if anything from the push
+    // on fails, Ctrl-C included
+    let target = PrTarget {
+        current_branch: current_branch.clone(),
+        base_branch,
+        describe_from,
+        existing_pr_url,
+    };
+    let progress = Cell::new(git::PushProgress::NotPushed);
+    let push_and_open = async {
+        push_if_needed(&cli, &remote, &current_branch, &progress).await?;
+        open_pr(&cli, config, target).await
+    };
+    let outcome = utils::until_interrupted(push_and_open, utils::ctrl_c()).await;
+    if let Err(error) = &outcome {
+        if let Some(hint) =
+            git::push_recovery_hint(&current_branch, progress.get(), cli.update, error)
+        {
+            eprintln!("\n{}", hint);
+        }
+    }
+    outcome
+}
+
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run().await {
-        exit_with_error(e);
+    // Load .env file if it exists
+    for name in autocommit_core::config::load_dotenv() {
+        eprintln!(
+            "Warning: ignoring {} from .env, set it in the environment instead",
+            name
+        );
+    }
+
+    // Parse CLI arguments
+    let cli = Cli::parse();
+    let error_format = cli.error_format;
+    if let Err(e) = utils::until_interrupted(run(cli), utils::ctrl_c()).await {
+        exit_with_error(e, error_format);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bash_completions() {
+        let mut out = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("autopr"));
+        assert!(script.contains("--dry-run"));
+    }
+
+    #[test]
+    fn test_quiet_conflicts_with_verbose() {
+        let err = Cli::try_parse_from(["autopr", "-q", "-v"]).err().unwrap();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        assert!(Cli::try_parse_from(["autopr", "-q"]).is_ok());
+    }
+
+    #[test]
+    fn test_pr_cache_key_covers_model_and_prompt() {
+        let prompt = Prompt {
+            system: Some("Write a PR description".to_string()),
+            messages: Vec::new(),
+            max_tokens: 1024,
+        };
+        let key = pr_cache_key("model-a", &prompt);
+        assert_eq!(key, pr_cache_key("model-a", &prompt.clone()));
+        assert_ne!(key, pr_cache_key("model-b", &prompt));
+        let in_french = Prompt {
+            system: Some("Write a PR description in French".to_string()),
+            ..prompt.clone()
+        };
+        assert_ne!(key, pr_cache_key("model-a", &in_french));
     }
 }